@@ -30,6 +30,7 @@ pub enum EntityValueError {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 #[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum EntityValue {
     None,
     INT64(i64),
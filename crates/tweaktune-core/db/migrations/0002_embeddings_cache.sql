@@ -0,0 +1,8 @@
+-- embeddings cache: vectors keyed by (model, text_hash) so repeated texts are not re-embedded
+ALTER TABLE embeddings ADD COLUMN model TEXT;
+ALTER TABLE embeddings ADD COLUMN text_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS ix_embeddings_key ON embeddings(key);
+CREATE INDEX IF NOT EXISTS ix_embeddings_model_text_hash ON embeddings(model, text_hash);
+
+PRAGMA user_version = 2;
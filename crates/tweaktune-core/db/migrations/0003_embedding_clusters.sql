@@ -0,0 +1,6 @@
+-- cluster ids assigned to stored embeddings by the corpus clustering utility
+ALTER TABLE embeddings ADD COLUMN cluster INTEGER;
+
+CREATE INDEX IF NOT EXISTS ix_embeddings_key_cluster ON embeddings(key, cluster);
+
+PRAGMA user_version = 3;
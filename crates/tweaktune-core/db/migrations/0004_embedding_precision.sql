@@ -0,0 +1,4 @@
+-- storage precision of embedding blobs: f32 (raw LE floats), f16, int8 (f32 scale + i8 values)
+ALTER TABLE embeddings ADD COLUMN precision TEXT NOT NULL DEFAULT 'f32';
+
+PRAGMA user_version = 4;
@@ -0,0 +1,11 @@
+-- memoized step results: values produced by cached steps keyed by a hash of step config and inputs
+CREATE TABLE IF NOT EXISTS step_cache (
+    key TEXT PRIMARY KEY,
+    step TEXT NOT NULL,
+    value JSON NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX IF NOT EXISTS ix_step_cache_step ON step_cache(step);
+
+PRAGMA user_version = 5;
@@ -0,0 +1,6 @@
+-- stable content based item id, lets outputs be joined back to items and deduped across runs
+ALTER TABLE items ADD COLUMN item_key TEXT;
+
+CREATE INDEX IF NOT EXISTS ix_items_item_key ON items(item_key);
+
+PRAGMA user_version = 6;
@@ -0,0 +1,242 @@
+use serde_json::{Number, Value};
+
+/// Fixes common syntax slips of LLM-written JSON: single quoted strings, trailing commas
+/// and Python literals (`True`, `False`, `None`). Text around the outermost object or
+/// array is dropped. Returns the fixed text and the names of the fixes applied.
+pub fn fix_json_syntax(text: &str) -> (String, Vec<String>) {
+    let start = text.find(['{', '[']).unwrap_or(0);
+    let end = text.rfind(['}', ']']).map(|i| i + 1).unwrap_or(text.len());
+    let text = if start < end { &text[start..end] } else { text };
+
+    let mut applied = Vec::new();
+    let mut note = |fix: &str| {
+        if !applied.iter().any(|a| a == fix) {
+            applied.push(fix.to_string());
+        }
+    };
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' => {
+                // copy double quoted strings verbatim
+                out.push('"');
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        out.push(chars[i]);
+                        i += 1;
+                    }
+                    out.push(chars[i]);
+                    i += 1;
+                }
+                out.push('"');
+                i += 1;
+            }
+            '\'' => {
+                note("single_quotes");
+                out.push('"');
+                i += 1;
+                while i < chars.len() && chars[i] != '\'' {
+                    match chars[i] {
+                        '\\' if i + 1 < chars.len() && chars[i + 1] == '\'' => {
+                            out.push('\'');
+                            i += 1;
+                        }
+                        '\\' if i + 1 < chars.len() => {
+                            out.push('\\');
+                            out.push(chars[i + 1]);
+                            i += 1;
+                        }
+                        '"' => out.push_str("\\\""),
+                        c => out.push(c),
+                    }
+                    i += 1;
+                }
+                out.push('"');
+                i += 1;
+            }
+            ',' => {
+                let next = chars[i + 1..].iter().find(|c| !c.is_whitespace());
+                if matches!(next, Some('}') | Some(']')) {
+                    note("trailing_commas");
+                } else {
+                    out.push(',');
+                }
+                i += 1;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let word: String = chars[i..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .collect();
+                i += word.chars().count();
+                match word.as_str() {
+                    "True" => {
+                        note("python_literals");
+                        out.push_str("true");
+                    }
+                    "False" => {
+                        note("python_literals");
+                        out.push_str("false");
+                    }
+                    "None" => {
+                        note("python_literals");
+                        out.push_str("null");
+                    }
+                    _ => out.push_str(&word),
+                }
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    (out, applied)
+}
+
+fn schema_types(schema: &Value) -> Vec<&str> {
+    match &schema["type"] {
+        Value::String(t) => vec![t.as_str()],
+        Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+        _ => vec![],
+    }
+}
+
+/// JSON Schema type name of a value.
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn convert(value: &Value, target: &str) -> Option<Value> {
+    match (value, target) {
+        (Value::String(s), "integer") => s.trim().parse::<i64>().ok().map(Value::from),
+        (Value::String(s), "number") => s
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        (Value::String(s), "boolean") => match s.trim().to_lowercase().as_str() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (Value::Number(n), "integer") => n
+            .as_f64()
+            .filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64)
+            .map(|f| Value::from(f as i64)),
+        (Value::Number(n), "string") => Some(Value::String(n.to_string())),
+        (Value::Bool(b), "string") => Some(Value::String(b.to_string())),
+        _ => None,
+    }
+}
+
+/// Converts scalars that do not match the `type` of their schema (e.g. `"5"` for an
+/// integer) when the conversion is lossless, recursing into `properties` and `items`.
+/// Every conversion is recorded in `applied` as `path: from -> to`.
+pub fn coerce_to_schema(value: &mut Value, schema: &Value, path: &str, applied: &mut Vec<String>) {
+    let types = schema_types(schema);
+    let current = type_name(value);
+    let matches = types.is_empty()
+        || types.contains(&current)
+        || (current == "integer" && types.contains(&"number"));
+    if !matches {
+        if let Some((target, converted)) = types
+            .iter()
+            .find_map(|t| convert(value, t).map(|v| (*t, v)))
+        {
+            applied.push(format!(
+                "{}: {} -> {}",
+                if path.is_empty() { "/" } else { path },
+                current,
+                target
+            ));
+            *value = converted;
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            if let Some(properties) = schema["properties"].as_object() {
+                for (key, property) in properties {
+                    if let Some(v) = map.get_mut(key) {
+                        coerce_to_schema(v, property, &format!("{}/{}", path, key), applied);
+                    }
+                }
+            }
+        }
+        Value::Array(items) if schema["items"].is_object() => {
+            for (i, item) in items.iter_mut().enumerate() {
+                coerce_to_schema(item, &schema["items"], &format!("{}/{}", path, i), applied);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fix_json_syntax() {
+        let (fixed, applied) =
+            fix_json_syntax("Sure! Here it is: {'name': 'O\\'Hara', 'ok': True, 'tags': [1, 2,],}");
+        assert_eq!(
+            serde_json::from_str::<Value>(&fixed).unwrap(),
+            json!({"name": "O'Hara", "ok": true, "tags": [1, 2]})
+        );
+        assert_eq!(
+            applied,
+            vec!["single_quotes", "python_literals", "trailing_commas"]
+        );
+
+        let (fixed, applied) = fix_json_syntax(r#"{"text": "it's, fine", "n": None}"#);
+        assert_eq!(fixed, r#"{"text": "it's, fine", "n": null}"#);
+        assert_eq!(applied, vec!["python_literals"]);
+    }
+
+    #[test]
+    fn test_coerce_to_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "score": {"type": "number"},
+                "active": {"type": "boolean"},
+                "zip": {"type": "string"},
+                "items": {"type": "array", "items": {"type": "integer"}},
+                "name": {"type": "string"}
+            }
+        });
+        let mut value = json!({
+            "age": "5", "score": "0.5", "active": "True", "zip": 12345,
+            "items": ["1", 2.0, "x"], "name": "Ann"
+        });
+        let mut applied = vec![];
+        coerce_to_schema(&mut value, &schema, "", &mut applied);
+        assert_eq!(
+            value,
+            json!({
+                "age": 5, "score": 0.5, "active": true, "zip": "12345",
+                "items": [1, 2, "x"], "name": "Ann"
+            })
+        );
+        assert_eq!(applied.len(), 6);
+        assert!(applied.contains(&"/age: string -> integer".to_string()));
+        assert!(applied.contains(&"/items/1: number -> integer".to_string()));
+    }
+}
@@ -6,7 +6,7 @@ use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
 use polars::prelude::*;
 use rand::distr::Alphanumeric;
-use rand::{Rng, RngCore};
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
 use regex::Regex;
 use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
@@ -108,6 +108,23 @@ pub fn generate_token(length: usize) -> String {
         .collect()
 }
 
+/// Rng of the item at `index`: reproducible when a `seed` is given, from the OS otherwise.
+pub fn item_rng(seed: Option<u64>, index: u64) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(index)),
+        None => StdRng::from_os_rng(),
+    }
+}
+
+/// Derives the seed of the step `name` from the pipeline seed, so steps don't share one
+/// random sequence.
+pub fn derive_seed(seed: u64, name: &str) -> u64 {
+    let hash = blake3::hash(name.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    seed ^ u64::from_le_bytes(bytes)
+}
+
 pub fn parse_device(device_type: Option<String>) -> Result<Device> {
     let device_type = device_type.unwrap_or("cpu".to_string());
 
@@ -230,6 +247,7 @@ pub fn print_type_of<T>(_: &T) {
     println!("{}", std::any::type_name::<T>())
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum SerializationType {
     JSON,
     YAML,
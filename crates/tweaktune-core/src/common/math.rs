@@ -0,0 +1,313 @@
+//! A small arithmetic evaluator and final answer extraction for math word problems.
+//!
+//! Supported: numbers (`1,000`, `2.5`, `1e3`, `15%`), `+ - * / ^ **`, parentheses,
+//! unary minus, `sqrt`, `abs`, `floor`, `ceil`, `round`, `ln`, `log`, `pi` and `e`,
+//! plus the LaTeX forms `\frac{a}{b}`, `\sqrt{x}`, `\cdot`, `\times` and `\div`.
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static FRAC: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\\[dt]?frac\{([^{}]*)\}\{([^{}]*)\}").expect("valid frac regex"));
+static SQRT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\\sqrt\{([^{}]*)\}").expect("valid sqrt regex"));
+static ANSWER_MARKER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)(?:####|answer is:?|answer:)\s*(.+)").expect("valid marker regex")
+});
+static NUMBER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"-?\d[\d,]*(?:\.\d+)?").expect("valid number regex"));
+static NUMBER_OR_PERCENT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"-?\d[\d,]*(?:\.\d+)?%?").expect("valid number regex"));
+
+/// Evaluates an arithmetic expression.
+pub fn evaluate(expression: &str) -> Result<f64> {
+    let expression = from_latex(expression);
+    let chars: Vec<char> = expression.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut parser = Parser { chars, pos: 0 };
+    let value = parser.parse_sum()?;
+    if parser.pos != parser.chars.len() {
+        bail!(
+            "🐔 Unexpected {:?} in expression {}",
+            parser.chars[parser.pos],
+            expression
+        );
+    }
+    if !value.is_finite() {
+        bail!("🐔 Expression {} is not finite", expression);
+    }
+    Ok(value)
+}
+
+fn from_latex(expression: &str) -> String {
+    let mut expression = expression.to_string();
+    // innermost first, so nested fractions resolve
+    while FRAC.is_match(&expression) || SQRT.is_match(&expression) {
+        expression = FRAC.replace_all(&expression, "(($1)/($2))").to_string();
+        expression = SQRT.replace_all(&expression, "sqrt($1)").to_string();
+    }
+    expression
+        .replace('$', "")
+        .replace("\\left", "")
+        .replace("\\right", "")
+        .replace("\\cdot", "*")
+        .replace("\\times", "*")
+        .replace("\\div", "/")
+        .replace("\\pi", "pi")
+        .replace("\\%", "%")
+        .replace(['{', '}'], "")
+        .replace('×', "*")
+        .replace('÷', "/")
+        .replace('−', "-")
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<f64> {
+        let mut value = self.parse_product()?;
+        loop {
+            if self.eat('+') {
+                value += self.parse_product()?;
+            } else if self.eat('-') {
+                value -= self.parse_product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<f64> {
+        let mut value = self.parse_unary()?;
+        loop {
+            if self.peek() == Some('*') && self.chars.get(self.pos + 1) != Some(&'*') {
+                self.pos += 1;
+                value *= self.parse_unary()?;
+            } else if self.eat('/') {
+                value /= self.parse_unary()?;
+            } else if matches!(self.peek(), Some('(')) {
+                // implicit multiplication: 2(3+4)
+                value *= self.parse_unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.parse_unary()?);
+        }
+        if self.eat('+') {
+            return self.parse_unary();
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> Result<f64> {
+        let base = self.parse_atom()?;
+        let power = if self.eat('^') {
+            true
+        } else if self.peek() == Some('*') && self.chars.get(self.pos + 1) == Some(&'*') {
+            self.pos += 2;
+            true
+        } else {
+            false
+        };
+        if power {
+            // right associative, binds tighter than unary minus on the left
+            Ok(base.powf(self.parse_unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<f64> {
+        let value = match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.parse_sum()?;
+                if !self.eat(')') {
+                    bail!("🐔 Missing closing parenthesis");
+                }
+                value
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.parse_number()?,
+            Some(c) if c.is_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_alphabetic()) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                match name.as_str() {
+                    "pi" => std::f64::consts::PI,
+                    "e" => std::f64::consts::E,
+                    function => {
+                        let argument = self.parse_atom()?;
+                        match function {
+                            "sqrt" => argument.sqrt(),
+                            "abs" => argument.abs(),
+                            "floor" => argument.floor(),
+                            "ceil" => argument.ceil(),
+                            "round" => argument.round(),
+                            "ln" => argument.ln(),
+                            "log" => argument.log10(),
+                            _ => bail!("🐔 Unknown function {}", function),
+                        }
+                    }
+                }
+            }
+            Some(c) => bail!("🐔 Unexpected {:?} in expression", c),
+            None => bail!("🐔 Unexpected end of expression"),
+        };
+        Ok(if self.eat('%') { value / 100.0 } else { value })
+    }
+
+    fn parse_number(&mut self) -> Result<f64> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            let thousands = c == ','
+                && self.chars[self.pos + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_digit())
+                    .count()
+                    == 3;
+            if c.is_ascii_digit() || c == '.' || thousands {
+                self.pos += 1;
+            } else if (c == 'e' || c == 'E')
+                && self
+                    .chars
+                    .get(self.pos + 1)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '-')
+            {
+                self.pos += 2;
+            } else {
+                break;
+            }
+        }
+        let number: String = self.chars[start..self.pos]
+            .iter()
+            .filter(|c| **c != ',')
+            .collect();
+        number
+            .parse::<f64>()
+            .map_err(|_| anyhow!("🐔 Invalid number {}", number))
+    }
+}
+
+/// The final answer of a worked solution: the last `\boxed{...}`, the text after a
+/// GSM8K style `####` or after "answer is"/"Answer:", otherwise the last number.
+pub fn final_answer(text: &str) -> Option<String> {
+    if let Some(start) = text.rfind("\\boxed{") {
+        // balanced braces, boxed answers are often fractions
+        let mut depth = 0;
+        let content = &text[start + "\\boxed{".len()..];
+        for (i, c) in content.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => return Some(content[..i].trim().to_string()),
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(captures) = ANSWER_MARKER.captures_iter(text).last() {
+        let answer = captures[1]
+            .trim()
+            .trim_end_matches('.')
+            .trim_start_matches(['$', '£', '€'])
+            .trim()
+            .to_string();
+        if !answer.is_empty() {
+            return Some(answer);
+        }
+    }
+
+    NUMBER
+        .find_iter(text)
+        .last()
+        .map(|m| m.as_str().trim_end_matches(',').to_string())
+}
+
+/// Numeric value of an answer: the whole answer as an expression, otherwise its first
+/// number (so "18 dollars" gives 18).
+pub fn answer_value(answer: &str) -> Option<f64> {
+    if let Ok(value) = evaluate(answer) {
+        return Some(value);
+    }
+    NUMBER_OR_PERCENT
+        .find(answer)
+        .and_then(|m| evaluate(m.as_str().trim_end_matches(',')).ok())
+}
+
+/// Whether `a` and `b` are equal within the relative `tolerance`.
+pub fn approx_eq(a: f64, b: f64, tolerance: f64) -> bool {
+    (a - b).abs() <= tolerance * a.abs().max(b.abs()).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let eval = |e: &str| evaluate(e).unwrap();
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("2^3^2"), 512.0);
+        assert_eq!(eval("-2**2"), -4.0);
+        assert_eq!(eval("2(3+4)"), 14.0);
+        assert_eq!(eval("1,000 / 8"), 125.0);
+        assert_eq!(eval("15% * 200"), 30.0);
+        assert_eq!(eval("sqrt(16) + abs(-2)"), 6.0);
+        assert_eq!(eval("1.5e2"), 150.0);
+        assert_eq!(eval("\\frac{3}{4}"), 0.75);
+        assert_eq!(eval("$\\frac{\\frac{1}{2}}{2}$"), 0.25);
+        assert_eq!(eval("2 \\times 3 \\div 4"), 1.5);
+        assert_eq!(eval("\\sqrt{9}"), 3.0);
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("foo(2)").is_err());
+        assert!(evaluate("1/0").is_err());
+    }
+
+    #[test]
+    fn test_final_answer() {
+        assert_eq!(
+            final_answer("So x = \\boxed{\\frac{1}{2}}."),
+            Some("\\frac{1}{2}".to_string())
+        );
+        assert_eq!(
+            final_answer("48/2 = <<48/2=24>>24 clips\n#### 72"),
+            Some("72".to_string())
+        );
+        assert_eq!(
+            final_answer("The answer is $1,250."),
+            Some("1,250".to_string())
+        );
+        assert_eq!(
+            final_answer("She has 3 apples, then 5."),
+            Some("5".to_string())
+        );
+        assert_eq!(final_answer("no idea"), None);
+        assert_eq!(answer_value("18 dollars"), Some(18.0));
+        assert_eq!(answer_value("1,250"), Some(1250.0));
+        assert!(approx_eq(0.1 + 0.2, 0.3, 1e-9));
+        assert!(!approx_eq(72.0, 73.0, 1e-6));
+    }
+}
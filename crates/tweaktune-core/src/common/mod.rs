@@ -1,4 +1,8 @@
+pub mod coerce;
 pub mod dedup;
 mod internal;
+pub mod math;
+pub mod sink;
 pub mod validators;
+pub mod workers;
 pub use self::internal::*;
@@ -0,0 +1,482 @@
+//! Buffered output files shared by the line based writers.
+//!
+//! Lines are appended to `<path>.partial` and written out once the buffer fills up or the
+//! flush interval has passed. `finish` writes the rest, fsyncs and renames the file to
+//! `path`, so an interrupted run leaves the previous output untouched instead of a torn
+//! last line. Writers of the same path share one open file.
+//!
+//! With an opendal operator the lines are streamed to the object named like the file of
+//! `path` instead, the object only appears once the upload is closed. Objects can't be
+//! appended to, so appending reads the current object back and uploads it again first.
+use anyhow::{bail, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression as GzCompression;
+use once_cell::sync::Lazy;
+use opendal::Operator;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+type SharedFile = Arc<tokio::sync::Mutex<Option<OpenFile>>>;
+
+static OPEN_FILES: Lazy<Mutex<HashMap<String, SharedFile>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Output compression of the line based writers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => bail!("🐔 Unknown compression {}, use none, gzip or zstd", s),
+        }
+    }
+}
+
+impl Compression {
+    /// The explicit `compression`, otherwise the one implied by the `.gz`/`.zst` extension.
+    pub fn resolve(path: &str, compression: Option<&str>) -> Result<Self> {
+        if let Some(compression) = compression {
+            return compression.parse();
+        }
+        Ok(if path.ends_with(".gz") || path.ends_with(".gzip") {
+            Compression::Gzip
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        })
+    }
+
+    /// Decompressed content of the file, compressed appends are read as one stream.
+    pub fn read(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        self.decode(std::io::BufReader::new(File::open(path)?))
+    }
+
+    pub fn decode(&self, mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        match self {
+            Compression::None => reader.read_to_end(&mut content)?,
+            Compression::Gzip => MultiGzDecoder::new(reader).read_to_end(&mut content)?,
+            Compression::Zstd => zstd::Decoder::new(reader)?.read_to_end(&mut content)?,
+        };
+        Ok(content)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// Whether a run continues the existing output, replaces it or refuses to touch it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    Append,
+    Overwrite,
+    ErrorIfExists,
+}
+
+impl FromStr for WriteMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "append" => Ok(WriteMode::Append),
+            "overwrite" => Ok(WriteMode::Overwrite),
+            "error-if-exists" | "error_if_exists" => Ok(WriteMode::ErrorIfExists),
+            _ => bail!(
+                "🐔 Unknown write mode {}, use append, overwrite or error-if-exists",
+                s
+            ),
+        }
+    }
+}
+
+/// Encodes into memory, the output is taken from the inner buffer when flushing.
+enum Encoder {
+    Plain(Vec<u8>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Encoder::Plain(Vec::new()),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), GzCompression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(Vec::new(), 0)?),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(buffer) => writeln!(buffer, "{}", line),
+            Encoder::Gzip(encoder) => writeln!(encoder, "{}", line),
+            Encoder::Zstd(encoder) => writeln!(encoder, "{}", line),
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Plain(buffer) => buffer,
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoder::Plain(buffer) => buffer,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+enum Target {
+    Local(tokio::fs::File),
+    Remote(opendal::Writer),
+}
+
+struct OpenFile {
+    path: String,
+    target: Target,
+    encoder: Encoder,
+    flushed: Instant,
+}
+
+impl OpenFile {
+    /// Starts the partial file, or the upload, from the current output when appending. The
+    /// `header` goes first into empty outputs.
+    async fn open(sink: &LineSink, path: &str) -> Result<Self> {
+        if sink.mode == WriteMode::ErrorIfExists && sink.exists(path).await? {
+            bail!("🐔 Output {} already exists", path);
+        }
+        let mut encoder = Encoder::new(sink.compression)?;
+        let (target, empty) = match &sink.operator {
+            Some(operator) => {
+                let current = match sink.mode {
+                    WriteMode::Append => sink.read(path).await?.unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+                let empty = current.is_empty();
+                let mut writer = operator.writer(&object_name(path)).await?;
+                if !empty {
+                    writer.write(current).await?;
+                }
+                (Target::Remote(writer), empty)
+            }
+            None => {
+                let partial = partial_path(path);
+                let copied =
+                    if sink.mode == WriteMode::Append && tokio::fs::try_exists(path).await? {
+                        tokio::fs::copy(path, &partial).await?
+                    } else {
+                        tokio::fs::File::create(&partial).await?;
+                        0
+                    };
+                let file = tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&partial)
+                    .await?;
+                (Target::Local(file), copied == 0)
+            }
+        };
+        if let (true, Some(header)) = (empty, &sink.header) {
+            encoder.write_line(header)?;
+        }
+        Ok(Self {
+            path: path.to_string(),
+            target,
+            encoder,
+            flushed: Instant::now(),
+        })
+    }
+
+    async fn write(&mut self, output: Vec<u8>) -> Result<()> {
+        if output.is_empty() {
+            return Ok(());
+        }
+        match &mut self.target {
+            Target::Local(file) => {
+                // tokio files write in the background until flushed
+                file.write_all(&output).await?;
+                file.flush().await?;
+            }
+            Target::Remote(writer) => writer.write(output).await?,
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let output = std::mem::take(self.encoder.output());
+        self.write(output).await?;
+        self.flushed = Instant::now();
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        let output = std::mem::replace(&mut self.encoder, Encoder::Plain(Vec::new())).finish()?;
+        self.write(output).await?;
+        match &mut self.target {
+            Target::Local(file) => {
+                file.sync_all().await?;
+                tokio::fs::rename(partial_path(&self.path), &self.path).await?;
+            }
+            Target::Remote(writer) => {
+                writer.close().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Name of the object `path` is written to, relative to the root of the operator like
+/// in the readers.
+pub fn object_name(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The file the output of `path` is written to until it is finished.
+pub fn partial_path(path: &str) -> String {
+    format!("{}.partial", path)
+}
+
+fn open_file(path: &str) -> Result<SharedFile> {
+    let mut files = OPEN_FILES.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+    Ok(files.entry(path.to_string()).or_default().clone())
+}
+
+/// Appends lines to one output path at a time, switching to another path finishes the
+/// previous one.
+pub struct LineSink {
+    pub compression: Compression,
+    pub flush_interval: Duration,
+    pub operator: Option<Operator>,
+    pub mode: WriteMode,
+    pub header: Option<String>,
+    current: tokio::sync::Mutex<Option<String>>,
+}
+
+impl LineSink {
+    pub fn new(
+        compression: Compression,
+        flush_interval: Duration,
+        operator: Option<Operator>,
+    ) -> Self {
+        Self {
+            compression,
+            flush_interval,
+            operator,
+            mode: WriteMode::Append,
+            header: None,
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: WriteMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// A line written first to every output that starts empty.
+    pub fn with_header(mut self, header: Option<String>) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub async fn write_line(&self, path: &str, line: &str) -> Result<()> {
+        let mut current = self.current.lock().await;
+        if current.as_deref() != Some(path) {
+            if let Some(previous) = current.take() {
+                finish_file(&previous).await?;
+            }
+            *current = Some(path.to_string());
+        }
+
+        let file = open_file(path)?;
+        let mut file = file.lock().await;
+        if file.is_none() {
+            *file = Some(OpenFile::open(self, path).await?);
+        }
+        if let Some(file) = file.as_mut() {
+            file.encoder.write_line(line)?;
+            if file.encoder.output().len() >= BUFFER_SIZE
+                || file.flushed.elapsed() >= self.flush_interval
+            {
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        if let Some(path) = self.current.lock().await.take() {
+            finish_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn exists(&self, path: &str) -> Result<bool> {
+        Ok(match &self.operator {
+            Some(operator) => operator.exists(&object_name(path)).await?,
+            None => tokio::fs::try_exists(path).await?,
+        })
+    }
+
+    /// Raw content of a finished output, `None` when it does not exist.
+    pub async fn read(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        if !self.exists(path).await? {
+            return Ok(None);
+        }
+        Ok(Some(match &self.operator {
+            Some(operator) => operator.read(&object_name(path)).await?.to_vec(),
+            None => tokio::fs::read(path).await?,
+        }))
+    }
+
+    /// Replaces a whole output at once, e.g. a manifest next to the lines.
+    pub async fn write(&self, path: &str, content: Vec<u8>) -> Result<()> {
+        match &self.operator {
+            Some(operator) => {
+                operator.write(&object_name(path), content).await?;
+            }
+            None => {
+                let partial = partial_path(path);
+                let mut file = tokio::fs::File::create(&partial).await?;
+                file.write_all(&content).await?;
+                file.sync_all().await?;
+                tokio::fs::rename(&partial, path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, path: &str) -> Result<()> {
+        match &self.operator {
+            Some(operator) => operator.delete(&object_name(path)).await?,
+            None => tokio::fs::remove_file(path).await?,
+        }
+        Ok(())
+    }
+}
+
+async fn finish_file(path: &str) -> Result<()> {
+    // another writer of the same path may have finished it already
+    let file = open_file(path)?.lock().await.take();
+    if let Some(file) = file {
+        file.finish().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_line_sink() -> Result<()> {
+        assert_eq!(Compression::resolve("a.jsonl", None)?, Compression::None);
+        assert_eq!(Compression::resolve("a.jsonl.gz", None)?, Compression::Gzip);
+        assert_eq!(Compression::resolve("a.csv.zst", None)?, Compression::Zstd);
+        assert_eq!(
+            Compression::resolve("a.jsonl.gz", Some("none"))?,
+            Compression::None
+        );
+        assert!(Compression::resolve("a.jsonl", Some("lz4")).is_err());
+
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.jsonl").to_string_lossy().to_string();
+        std::fs::write(&path, "old\n")?;
+
+        // two writers of the same path share the partial file
+        let first = LineSink::new(Compression::None, Duration::from_secs(3600), None);
+        let second = LineSink::new(Compression::None, Duration::from_secs(3600), None);
+        first.write_line(&path, "a").await?;
+        second.write_line(&path, "b").await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "old\n");
+        assert!(std::path::Path::new(&partial_path(&path)).exists());
+
+        first.finish().await?;
+        second.finish().await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "old\na\nb\n");
+        assert!(!std::path::Path::new(&partial_path(&path)).exists());
+
+        // a zero interval writes every line through
+        let eager = LineSink::new(Compression::None, Duration::ZERO, None);
+        eager.write_line(&path, "c").await?;
+        assert_eq!(
+            std::fs::read_to_string(partial_path(&path))?,
+            "old\na\nb\nc\n"
+        );
+        eager.finish().await?;
+
+        // overwriting starts over, the header goes into the empty output
+        let header = LineSink::new(Compression::None, Duration::ZERO, None)
+            .with_mode(WriteMode::Overwrite)
+            .with_header(Some("h".to_string()));
+        header.write_line(&path, "d").await?;
+        header.finish().await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "h\nd\n");
+        let appended = LineSink::new(Compression::None, Duration::ZERO, None)
+            .with_header(Some("h".to_string()));
+        appended.write_line(&path, "e").await?;
+        appended.finish().await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "h\nd\ne\n");
+        let refused = LineSink::new(Compression::None, Duration::ZERO, None)
+            .with_mode(WriteMode::ErrorIfExists);
+        assert!(refused.write_line(&path, "f").await.is_err());
+        assert_eq!(refused.read(&path).await?, Some(b"h\nd\ne\n".to_vec()));
+
+        // remote output goes through the operator, nothing is written next to `path`
+        let remote = tmp.path().join("remote");
+        let config = format!(r#"{{"type": "Fs", "root": "{}"}}"#, remote.display());
+        let operator = crate::readers::build_operator(&path, Some(config))?;
+        let upload = LineSink::new(Compression::Gzip, Duration::ZERO, Some(operator));
+        let path = "out/remote.jsonl.gz";
+        upload.write_line(path, "a").await?;
+        upload.write_line(path, "b").await?;
+        upload.finish().await?;
+        let uploaded = remote.join("remote.jsonl.gz").to_string_lossy().to_string();
+        assert_eq!(Compression::Gzip.read(&uploaded)?, b"a\nb\n");
+        assert!(!std::path::Path::new(&partial_path(path)).exists());
+
+        // appending uploads the current object again, overwriting replaces it
+        upload.write_line(path, "c").await?;
+        upload.finish().await?;
+        assert_eq!(Compression::Gzip.read(&uploaded)?, b"a\nb\nc\n");
+        let replace = LineSink::new(Compression::Gzip, Duration::ZERO, upload.operator.clone())
+            .with_mode(WriteMode::Overwrite);
+        replace.write_line(path, "d").await?;
+        replace.finish().await?;
+        assert_eq!(Compression::Gzip.read(&uploaded)?, b"d\n");
+        Ok(())
+    }
+}
@@ -839,14 +839,8 @@ pub fn validate_tool_call_schema(value: &Value) -> Result<()> {
 mod tests {
     use super::*;
     // use crate::common::*; // not used in these tests
-    use serde::{Deserialize, Serialize};
     use serde_json::json;
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    struct Person {
-        name: String,
-    }
-
     #[tokio::test]
     async fn test_validate_function_call_format_tool_valid() -> Result<()> {
         use serde_json::json;
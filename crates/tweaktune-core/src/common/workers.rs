@@ -0,0 +1,83 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+tokio::task_local! {
+    static WORKER: usize;
+}
+
+/// Runs `future` as the worker `worker`, see [`current_worker`].
+pub async fn in_worker<F: Future>(worker: usize, future: F) -> F::Output {
+    WORKER.scope(worker, future).await
+}
+
+/// The worker processing the current item, `None` outside of a run.
+pub fn current_worker() -> Option<usize> {
+    WORKER.try_with(|worker| *worker).ok()
+}
+
+/// Numbers the items in progress: an item takes the lowest worker free, so with `n` items
+/// at once the workers are `0..n`.
+#[derive(Debug, Default)]
+pub struct WorkerIds {
+    free: Mutex<BTreeSet<usize>>,
+    next: AtomicUsize,
+}
+
+impl WorkerIds {
+    pub fn acquire(&self) -> usize {
+        if let Some(worker) = self.free.lock().unwrap().pop_first() {
+            return worker;
+        }
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn release(&self, worker: usize) {
+        self.free.lock().unwrap().insert(worker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::StepContext;
+    use crate::PipelineResources;
+    use rand::RngCore;
+
+    #[tokio::test]
+    async fn test_workers() {
+        assert_eq!(current_worker(), None);
+        assert_eq!(in_worker(3, async { current_worker() }).await, Some(3));
+
+        let ids = WorkerIds::default();
+        assert_eq!((ids.acquire(), ids.acquire(), ids.acquire()), (0, 1, 2));
+        ids.release(1);
+        ids.release(0);
+        assert_eq!((ids.acquire(), ids.acquire(), ids.acquire()), (0, 1, 3));
+    }
+
+    #[tokio::test]
+    async fn test_worker_rng() {
+        let mut resources = PipelineResources::new(None);
+        resources.set_seed(7);
+        resources.worker_rng = true;
+        let context = StepContext::new();
+        let draw = |worker| {
+            let (resources, context) = (&resources, &context);
+            in_worker(worker, async move {
+                resources.rng("S", None, context).next_u64()
+            })
+        };
+        let first = draw(0).await;
+        assert_ne!(first, draw(0).await);
+        assert_ne!(first, draw(1).await);
+        resources.reset_worker_rngs();
+        assert_eq!(first, draw(0).await);
+        // outside of a worker the rng of the item
+        assert_eq!(
+            resources.rng("S", None, &context).next_u64(),
+            resources.rng("S", None, &context).next_u64()
+        );
+    }
+}
@@ -1,11 +1,14 @@
-use crate::common::{create_rows_stream, df_to_values};
+use crate::common::{create_rows_stream, df_to_values, item_rng};
 use crate::config::read_config;
 use crate::dictionaries::phf_to_df;
 use crate::readers::build_reader;
+use crate::steps::dataset_df;
+use crate::Resources;
 use anyhow::Result;
 use polars::prelude::*;
 use polars_utils::mmap::MemSlice;
-use rand::seq::IndexedRandom;
+use rand::seq::{IndexedRandom, SliceRandom};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
@@ -411,12 +414,15 @@ impl MixedDataset {
         Ok(Value::Object(mix_obj))
     }
 
+    /// Random `size` combinations, all of them in random order without a `size`.
     pub fn sample(
         &self,
-        size: usize,
+        size: Option<usize>,
         datasets: &HashMap<String, DatasetType>,
+        rng: &mut impl Rng,
     ) -> Result<Vec<Value>> {
         let total = self.indexes.len();
+        let size = size.unwrap_or(total);
         if size > total {
             return Err(anyhow::anyhow!(
                 "Sample size exceeds total number of combinations"
@@ -424,7 +430,7 @@ impl MixedDataset {
         }
         let samples = self
             .indexes
-            .choose_multiple(&mut rand::rng(), size)
+            .choose_multiple(rng, size)
             .cloned()
             .collect::<Vec<_>>();
         let num = samples.len();
@@ -458,6 +464,199 @@ impl Dataset for MixedDataset {
     }
 }
 
+/// Reads a registered dataset from its source.
+type ReadDataset = Box<dyn FnOnce() -> Result<DatasetType> + Send + Sync>;
+
+enum PendingDataset {
+    Read(ReadDataset),
+    /// Mixed from the named datasets once they are read.
+    Mixed(Vec<String>),
+}
+
+/// Datasets registered but not read yet. Reading them waits for the run to start, then they
+/// are all read at once, each on its own thread.
+#[derive(Default)]
+pub struct DatasetLoader {
+    pending: Vec<(String, PendingDataset)>,
+}
+
+impl DatasetLoader {
+    pub fn add(
+        &mut self,
+        name: String,
+        read: impl FnOnce() -> Result<DatasetType> + Send + Sync + 'static,
+    ) {
+        self.pending
+            .push((name, PendingDataset::Read(Box::new(read))));
+    }
+
+    pub fn add_mixed(&mut self, name: String, datasets: Vec<String>) {
+        self.pending.push((name, PendingDataset::Mixed(datasets)));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pending.iter().any(|(pending, _)| pending == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Reads the datasets of all the `loaders` in parallel, calling `loaded` with the name, the
+    /// rows and the seconds of each as it's read. The datasets come back in the order of the
+    /// `loaders`, to be added with [`LoadedDatasets::add_to`].
+    pub fn load_all(
+        loaders: Vec<DatasetLoader>,
+        mut loaded: impl FnMut(&str, usize, f64),
+    ) -> Result<Vec<LoadedDatasets>> {
+        std::thread::scope(|scope| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let mut slots = loaders
+                .into_iter()
+                .enumerate()
+                .map(|(loader, DatasetLoader { pending })| {
+                    pending
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, (name, dataset))| match dataset {
+                            PendingDataset::Read(read) => {
+                                let sender = sender.clone();
+                                scope.spawn(move || {
+                                    let started = std::time::Instant::now();
+                                    let dataset = read();
+                                    sender
+                                        .send((loader, index, dataset, started.elapsed()))
+                                        .ok();
+                                });
+                                (name, None)
+                            }
+                            PendingDataset::Mixed(datasets) => {
+                                (name, Some(LoadedDataset::Mixed(datasets)))
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            drop(sender);
+            for (loader, index, dataset, elapsed) in receiver {
+                let (name, slot) = &mut slots[loader][index];
+                let dataset = dataset
+                    .map_err(|e| e.context(format!("🐔 Failed to load dataset {}", name)))?;
+                let rows = dataset_df(&dataset).map_or(0, DataFrame::height);
+                loaded(name, rows, elapsed.as_secs_f64());
+                *slot = Some(LoadedDataset::Read(dataset));
+            }
+            Ok(slots
+                .into_iter()
+                .map(|slots| {
+                    LoadedDatasets(
+                        slots
+                            .into_iter()
+                            .map(|(name, slot)| (name, slot.expect("Dataset not read")))
+                            .collect(),
+                    )
+                })
+                .collect())
+        })
+    }
+}
+
+enum LoadedDataset {
+    Read(DatasetType),
+    Mixed(Vec<String>),
+}
+
+/// Datasets read by [`DatasetLoader::load_all`], in the order they were registered.
+pub struct LoadedDatasets(Vec<(String, LoadedDataset)>);
+
+impl LoadedDatasets {
+    /// Adds the datasets to `datasets`, a mixed dataset once those it mixes were added.
+    pub fn add_to(self, datasets: &mut Resources<DatasetType>) -> Result<()> {
+        for (name, dataset) in self.0 {
+            let dataset = match dataset {
+                LoadedDataset::Read(dataset) => dataset,
+                LoadedDataset::Mixed(selected) => DatasetType::Mixed(MixedDataset::new(
+                    name.clone(),
+                    selected,
+                    &datasets.resources,
+                )?),
+            };
+            datasets.add(name, dataset);
+        }
+        Ok(())
+    }
+}
+
+/// Hash of the schema and content of `df`, identifies the data a run was made from.
+pub fn fingerprint_df(df: &DataFrame) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    IpcWriter::new(&mut hasher).finish(&mut df.clone())?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Rows of the `datasets` side by side, as objects keyed by the dataset name, until the
+/// shortest dataset ends.
+pub fn zip_rows<'a>(
+    datasets: &'a [(String, &'a DataFrame)],
+) -> Result<impl Iterator<Item = Result<Value>> + 'a> {
+    let mut streams = datasets
+        .iter()
+        .map(|(name, df)| Ok((name, create_rows_stream(df)?)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(std::iter::from_fn(move || {
+        let mut row = serde_json::Map::new();
+        for (name, stream) in streams.iter_mut() {
+            match stream.next()? {
+                Ok(value) => row.insert(name.to_string(), value),
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        Some(Ok(Value::Object(row)))
+    }))
+}
+
+/// Repeats the `rows` (objects keyed by the dataset name) for `epochs` epochs, adding the
+/// `epoch` to each. With `shuffle` every epoch comes in a new order, reproducible with a `seed`.
+pub fn epoch_rows(
+    rows: Vec<Value>,
+    epochs: usize,
+    shuffle: bool,
+    seed: Option<u64>,
+) -> impl Iterator<Item = Value> {
+    (0..epochs).flat_map(move |epoch| {
+        let mut order = (0..rows.len()).collect::<Vec<_>>();
+        if shuffle {
+            order.shuffle(&mut item_rng(seed, epoch as u64));
+        }
+        order
+            .into_iter()
+            .map(|i| {
+                let mut row = rows[i].clone();
+                row["epoch"] = json!(epoch);
+                row
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Every combination of rows of the `datasets`, as objects keyed by the dataset name. The
+/// last dataset varies fastest.
+pub fn product_rows(datasets: &[(String, &DataFrame)]) -> Result<impl Iterator<Item = Value>> {
+    let values = datasets
+        .iter()
+        .map(|(name, df)| Ok((name.clone(), df_to_values(df)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let total = values.iter().map(|(_, rows)| rows.len()).product::<usize>();
+    Ok((0..total).map(move |mut index| {
+        let mut row = serde_json::Map::new();
+        for (name, rows) in values.iter().rev() {
+            row.insert(name.clone(), rows[index % rows.len()].clone());
+            index /= rows.len();
+        }
+        Value::Object(row)
+    }))
+}
+
 #[derive(Clone, Debug)]
 pub struct OpenApiDataset {
     _name: String,
@@ -757,9 +956,122 @@ struct OpenApiProperty {
 
 #[cfg(test)]
 mod tests {
-    use anyhow::Result;
+    use super::*;
     // use serde_json;
 
+    #[test]
+    fn test_fingerprint_df() -> Result<()> {
+        let questions = df!("q" => ["a", "b"])?;
+        assert_eq!(
+            fingerprint_df(&questions)?,
+            fingerprint_df(&df!("q" => ["a", "b"])?)?
+        );
+        assert_ne!(
+            fingerprint_df(&questions)?,
+            fingerprint_df(&df!("q" => ["a", "c"])?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dataset_loader() -> Result<()> {
+        let json_list = |name: &str, rows: &[&str]| {
+            let (name, rows) = (name.to_string(), rows.iter().map(|row| row.to_string()));
+            let rows = rows.collect::<Vec<_>>();
+            move || {
+                Ok(DatasetType::JsonList(JsonListDataset::new(
+                    name, rows, None,
+                )?))
+            }
+        };
+        let mut loader = DatasetLoader::default();
+        loader.add(
+            "q".to_string(),
+            json_list("q", &[r#"{"q":"a"}"#, r#"{"q":"b"}"#]),
+        );
+        loader.add("c".to_string(), json_list("c", &[r#"{"c":1}"#]));
+        loader.add_mixed("mix".to_string(), vec!["q".to_string(), "c".to_string()]);
+        assert!(loader.contains("mix") && !loader.contains("other"));
+
+        let mut other = DatasetLoader::default();
+        other.add("o".to_string(), json_list("o", &[r#"{"o":1}"#]));
+        let mut read = Vec::new();
+        let loaded = DatasetLoader::load_all(vec![loader, other], |name, rows, _| {
+            read.push((name.to_string(), rows))
+        })?;
+        read.sort();
+        assert_eq!(
+            read,
+            vec![
+                ("c".to_string(), 1),
+                ("o".to_string(), 1),
+                ("q".to_string(), 2)
+            ]
+        );
+        let empty = || Resources {
+            resources: HashMap::new(),
+        };
+        let (mut datasets, mut others) = (empty(), empty());
+        let mut loaded = loaded.into_iter();
+        loaded.next().unwrap().add_to(&mut datasets)?;
+        loaded.next().unwrap().add_to(&mut others)?;
+        assert!(matches!(datasets.get("mix"), Some(DatasetType::Mixed(_))));
+        assert_eq!(
+            (datasets.list().len(), others.list()),
+            (3, vec!["o".to_string()])
+        );
+
+        let mut loader = DatasetLoader::default();
+        loader.add("bad".to_string(), || Err(anyhow::anyhow!("no such file")));
+        let error = DatasetLoader::load_all(vec![loader], |_, _, _| {})
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "🐔 Failed to load dataset bad");
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip_and_product_rows() -> Result<()> {
+        let questions = df!("q" => ["a", "b", "c"])?;
+        let contexts = df!("c" => [1, 2])?;
+        let datasets = vec![
+            ("questions".to_string(), &questions),
+            ("contexts".to_string(), &contexts),
+        ];
+
+        let zipped = zip_rows(&datasets)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            zipped,
+            vec![
+                json!({"questions": {"q": "a"}, "contexts": {"c": 1}}),
+                json!({"questions": {"q": "b"}, "contexts": {"c": 2}}),
+            ]
+        );
+
+        let epochs = epoch_rows(zipped.clone(), 3, true, Some(7)).collect::<Vec<_>>();
+        assert_eq!(epochs.len(), 6);
+        assert_eq!(epochs[5]["epoch"], 2);
+        assert!(epochs[..2].iter().all(|row| row["epoch"] == 0));
+        assert_eq!(
+            epochs,
+            epoch_rows(zipped.clone(), 3, true, Some(7)).collect::<Vec<_>>()
+        );
+        let ordered = epoch_rows(zipped.clone(), 2, false, None).collect::<Vec<_>>();
+        assert_eq!(ordered[2]["questions"], zipped[0]["questions"]);
+
+        let product = product_rows(&datasets)?.collect::<Vec<_>>();
+        assert_eq!(product.len(), 6);
+        assert_eq!(
+            product[1],
+            json!({"questions": {"q": "a"}, "contexts": {"c": 2}})
+        );
+        assert_eq!(
+            product[5],
+            json!({"questions": {"q": "c"}, "contexts": {"c": 2}})
+        );
+        Ok(())
+    }
+
     #[test]
     fn it_works() -> Result<()> {
         //let url = "https://petstore3.swagger.io/api/v3/openapi.json";
@@ -1,4 +1,5 @@
 pub mod openings;
+pub mod personas;
 use polars::prelude::*;
 
 pub fn phf_to_df(set: &phf::Set<&'static str>, column_name: &str) -> DataFrame {
@@ -18,3 +19,10 @@ pub fn get_ask_words() -> DataFrame {
 pub fn get_neutral_words() -> DataFrame {
     phf_to_df(&openings::NEUTRAL, "neutral")
 }
+
+pub fn get_persona_attribute(name: &str) -> Option<DataFrame> {
+    personas::ATTRIBUTES
+        .iter()
+        .find(|(attribute, _)| *attribute == name)
+        .map(|(attribute, set)| phf_to_df(set, attribute))
+}
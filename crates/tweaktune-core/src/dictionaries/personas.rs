@@ -0,0 +1,93 @@
+use phf::phf_set;
+
+pub static AGE_GROUP: phf::Set<&'static str> = phf_set! {
+    "teenager",
+    "young adult",
+    "adult in their thirties",
+    "middle-aged adult",
+    "adult in their fifties",
+    "senior"
+};
+
+pub static GENDER: phf::Set<&'static str> = phf_set! {
+    "woman",
+    "man",
+    "non-binary person"
+};
+
+pub static OCCUPATION: phf::Set<&'static str> = phf_set! {
+    "student",
+    "teacher",
+    "nurse",
+    "software engineer",
+    "accountant",
+    "small business owner",
+    "lawyer",
+    "electrician",
+    "farmer",
+    "journalist",
+    "retail worker",
+    "researcher",
+    "graphic designer",
+    "truck driver",
+    "chef",
+    "retiree",
+    "project manager",
+    "pharmacist",
+    "marketing specialist",
+    "civil servant"
+};
+
+pub static EXPERTISE: phf::Set<&'static str> = phf_set! {
+    "novice",
+    "casual user",
+    "intermediate",
+    "advanced",
+    "domain expert"
+};
+
+pub static TONE: phf::Set<&'static str> = phf_set! {
+    "formal",
+    "casual",
+    "friendly",
+    "terse",
+    "impatient",
+    "curious",
+    "skeptical",
+    "enthusiastic",
+    "polite",
+    "frustrated"
+};
+
+pub static STYLE: phf::Set<&'static str> = phf_set! {
+    "short questions",
+    "detailed explanations",
+    "bullet points",
+    "step by step requests",
+    "informal chat with typos",
+    "precise technical language"
+};
+
+pub static REGION: phf::Set<&'static str> = phf_set! {
+    "Western Europe",
+    "Central Europe",
+    "Northern Europe",
+    "Southern Europe",
+    "North America",
+    "Latin America",
+    "East Asia",
+    "South Asia",
+    "Africa",
+    "Oceania"
+};
+
+/// Persona attributes by name, in the order they are written into the context.
+pub static ATTRIBUTES: [(&str, &phf::Set<&'static str>); 7] = [
+    ("age_group", &AGE_GROUP),
+    ("gender", &GENDER),
+    ("occupation", &OCCUPATION),
+    ("expertise", &EXPERTISE),
+    ("tone", &TONE),
+    ("style", &STYLE),
+    ("region", &REGION),
+];
@@ -0,0 +1,174 @@
+use crate::common::hf_hub_get;
+use crate::common::{parse_device, ResultExt};
+use crate::embeddings::Embeddings;
+use anyhow::{bail, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config as BertConfig};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+pub const BERT_MODEL_REPO: &str = "sentence-transformers/all-MiniLM-L6-v2";
+
+static BERT_INSTANCES: OnceCell<Mutex<HashMap<String, Arc<Mutex<BertEmbeddingsModel>>>>> =
+    OnceCell::new();
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// Average of the token embeddings, ignoring padding (MiniLM, mpnet).
+    #[default]
+    Mean,
+    /// Embedding of the first ([CLS]) token (BGE).
+    Cls,
+}
+
+impl std::str::FromStr for Pooling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(Pooling::Mean),
+            "cls" => Ok(Pooling::Cls),
+            _ => bail!("🐔 Unsupported pooling: {}", s),
+        }
+    }
+}
+
+/// Sentence-transformers style BERT encoder (MiniLM, BGE-small, ...).
+#[derive(Deserialize, Debug, Clone)]
+pub struct BertSpec {
+    pub name: String,
+    pub model_repo: Option<String>,
+    pub device: Option<String>,
+    pub hf_token: Option<String>,
+    pub pooling: Option<Pooling>,
+    pub normalize: Option<bool>,
+}
+
+pub struct BertEmbeddingsModel {
+    pub spec: BertSpec,
+    pub model: BertModel,
+    pub tokenizer: Tokenizer,
+    pub pooling: Pooling,
+    pub normalize: bool,
+    pub device: Device,
+}
+
+impl BertEmbeddingsModel {
+    pub fn lazy(spec: BertSpec) -> Result<Arc<Mutex<BertEmbeddingsModel>>> {
+        let name = spec.name.clone();
+
+        if BERT_INSTANCES.get().is_none() {
+            let _ = BERT_INSTANCES.set(Mutex::new(HashMap::new()));
+        }
+
+        let map = BERT_INSTANCES.get().expect("BERT_INSTANCES");
+        {
+            let guard = map.lock().map_anyhow_err()?;
+            if let Some(existing) = guard.get(&name) {
+                return Ok(existing.clone());
+            }
+        }
+
+        let model = BertEmbeddingsModel::load(spec)?;
+        let arc = Arc::new(Mutex::new(model));
+        let mut guard = map.lock().map_anyhow_err()?;
+        guard.insert(name, arc.clone());
+        Ok(arc)
+    }
+
+    pub fn load(spec: BertSpec) -> Result<BertEmbeddingsModel> {
+        let spec_clone = spec.clone();
+        let model_repo = spec
+            .model_repo
+            .clone()
+            .unwrap_or_else(|| BERT_MODEL_REPO.to_string());
+        let weights = hf_hub_get(
+            &model_repo,
+            "model.safetensors",
+            spec.hf_token.clone(),
+            None,
+        )?;
+        let tokenizer = hf_hub_get(&model_repo, "tokenizer.json", spec.hf_token.clone(), None)?;
+        let candle_config = hf_hub_get(&model_repo, "config.json", spec.hf_token, None)?;
+        let candle_config: BertConfig = serde_json::from_slice(&candle_config)?;
+
+        let device = parse_device(spec.device)?;
+        let mut tokenizer = Tokenizer::from_bytes(&tokenizer).map_anyhow_err()?;
+
+        if let Some(pp) = tokenizer.get_padding_mut() {
+            pp.strategy = tokenizers::PaddingStrategy::BatchLongest
+        } else {
+            let pp = PaddingParams {
+                strategy: tokenizers::PaddingStrategy::BatchLongest,
+                ..Default::default()
+            };
+            tokenizer.with_padding(Some(pp));
+        }
+
+        // longer inputs would overflow the position embeddings
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: candle_config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_anyhow_err()?;
+
+        let vb = VarBuilder::from_buffered_safetensors(weights, DType::F32, &device)?;
+        let model = BertModel::load(vb, &candle_config)?;
+        Ok(BertEmbeddingsModel {
+            pooling: spec.pooling.unwrap_or_default(),
+            normalize: spec.normalize.unwrap_or(true),
+            spec: spec_clone,
+            model,
+            tokenizer,
+            device,
+        })
+    }
+}
+
+impl Embeddings for BertEmbeddingsModel {
+    fn embed(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let device = &self.device;
+        let tokens = self.tokenizer.encode_batch(input, true).map_anyhow_err()?;
+
+        let token_ids: Vec<Tensor> = tokens
+            .iter()
+            .map(|tokens| Tensor::new(tokens.get_ids(), device))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let attention_mask: Vec<Tensor> = tokens
+            .iter()
+            .map(|tokens| Tensor::new(tokens.get_attention_mask(), device))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+
+        let embeddings = match self.pooling {
+            Pooling::Cls => embeddings.narrow(1, 0, 1)?.squeeze(1)?,
+            Pooling::Mean => {
+                let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+                let summed = embeddings.broadcast_mul(&mask)?.sum(1)?;
+                let counts = mask.sum(1)?.clamp(1e-9, f64::MAX)?;
+                summed.broadcast_div(&counts)?
+            }
+        };
+
+        let embeddings = if self.normalize {
+            embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?
+        } else {
+            embeddings
+        };
+        Ok(embeddings.to_vec2()?)
+    }
+}
@@ -0,0 +1,168 @@
+use crate::state::State;
+use anyhow::{bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub const KMEANS_MAX_ITER: usize = 100;
+
+/// Spherical k-means: vectors are L2 normalized and assigned to the centroid with
+/// the highest cosine similarity. Centroids are seeded with k-means++.
+/// Returns the cluster index of every input vector.
+pub fn kmeans(vectors: &[Vec<f32>], k: usize, max_iter: usize, seed: Option<u64>) -> Vec<usize> {
+    if vectors.is_empty() || k == 0 {
+        return vec![0; vectors.len()];
+    }
+
+    let k = k.min(vectors.len());
+    let points: Vec<Vec<f32>> = vectors.iter().map(|v| normalize(v)).collect();
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+
+    // k-means++ initialization on cosine distance
+    let mut centroids = vec![points[rng.random_range(0..points.len())].clone()];
+    while centroids.len() < k {
+        let dists: Vec<f32> = points
+            .iter()
+            .map(|p| {
+                centroids
+                    .iter()
+                    .map(|c| (1.0 - dot(p, c)).max(0.0))
+                    .fold(f32::MAX, f32::min)
+            })
+            .collect();
+        let total: f32 = dists.iter().sum();
+        if total <= 0.0 {
+            // fewer distinct points than clusters
+            break;
+        }
+        let mut target = rng.random_range(0.0..total);
+        let mut next = dists.len() - 1;
+        for (i, d) in dists.iter().enumerate() {
+            if target < *d {
+                next = i;
+                break;
+            }
+            target -= d;
+        }
+        centroids.push(points[next].clone());
+    }
+
+    let mut assignments = vec![0usize; points.len()];
+    for iter in 0..max_iter.max(1) {
+        let mut changed = false;
+        for (i, p) in points.iter().enumerate() {
+            let best = nearest(p, &centroids);
+            if best != assignments[i] {
+                assignments[i] = best;
+                changed = true;
+            }
+        }
+
+        if !changed && iter > 0 {
+            break;
+        }
+
+        let dim = points[0].len();
+        let mut sums = vec![vec![0.0f32; dim]; centroids.len()];
+        for (p, &c) in points.iter().zip(&assignments) {
+            for (s, x) in sums[c].iter_mut().zip(p) {
+                *s += x;
+            }
+        }
+        for (centroid, sum) in centroids.iter_mut().zip(sums) {
+            // empty clusters keep their previous centroid
+            if sum.iter().any(|x| *x != 0.0) {
+                *centroid = normalize(&sum);
+            }
+        }
+    }
+
+    assignments
+}
+
+/// Clusters all vectors stored in the state db under `key` and writes the cluster
+/// ids back to the `embeddings` table. Returns the number of clustered vectors.
+pub async fn cluster_embeddings(
+    state: &State,
+    key: &str,
+    k: usize,
+    max_iter: Option<usize>,
+    seed: Option<u64>,
+) -> Result<usize> {
+    if k == 0 {
+        bail!("🐔 Number of clusters must be greater than 0");
+    }
+
+    let stored = state.embeddings_by_key(key).await?;
+    let vectors: Vec<Vec<f32>> = stored.iter().map(|(_, v)| v.clone()).collect();
+    let assignments = kmeans(&vectors, k, max_iter.unwrap_or(KMEANS_MAX_ITER), seed);
+
+    let clusters: Vec<(i64, i64)> = stored
+        .iter()
+        .zip(assignments)
+        .map(|((id, _), c)| (*id, c as i64))
+        .collect();
+    state.set_embedding_clusters(&clusters).await?;
+
+    Ok(clusters.len())
+}
+
+fn nearest(point: &[f32], centroids: &[Vec<f32>]) -> usize {
+    let mut best = 0;
+    let mut best_sim = f32::MIN;
+    for (i, c) in centroids.iter().enumerate() {
+        let sim = dot(point, c);
+        if sim > best_sim {
+            best_sim = sim;
+            best = i;
+        }
+    }
+    best
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalize(v: &[f32]) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return v.to_vec();
+    }
+    v.iter().map(|x| x / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans_separates_directions() {
+        let vectors = vec![
+            vec![1.0, 0.05, 0.0],
+            vec![0.9, 0.0, 0.1],
+            vec![0.0, 1.0, 0.05],
+            vec![0.1, 0.95, 0.0],
+            vec![0.0, 0.05, 1.0],
+            vec![0.05, 0.0, 0.8],
+        ];
+
+        let assignments = kmeans(&vectors, 3, KMEANS_MAX_ITER, Some(42));
+        assert_eq!(assignments.len(), 6);
+        assert_eq!(assignments[0], assignments[1]);
+        assert_eq!(assignments[2], assignments[3]);
+        assert_eq!(assignments[4], assignments[5]);
+        assert_ne!(assignments[0], assignments[2]);
+        assert_ne!(assignments[2], assignments[4]);
+        assert_ne!(assignments[0], assignments[4]);
+    }
+
+    #[test]
+    fn test_kmeans_more_clusters_than_points() {
+        let vectors = vec![vec![1.0, 0.0], vec![1.0, 0.0]];
+        let assignments = kmeans(&vectors, 5, KMEANS_MAX_ITER, Some(1));
+        assert_eq!(assignments, vec![0, 0]);
+    }
+}
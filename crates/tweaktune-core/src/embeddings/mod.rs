@@ -1,5 +1,10 @@
+pub mod bert;
+pub mod cluster;
 pub mod e5;
-use crate::embeddings::e5::E5Spec;
+use crate::common::{blake3_hash, ResultExt};
+use crate::embeddings::bert::{BertEmbeddingsModel, BertSpec, Pooling, BERT_MODEL_REPO};
+use crate::embeddings::e5::{E5Model, E5Spec, E5_MODEL_REPO};
+use crate::state::State;
 use anyhow::Result;
 
 pub trait Embeddings {
@@ -9,7 +14,212 @@ pub trait Embeddings {
 #[derive(Clone)]
 pub enum EmbeddingsType {
     OpenAI(OpenAIEmbeddings),
+    Cohere(CohereEmbeddings),
+    Jina(JinaEmbeddings),
     E5(E5Spec),
+    Bert(BertSpec),
+}
+
+impl EmbeddingsType {
+    /// Identifier of the underlying model, used to key cached vectors. Settings that change
+    /// the vectors (Cohere `input_type`, Jina `task`, Bert pooling and normalization) are
+    /// part of it.
+    pub fn model_id(&self) -> String {
+        match self {
+            EmbeddingsType::OpenAI(e) => e.model.clone(),
+            EmbeddingsType::Cohere(e) => {
+                format!("{}:{}", e.model, e.input_type.as_deref().unwrap_or(""))
+            }
+            EmbeddingsType::Jina(e) => format!("{}:{}", e.model, e.task.as_deref().unwrap_or("")),
+            EmbeddingsType::E5(spec) => spec
+                .model_repo
+                .clone()
+                .unwrap_or_else(|| E5_MODEL_REPO.to_string()),
+            EmbeddingsType::Bert(spec) => {
+                let pooling = match spec.pooling.unwrap_or_default() {
+                    Pooling::Mean => "mean",
+                    Pooling::Cls => "cls",
+                };
+                format!(
+                    "{}:{}:{}",
+                    spec.model_repo.as_deref().unwrap_or(BERT_MODEL_REPO),
+                    pooling,
+                    spec.normalize.unwrap_or(true)
+                )
+            }
+        }
+    }
+}
+
+impl Embeddings for EmbeddingsType {
+    fn embed(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match self {
+            // blocking client must not run directly on a runtime worker
+            EmbeddingsType::OpenAI(e) => tokio::task::block_in_place(|| e.embed(input)),
+            EmbeddingsType::Cohere(e) => tokio::task::block_in_place(|| e.embed(input)),
+            EmbeddingsType::Jina(e) => tokio::task::block_in_place(|| e.embed(input)),
+            EmbeddingsType::E5(spec) => {
+                let instance = E5Model::lazy(spec.clone())?;
+                let guard = instance.lock().map_anyhow_err()?;
+                guard.embed(input)
+            }
+            EmbeddingsType::Bert(spec) => {
+                let instance = BertEmbeddingsModel::lazy(spec.clone())?;
+                let guard = instance.lock().map_anyhow_err()?;
+                guard.embed(input)
+            }
+        }
+    }
+}
+
+/// Embeds `input` reusing vectors cached in the state db. Only texts missing from the
+/// cache are sent to the model, and their vectors are stored for subsequent runs.
+pub async fn embed_cached(
+    embeddings: &EmbeddingsType,
+    state: Option<&State>,
+    input: Vec<String>,
+) -> Result<Vec<Vec<f32>>> {
+    let state = match state {
+        Some(state) => state,
+        None => return embeddings.embed(input),
+    };
+
+    let model = embeddings.model_id();
+    let hashes: Vec<String> = input.iter().map(|t| blake3_hash(t)).collect();
+
+    let mut out: Vec<Option<Vec<f32>>> = Vec::with_capacity(input.len());
+    let mut missing = Vec::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        let cached = state.cached_embedding(&model, hash).await?;
+        if cached.is_none() {
+            missing.push(i);
+        }
+        out.push(cached);
+    }
+
+    if !missing.is_empty() {
+        let texts = missing.iter().map(|&i| input[i].clone()).collect();
+        let embedded = embeddings.embed(texts)?;
+        for (i, emb) in missing.into_iter().zip(embedded) {
+            state.cache_embedding(&model, &hashes[i], &emb).await?;
+            out[i] = Some(emb);
+        }
+    }
+
+    out.into_iter()
+        .map(|e| e.ok_or_else(|| anyhow::anyhow!("🐔 Embedding missing in model response")))
+        .collect()
+}
+
+/// Storage precision of vectors persisted in the state db.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbeddingPrecision {
+    #[default]
+    F32,
+    F16,
+    /// Symmetric int8 quantization, the blob starts with the f32 scale.
+    Int8,
+}
+
+impl EmbeddingPrecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmbeddingPrecision::F32 => "f32",
+            EmbeddingPrecision::F16 => "f16",
+            EmbeddingPrecision::Int8 => "int8",
+        }
+    }
+
+    pub fn encode(&self, embedding: &[f32]) -> Vec<u8> {
+        match self {
+            EmbeddingPrecision::F32 => {
+                let mut buf = Vec::with_capacity(embedding.len() * 4);
+                for v in embedding {
+                    buf.extend_from_slice(&v.to_le_bytes());
+                }
+                buf
+            }
+            EmbeddingPrecision::F16 => {
+                if embedding.is_empty() {
+                    return Vec::new();
+                }
+                f16_to_blob(&quantize_f32_to_f16(&[embedding.to_vec()]))
+            }
+            EmbeddingPrecision::Int8 => {
+                let max = embedding.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+                let scale = if max == 0.0 { 1.0 } else { max / 127.0 };
+                let mut buf = Vec::with_capacity(embedding.len() + 4);
+                buf.extend_from_slice(&scale.to_le_bytes());
+                for v in embedding {
+                    buf.push((v / scale).round().clamp(-127.0, 127.0) as i8 as u8);
+                }
+                buf
+            }
+        }
+    }
+
+    pub fn decode(&self, blob: &[u8]) -> Vec<f32> {
+        match self {
+            EmbeddingPrecision::F32 => blob
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            EmbeddingPrecision::F16 => {
+                if blob.len() < 2 {
+                    return Vec::new();
+                }
+                dequantize_f16_to_f32(&blob_to_f16(blob, blob.len() / 2))
+            }
+            EmbeddingPrecision::Int8 => {
+                if blob.len() < 4 {
+                    return Vec::new();
+                }
+                let scale = f32::from_le_bytes([blob[0], blob[1], blob[2], blob[3]]);
+                blob[4..]
+                    .iter()
+                    .map(|b| (*b as i8) as f32 * scale)
+                    .collect()
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for EmbeddingPrecision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "f32" | "float32" => Ok(EmbeddingPrecision::F32),
+            "f16" | "float16" => Ok(EmbeddingPrecision::F16),
+            "int8" | "i8" => Ok(EmbeddingPrecision::Int8),
+            _ => Err(anyhow::anyhow!("🐔 Unsupported embedding precision: {}", s)),
+        }
+    }
+}
+
+/// Matryoshka style truncation: keeps the first `dims` components and L2
+/// renormalizes them. Vectors shorter than `dims` are only renormalized.
+pub fn truncate_embedding(embedding: &[f32], dims: usize) -> Vec<f32> {
+    let head = &embedding[..dims.min(embedding.len())];
+    let norm = head.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return head.to_vec();
+    }
+    head.iter().map(|x| x / norm).collect()
+}
+
+/// Cosine similarity of two vectors, `0.0` when either of them is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut na, mut nb) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        return 0.0;
+    }
+    dot / (na.sqrt() * nb.sqrt())
 }
 
 #[derive(Clone)]
@@ -53,31 +263,150 @@ impl Embeddings for OpenAIEmbeddings {
         }
 
         let resp_json: serde_json::Value = resp.json()?;
-        let embeddings: Vec<Vec<f32>> = resp_json["data"]
-            .as_array()
-            .ok_or_else(|| anyhow::anyhow!("Invalid response format"))?
-            .iter()
-            .map(|item| {
-                item["embedding"]
-                    .as_array()
-                    .ok_or_else(|| anyhow::anyhow!("Invalid embedding format"))
-                    .and_then(|arr| {
-                        arr.iter()
-                            .map(|v| {
-                                v.as_f64()
-                                    .map(|f| f as f32)
-                                    .ok_or_else(|| anyhow::anyhow!("Invalid float value"))
-                            })
-                            .collect()
-                    })
-            })
-            .collect::<Result<Vec<Vec<f32>>>>()?;
+        parse_vectors(&resp_json["data"], |item| &item["embedding"])
+    }
+}
+
+/// Cohere embed API (v2). `input_type` is required by the v3+ models and
+/// defaults to `search_document`.
+#[derive(Clone)]
+pub struct CohereEmbeddings {
+    pub name: String,
+    pub base_url: String,
+    pub api_key: String,
+    pub model: String,
+    pub input_type: Option<String>,
+}
 
-        Ok(embeddings)
+impl CohereEmbeddings {
+    pub fn new(
+        name: String,
+        base_url: String,
+        api_key: String,
+        model: String,
+        input_type: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            base_url,
+            api_key,
+            model,
+            input_type,
+        }
     }
 }
 
-#[allow(dead_code)]
+impl Embeddings for CohereEmbeddings {
+    fn embed(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let client = reqwest::blocking::Client::new();
+        let url = format!("{}/v2/embed", self.base_url);
+        let body = serde_json::json!({
+            "texts": input,
+            "model": self.model,
+            "input_type": self.input_type.as_deref().unwrap_or("search_document"),
+            "embedding_types": ["float"],
+        });
+        let resp = client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(&body)
+            .send()?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Cohere API request failed with status: {}",
+                resp.status()
+            ));
+        }
+
+        let resp_json: serde_json::Value = resp.json()?;
+        parse_vectors(&resp_json["embeddings"]["float"], |item| item)
+    }
+}
+
+/// Jina embeddings API. `task` selects the LoRA adapter of the v3 models
+/// (e.g. `retrieval.passage`, `text-matching`) and is omitted when not set.
+#[derive(Clone)]
+pub struct JinaEmbeddings {
+    pub name: String,
+    pub base_url: String,
+    pub api_key: String,
+    pub model: String,
+    pub task: Option<String>,
+}
+
+impl JinaEmbeddings {
+    pub fn new(
+        name: String,
+        base_url: String,
+        api_key: String,
+        model: String,
+        task: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            base_url,
+            api_key,
+            model,
+            task,
+        }
+    }
+}
+
+impl Embeddings for JinaEmbeddings {
+    fn embed(&self, input: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let client = reqwest::blocking::Client::new();
+        let url = format!("{}/v1/embeddings", self.base_url);
+        let mut body = serde_json::json!({
+            "input": input,
+            "model": self.model,
+        });
+        if let Some(task) = &self.task {
+            body["task"] = serde_json::json!(task);
+        }
+        let resp = client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(&body)
+            .send()?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Jina API request failed with status: {}",
+                resp.status()
+            ));
+        }
+
+        let resp_json: serde_json::Value = resp.json()?;
+        parse_vectors(&resp_json["data"], |item| &item["embedding"])
+    }
+}
+
+fn parse_vectors<F>(items: &serde_json::Value, vector: F) -> Result<Vec<Vec<f32>>>
+where
+    F: Fn(&serde_json::Value) -> &serde_json::Value,
+{
+    items
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("Invalid response format"))?
+        .iter()
+        .map(|item| {
+            vector(item)
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("Invalid embedding format"))
+                .and_then(|arr| {
+                    arr.iter()
+                        .map(|v| {
+                            v.as_f64()
+                                .map(|f| f as f32)
+                                .ok_or_else(|| anyhow::anyhow!("Invalid float value"))
+                        })
+                        .collect()
+                })
+        })
+        .collect()
+}
+
 fn quantize_f32_to_f16(rows: &[Vec<f32>]) -> Vec<Vec<u16>> {
     rows.iter()
         .map(|vec| {
@@ -88,7 +417,6 @@ fn quantize_f32_to_f16(rows: &[Vec<f32>]) -> Vec<Vec<u16>> {
         .collect()
 }
 
-#[allow(dead_code)]
 fn dequantize_f16_to_f32(input: &[Vec<u16>]) -> Vec<f32> {
     input
         .iter()
@@ -96,7 +424,6 @@ fn dequantize_f16_to_f32(input: &[Vec<u16>]) -> Vec<f32> {
         .collect()
 }
 
-#[allow(dead_code)]
 fn f16_to_blob(input: &[Vec<u16>]) -> Vec<u8> {
     let mut blob = Vec::with_capacity(input.len() * input[0].len() * 2);
     for row in input {
@@ -107,7 +434,6 @@ fn f16_to_blob(input: &[Vec<u16>]) -> Vec<u8> {
     blob
 }
 
-#[allow(dead_code)]
 fn blob_to_f16(blob: &[u8], dim: usize) -> Vec<Vec<u16>> {
     let num_rows = blob.len() / (dim * 2);
     let mut result = Vec::with_capacity(num_rows);
@@ -123,3 +449,106 @@ fn blob_to_f16(blob: &[u8], dim: usize) -> Vec<Vec<u16>> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn test_embedding_precision_roundtrip() {
+        let v = vec![0.5f32, -0.25, 1.0, 0.0];
+        for precision in [
+            EmbeddingPrecision::F32,
+            EmbeddingPrecision::F16,
+            EmbeddingPrecision::Int8,
+        ] {
+            let blob = precision.encode(&v);
+            let decoded = precision.decode(&blob);
+            assert_eq!(decoded.len(), v.len());
+            assert!(cosine_similarity(&v, &decoded) > 0.999);
+        }
+        assert_eq!(EmbeddingPrecision::F16.encode(&v).len(), 8);
+        assert_eq!(EmbeddingPrecision::Int8.encode(&v).len(), 8);
+        assert_eq!(
+            "int8".parse::<EmbeddingPrecision>().unwrap(),
+            EmbeddingPrecision::Int8
+        );
+    }
+
+    #[test]
+    fn test_truncate_embedding() {
+        assert_eq!(truncate_embedding(&[3.0, 4.0, 12.0], 2), vec![0.6, 0.8]);
+        assert_eq!(truncate_embedding(&[2.0, 0.0], 4), vec![1.0, 0.0]);
+        assert_eq!(truncate_embedding(&[0.0, 0.0, 1.0], 2), vec![0.0, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_model_id_input_type_cache() -> Result<()> {
+        let cohere = |input_type: &str| {
+            EmbeddingsType::Cohere(CohereEmbeddings::new(
+                "cohere".to_string(),
+                "http://localhost".to_string(),
+                "key".to_string(),
+                "embed-v4.0".to_string(),
+                Some(input_type.to_string()),
+            ))
+        };
+        let document = cohere("search_document").model_id();
+        let query = cohere("search_query").model_id();
+        assert_ne!(document, query);
+
+        let jina = |task: Option<&str>| {
+            EmbeddingsType::Jina(JinaEmbeddings::new(
+                "jina".to_string(),
+                "http://localhost".to_string(),
+                "key".to_string(),
+                "jina-embeddings-v3".to_string(),
+                task.map(str::to_string),
+            ))
+        };
+        assert_ne!(
+            jina(Some("retrieval.query")).model_id(),
+            jina(Some("retrieval.passage")).model_id()
+        );
+        assert_ne!(
+            jina(None).model_id(),
+            jina(Some("text-matching")).model_id()
+        );
+
+        let tmp = tempfile::TempDir::new()?;
+        let state = State::new(tmp.path().to_str().unwrap()).await?;
+        state.cache_embedding(&document, "h1", &[1.0, 0.0]).await?;
+        assert_eq!(
+            state.cached_embedding(&document, "h1").await?,
+            Some(vec![1.0, 0.0])
+        );
+        assert!(state.cached_embedding(&query, "h1").await?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_id_bert_pooling() {
+        let bert = |pooling: Option<Pooling>, normalize: Option<bool>| {
+            EmbeddingsType::Bert(BertSpec {
+                name: "bert".to_string(),
+                model_repo: None,
+                device: None,
+                hf_token: None,
+                pooling,
+                normalize,
+            })
+            .model_id()
+        };
+        assert_eq!(bert(None, None), bert(Some(Pooling::Mean), Some(true)));
+        assert_ne!(bert(None, None), bert(Some(Pooling::Cls), None));
+        assert_ne!(bert(None, None), bert(None, Some(false)));
+    }
+}
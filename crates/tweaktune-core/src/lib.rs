@@ -1,9 +1,17 @@
 use std::collections::HashMap;
+use std::sync::Mutex;
 
 use crate::{
-    datasets::DatasetType, embeddings::EmbeddingsType, llms::LLMType, state::State,
-    templates::Templates, tokenizers::TokenizerWrapper,
+    common::{derive_seed, item_rng, workers::current_worker},
+    datasets::{DatasetLoader, DatasetType},
+    embeddings::EmbeddingsType,
+    llms::LLMType,
+    state::State,
+    steps::StepContext,
+    templates::Templates,
+    tokenizers::TokenizerWrapper,
 };
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 
 pub mod common;
 pub mod config;
@@ -20,11 +28,17 @@ pub mod tokenizers;
 
 pub struct PipelineResources {
     pub datasets: Resources<DatasetType>,
+    /// Datasets read when the run starts, added to `datasets` then.
+    pub pending_datasets: DatasetLoader,
     pub embeddings: Resources<EmbeddingsType>,
     pub llms: Resources<LLMType>,
     pub templates: Templates,
     pub tokenizers: Resources<TokenizerWrapper>,
     pub state: Option<State>,
+    pub seed: Option<u64>,
+    /// Whether seeded steps draw from a random stream of each worker, see [`Self::rng`].
+    pub worker_rng: bool,
+    worker_rngs: Mutex<HashMap<(String, usize), StdRng>>,
 }
 
 impl PipelineResources {
@@ -33,6 +47,7 @@ impl PipelineResources {
             datasets: Resources {
                 resources: HashMap::new(),
             },
+            pending_datasets: DatasetLoader::default(),
             embeddings: Resources {
                 resources: HashMap::new(),
             },
@@ -44,8 +59,48 @@ impl PipelineResources {
                 resources: HashMap::new(),
             },
             state,
+            seed: None,
+            worker_rng: false,
+            worker_rngs: Mutex::default(),
         }
     }
+
+    /// Seeds the random choices of all steps, the template filters and the API LLMs.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.templates.seed = Some(seed);
+        for llm in self.llms.resources.values_mut() {
+            if let LLMType::Api(llm) = llm {
+                llm.seed = Some(seed as u32);
+            }
+        }
+    }
+
+    /// The step's own `seed`, or one derived from the pipeline seed for the step `name`.
+    pub fn step_seed(&self, name: &str, seed: Option<u64>) -> Option<u64> {
+        seed.or_else(|| self.seed.map(|seed| derive_seed(seed, name)))
+    }
+
+    /// Rng of the step `name` for the item in `context`, see [`Self::step_seed`]. With
+    /// `worker_rng` it's the next draw of the step's stream on the current worker instead, so
+    /// each worker's sequence is reproducible but not which items it gets.
+    pub fn rng(&self, name: &str, seed: Option<u64>, context: &StepContext) -> StdRng {
+        let seed = self.step_seed(name, seed);
+        if let (true, Some(seed), Some(worker)) = (self.worker_rng, seed, current_worker()) {
+            let mut rngs = self.worker_rngs.lock().unwrap();
+            let rng = rngs.entry((name.to_string(), worker)).or_insert_with(|| {
+                StdRng::seed_from_u64(derive_seed(seed, &format!("worker-{}", worker)))
+            });
+            return StdRng::seed_from_u64(rng.next_u64());
+        }
+        let index = context.get("index").and_then(|i| i.as_u64()).unwrap_or(0);
+        item_rng(seed, index)
+    }
+
+    /// Restarts the random streams of the workers, for a new run.
+    pub fn reset_worker_rngs(&self) {
+        self.worker_rngs.lock().unwrap().clear();
+    }
 }
 
 #[derive(Default, Clone)]
@@ -1,13 +1,133 @@
-use anyhow::Result;
+use crate::common::workers::current_worker;
+use anyhow::{bail, Result};
 use log::error;
 use pyo3::prelude::*;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, sync::OnceLock};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
 
+/// API LLMs called while processing an item or step and the tokens they reported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LlmUsage {
+    pub tokens: u64,
+    pub llms: BTreeSet<String>,
+}
+
+impl LlmUsage {
+    pub fn add(&mut self, other: &LlmUsage) {
+        self.tokens += other.tokens;
+        self.llms.extend(other.llms.iter().cloned());
+    }
+}
+
+tokio::task_local! {
+    static LLM_USAGE: RefCell<LlmUsage>;
+}
+
+/// Runs `future` and tracks the API LLM calls it makes, nested usage adds up to the
+/// enclosing one.
+pub async fn track_llm_usage<F: Future>(future: F) -> (F::Output, LlmUsage) {
+    let (output, usage) = LLM_USAGE
+        .scope(RefCell::default(), async move {
+            let output = future.await;
+            (output, LLM_USAGE.with(|usage| usage.take()))
+        })
+        .await;
+    let _ = LLM_USAGE.try_with(|outer| outer.borrow_mut().add(&usage));
+    (output, usage)
+}
+
+fn record_llm_usage(llm: &str, tokens: u64) {
+    let _ = LLM_USAGE.try_with(|usage| {
+        let mut usage = usage.borrow_mut();
+        usage.tokens += tokens;
+        usage.llms.insert(llm.to_string());
+    });
+}
+
+/// Calls of all API LLMs since the last [`LlmPressure::take`], feeds the adaptive concurrency
+/// of the pipeline.
+pub static LLM_PRESSURE: LlmPressure = LlmPressure::new();
+
+pub enum LlmOutcome {
+    Ok,
+    Throttled,
+    Error,
+}
+
+pub struct LlmPressure {
+    calls: AtomicU64,
+    throttled: AtomicU64,
+    errors: AtomicU64,
+    latency_ms: AtomicU64,
+}
+
+/// Counts of the calls in a window, `latency` is the mean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LlmWindow {
+    pub calls: u64,
+    pub throttled: u64,
+    pub errors: u64,
+    pub latency: Duration,
+}
+
+impl LlmPressure {
+    pub const fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: Duration, outcome: LlmOutcome) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.latency_ms
+            .fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+        match outcome {
+            LlmOutcome::Ok => {}
+            LlmOutcome::Throttled => {
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+            }
+            LlmOutcome::Error => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the calls recorded so far and starts a new window.
+    pub fn take(&self) -> LlmWindow {
+        let calls = self.calls.swap(0, Ordering::Relaxed);
+        let latency_ms = self.latency_ms.swap(0, Ordering::Relaxed);
+        LlmWindow {
+            calls,
+            throttled: self.throttled.swap(0, Ordering::Relaxed),
+            errors: self.errors.swap(0, Ordering::Relaxed),
+            latency: Duration::from_millis(latency_ms.checked_div(calls).unwrap_or(0)),
+        }
+    }
+}
+
+impl Default for LlmPressure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub trait LLM {
     fn chat_completion(
         &self,
@@ -117,6 +237,7 @@ impl LLM for MistralrsLLM {
                     content: result,
                 },
             }],
+            usage: None,
         };
         Ok(response)
     }
@@ -207,6 +328,7 @@ impl LLM for UnslothLLM {
                     content: result,
                 },
             }],
+            usage: None,
         };
         Ok(response)
     }
@@ -230,20 +352,15 @@ impl LLM for UnslothLLM {
     }
 }
 
+/// Url and key header an API LLM is called with.
 #[derive(Clone)]
-pub struct ApiLLM {
-    pub name: String,
+pub struct ApiEndpoint {
     pub url: String,
     pub api_key_header: (String, String),
-    pub model: Option<String>,
-    pub max_tokens: u32,
-    pub temperature: f32,
 }
 
-impl ApiLLM {
-    pub fn new(name: String, mode: ApiLLMMode, max_tokens: u32, temperature: f32) -> Self {
-        HTTP_CLIENT.get_or_init(Client::new);
-
+impl ApiEndpoint {
+    fn new(mode: ApiLLMMode) -> (Self, Option<String>) {
         let (url, api_key_header, model) = match mode {
             ApiLLMMode::Api {
                 api_key,
@@ -273,16 +390,60 @@ impl ApiLLM {
                 None,
             ),
         };
+        (
+            Self {
+                url,
+                api_key_header,
+            },
+            model,
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiLLM {
+    pub name: String,
+    /// Keys or endpoints of one model: each worker sticks to one of them, calls made outside
+    /// of a worker take turns.
+    pub endpoints: Vec<ApiEndpoint>,
+    next_endpoint: Arc<AtomicUsize>,
+    pub model: Option<String>,
+    pub max_tokens: u32,
+    pub temperature: f32,
+    /// Sent as the request `seed`, providers that support it sample deterministically.
+    pub seed: Option<u32>,
+}
+
+impl ApiLLM {
+    pub fn new(name: String, mode: ApiLLMMode, max_tokens: u32, temperature: f32) -> Self {
+        HTTP_CLIENT.get_or_init(Client::new);
+
+        let (endpoint, model) = ApiEndpoint::new(mode);
 
         Self {
             name,
-            url,
-            api_key_header,
+            endpoints: vec![endpoint],
+            next_endpoint: Arc::default(),
             model,
             max_tokens,
             temperature,
+            seed: None,
         }
     }
+
+    /// Adds the keys or endpoints of `modes` to rotate through, their model is ignored.
+    pub fn with_endpoints(mut self, modes: Vec<ApiLLMMode>) -> Self {
+        self.endpoints
+            .extend(modes.into_iter().map(|mode| ApiEndpoint::new(mode).0));
+        self
+    }
+
+    /// The endpoint of the current worker, the next one in turn outside of a worker.
+    pub fn endpoint(&self) -> &ApiEndpoint {
+        let turn =
+            current_worker().unwrap_or_else(|| self.next_endpoint.fetch_add(1, Ordering::Relaxed));
+        &self.endpoints[turn % self.endpoints.len()]
+    }
 }
 
 impl LLM for ApiLLM {
@@ -302,7 +463,7 @@ impl LLM for ApiLLM {
                 self.max_tokens
             },
             stream: None,
-            seed: None,
+            seed: self.seed,
             temperature: if temperature.is_some() {
                 temperature
             } else {
@@ -317,17 +478,42 @@ impl LLM for ApiLLM {
             },
         };
 
+        let endpoint = self.endpoint();
+        let started = Instant::now();
         let response = HTTP_CLIENT
             .get()
             .expect("HTTP client not initialized")
-            .post(&self.url)
-            .header(&self.api_key_header.0, &self.api_key_header.1)
+            .post(&endpoint.url)
+            .header(&endpoint.api_key_header.0, &endpoint.api_key_header.1)
             .header("Content-Type", "application/json")
             .json(&request)
             .send()
-            .await?
-            .json::<ChatCompletionResponse>()
-            .await?;
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                LLM_PRESSURE.record(started.elapsed(), LlmOutcome::Error);
+                return Err(e.into());
+            }
+        };
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            LLM_PRESSURE.record(started.elapsed(), LlmOutcome::Throttled);
+            bail!("🐔 LLM {} is rate limited (429)", self.name);
+        }
+        if status.is_server_error() {
+            LLM_PRESSURE.record(started.elapsed(), LlmOutcome::Error);
+            bail!("🐔 LLM {} failed with status {}", self.name, status);
+        }
+        LLM_PRESSURE.record(started.elapsed(), LlmOutcome::Ok);
+        let response = response.json::<ChatCompletionResponse>().await?;
+        record_llm_usage(
+            &self.name,
+            response
+                .usage
+                .as_ref()
+                .map_or(0, |usage| usage.total_tokens),
+        );
         Ok(response)
     }
 
@@ -377,6 +563,18 @@ pub struct ChatMessage {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatCompletionResponse {
     pub choices: Vec<ChatChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -386,12 +584,48 @@ pub struct ChatChoice {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::common::workers::in_worker;
 
     #[tokio::test]
     async fn test_openai_invoke() {
         println!("hello");
     }
 
+    #[tokio::test]
+    async fn test_track_llm_usage() {
+        let (inner, usage) = track_llm_usage(async {
+            record_llm_usage("gpt", 3);
+            let ((), inner) = track_llm_usage(async { record_llm_usage("judge", 2) }).await;
+            inner
+        })
+        .await;
+        assert_eq!(inner.tokens, 2);
+        assert_eq!(usage.tokens, 5);
+        assert_eq!(
+            usage.llms,
+            BTreeSet::from(["gpt".to_string(), "judge".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let key = |api_key: &str| ApiLLMMode::Api {
+            api_key: api_key.to_string(),
+            model: "model".to_string(),
+            base_url: "http://localhost".to_string(),
+        };
+        let llm = ApiLLM::new("gpt".to_string(), key("a"), 100, 0.7)
+            .with_endpoints(vec![key("b"), key("c")]);
+        let keys = (0..4)
+            .map(|_| llm.endpoint().api_key_header.1.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["Bearer a", "Bearer b", "Bearer c", "Bearer a"]);
+
+        let key = in_worker(4, async { llm.endpoint().api_key_header.1.clone() }).await;
+        assert_eq!(key, "Bearer b");
+    }
+
     #[test]
     fn it_works() {
         println!("hello");
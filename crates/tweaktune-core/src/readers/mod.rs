@@ -77,37 +77,25 @@ pub fn path_to_operator(path: &str) -> Result<OpConfig> {
     }
 }
 
+/// The operator of `op_config`, or of the storage implied by `path` without it.
+pub fn build_operator(path: &str, op_config: Option<String>) -> Result<AsyncOperator> {
+    let op_config = match op_config {
+        Some(config) => serde_json::from_str(&config)?,
+        None => path_to_operator(path)?,
+    };
+    Ok(match op_config {
+        OpConfig::Fs(config) => AsyncOperator::from_config(config)?.finish(),
+        OpConfig::S3(config) => AsyncOperator::from_config(config)?.finish(),
+        OpConfig::Gcs(config) => AsyncOperator::from_config(config)?.finish(),
+        OpConfig::Azblob(config) => AsyncOperator::from_config(config)?.finish(),
+        OpConfig::Http(config) => AsyncOperator::from_config(config)?.finish(),
+    })
+}
+
 pub fn build_reader(path: &str, op_config: Option<String>) -> Result<OpReader> {
     let p = Path::new(path);
-    // let dir = p.parent().unwrap().to_str().unwrap();
     let file_name = p.file_name().unwrap().to_str().unwrap();
-    // TODO: implement the other operators
-    // let builder = Fs::default().root(dir);
-    // let json_config = format!("{{\"type\":\"Fs\", \"root\": \"{}\"}}", dir);
-    let operator = match op_config {
-        Some(config) => {
-            let op_config: OpConfig = serde_json::from_str(&config)?;
-            match op_config {
-                OpConfig::Fs(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::S3(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Gcs(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Azblob(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Http(config) => AsyncOperator::from_config(config)?.finish(),
-            }
-        }
-        None => {
-            let op_config = path_to_operator(path)?;
-            match op_config {
-                OpConfig::Fs(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::S3(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Gcs(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Azblob(config) => AsyncOperator::from_config(config)?.finish(),
-                OpConfig::Http(config) => AsyncOperator::from_config(config)?.finish(),
-            }
-            // let builder = Fs::default().root(dir);
-            // Operator::new(builder)?.finish()
-        }
-    };
+    let operator = build_operator(path, op_config)?;
 
     let _guard = enter_runtime();
     let op = Operator::new(operator)?;
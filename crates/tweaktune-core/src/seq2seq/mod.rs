@@ -26,6 +26,23 @@ pub enum Which {
     Mt5Large,
 }
 
+impl std::str::FromStr for Which {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "t5-base" => Ok(Which::T5Base),
+            "t5-small" => Ok(Which::T5Small),
+            "t5-large" => Ok(Which::T5Large),
+            "t5-3b" => Ok(Which::T5_3B),
+            "mt5-base" => Ok(Which::Mt5Base),
+            "mt5-small" => Ok(Which::Mt5Small),
+            "mt5-large" => Ok(Which::Mt5Large),
+            _ => anyhow::bail!("🐔 Unsupported seq2seq model: {}", s),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Seq2SeqSpec {
     pub name: String,
@@ -1,3 +1,4 @@
+use crate::embeddings::{cosine_similarity, truncate_embedding, EmbeddingPrecision};
 use libsqlite3_sys as ffi;
 use once_cell::sync::Lazy;
 use serde_json::Value as JsonValue;
@@ -7,7 +8,17 @@ use sqlx::{
     sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
     SqlitePool,
 };
-use std::{path::Path, str::FromStr};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// Key under which cached vectors are stored in the `embeddings` table, kept apart
+/// from the per-input keys used by `knn_embeddings`.
+pub const EMBEDDINGS_CACHE_KEY: &str = "__cache__";
 
 // unsafe {
 //     libsqlite3_sys::sqlite3_auto_extension(Some(std::mem::transmute(
@@ -62,16 +73,69 @@ pub async fn open_state_db(db_path: &Path) -> Result<SqlitePool, sqlx::Error> {
     Ok(pool)
 }
 
+fn decode_row(row: &sqlx::sqlite::SqliteRow) -> Vec<f32> {
+    let blob: Vec<u8> = row.get("embedding");
+    let precision: String = row.get("precision");
+    precision
+        .parse::<EmbeddingPrecision>()
+        .unwrap_or_default()
+        .decode(&blob)
+}
+
 #[derive(Clone)]
 pub struct State {
     pub db: SqlitePool,
+    dimensions: Arc<RwLock<HashMap<String, usize>>>,
+    precisions: Arc<RwLock<HashMap<String, EmbeddingPrecision>>>,
 }
 
 impl State {
     pub async fn new(path: &str) -> Result<Self, sqlx::Error> {
         let db_path = &std::path::PathBuf::from(format!("{}/{}", &path, "state.db"));
         let db = open_state_db(db_path).await?;
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            dimensions: Arc::new(RwLock::new(HashMap::new())),
+            precisions: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Vectors stored (and queried) under `key` are truncated to `dims` and renormalized.
+    pub fn set_embedding_dimensions(&self, key: &str, dims: usize) {
+        self.dimensions
+            .write()
+            .expect("dimensions lock poisoned")
+            .insert(key.to_string(), dims);
+    }
+
+    /// Vectors stored under `key` are quantized to `precision`.
+    pub fn set_embedding_precision(&self, key: &str, precision: EmbeddingPrecision) {
+        self.precisions
+            .write()
+            .expect("precisions lock poisoned")
+            .insert(key.to_string(), precision);
+    }
+
+    fn precision(&self, key: &str) -> EmbeddingPrecision {
+        self.precisions
+            .read()
+            .expect("precisions lock poisoned")
+            .get(key)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn fit_dimensions<'a>(&self, key: &str, embedding: &'a [f32]) -> Cow<'a, [f32]> {
+        let dims = self
+            .dimensions
+            .read()
+            .expect("dimensions lock poisoned")
+            .get(key)
+            .copied();
+        match dims {
+            Some(dims) => Cow::Owned(truncate_embedding(embedding, dims)),
+            None => Cow::Borrowed(embedding),
+        }
     }
 
     // Runs
@@ -124,6 +188,66 @@ impl State {
         Ok(())
     }
 
+    /// Merges `patch` into the item's JSON metadata (RFC 7396 merge patch).
+    pub async fn merge_item_metadata(
+        &self,
+        item_id: &str,
+        patch: &JsonValue,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE items SET metadata = json_patch(COALESCE(metadata, '{}'), ?) WHERE item_id = ?",
+        )
+        .bind(patch.to_string())
+        .bind(item_id)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn items_metadata(
+        &self,
+        item_ids: &[String],
+    ) -> Result<HashMap<String, JsonValue>, sqlx::Error> {
+        let mut out = HashMap::new();
+        for item_id in item_ids {
+            let meta: Option<Option<String>> =
+                sqlx::query_scalar("SELECT metadata FROM items WHERE item_id = ?")
+                    .bind(item_id)
+                    .fetch_optional(&self.db)
+                    .await?;
+            if let Some(meta) = meta.flatten() {
+                let value = serde_json::from_str(&meta).unwrap_or(JsonValue::String(meta));
+                out.insert(item_id.clone(), value);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Stores the content based key of an item, returns whether another item already had it.
+    pub async fn set_item_key(&self, item_id: &str, item_key: &str) -> Result<bool, sqlx::Error> {
+        let seen: Option<i64> =
+            sqlx::query_scalar("SELECT 1 FROM items WHERE item_key = ? AND item_id != ? LIMIT 1")
+                .bind(item_key)
+                .bind(item_id)
+                .fetch_optional(&self.db)
+                .await?;
+        sqlx::query("UPDATE items SET item_key = ? WHERE item_id = ?")
+            .bind(item_key)
+            .bind(item_id)
+            .execute(&self.db)
+            .await?;
+        Ok(seen.is_some())
+    }
+
+    pub async fn items_by_key(&self, item_key: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT item_id FROM items WHERE item_key = ? ORDER BY created_at, rowid",
+        )
+        .bind(item_key)
+        .fetch_all(&self.db)
+        .await
+    }
+
     pub async fn delete_item(&self, item_id: &str) -> Result<(), sqlx::Error> {
         sqlx::query("DELETE FROM items WHERE item_id = ?")
             .bind(item_id)
@@ -153,6 +277,32 @@ impl State {
         Ok(v.is_some())
     }
 
+    /// Adds the hash unless it's already stored for the key, `false` when it was.
+    pub async fn claim_hash(
+        &self,
+        item_id: &str,
+        key: &str,
+        hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("INSERT OR IGNORE INTO hashes(item_id, key, hash) VALUES (?, ?, ?)")
+                .bind(item_id)
+                .bind(key)
+                .bind(hash)
+                .execute(&self.db)
+                .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn remove_hash(&self, key: &str, hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM hashes WHERE key = ? AND hash = ?")
+            .bind(key)
+            .bind(hash)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
     // Simhashes
     pub async fn add_simhash(
         &self,
@@ -175,29 +325,192 @@ impl State {
         item_id: &str,
         key: &str,
         embedding: &[f32],
+        model: Option<&str>,
+        text_hash: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let embedding = self.fit_dimensions(key, embedding);
+        let precision = self.precision(key);
+        let buf = precision.encode(&embedding);
+
+        sqlx::query(
+            "INSERT INTO embeddings(item_id, key, embedding, model, text_hash, precision) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(item_id)
+        .bind(key)
+        .bind(buf)
+        .bind(model)
+        .bind(text_hash)
+        .bind(precision.as_str())
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns all vectors stored for `key` as (row id, embedding) pairs.
+    pub async fn embeddings_by_key(&self, key: &str) -> Result<Vec<(i64, Vec<f32>)>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT id, embedding, precision FROM embeddings WHERE key = ? ORDER BY id",
+        )
+        .bind(key)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows.iter().map(|r| (r.get("id"), decode_row(r))).collect())
+    }
+
+    pub async fn set_embedding_clusters(&self, clusters: &[(i64, i64)]) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        for (id, cluster) in clusters {
+            sqlx::query("UPDATE embeddings SET cluster = ? WHERE id = ?")
+                .bind(cluster)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns (item_id, text_hash, cluster) for every clustered vector stored for `key`.
+    pub async fn embedding_clusters(
+        &self,
+        key: &str,
+    ) -> Result<Vec<(Option<String>, Option<String>, i64)>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT item_id, text_hash, cluster FROM embeddings WHERE key = ? AND cluster IS NOT NULL",
+        )
+        .bind(key)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| (r.get("item_id"), r.get("text_hash"), r.get("cluster")))
+            .collect())
+    }
+
+    // Embeddings cache
+    /// Looks up the cached vector for (model, text_hash). Only cache entries are used, vectors
+    /// persisted by pipeline steps may be truncated or stored at a lower precision.
+    pub async fn cached_embedding(
+        &self,
+        model: &str,
+        text_hash: &str,
+    ) -> Result<Option<Vec<f32>>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT embedding, precision FROM embeddings WHERE key = ? AND model = ? AND text_hash = ? LIMIT 1",
+        )
+        .bind(EMBEDDINGS_CACHE_KEY)
+        .bind(model)
+        .bind(text_hash)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(row.map(|r| decode_row(&r)))
+    }
+
+    pub async fn cache_embedding(
+        &self,
+        model: &str,
+        text_hash: &str,
+        embedding: &[f32],
     ) -> Result<(), sqlx::Error> {
-        // Serialize f32 slice to little-endian bytes
         let mut buf = Vec::with_capacity(embedding.len() * 4);
         for v in embedding {
             buf.extend_from_slice(&v.to_le_bytes());
         }
 
-        sqlx::query("INSERT INTO embeddings(item_id, key, embedding) VALUES (?, ?, ?)")
-            .bind(item_id)
+        sqlx::query(
+            "INSERT INTO embeddings(item_id, key, embedding, model, text_hash) SELECT NULL, ?, ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM embeddings WHERE key = ? AND model = ? AND text_hash = ?)",
+        )
+        .bind(EMBEDDINGS_CACHE_KEY)
+        .bind(buf)
+        .bind(model)
+        .bind(text_hash)
+        .bind(EMBEDDINGS_CACHE_KEY)
+        .bind(model)
+        .bind(text_hash)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes cache entries, either for a single model or all of them. Vectors
+    /// persisted by pipeline steps are kept. Returns the number of deleted entries.
+    pub async fn flush_embeddings_cache(&self, model: Option<&str>) -> Result<u64, sqlx::Error> {
+        let res = match model {
+            Some(model) => {
+                sqlx::query("DELETE FROM embeddings WHERE key = ? AND model = ?")
+                    .bind(EMBEDDINGS_CACHE_KEY)
+                    .bind(model)
+                    .execute(&self.db)
+                    .await?
+            }
+            None => {
+                sqlx::query("DELETE FROM embeddings WHERE key = ?")
+                    .bind(EMBEDDINGS_CACHE_KEY)
+                    .execute(&self.db)
+                    .await?
+            }
+        };
+        Ok(res.rows_affected())
+    }
+
+    // Step cache
+    pub async fn cached_step_result(&self, key: &str) -> Result<Option<JsonValue>, sqlx::Error> {
+        let row = sqlx::query("SELECT value FROM step_cache WHERE key = ?")
             .bind(key)
-            .bind(buf)
-            .execute(&self.db)
+            .fetch_optional(&self.db)
             .await?;
+        Ok(row.and_then(|r| serde_json::from_str(&r.get::<String, _>("value")).ok()))
+    }
 
+    pub async fn cache_step_result(
+        &self,
+        step: &str,
+        key: &str,
+        value: &JsonValue,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO step_cache(key, step, value) VALUES (?, ?, ?)")
+            .bind(key)
+            .bind(step)
+            .bind(value.to_string())
+            .execute(&self.db)
+            .await?;
         Ok(())
     }
 
+    /// Removes cached step results, either of a single step or all of them. Returns the
+    /// number of deleted entries.
+    pub async fn flush_step_cache(&self, step: Option<&str>) -> Result<u64, sqlx::Error> {
+        let res = match step {
+            Some(step) => {
+                // python names steps `NAME--index`, either form is accepted
+                sqlx::query("DELETE FROM step_cache WHERE step = ? OR step LIKE ? || '--%'")
+                    .bind(step)
+                    .bind(step)
+                    .execute(&self.db)
+                    .await?
+            }
+            None => {
+                sqlx::query("DELETE FROM step_cache")
+                    .execute(&self.db)
+                    .await?
+            }
+        };
+        Ok(res.rows_affected())
+    }
+
     pub async fn knn_embeddings(
         &self,
         key: &str,
         query: &[f32],
         k: usize,
     ) -> Result<Vec<(Option<String>, f32)>, sqlx::Error> {
+        let query = self.fit_dimensions(key, query);
+
         // serialize query as f32 LE BLOB (sqlite-vec accepts vec_f32('[1,2,3]') but
         // we will pass the raw blob using vec_f32(?) by creating the same format
         // as vec_f32: the extension provides vec_f32(text) to create blob from text.
@@ -218,7 +531,7 @@ impl State {
         // vec_distance_cosine returns a distance: 1 - cosine; similarity = 1 - distance
         // Order by distance ascending, but return similarity.
         let q = sqlx::query(
-            "SELECT item_id, (1.0 - vec_distance_cosine(embedding, vec_f32(?))) as similarity FROM embeddings WHERE key = ? ORDER BY vec_distance_cosine(embedding, vec_f32(?)) ASC LIMIT ?",
+            "SELECT item_id, (1.0 - vec_distance_cosine(embedding, vec_f32(?))) as similarity FROM embeddings WHERE key = ? AND precision = 'f32' ORDER BY vec_distance_cosine(embedding, vec_f32(?)) ASC LIMIT ?",
         )
         .bind(&s)
         .bind(key)
@@ -234,6 +547,23 @@ impl State {
             out.push((item_id, sim));
         }
 
+        // sqlite-vec only handles f32 blobs, quantized vectors are compared in Rust
+        let quantized = sqlx::query(
+            "SELECT item_id, embedding, precision FROM embeddings WHERE key = ? AND precision != 'f32'",
+        )
+        .bind(key)
+        .fetch_all(&self.db)
+        .await?;
+
+        if !quantized.is_empty() {
+            for row in quantized.iter() {
+                let sim = cosine_similarity(&query, &decode_row(row));
+                out.push((row.get("item_id"), sim));
+            }
+            out.sort_by(|a, b| b.1.total_cmp(&a.1));
+            out.truncate(k);
+        }
+
         Ok(out)
     }
 
@@ -326,6 +656,10 @@ mod tests {
         assert!(!state.hash_exists("k1", "h1").await?);
         state.add_hash("item1", "k1", "h1").await?;
         assert!(state.hash_exists("k1", "h1").await?);
+        assert!(!state.claim_hash("item1", "k1", "h1").await?);
+        assert!(state.claim_hash("item1", "k1", "h2").await?);
+        state.remove_hash("k1", "h2").await?;
+        assert!(!state.hash_exists("k1", "h2").await?);
 
         // simhash
         let q: u64 = 0x0123_4567_89AB_CDEF;
@@ -380,8 +714,12 @@ mod tests {
         let b = vec![0.0f32, 1.0, 0.0];
         let q = vec![1.0f32, 0.0, 0.0];
 
-        state.add_embedding("item_sql_1", "sek", &a).await?;
-        state.add_embedding("item_sql_2", "sek", &b).await?;
+        state
+            .add_embedding("item_sql_1", "sek", &a, None, None)
+            .await?;
+        state
+            .add_embedding("item_sql_2", "sek", &b, Some("m1"), Some("h2"))
+            .await?;
 
         let res = state.knn_embeddings("sek", &q, 2).await?;
         assert_eq!(res.len(), 2);
@@ -391,4 +729,196 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_embeddings_cache() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        assert!(state.cached_embedding("m1", "h1").await?.is_none());
+
+        let a = vec![0.25f32, -1.0, 3.5];
+        state.cache_embedding("m1", "h1", &a).await?;
+        state.cache_embedding("m1", "h1", &a).await?;
+        state.cache_embedding("m2", "h1", &[1.0, 0.0, 0.0]).await?;
+
+        assert_eq!(state.cached_embedding("m1", "h1").await?, Some(a));
+
+        // cached vectors must not show up in knn lookups
+        let res = state.knn_embeddings("sek", &[1.0, 0.0, 0.0], 5).await?;
+        assert!(res.is_empty());
+
+        assert_eq!(state.flush_embeddings_cache(Some("m1")).await?, 1);
+        assert!(state.cached_embedding("m1", "h1").await?.is_none());
+        assert!(state.cached_embedding("m2", "h1").await?.is_some());
+        assert_eq!(state.flush_embeddings_cache(None).await?, 1);
+
+        // vectors persisted by steps aren't served from the cache and survive a flush
+        state.add_run("run_cache", "/tmp/log", None).await?;
+        state.add_item("item_cache", "run_cache", 0, None).await?;
+        state
+            .add_embedding("item_cache", "sek", &[0.0, 1.0], Some("m1"), Some("h3"))
+            .await?;
+        assert_eq!(state.cached_embedding("m1", "h3").await?, None);
+        state.cache_embedding("m1", "h3", &[1.0, 1.0]).await?;
+        assert_eq!(
+            state.cached_embedding("m1", "h3").await?,
+            Some(vec![1.0, 1.0])
+        );
+        assert_eq!(state.flush_embeddings_cache(None).await?, 1);
+        assert_eq!(state.embeddings_by_key("sek").await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embedding_clusters() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        state.add_run("run_cl", "/tmp/log", None).await?;
+        state.add_item("item_cl_1", "run_cl", 0, None).await?;
+        state.add_item("item_cl_2", "run_cl", 1, None).await?;
+        state
+            .add_embedding("item_cl_1", "ck", &[1.0, 0.0], None, Some("h1"))
+            .await?;
+        state
+            .add_embedding("item_cl_2", "ck", &[0.0, 1.0], None, Some("h2"))
+            .await?;
+
+        let stored = state.embeddings_by_key("ck").await?;
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[1].1, vec![0.0, 1.0]);
+        assert!(state.embedding_clusters("ck").await?.is_empty());
+
+        state
+            .set_embedding_clusters(&[(stored[0].0, 0), (stored[1].0, 1)])
+            .await?;
+        let mut clusters = state.embedding_clusters("ck").await?;
+        clusters.sort_by_key(|c| c.2);
+        assert_eq!(clusters[0].0.as_deref(), Some("item_cl_1"));
+        assert_eq!(clusters[1].1.as_deref(), Some("h2"));
+        assert_eq!(clusters[1].2, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embedding_dimensions() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        state.add_run("run_dim", "/tmp/log", None).await?;
+        state.add_item("item_dim", "run_dim", 0, None).await?;
+        state.set_embedding_dimensions("dk", 2);
+        state
+            .add_embedding("item_dim", "dk", &[3.0, 4.0, 100.0], None, None)
+            .await?;
+
+        let stored = state.embeddings_by_key("dk").await?;
+        assert_eq!(stored[0].1, vec![0.6, 0.8]);
+
+        // queries are truncated the same way
+        let res = state.knn_embeddings("dk", &[3.0, 4.0, -7.0], 1).await?;
+        assert!((res[0].1 - 1.0).abs() < 1e-5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embedding_precision() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        state.add_run("run_q", "/tmp/log", None).await?;
+        state.add_item("item_q_1", "run_q", 0, None).await?;
+        state.add_item("item_q_2", "run_q", 1, None).await?;
+        state.add_item("item_q_3", "run_q", 2, None).await?;
+
+        // mixed precisions under one key (e.g. changed between runs)
+        state
+            .add_embedding("item_q_1", "qk", &[0.0, 1.0, 0.0], None, None)
+            .await?;
+        state.set_embedding_precision("qk", EmbeddingPrecision::F16);
+        state
+            .add_embedding("item_q_2", "qk", &[1.0, 0.1, 0.0], None, None)
+            .await?;
+        state.set_embedding_precision("qk", EmbeddingPrecision::Int8);
+        state
+            .add_embedding("item_q_3", "qk", &[0.0, 0.0, 1.0], Some("m"), Some("h"))
+            .await?;
+
+        let res = state.knn_embeddings("qk", &[1.0, 0.0, 0.0], 2).await?;
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].0.as_deref(), Some("item_q_2"));
+        assert!(res[0].1 > 0.99);
+
+        // lossy vectors are never reused as model output
+        assert_eq!(state.cached_embedding("m", "h").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_items_metadata() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        state.add_run("run_meta", "/tmp/log", None).await?;
+        state
+            .add_item(
+                "item_meta_1",
+                "run_meta",
+                0,
+                Some(serde_json::json!({"a": 1})),
+            )
+            .await?;
+        state.add_item("item_meta_2", "run_meta", 1, None).await?;
+
+        state
+            .merge_item_metadata("item_meta_1", &serde_json::json!({"text": "hello"}))
+            .await?;
+        state
+            .merge_item_metadata("item_meta_2", &serde_json::json!({"text": "world"}))
+            .await?;
+
+        let meta = state
+            .items_metadata(&["item_meta_1".to_string(), "item_meta_2".to_string()])
+            .await?;
+        assert_eq!(
+            meta["item_meta_1"],
+            serde_json::json!({"a": 1, "text": "hello"})
+        );
+        assert_eq!(meta["item_meta_2"], serde_json::json!({"text": "world"}));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_item_key() -> Result<(), sqlx::Error> {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        let state = State::new(path).await?;
+
+        state.add_run("run_key_1", "/tmp/log", None).await?;
+        state.add_run("run_key_2", "/tmp/log", None).await?;
+        state.add_item("item_key_1", "run_key_1", 0, None).await?;
+        state.add_item("item_key_2", "run_key_2", 0, None).await?;
+
+        assert!(!state.set_item_key("item_key_1", "k").await?);
+        // setting it again for the same item is not a duplicate
+        assert!(!state.set_item_key("item_key_1", "k").await?);
+        assert!(state.set_item_key("item_key_2", "k").await?);
+        assert_eq!(
+            state.items_by_key("k").await?,
+            vec!["item_key_1".to_string(), "item_key_2".to_string()]
+        );
+
+        Ok(())
+    }
 }
@@ -1,10 +1,14 @@
 use crate::{
-    common::validators::validate_tool_format_messages,
-    steps::{Step, StepContext, StepStatus},
+    common::{extract_json, validators::validate_tool_format_messages, ResultExt},
+    steps::{
+        generators::TextGenerationStep, tools::SimulateToolResponseStep, Step, StepContext,
+        StepStatus,
+    },
     PipelineResources,
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use log::error;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
 use serde_json::{json, Value};
 use std::borrow::Cow;
 
@@ -517,3 +521,1010 @@ impl Step for RenderGRPOStep {
         Ok(context)
     }
 }
+
+/// Drops the oldest turns of `messages` until at most `max_turns` turns and `max_tokens`
+/// tokens (as counted by `count` per message) are left. Leading system messages are always
+/// kept and a turn (a user message with the replies and tool messages following it) is never
+/// split. Returns `None` when even the system messages and the last turn do not fit.
+pub fn truncate_messages(
+    messages: &[Value],
+    max_turns: Option<usize>,
+    max_tokens: Option<usize>,
+    count: impl Fn(&Value) -> usize,
+) -> Option<Vec<Value>> {
+    let system_len = messages
+        .iter()
+        .take_while(|m| m["role"] == "system")
+        .count();
+    let (system, rest) = messages.split_at(system_len);
+
+    let mut turns: Vec<&[Value]> = Vec::new();
+    let mut start = 0;
+    for i in 1..rest.len() {
+        if rest[i]["role"] == "user" {
+            turns.push(&rest[start..i]);
+            start = i;
+        }
+    }
+    if start < rest.len() {
+        turns.push(&rest[start..]);
+    }
+
+    let tokens = |messages: &[Value]| messages.iter().map(&count).sum::<usize>();
+    let mut total = tokens(system) + turns.iter().map(|t| tokens(t)).sum::<usize>();
+    let mut first = 0;
+    while first < turns.len()
+        && (max_turns.is_some_and(|max| turns.len() - first > max)
+            || max_tokens.is_some_and(|max| total > max))
+    {
+        total -= tokens(turns[first]);
+        first += 1;
+    }
+    if first == turns.len() && !turns.is_empty() || max_tokens.is_some_and(|max| total > max) {
+        return None;
+    }
+
+    Some(
+        system
+            .iter()
+            .chain(turns[first..].iter().flat_map(|t| t.iter()))
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Trims a conversation (a `messages` list or an object holding one) to the most recent
+/// `max_turns` turns and/or `max_tokens` tokens, keeping the system message.
+pub struct TruncateConversationStep {
+    pub name: String,
+    pub input: String,
+    pub output: Option<String>,
+    pub max_turns: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub tokenizer: Option<String>,
+}
+
+impl TruncateConversationStep {
+    pub fn new(
+        name: String,
+        input: String,
+        output: Option<String>,
+        max_turns: Option<usize>,
+        max_tokens: Option<usize>,
+        tokenizer: Option<String>,
+    ) -> Result<Self> {
+        if max_tokens.is_some() && tokenizer.is_none() {
+            anyhow::bail!("🐔 Token limit requires a tokenizer");
+        }
+        Ok(Self {
+            name,
+            input,
+            output,
+            max_turns,
+            max_tokens,
+            tokenizer,
+        })
+    }
+}
+
+impl Step for TruncateConversationStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let tokenizer = match &self.tokenizer {
+            Some(name) => Some(
+                resources
+                    .tokenizers
+                    .get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Tokenizer not found: {}", name))?,
+            ),
+            None => None,
+        };
+
+        let mut conversation = context.get(&self.input).cloned().unwrap_or_default();
+        let messages = match &mut conversation {
+            Value::Array(messages) => messages,
+            Value::Object(obj) => match obj.get_mut("messages") {
+                Some(Value::Array(messages)) => messages,
+                _ => {
+                    error!(target: "conversation_step", "🐔 Conversation {} has no messages", self.input);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            },
+            _ => {
+                error!(target: "conversation_step", "🐔 Conversation {} is missing or invalid", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        // message contents are counted, tool calls by their JSON
+        let count = |message: &Value| match (tokenizer, &message["content"]) {
+            (None, _) => 0,
+            (Some(tokenizer), Value::String(content)) if message.get("tool_calls").is_none() => {
+                tokenizer
+                    .count(content)
+                    .map_anyhow_err()
+                    .unwrap_or_default()
+            }
+            (Some(tokenizer), _) => tokenizer
+                .count(&message.to_string())
+                .map_anyhow_err()
+                .unwrap_or_default(),
+        };
+
+        match truncate_messages(messages, self.max_turns, self.max_tokens, count) {
+            Some(truncated) => *messages = truncated,
+            None => {
+                error!(target: "conversation_step", "🐔 Conversation {} does not fit the limits", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        }
+
+        context.set(self.output.as_ref().unwrap_or(&self.input), conversation);
+        Ok(context)
+    }
+}
+
+/// Replaces the content of the leading system message, or inserts one.
+pub fn set_system_prompt(messages: &mut Vec<Value>, prompt: &str) {
+    match messages.first_mut() {
+        Some(message) if message["role"] == "system" => message["content"] = json!(prompt),
+        _ => messages.insert(0, json!({"role": "system", "content": prompt})),
+    }
+}
+
+/// Perturbs an existing conversation (a `messages` list or an object with `messages` and
+/// `tools`): picks one of `system_prompts` (template keys), shuffles the tools and lets an
+/// LLM paraphrase user turns with probability `paraphrase_prob`. With a `seed` the choices
+/// are reproducible per item `index`.
+pub struct AugmentConversationStep {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub system_prompts: Vec<String>,
+    pub shuffle_tools: bool,
+    pub paraphrase: Option<TextGenerationStep>,
+    pub paraphrase_prob: f64,
+    pub seed: Option<u64>,
+}
+
+impl AugmentConversationStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        input: String,
+        output: String,
+        system_prompts: Vec<String>,
+        shuffle_tools: bool,
+        paraphrase: Option<TextGenerationStep>,
+        paraphrase_prob: f64,
+        seed: Option<u64>,
+    ) -> Self {
+        Self {
+            name,
+            input,
+            output,
+            system_prompts,
+            shuffle_tools,
+            paraphrase,
+            paraphrase_prob: paraphrase_prob.clamp(0.0, 1.0),
+            seed,
+        }
+    }
+
+    pub fn rng(&self, resources: &PipelineResources, context: &StepContext) -> StdRng {
+        resources.rng(&self.name, self.seed, context)
+    }
+}
+
+impl Step for AugmentConversationStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let mut rng = self.rng(resources, &context);
+
+        let mut conversation = context.get(&self.input).cloned().unwrap_or_default();
+        let (messages, tools) = match &mut conversation {
+            Value::Array(messages) => (messages, None),
+            Value::Object(obj) => {
+                let mut tools = obj.remove("tools");
+                if let Some(Value::Array(tools)) = &mut tools {
+                    if self.shuffle_tools {
+                        tools.shuffle(&mut rng);
+                    }
+                }
+                match obj.get_mut("messages") {
+                    Some(Value::Array(messages)) => (messages, tools),
+                    _ => {
+                        error!(target: "conversation_step", "🐔 Conversation {} has no messages", self.input);
+                        context.set_status(StepStatus::Failed);
+                        return Ok(context);
+                    }
+                }
+            }
+            _ => {
+                error!(target: "conversation_step", "🐔 Conversation {} is missing or invalid", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        if !self.system_prompts.is_empty() {
+            let template = &self.system_prompts[rng.random_range(0..self.system_prompts.len())];
+            let prompt = resources
+                .templates
+                .render(template.clone(), context.data.clone())?;
+            set_system_prompt(messages, &prompt);
+        }
+
+        if let Some(paraphrase) = &self.paraphrase {
+            for message in messages.iter_mut() {
+                let Some(content) = message["content"].as_str().map(|c| c.to_string()) else {
+                    continue;
+                };
+                if message["role"] != "user" || !rng.random_bool(self.paraphrase_prob) {
+                    continue;
+                }
+
+                let mut paraphrase_context = context.clone();
+                paraphrase_context.set("message", content);
+                match paraphrase
+                    .generate(
+                        &resources.datasets.resources,
+                        &resources.templates,
+                        &resources.llms.resources,
+                        &resources.embeddings.resources,
+                        &paraphrase_context,
+                        None,
+                        paraphrase.max_tokens,
+                        paraphrase.temperature,
+                    )
+                    .await?
+                {
+                    Some(text) => message["content"] = json!(text.trim()),
+                    None => {
+                        context.set_status(StepStatus::Failed);
+                        return Ok(context);
+                    }
+                }
+            }
+        }
+
+        if let (Value::Object(obj), Some(tools)) = (&mut conversation, tools) {
+            obj.insert("tools".to_string(), tools);
+        }
+        context.set(&self.output, conversation);
+        Ok(context)
+    }
+}
+
+/// Conversation layouts understood by [`ConvertFormatStep`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationFormat {
+    /// `{"messages": [{"role", "content", "tool_calls"}], "tools"}`
+    OpenAi,
+    /// `{"conversations": [{"from": "human" | "gpt" | "function_call" | "observation", "value"}]}`
+    ShareGpt,
+    /// `{"instruction", "input", "output", "system", "history": [[user, assistant]]}`
+    Alpaca,
+    /// `{"conversation": [{"speaker", "message", "action", "details"}], "function_descriptions"}`
+    Internal,
+}
+
+impl std::str::FromStr for ConversationFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "openai" | "messages" => Ok(Self::OpenAi),
+            "sharegpt" => Ok(Self::ShareGpt),
+            "alpaca" => Ok(Self::Alpaca),
+            "internal" | "function_call" => Ok(Self::Internal),
+            _ => bail!(
+                "🐔 Unsupported conversation format '{}'. Allowed: openai, sharegpt, alpaca, internal",
+                s
+            ),
+        }
+    }
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+fn tool_call(name: &Value, arguments: &Value) -> Value {
+    let arguments = match arguments {
+        Value::String(arguments) => serde_json::from_str(arguments).unwrap_or_default(),
+        Value::Null => json!({}),
+        arguments => arguments.clone(),
+    };
+    json!({"type": "function", "function": {"name": name, "arguments": arguments}})
+}
+
+fn calls_of(message: &Value) -> Vec<(Value, Value)> {
+    message["tool_calls"]
+        .as_array()
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| {
+                    let function = call.get("function").unwrap_or(call);
+                    (function["name"].clone(), function["arguments"].clone())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a conversation in `format` as `messages` and `tools`.
+pub fn to_messages(value: &Value, format: ConversationFormat) -> Result<(Vec<Value>, Value)> {
+    let value = match value {
+        Value::String(s) => serde_json::from_str(s)?,
+        value => value.clone(),
+    };
+    let mut messages = Vec::new();
+    match format {
+        ConversationFormat::OpenAi => {
+            let list = match &value {
+                Value::Array(list) => list,
+                _ => value["messages"]
+                    .as_array()
+                    .ok_or_else(|| anyhow!("🐔 Missing 'messages' list"))?,
+            };
+            for message in list {
+                let mut message = message.clone();
+                if message["tool_calls"].is_array() {
+                    let calls = calls_of(&message)
+                        .iter()
+                        .map(|(name, arguments)| tool_call(name, arguments))
+                        .collect::<Vec<_>>();
+                    message["tool_calls"] = json!(calls);
+                }
+                messages.push(message);
+            }
+        }
+        ConversationFormat::ShareGpt => {
+            if let Some(system) = value["system"].as_str() {
+                messages.push(json!({"role": "system", "content": system}));
+            }
+            let turns = value["conversations"]
+                .as_array()
+                .ok_or_else(|| anyhow!("🐔 Missing 'conversations' list"))?;
+            for turn in turns {
+                let text = text_value(&turn["value"]);
+                let message = match turn["from"].as_str().unwrap_or_default() {
+                    "system" => json!({"role": "system", "content": text}),
+                    "human" | "user" => json!({"role": "user", "content": text}),
+                    "gpt" | "assistant" => json!({"role": "assistant", "content": text}),
+                    "function_call" => {
+                        let calls = match serde_json::from_str::<Value>(&text)? {
+                            Value::Array(calls) => calls,
+                            call => vec![call],
+                        };
+                        let calls = calls
+                            .iter()
+                            .map(|call| tool_call(&call["name"], &call["arguments"]))
+                            .collect::<Vec<_>>();
+                        json!({"role": "assistant", "tool_calls": calls})
+                    }
+                    "observation" | "tool" => json!({"role": "tool", "content": text}),
+                    other => bail!("🐔 Unsupported ShareGPT speaker '{}'", other),
+                };
+                messages.push(message);
+            }
+        }
+        ConversationFormat::Alpaca => {
+            if let Some(system) = value["system"].as_str().filter(|s| !s.is_empty()) {
+                messages.push(json!({"role": "system", "content": system}));
+            }
+            for pair in value["history"].as_array().into_iter().flatten() {
+                messages.push(json!({"role": "user", "content": text_value(&pair[0])}));
+                messages.push(json!({"role": "assistant", "content": text_value(&pair[1])}));
+            }
+            let instruction = value["instruction"]
+                .as_str()
+                .ok_or_else(|| anyhow!("🐔 Missing 'instruction'"))?;
+            let content = match value["input"].as_str().filter(|s| !s.is_empty()) {
+                Some(input) => format!("{}\n\n{}", instruction, input),
+                None => instruction.to_string(),
+            };
+            messages.push(json!({"role": "user", "content": content}));
+            if let Some(output) = value.get("output") {
+                messages.push(json!({"role": "assistant", "content": text_value(output)}));
+            }
+        }
+        ConversationFormat::Internal => {
+            let turns = value["conversation"]
+                .as_array()
+                .ok_or_else(|| anyhow!("🐔 Missing 'conversation' list"))?;
+            for turn in turns {
+                let speaker = turn["speaker"].as_str().unwrap_or_default();
+                match (speaker, turn["action"].as_str()) {
+                    (_, Some("function-call")) => {
+                        let details = &turn["details"];
+                        messages.push(json!({
+                            "role": "assistant",
+                            "tool_calls": [tool_call(&details["name"], &details["arguments"])]
+                        }));
+                    }
+                    (_, Some("function-response")) => {
+                        for (name, result) in turn["details"].as_object().into_iter().flatten() {
+                            messages.push(
+                                json!({"role": "tool", "name": name, "content": text_value(result)}),
+                            );
+                        }
+                    }
+                    ("human", _) => messages
+                        .push(json!({"role": "user", "content": text_value(&turn["message"])})),
+                    ("assistant" | "system", _) => messages
+                        .push(json!({"role": speaker, "content": text_value(&turn["message"])})),
+                    (other, _) => bail!("🐔 Unsupported speaker '{}'", other),
+                }
+            }
+        }
+    }
+
+    let tools = match format {
+        ConversationFormat::Internal => value["function_descriptions"].clone(),
+        ConversationFormat::Alpaca => Value::Null,
+        _ => match &value["tools"] {
+            Value::String(tools) => serde_json::from_str(tools)?,
+            tools => tools.clone(),
+        },
+    };
+    Ok((messages, tools))
+}
+
+/// Writes `messages` and `tools` as a conversation in `format`.
+pub fn from_messages(
+    messages: &[Value],
+    tools: &Value,
+    format: ConversationFormat,
+) -> Result<Value> {
+    let role = |message: &Value| message["role"].as_str().unwrap_or_default().to_string();
+    let mut out = match format {
+        ConversationFormat::OpenAi => json!({"messages": messages}),
+        ConversationFormat::ShareGpt => {
+            let mut turns = Vec::with_capacity(messages.len());
+            for message in messages {
+                let calls = calls_of(message);
+                let (from, value) = match role(message).as_str() {
+                    "assistant" if !calls.is_empty() => {
+                        let calls = calls
+                            .iter()
+                            .map(|(name, arguments)| json!({"name": name, "arguments": arguments}))
+                            .collect::<Vec<_>>();
+                        let calls = match calls.as_slice() {
+                            [call] => call.clone(),
+                            _ => json!(calls),
+                        };
+                        ("function_call", calls.to_string())
+                    }
+                    "assistant" => ("gpt", text_value(&message["content"])),
+                    "user" => ("human", text_value(&message["content"])),
+                    "system" => ("system", text_value(&message["content"])),
+                    "tool" => ("observation", text_value(&message["content"])),
+                    other => bail!("🐔 Unsupported role '{}'", other),
+                };
+                turns.push(json!({"from": from, "value": value}));
+            }
+            json!({"conversations": turns})
+        }
+        ConversationFormat::Alpaca => {
+            let mut out = json!({"instruction": "", "input": "", "output": "", "system": ""});
+            let mut rest = messages;
+            if let Some((first, tail)) = rest.split_first().filter(|(m, _)| role(m) == "system") {
+                out["system"] = first["content"].clone();
+                rest = tail;
+            }
+            if !rest.len().is_multiple_of(2) || rest.is_empty() {
+                bail!("🐔 Alpaca needs user/assistant pairs ending with an assistant reply");
+            }
+            let mut history = Vec::new();
+            for pair in rest.chunks(2) {
+                if role(&pair[0]) != "user"
+                    || role(&pair[1]) != "assistant"
+                    || !calls_of(&pair[1]).is_empty()
+                {
+                    bail!("🐔 Alpaca cannot hold tool calls or consecutive messages of one role");
+                }
+                history.push(json!([pair[0]["content"], pair[1]["content"]]));
+            }
+            let last = history.pop().expect("at least one pair");
+            out["instruction"] = last[0].clone();
+            out["output"] = last[1].clone();
+            if !history.is_empty() {
+                out["history"] = json!(history);
+            }
+            return Ok(out);
+        }
+        ConversationFormat::Internal => {
+            let mut turns = Vec::with_capacity(messages.len());
+            // tool messages without a name answer the calls in order
+            let mut pending = std::collections::VecDeque::new();
+            for message in messages {
+                let calls = calls_of(message);
+                match role(message).as_str() {
+                    "assistant" if !calls.is_empty() => {
+                        for (name, arguments) in calls {
+                            let arguments = match arguments {
+                                Value::String(arguments) => {
+                                    serde_json::from_str(&arguments).unwrap_or_default()
+                                }
+                                arguments => arguments,
+                            };
+                            pending.push_back(name.clone());
+                            turns.push(json!({
+                                "speaker": "assistant",
+                                "message": null,
+                                "action": "function-call",
+                                "details": {"name": name, "arguments": arguments}
+                            }));
+                        }
+                    }
+                    "tool" => {
+                        let called = pending.pop_front();
+                        let name = message["name"]
+                            .as_str()
+                            .map(String::from)
+                            .or_else(|| called.and_then(|n| n.as_str().map(String::from)))
+                            .ok_or_else(|| anyhow!("🐔 Tool message without a call"))?;
+                        let content = text_value(&message["content"]);
+                        let result = serde_json::from_str::<Value>(&content)
+                            .unwrap_or(Value::String(content));
+                        turns.push(json!({
+                            "speaker": "assistant",
+                            "message": null,
+                            "action": "function-response",
+                            "details": {name: result}
+                        }));
+                    }
+                    role @ ("user" | "assistant" | "system") => turns.push(json!({
+                        "speaker": if role == "user" { "human" } else { role },
+                        "message": message["content"],
+                        "action": null,
+                        "details": null
+                    })),
+                    other => bail!("🐔 Unsupported role '{}'", other),
+                }
+            }
+            let mut out = json!({"conversation": turns});
+            if !tools.is_null() {
+                out["function_descriptions"] = tools.clone();
+            }
+            return Ok(out);
+        }
+    };
+    if !tools.is_null() {
+        out["tools"] = match format {
+            ConversationFormat::ShareGpt => Value::String(tools.to_string()),
+            _ => tools.clone(),
+        };
+    }
+    Ok(out)
+}
+
+/// Converts the conversation in `input` from one [`ConversationFormat`] to another.
+/// Items that cannot be represented in the target format (e.g. tool calls in alpaca) fail.
+pub struct ConvertFormatStep {
+    pub name: String,
+    pub input: String,
+    pub from: ConversationFormat,
+    pub to: ConversationFormat,
+    pub output: String,
+}
+
+impl ConvertFormatStep {
+    pub fn new(name: String, input: String, from: &str, to: &str, output: String) -> Result<Self> {
+        Ok(Self {
+            name,
+            input,
+            from: from.parse()?,
+            to: to.parse()?,
+            output,
+        })
+    }
+}
+
+impl Step for ConvertFormatStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let Some(value) = context.get(&self.input) else {
+            error!(target: "convert_format_step", "🐔 Conversation {} not found", self.input);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        };
+        let converted = to_messages(value, self.from)
+            .and_then(|(messages, tools)| from_messages(&messages, &tools, self.to));
+        match converted {
+            Ok(converted) => context.set(&self.output, converted),
+            Err(e) => {
+                error!(target: "convert_format_step", "🐔 Failed to convert {}: {}", self.input, e);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+/// Marker the simulated user replies with once its goal is reached.
+pub const END_OF_DIALOGUE: &str = "[END]";
+
+/// Turns an assistant reply into a message: a JSON object with `tool_calls` (entries as
+/// `{name, arguments}` or `{function: {name, arguments}}`) becomes a tool call message, an
+/// object with `content` or any other text a plain reply.
+pub fn assistant_message(reply: &str) -> Value {
+    let parsed = reply
+        .contains('{')
+        .then(|| extract_json(reply).ok())
+        .flatten();
+    match parsed {
+        Some(Value::Object(obj)) => match (obj.get("tool_calls"), obj.get("content")) {
+            (Some(Value::Array(calls)), _) if !calls.is_empty() => {
+                let tool_calls = calls
+                    .iter()
+                    .map(|call| {
+                        let function = call.get("function").unwrap_or(call);
+                        json!({"function": {
+                            "name": function["name"],
+                            "arguments": match &function["arguments"] {
+                                Value::String(arguments) => {
+                                    serde_json::from_str(arguments).unwrap_or_default()
+                                }
+                                Value::Null => json!({}),
+                                arguments => arguments.clone(),
+                            }
+                        }})
+                    })
+                    .collect::<Vec<_>>();
+                json!({"role": "assistant", "tool_calls": tool_calls})
+            }
+            (_, Some(Value::String(content))) => json!({"role": "assistant", "content": content}),
+            _ => json!({"role": "assistant", "content": reply.trim()}),
+        },
+        _ => json!({"role": "assistant", "content": reply.trim()}),
+    }
+}
+
+/// Generates a whole conversation by letting an LLM play the user and another (or the same)
+/// LLM the assistant for up to `max_turns` turns. With `tools` the assistant may call them
+/// and `tool_responses` answers the calls, up to `max_tool_rounds` times per turn. Both
+/// prompts get the dialogue so far as `__dialogue`; the user ends it early by replying with
+/// [`END_OF_DIALOGUE`].
+pub struct DialogueStep {
+    pub name: String,
+    pub output: String,
+    pub user: TextGenerationStep,
+    pub assistant: TextGenerationStep,
+    pub max_turns: usize,
+    pub instructions: Option<String>,
+    pub tools: Option<String>,
+    pub system_template: Option<String>,
+    pub tool_responses: Option<SimulateToolResponseStep>,
+    pub max_tool_rounds: usize,
+}
+
+impl DialogueStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        output: String,
+        user: TextGenerationStep,
+        assistant: TextGenerationStep,
+        max_turns: usize,
+        instructions: Option<String>,
+        tools: Option<String>,
+        system_template: Option<String>,
+        tool_responses: Option<SimulateToolResponseStep>,
+        max_tool_rounds: usize,
+    ) -> Self {
+        Self {
+            name,
+            output,
+            user,
+            assistant,
+            max_turns: max_turns.max(1),
+            instructions,
+            tools,
+            system_template,
+            tool_responses,
+            max_tool_rounds,
+        }
+    }
+
+    async fn reply(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+        generation_step: &TextGenerationStep,
+        dialogue: Value,
+    ) -> Result<Option<String>> {
+        let mut dialogue_context = context.clone();
+        dialogue_context.set("__dialogue", dialogue);
+        generation_step
+            .generate(
+                &resources.datasets.resources,
+                &resources.templates,
+                &resources.llms.resources,
+                &resources.embeddings.resources,
+                &dialogue_context,
+                None,
+                generation_step.max_tokens,
+                generation_step.temperature,
+            )
+            .await
+    }
+}
+
+impl Step for DialogueStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let instructions = self.instructions.as_ref().and_then(|key| context.get(key));
+        let tools = self.tools.as_ref().and_then(|key| context.get(key));
+        let dialogue = |messages: &Vec<Value>, turn: usize| {
+            json!({
+                "messages": messages,
+                "tools": tools,
+                "instructions": instructions,
+                "turn": turn,
+                "max_turns": self.max_turns,
+            })
+        };
+
+        let mut messages = Vec::new();
+        if let Some(template) = &self.system_template {
+            let prompt = resources
+                .templates
+                .render(template.clone(), context.data.clone())?;
+            set_system_prompt(&mut messages, &prompt);
+        }
+        let mut rng = self
+            .tool_responses
+            .as_ref()
+            .map(|t| t.rng(resources, &context));
+
+        'turns: for turn in 0..self.max_turns {
+            let Some(text) = self
+                .reply(resources, &context, &self.user, dialogue(&messages, turn))
+                .await?
+            else {
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            };
+            let text = text.trim();
+            if text.contains(END_OF_DIALOGUE) {
+                break;
+            }
+            messages.push(json!({"role": "user", "content": text}));
+
+            for round in 0..=self.max_tool_rounds {
+                let Some(reply) = self
+                    .reply(
+                        resources,
+                        &context,
+                        &self.assistant,
+                        dialogue(&messages, turn),
+                    )
+                    .await?
+                else {
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                };
+                let message = assistant_message(&reply);
+                let calls_tools = message.get("tool_calls").is_some();
+                messages.push(message);
+                if !calls_tools {
+                    break;
+                }
+
+                let (Some(tool_responses), Some(rng)) = (&self.tool_responses, &mut rng) else {
+                    // without simulated responses the dialogue ends on the tool call
+                    break 'turns;
+                };
+                if round == self.max_tool_rounds {
+                    error!(target: "conversation_step", "🐔 Assistant did not reply after {} tool rounds", self.max_tool_rounds);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+                if let Err(e) = tool_responses
+                    .answer(resources, &context, &mut messages, rng)
+                    .await
+                {
+                    error!(target: "conversation_step", "🐔 Failed to simulate tool response: {}", e);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        if !messages.iter().any(|m| m["role"] == "user") {
+            error!(target: "conversation_step", "🐔 Dialogue ended before the first user turn");
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        }
+        context.set(&self.output, messages);
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Value {
+        json!({"role": role, "content": content})
+    }
+
+    #[test]
+    fn test_truncate_messages() {
+        let messages = vec![
+            message("system", "s"),
+            message("user", "u1"),
+            message("assistant", "a1"),
+            message("user", "u2"),
+            json!({"role": "assistant", "tool_calls": [{"function": {"name": "f"}}]}),
+            message("tool", "t2"),
+            message("assistant", "a2"),
+            message("user", "u3"),
+            message("assistant", "a3"),
+        ];
+        let contents = |messages: Option<Vec<Value>>| {
+            messages.map(|m| {
+                m.iter()
+                    .map(|m| m["content"].as_str().unwrap_or("call").to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let count = |_: &Value| 1;
+
+        assert_eq!(
+            contents(truncate_messages(&messages, Some(1), None, count)),
+            Some(vec!["s".into(), "u3".into(), "a3".into()])
+        );
+        // whole turns are dropped so the tool call keeps its response
+        assert_eq!(
+            contents(truncate_messages(&messages, None, Some(7), count)),
+            Some(vec![
+                "s".into(),
+                "u2".into(),
+                "call".into(),
+                "t2".into(),
+                "a2".into(),
+                "u3".into(),
+                "a3".into()
+            ])
+        );
+        assert_eq!(
+            truncate_messages(&messages, Some(5), None, count).unwrap(),
+            messages
+        );
+        assert!(truncate_messages(&messages, None, Some(2), count).is_none());
+    }
+
+    #[test]
+    fn test_set_system_prompt() {
+        let mut messages = vec![message("user", "u1")];
+        set_system_prompt(&mut messages, "s1");
+        assert_eq!(
+            messages,
+            vec![message("system", "s1"), message("user", "u1")]
+        );
+        set_system_prompt(&mut messages, "s2");
+        assert_eq!(
+            messages,
+            vec![message("system", "s2"), message("user", "u1")]
+        );
+    }
+
+    #[test]
+    fn test_assistant_message() {
+        assert_eq!(
+            assistant_message("  Sure, done. "),
+            message("assistant", "Sure, done.")
+        );
+        assert_eq!(
+            assistant_message(r#"{"content": "Hi"}"#),
+            message("assistant", "Hi")
+        );
+        assert_eq!(
+            assistant_message(
+                r#"```json
+{"tool_calls": [{"name": "get_item", "arguments": "{\"item_id\": 3}"}]}
+```"#
+            ),
+            json!({"role": "assistant", "tool_calls": [
+                {"function": {"name": "get_item", "arguments": {"item_id": 3}}}
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_convert_format() {
+        use ConversationFormat::*;
+        let internal = json!({
+            "conversation": [
+                {"speaker": "system", "message": "Be brief.", "action": null, "details": null},
+                {"speaker": "human", "message": "Tip for 250?", "action": null, "details": null},
+                {"speaker": "assistant", "message": null, "action": "function-call",
+                 "details": {"name": "tip", "arguments": {"bill": 250}}},
+                {"speaker": "assistant", "message": null, "action": "function-response",
+                 "details": {"tip": {"amount": 50}}},
+                {"speaker": "assistant", "message": "50.", "action": null, "details": null}
+            ],
+            "function_descriptions": [{
+                "name": "tip",
+                "parameters": {
+                    "type": "object",
+                    "properties": {"bill": {"type": "number"}},
+                    "required": ["bill"]
+                }
+            }]
+        });
+        let convert = |value: &Value, from, to| {
+            let (messages, tools) = to_messages(value, from).unwrap();
+            from_messages(&messages, &tools, to)
+        };
+
+        let openai = convert(&internal, Internal, OpenAi).unwrap();
+        assert_eq!(
+            openai["messages"][2]["tool_calls"][0]["function"]["arguments"],
+            json!({"bill": 250})
+        );
+        assert_eq!(
+            openai["messages"][3],
+            json!({"role": "tool", "name": "tip", "content": "{\"amount\":50}"})
+        );
+        assert!(validate_tool_format_messages(&openai).is_ok());
+
+        let sharegpt = convert(&openai, OpenAi, ShareGpt).unwrap();
+        let froms = sharegpt["conversations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["from"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            froms,
+            vec!["system", "human", "function_call", "observation", "gpt"]
+        );
+        assert!(sharegpt["tools"].is_string());
+
+        // tool messages of sharegpt carry no name, it comes from the call
+        assert_eq!(convert(&sharegpt, ShareGpt, Internal).unwrap(), internal);
+        assert!(convert(&internal, Internal, Alpaca).is_err());
+
+        let alpaca = json!({
+            "instruction": "Translate", "input": "kot", "output": "cat",
+            "system": "", "history": [["Hi", "Hello"]]
+        });
+        let openai = convert(&alpaca, Alpaca, OpenAi).unwrap();
+        assert_eq!(openai["messages"].as_array().unwrap().len(), 4);
+        assert_eq!(openai["messages"][2]["content"], "Translate\n\nkot");
+        let back = convert(&openai, OpenAi, Alpaca).unwrap();
+        assert_eq!(back["instruction"], "Translate\n\nkot");
+        assert_eq!(back["history"], json!([["Hi", "Hello"]]));
+
+        assert!("markdown".parse::<ConversationFormat>().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::steps::{StepContext, StepStatus, StepType};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+/// Context keys a step reads and writes, the edges of a DAG pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct StepIo {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+/// Orders `steps` into waves, the steps of a wave only depend on earlier waves and can run
+/// concurrently.
+///
+/// A step depends on the closest earlier step writing each of its inputs, or on all later ones
+/// when no earlier step does. Inputs nobody writes are expected on the item. Steps without
+/// declared `io` run alone, after all steps added before them and before all added after them.
+pub fn plan(steps: &[StepType], io: &HashMap<String, StepIo>) -> Result<Vec<Vec<usize>>> {
+    for step in steps {
+        if matches!(step, StepType::Explode(_) | StepType::Accumulate(_)) {
+            bail!("🐔 Step {} can't run in a DAG pipeline", step.name());
+        }
+    }
+
+    let mut deps = vec![BTreeSet::new(); steps.len()];
+    for (i, step) in steps.iter().enumerate() {
+        let Some(step_io) = io.get(step.name()) else {
+            deps[i].extend(0..i);
+            for later in deps.iter_mut().skip(i + 1) {
+                later.insert(i);
+            }
+            continue;
+        };
+        for input in &step_io.inputs {
+            let writes = |j: &usize| {
+                io.get(steps[*j].name())
+                    .is_some_and(|other| other.outputs.contains(input))
+            };
+            match (0..i).rev().find(writes) {
+                Some(j) => {
+                    deps[i].insert(j);
+                }
+                None => deps[i].extend((i + 1..steps.len()).filter(writes)),
+            }
+        }
+    }
+
+    let mut done = vec![false; steps.len()];
+    let mut waves = Vec::new();
+    while done.iter().any(|done| !done) {
+        let wave = (0..steps.len())
+            .filter(|&i| !done[i] && deps[i].iter().all(|&j| done[j]))
+            .collect::<Vec<_>>();
+        if wave.is_empty() {
+            let cycle = (0..steps.len())
+                .filter(|&i| !done[i])
+                .map(|i| steps[i].name())
+                .collect::<Vec<_>>();
+            bail!("🐔 Steps {} depend on each other", cycle.join(", "));
+        }
+        for &i in &wave {
+            done[i] = true;
+        }
+        waves.push(wave);
+    }
+    Ok(waves)
+}
+
+/// Fails when one of the `inputs` is missing on the item.
+pub fn check_inputs(step: &str, io: &StepIo, context: &StepContext) -> Result<()> {
+    if let Some(input) = io.inputs.iter().find(|input| context.get(input).is_none()) {
+        bail!("🐔 Step {} is missing its input {}", step, input);
+    }
+    Ok(())
+}
+
+/// Copies the `outputs` of a branch back to the item, along with its failure.
+pub fn merge(context: &mut StepContext, branch: &StepContext, outputs: &[String]) {
+    for output in outputs {
+        if let Some(value) = branch.get(output) {
+            context.set(output, value);
+        }
+    }
+    if let Some(error) = branch.error() {
+        context.set_error(error);
+    }
+    match branch.get_status() {
+        StepStatus::Failed => {
+            if let Some(step) = branch.failed_step() {
+                context.set_failed_step(step);
+            }
+            context.set_status(StepStatus::Failed);
+        }
+        StepStatus::Completed => context.set_status(StepStatus::Completed),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::logic::MutateStep;
+
+    fn step(name: &str) -> StepType {
+        StepType::Mutate(MutateStep::new(
+            name.to_string(),
+            "x".to_string(),
+            "x".to_string(),
+            false,
+        ))
+    }
+
+    fn io(inputs: &[&str], outputs: &[&str]) -> StepIo {
+        StepIo {
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let steps = ["A", "B", "C", "D", "W"].map(step);
+        let mut steps_io = HashMap::from([
+            ("A".to_string(), io(&["topic"], &["question"])),
+            ("B".to_string(), io(&["topic"], &["style"])),
+            ("C".to_string(), io(&["question", "style"], &["answer"])),
+            ("D".to_string(), io(&["topic"], &["tags"])),
+        ]);
+        let waves = plan(&steps, &steps_io).unwrap();
+        assert_eq!(waves, vec![vec![0, 1, 3], vec![2], vec![4]]);
+
+        // inputs written by later steps are waited for
+        steps_io.insert("A".to_string(), io(&["answer"], &["question"]));
+        assert!(plan(&steps, &steps_io).is_err());
+        steps_io.insert("A".to_string(), io(&["tags"], &["question"]));
+        let waves = plan(&steps, &steps_io).unwrap();
+        assert_eq!(waves, vec![vec![1, 3], vec![0], vec![2], vec![4]]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let steps_io = io(&["topic"], &["question"]);
+        let mut context = StepContext::new();
+        assert!(check_inputs("A", &steps_io, &context).is_err());
+        context.set("topic", "math");
+        assert!(check_inputs("A", &steps_io, &context).is_ok());
+
+        let mut branch = context.clone();
+        branch.set("question", "2+2?");
+        branch.set("scratch", true);
+        branch.set_status(StepStatus::Failed);
+        branch.set_failed_step("A");
+        merge(&mut context, &branch, &steps_io.outputs);
+        assert_eq!(context.get("question").unwrap(), "2+2?");
+        assert!(context.get("scratch").is_none());
+        assert!(matches!(context.get_status(), StepStatus::Failed));
+        assert_eq!(context.failed_step(), Some("A"));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::{
-    embeddings::{e5::E5Model, Embeddings, EmbeddingsType},
-    steps::{Step, StepContext, StepStatus},
+    common::{blake3_hash, df_to_values},
+    embeddings::{cosine_similarity, embed_cached},
+    steps::{dataset_df, generators::TextGenerationStep, Step, StepContext, StepStatus},
     PipelineResources,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{error, info};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
 
 pub struct CheckEmbeddingStep {
     pub name: String,
@@ -46,58 +49,46 @@ impl Step for CheckEmbeddingStep {
                     .get(&self.embedding)
                     .ok_or_else(|| anyhow::anyhow!("Embedding not found: {}", self.embedding))?;
 
-                match embedding {
-                    EmbeddingsType::E5(spec) => {
-                        let text = if let Some(text) = value.as_str() {
-                            text
-                        } else {
-                            error!(target: "steps_embeddings", "🐔 Embedding input is not a string");
-                            context.set_status(StepStatus::Failed);
-                            return Ok(context);
-                        };
-
-                        let instance = E5Model::lazy(spec.clone())?;
-                        let emb = {
-                            let guard = instance
-                                .lock()
-                                .map_err(|e| anyhow::anyhow!("lock error: {:?}", e))?;
-                            guard.embed(vec![text.to_string()])?
-                        }; // guard is dropped here, before any await
-
-                        if let Some(state) = resources.state.as_ref() {
-                            let nearest = state
-                                .knn_embeddings(&self.input.clone(), &emb[0], 1)
-                                .await?;
-
-                            if !nearest.is_empty() && (nearest[0].1 - 1.0).abs() < self.treshold {
-                                info!(target: "steps_embeddings", "✅ Similar embedding found for input");
-                                context.set_status(StepStatus::Failed);
+                let text = if let Some(text) = value.as_str() {
+                    text
+                } else {
+                    error!(target: "steps_embeddings", "🐔 Embedding input is not a string");
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                };
+
+                let text_hash = blake3_hash(text);
+                let model = embedding.model_id();
+                let emb = embed_cached(embedding, resources.state.as_ref(), vec![text.to_string()])
+                    .await?;
+
+                if let Some(state) = resources.state.as_ref() {
+                    let nearest = state
+                        .knn_embeddings(&self.input.clone(), &emb[0], 1)
+                        .await?;
+
+                    if !nearest.is_empty() && (nearest[0].1 - 1.0).abs() < self.treshold {
+                        info!(target: "steps_embeddings", "✅ Similar embedding found for input");
+                        context.set_status(StepStatus::Failed);
+                    } else {
+                        state
+                            .add_embedding(
+                                &context.id.to_string(),
+                                &self.input,
+                                &emb[0],
+                                Some(&model),
+                                Some(&text_hash),
+                            )
+                            .await?;
+                        if let Some(output) = &self.similarity_output {
+                            if !nearest.is_empty() {
+                                let similarity = (nearest[0].1 - 1.0).abs();
+                                context.set(output, similarity);
                             } else {
-                                state
-                                    .add_embedding(&context.id.to_string(), &self.input, &emb[0])
-                                    .await?;
-                                if let Some(output) = &self.similarity_output {
-                                    if !nearest.is_empty() {
-                                        let similarity = (nearest[0].1 - 1.0).abs();
-                                        context.set(output, similarity);
-                                    } else {
-                                        context.set(output, 0.0);
-                                    }
-                                }
+                                context.set(output, 0.0);
                             }
-                            // if let Err(e) = state
-                            //     .add_hash(&context.id.to_string(), &self.input, &hash.clone())
-                            //     .await
-                            // {
-                            //     error!(target: "steps_quality", "🐔 Hash validation failed to add hash: {}", e);
-                            //     context.set_status(StepStatus::Failed);
-                            // }
                         }
                     }
-                    _ => {
-                        error!(target: "steps_embeddings", "🐔 Unsupported embedding type");
-                        context.set_status(StepStatus::Failed);
-                    }
                 }
             }
             None => {
@@ -109,3 +100,464 @@ impl Step for CheckEmbeddingStep {
         Ok(context)
     }
 }
+
+pub struct EmbedStep {
+    pub name: String,
+    pub embedding: String,
+    pub input: String,
+    pub output: String,
+    pub persist: bool,
+}
+
+impl EmbedStep {
+    pub fn new(
+        name: String,
+        embedding: String,
+        input: String,
+        output: String,
+        persist: bool,
+    ) -> Self {
+        Self {
+            name,
+            embedding,
+            input,
+            output,
+            persist,
+        }
+    }
+}
+
+impl Step for EmbedStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.data.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "steps_embeddings", "🐔 Embedding input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let embedding = resources
+            .embeddings
+            .get(&self.embedding)
+            .ok_or_else(|| anyhow::anyhow!("Embedding not found: {}", self.embedding))?;
+
+        let text_hash = blake3_hash(&text);
+        let model = embedding.model_id();
+        let emb = embed_cached(embedding, resources.state.as_ref(), vec![text.clone()])
+            .await?
+            .remove(0);
+
+        if self.persist {
+            if let Some(state) = resources.state.as_ref() {
+                state
+                    .add_embedding(
+                        &context.id.to_string(),
+                        &self.input,
+                        &emb,
+                        Some(&model),
+                        Some(&text_hash),
+                    )
+                    .await?;
+                // keep the source text so retrieval can return it
+                state
+                    .merge_item_metadata(
+                        &context.id.to_string(),
+                        &serde_json::json!({ &self.input: text }),
+                    )
+                    .await?;
+            }
+        }
+
+        context.set(&self.output, emb);
+        Ok(context)
+    }
+}
+
+/// Keeps items whose `input` is semantically close to, but not a copy of, the
+/// reference fields. The highest similarity across references has to fall
+/// within `[min_similarity, max_similarity]`.
+pub struct SimilarityFilterStep {
+    pub name: String,
+    pub embedding: String,
+    pub input: String,
+    pub references: Vec<String>,
+    pub min_similarity: Option<f32>,
+    pub max_similarity: Option<f32>,
+    pub similarity_output: Option<String>,
+}
+
+impl SimilarityFilterStep {
+    pub fn new(
+        name: String,
+        embedding: String,
+        input: String,
+        references: Vec<String>,
+        min_similarity: Option<f32>,
+        max_similarity: Option<f32>,
+        similarity_output: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            embedding,
+            input,
+            references,
+            min_similarity,
+            max_similarity,
+            similarity_output,
+        }
+    }
+}
+
+impl Step for SimilarityFilterStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+
+        let mut texts = Vec::with_capacity(self.references.len() + 1);
+        for key in std::iter::once(&self.input).chain(self.references.iter()) {
+            match context.data.get(key).and_then(|v| v.as_str()) {
+                Some(text) => texts.push(text.to_string()),
+                None => {
+                    error!(target: "steps_embeddings", "🐔 Similarity input {} is missing or not a string", key);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        let embedding = resources
+            .embeddings
+            .get(&self.embedding)
+            .ok_or_else(|| anyhow::anyhow!("Embedding not found: {}", self.embedding))?;
+
+        let vectors = embed_cached(embedding, resources.state.as_ref(), texts).await?;
+        let similarity = vectors[1..]
+            .iter()
+            .map(|r| cosine_similarity(&vectors[0], r))
+            .fold(f32::MIN, f32::max);
+
+        if let Some(output) = &self.similarity_output {
+            context.set(output, similarity);
+        }
+
+        let too_far = self.min_similarity.is_some_and(|min| similarity < min);
+        let too_close = self.max_similarity.is_some_and(|max| similarity > max);
+        if too_far || too_close {
+            info!(target: "steps_embeddings", "✅ Similarity {} outside of the configured band", similarity);
+            context.set_status(StepStatus::Failed);
+        }
+
+        Ok(context)
+    }
+}
+
+/// Embeds the `query` field and injects the `k` nearest items stored under `key`
+/// (item id, similarity and item metadata) into the context.
+pub struct RetrieveStep {
+    pub name: String,
+    pub embedding: String,
+    pub query: String,
+    pub key: String,
+    pub k: usize,
+    pub output: String,
+}
+
+impl RetrieveStep {
+    pub fn new(
+        name: String,
+        embedding: String,
+        query: String,
+        key: String,
+        k: usize,
+        output: String,
+    ) -> Self {
+        Self {
+            name,
+            embedding,
+            query,
+            key,
+            k,
+            output,
+        }
+    }
+}
+
+impl Step for RetrieveStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let state = match resources.state.as_ref() {
+            Some(state) => state,
+            None => {
+                error!(target: "steps_embeddings", "🐔 Retrieval requires metadata (state) to be enabled");
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let query = match context.data.get(&self.query).and_then(|v| v.as_str()) {
+            Some(query) => query.to_string(),
+            None => {
+                error!(target: "steps_embeddings", "🐔 Retrieval query {} is missing or not a string", self.query);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let embedding = resources
+            .embeddings
+            .get(&self.embedding)
+            .ok_or_else(|| anyhow::anyhow!("Embedding not found: {}", self.embedding))?;
+
+        let emb = embed_cached(embedding, Some(state), vec![query])
+            .await?
+            .remove(0);
+        let nearest = state.knn_embeddings(&self.key, &emb, self.k).await?;
+
+        let ids: Vec<String> = nearest.iter().filter_map(|(id, _)| id.clone()).collect();
+        let metadata = state.items_metadata(&ids).await?;
+
+        let results: Vec<serde_json::Value> = nearest
+            .into_iter()
+            .map(|(item_id, similarity)| {
+                let meta = item_id
+                    .as_ref()
+                    .and_then(|id| metadata.get(id))
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null);
+                serde_json::json!({
+                    "item_id": item_id,
+                    "similarity": similarity,
+                    "metadata": meta,
+                })
+            })
+            .collect();
+
+        context.set(&self.output, results);
+        Ok(context)
+    }
+}
+
+/// Indices and similarities of the `k` vectors closest to `query`, best first, skipping
+/// the ones below `min_similarity`.
+pub fn top_k(
+    query: &[f32],
+    vectors: &[Vec<f32>],
+    k: usize,
+    min_similarity: Option<f32>,
+) -> Vec<(usize, f32)> {
+    let mut scored = vectors
+        .iter()
+        .map(|v| cosine_similarity(query, v))
+        .enumerate()
+        .filter(|(_, similarity)| min_similarity.is_none_or(|min| *similarity >= min))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(k);
+    scored
+}
+
+struct Chunk {
+    id: Value,
+    text: String,
+    vector: Vec<f32>,
+}
+
+/// Retrieval augmented generation: embeds the chunks of a document dataset (once, on
+/// first use), injects the `k` chunks closest to `query` into the context under `chunks`
+/// and generates `output` from them. The ids and similarities of the chunks used are
+/// written to `sources`.
+pub struct GroundedGenerationStep {
+    pub name: String,
+    pub dataset: String,
+    pub embedding: String,
+    pub query: String,
+    pub k: usize,
+    pub text_column: String,
+    pub id_column: Option<String>,
+    pub min_similarity: Option<f32>,
+    pub chunks: String,
+    pub sources: String,
+    pub generation: TextGenerationStep,
+    index: OnceCell<Vec<Chunk>>,
+}
+
+impl GroundedGenerationStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        dataset: String,
+        embedding: String,
+        query: String,
+        k: usize,
+        text_column: String,
+        id_column: Option<String>,
+        min_similarity: Option<f32>,
+        chunks: String,
+        sources: String,
+        generation: TextGenerationStep,
+    ) -> Self {
+        Self {
+            name,
+            dataset,
+            embedding,
+            query,
+            k,
+            text_column,
+            id_column,
+            min_similarity,
+            chunks,
+            sources,
+            generation,
+            index: OnceCell::new(),
+        }
+    }
+
+    async fn build_index(&self, resources: &PipelineResources) -> Result<Vec<Chunk>> {
+        let dataset = resources
+            .datasets
+            .get(&self.dataset)
+            .ok_or_else(|| anyhow!("Dataset not found: {}", self.dataset))?;
+        let df = dataset_df(dataset)
+            .ok_or_else(|| anyhow!("🐔 Grounding is not supported for mixed datasets"))?;
+        let embedding = resources
+            .embeddings
+            .get(&self.embedding)
+            .ok_or_else(|| anyhow!("Embedding not found: {}", self.embedding))?;
+
+        let mut chunks = Vec::new();
+        for (row, value) in df_to_values(df)?.into_iter().enumerate() {
+            let Some(text) = value.get(&self.text_column).and_then(|t| t.as_str()) else {
+                continue;
+            };
+            let id = match &self.id_column {
+                Some(column) => value.get(column).cloned().unwrap_or(Value::Null),
+                None => json!(row),
+            };
+            chunks.push((id, text.to_string()));
+        }
+
+        let texts = chunks.iter().map(|(_, text)| text.clone()).collect();
+        let vectors = embed_cached(embedding, resources.state.as_ref(), texts).await?;
+        info!(target: "steps_embeddings", "✅ Indexed {} chunks of {}", chunks.len(), self.dataset);
+        Ok(chunks
+            .into_iter()
+            .zip(vectors)
+            .map(|((id, text), vector)| Chunk { id, text, vector })
+            .collect())
+    }
+}
+
+impl Step for GroundedGenerationStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let query = match context.data.get(&self.query).and_then(|v| v.as_str()) {
+            Some(query) => query.to_string(),
+            None => {
+                error!(target: "steps_embeddings", "🐔 Grounding query {} is missing or not a string", self.query);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let index = self
+            .index
+            .get_or_try_init(|| self.build_index(resources))
+            .await?;
+        let embedding = resources
+            .embeddings
+            .get(&self.embedding)
+            .ok_or_else(|| anyhow!("Embedding not found: {}", self.embedding))?;
+        let emb = embed_cached(embedding, resources.state.as_ref(), vec![query])
+            .await?
+            .remove(0);
+
+        let vectors = index.iter().map(|c| c.vector.clone()).collect::<Vec<_>>();
+        let nearest = top_k(&emb, &vectors, self.k, self.min_similarity);
+        if nearest.is_empty() {
+            error!(target: "steps_embeddings", "🐔 No chunks of {} are relevant to the query", self.dataset);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        }
+
+        let chunks = nearest
+            .iter()
+            .map(|(i, similarity)| {
+                json!({"id": index[*i].id, "text": index[*i].text, "similarity": similarity})
+            })
+            .collect::<Vec<_>>();
+        context.set(&self.chunks, &chunks);
+
+        let generation = &self.generation;
+        match generation
+            .generate(
+                &resources.datasets.resources,
+                &resources.templates,
+                &resources.llms.resources,
+                &resources.embeddings.resources,
+                &context,
+                None,
+                generation.max_tokens,
+                generation.temperature,
+            )
+            .await?
+        {
+            Some(text) => {
+                context.set(&generation.output, text);
+                context.set(
+                    &self.sources,
+                    nearest
+                        .iter()
+                        .map(
+                            |(i, similarity)| json!({"id": index[*i].id, "similarity": similarity}),
+                        )
+                        .collect::<Vec<_>>(),
+                );
+            }
+            None => context.set_status(StepStatus::Failed),
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k() {
+        let vectors = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![0.8, 0.6],
+            vec![-1.0, 0.0],
+        ];
+        let nearest = top_k(&[1.0, 0.0], &vectors, 2, None);
+        assert_eq!(
+            nearest.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert!((nearest[1].1 - 0.8).abs() < 1e-6);
+
+        let nearest = top_k(&[1.0, 0.0], &vectors, 10, Some(0.5));
+        assert_eq!(nearest.len(), 2);
+        assert!(top_k(&[1.0, 0.0], &vectors, 0, None).is_empty());
+    }
+}
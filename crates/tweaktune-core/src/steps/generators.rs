@@ -1,14 +1,19 @@
 use crate::{
-    common::extract_json,
+    common::{extract_json, ResultExt},
     datasets::DatasetType,
     embeddings::{self},
     llms::{self, LLM},
-    steps::{Step, StepContext, StepStatus},
+    seq2seq::{Seq2SeqModel, Seq2SeqSpec},
+    steps::{
+        validators::{object_schema, validate_json_text},
+        Step, StepContext, StepStatus,
+    },
     templates::Templates,
     PipelineResources,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::{debug, error};
+use regex::Regex;
 use serde_json::{json, Value};
 use std::collections::HashMap;
 
@@ -400,3 +405,954 @@ impl Step for JudgeConversationStep {
         Ok(result)
     }
 }
+
+/// Scores an item with a judge LLM using a rubric template. The judge is asked for
+/// `{"score": <number>, "rationale": <string>}`, free-text answers fall back to the
+/// first number found in the response.
+pub struct JudgeStep {
+    pub name: String,
+    pub output: String,
+    pub rationale_output: Option<String>,
+    pub threshold: Option<f64>,
+    pub generation_step: TextGenerationStep,
+}
+
+#[allow(clippy::too_many_arguments)]
+impl JudgeStep {
+    pub fn new(
+        name: String,
+        template: String,
+        llm: String,
+        output: String,
+        rationale_output: Option<String>,
+        threshold: Option<f64>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Self {
+        Self {
+            generation_step: TextGenerationStep::new(
+                name.clone(),
+                template,
+                llm,
+                output.clone(),
+                system_template,
+                max_tokens.or(Some(1024)),
+                temperature.or(Some(0.0)),
+            ),
+            name,
+            output,
+            rationale_output,
+            threshold,
+        }
+    }
+
+    fn json_schema() -> String {
+        json!({
+            "name": "JudgeResponse",
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "score": {"description": "Score assigned according to the rubric.", "title": "Score", "type": "number"},
+                    "rationale": {"description": "Rationale for the score.", "title": "Rationale", "type": "string"}
+                },
+                "required": ["score", "rationale"],
+                "additionalProperties": false
+            },
+            "strict": true
+        })
+        .to_string()
+    }
+}
+
+impl JudgeStep {
+    /// Asks the judge and parses its score and rationale, `None` when there is no answer
+    /// or no score in it.
+    pub async fn score(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<Option<(f64, Option<String>)>> {
+        let result = self
+            .generation_step
+            .generate(
+                &resources.datasets.resources,
+                &resources.templates,
+                &resources.llms.resources,
+                &resources.embeddings.resources,
+                context,
+                Some(Self::json_schema()),
+                self.generation_step.max_tokens,
+                self.generation_step.temperature,
+            )
+            .await?;
+        let Some(response) = result else {
+            return Ok(None);
+        };
+        let parsed = parse_judge_response(&response);
+        if parsed.is_none() {
+            error!(target: "judge_step", "🐔 Failed to parse judge score from: {}", response);
+        }
+        Ok(parsed)
+    }
+}
+
+/// Parses a judge answer into a score and an optional rationale.
+pub fn parse_judge_response(response: &str) -> Option<(f64, Option<String>)> {
+    if let Ok(value) = extract_json(response) {
+        let score = match &value["score"] {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        };
+        if let Some(score) = score {
+            let rationale = value["rationale"].as_str().map(|s| s.to_string());
+            return Some((score, rationale));
+        }
+    }
+
+    let start = response.find(|c: char| c.is_ascii_digit())?;
+    let number: String = response[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let score = number.trim_end_matches('.').parse::<f64>().ok()?;
+    Some((score, Some(response.trim().to_string())))
+}
+
+impl Step for JudgeStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let (score, rationale) = match self.score(resources, &context).await? {
+            Some(parsed) => parsed,
+            None => {
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        debug!(target: "judge_step", "🤗 Judge score: {}", score);
+        context.set(&self.output, score);
+        if let Some(rationale_output) = &self.rationale_output {
+            context.set(rationale_output, rationale);
+        }
+
+        if let Some(threshold) = self.threshold {
+            if score < threshold {
+                debug!(target: "judge_step", "🐔 Judge score {} below threshold {}", score, threshold);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+
+        Ok(context)
+    }
+}
+
+/// Compares two candidates with a judge LLM. The template sees them as `candidate_a`
+/// and `candidate_b`; with `swap` the judge is asked a second time with the candidates
+/// swapped to cancel out position bias.
+pub struct PairwiseJudgeStep {
+    pub name: String,
+    pub candidate_a: String,
+    pub candidate_b: String,
+    pub output: String,
+    pub confidence_output: Option<String>,
+    pub swap: bool,
+    pub generation_step: TextGenerationStep,
+}
+
+#[allow(clippy::too_many_arguments)]
+impl PairwiseJudgeStep {
+    pub fn new(
+        name: String,
+        template: String,
+        llm: String,
+        candidate_a: String,
+        candidate_b: String,
+        output: String,
+        confidence_output: Option<String>,
+        swap: bool,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Self {
+        Self {
+            generation_step: TextGenerationStep::new(
+                name.clone(),
+                template,
+                llm,
+                output.clone(),
+                system_template,
+                max_tokens.or(Some(1024)),
+                temperature.or(Some(0.0)),
+            ),
+            name,
+            candidate_a,
+            candidate_b,
+            output,
+            confidence_output,
+            swap,
+        }
+    }
+
+    fn json_schema() -> String {
+        json!({
+            "name": "PairwiseJudgeResponse",
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "winner": {"description": "Better candidate.", "title": "Winner", "type": "string", "enum": ["A", "B", "tie"]},
+                    "rationale": {"description": "Rationale for the choice.", "title": "Rationale", "type": "string"}
+                },
+                "required": ["winner", "rationale"],
+                "additionalProperties": false
+            },
+            "strict": true
+        })
+        .to_string()
+    }
+
+    async fn ask(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+        first: &Value,
+        second: &Value,
+    ) -> Result<Option<PairwiseVote>> {
+        let mut context = context.clone();
+        context.set("candidate_a", first);
+        context.set("candidate_b", second);
+        let result = self
+            .generation_step
+            .generate(
+                &resources.datasets.resources,
+                &resources.templates,
+                &resources.llms.resources,
+                &resources.embeddings.resources,
+                &context,
+                Some(Self::json_schema()),
+                self.generation_step.max_tokens,
+                self.generation_step.temperature,
+            )
+            .await?;
+        Ok(result.as_deref().and_then(parse_pairwise_vote))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairwiseVote {
+    A,
+    B,
+    Tie,
+}
+
+impl PairwiseVote {
+    fn swapped(self) -> Self {
+        match self {
+            PairwiseVote::A => PairwiseVote::B,
+            PairwiseVote::B => PairwiseVote::A,
+            PairwiseVote::Tie => PairwiseVote::Tie,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            PairwiseVote::A => "A",
+            PairwiseVote::B => "B",
+            PairwiseVote::Tie => "tie",
+        }
+    }
+}
+
+pub fn parse_pairwise_vote(response: &str) -> Option<PairwiseVote> {
+    let winner = match extract_json(response) {
+        Ok(value) => value["winner"].as_str()?.to_string(),
+        Err(_) => response.to_string(),
+    };
+    match winner
+        .trim()
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase()
+        .as_str()
+    {
+        "a" => Some(PairwiseVote::A),
+        "b" => Some(PairwiseVote::B),
+        "tie" | "draw" => Some(PairwiseVote::Tie),
+        _ => None,
+    }
+}
+
+/// Combines votes (already mapped back to the original order) into a winner and the
+/// share of votes supporting it, ties count as half a vote for each side.
+pub fn pairwise_verdict(votes: &[PairwiseVote]) -> (PairwiseVote, f64) {
+    if votes.is_empty() {
+        return (PairwiseVote::Tie, 0.0);
+    }
+    let (a, b) = votes.iter().fold((0.0, 0.0), |(a, b), vote| match vote {
+        PairwiseVote::A => (a + 1.0, b),
+        PairwiseVote::B => (a, b + 1.0),
+        PairwiseVote::Tie => (a + 0.5, b + 0.5),
+    });
+    let total = votes.len() as f64;
+    if a > b {
+        (PairwiseVote::A, a / total)
+    } else if b > a {
+        (PairwiseVote::B, b / total)
+    } else {
+        (PairwiseVote::Tie, a / total)
+    }
+}
+
+impl Step for PairwiseJudgeStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let (a, b) = match (
+            context.data.get(&self.candidate_a),
+            context.data.get(&self.candidate_b),
+        ) {
+            (Some(a), Some(b)) => (a.clone(), b.clone()),
+            _ => {
+                error!(target: "pairwise_judge_step", "🐔 Candidates '{}' and '{}' must be set", self.candidate_a, self.candidate_b);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let mut votes = Vec::new();
+        match self.ask(resources, &context, &a, &b).await? {
+            Some(vote) => votes.push(vote),
+            None => {
+                error!(target: "pairwise_judge_step", "🐔 Failed to parse judge verdict");
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        }
+        if self.swap {
+            match self.ask(resources, &context, &b, &a).await? {
+                Some(vote) => votes.push(vote.swapped()),
+                None => {
+                    error!(target: "pairwise_judge_step", "🐔 Failed to parse swapped judge verdict");
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        let (winner, confidence) = pairwise_verdict(&votes);
+        debug!(target: "pairwise_judge_step", "🤗 Pairwise winner: {} ({})", winner.as_str(), confidence);
+        context.set(&self.output, winner.as_str());
+        if let Some(confidence_output) = &self.confidence_output {
+            context.set(confidence_output, confidence);
+        }
+        Ok(context)
+    }
+}
+
+/// Samples the generation `samples` times and keeps the majority answer. Answers are
+/// taken from `json_path` of the JSON response, the first capture group of `pattern`
+/// (or the whole match), or the trimmed response.
+pub struct SelfConsistencyStep {
+    pub name: String,
+    pub output: String,
+    pub samples: usize,
+    pub json_path: Option<String>,
+    pub pattern: Option<Regex>,
+    pub agreement_output: Option<String>,
+    pub generation_step: TextGenerationStep,
+}
+
+#[allow(clippy::too_many_arguments)]
+impl SelfConsistencyStep {
+    pub fn new(
+        name: String,
+        template: String,
+        llm: String,
+        output: String,
+        samples: usize,
+        json_path: Option<String>,
+        pattern: Option<String>,
+        agreement_output: Option<String>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<Self> {
+        let pattern = pattern.map(|p| Regex::new(&p)).transpose()?;
+        Ok(Self {
+            generation_step: TextGenerationStep::new(
+                name.clone(),
+                template,
+                llm,
+                output.clone(),
+                system_template,
+                max_tokens,
+                temperature.or(Some(0.8)),
+            ),
+            name,
+            output,
+            samples,
+            json_path,
+            pattern,
+            agreement_output,
+        })
+    }
+}
+
+pub fn extract_answer(
+    response: &str,
+    json_path: Option<&str>,
+    pattern: Option<&Regex>,
+) -> Option<Value> {
+    if let Some(json_path) = json_path {
+        let mut value = extract_json(response).ok()?;
+        for key in json_path.split('.') {
+            value = value.get(key)?.clone();
+        }
+        return (!value.is_null()).then_some(value);
+    }
+
+    if let Some(pattern) = pattern {
+        let captures = pattern.captures(response)?;
+        let m = captures.get(1).or_else(|| captures.get(0))?;
+        return Some(Value::String(m.as_str().trim().to_string()));
+    }
+
+    let answer = response.trim();
+    (!answer.is_empty()).then(|| Value::String(answer.to_string()))
+}
+
+/// Returns the most frequent answer (first seen wins ties) with its count.
+pub fn majority_answer(answers: &[Value]) -> Option<(Value, usize)> {
+    let mut counts: Vec<(String, &Value, usize)> = Vec::new();
+    for answer in answers {
+        let key = match answer {
+            Value::String(s) => s.trim().to_lowercase(),
+            other => other.to_string(),
+        };
+        match counts.iter_mut().find(|(k, _, _)| *k == key) {
+            Some((_, _, count)) => *count += 1,
+            None => counts.push((key, answer, 1)),
+        }
+    }
+
+    let mut best: Option<(&Value, usize)> = None;
+    for (_, answer, count) in counts {
+        if best.map(|(_, c)| count > c).unwrap_or(true) {
+            best = Some((answer, count));
+        }
+    }
+    best.map(|(answer, count)| (answer.clone(), count))
+}
+
+impl Step for SelfConsistencyStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let mut answers = Vec::with_capacity(self.samples);
+        for _ in 0..self.samples {
+            let result = self
+                .generation_step
+                .generate(
+                    &resources.datasets.resources,
+                    &resources.templates,
+                    &resources.llms.resources,
+                    &resources.embeddings.resources,
+                    &context,
+                    None,
+                    self.generation_step.max_tokens,
+                    self.generation_step.temperature,
+                )
+                .await?;
+
+            if let Some(response) = result {
+                match extract_answer(&response, self.json_path.as_deref(), self.pattern.as_ref()) {
+                    Some(answer) => answers.push(answer),
+                    None => {
+                        debug!(target: "self_consistency_step", "🐔 No answer found in: {}", response)
+                    }
+                }
+            }
+        }
+
+        let (answer, count) = match majority_answer(&answers) {
+            Some(majority) => majority,
+            None => {
+                error!(target: "self_consistency_step", "🐔 No answers extracted from {} samples", self.samples);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let agreement = count as f64 / self.samples as f64;
+        debug!(target: "self_consistency_step", "🤗 Majority answer: {} ({})", answer, agreement);
+        context.set(&self.output, answer);
+        if let Some(agreement_output) = &self.agreement_output {
+            context.set(agreement_output, agreement);
+        }
+        Ok(context)
+    }
+}
+
+/// Validates the JSON in `input` against `schema` and, when it does not match, asks the
+/// LLM to fix it given the validation errors, up to `max_attempts` times.
+pub struct RepairJsonStep {
+    pub name: String,
+    pub schema: String,
+    pub input: String,
+    pub output: String,
+    pub max_attempts: usize,
+    pub generation_step: TextGenerationStep,
+}
+
+impl RepairJsonStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        schema: String,
+        input: String,
+        template: String,
+        llm: String,
+        output: String,
+        max_attempts: usize,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Self {
+        Self {
+            name: name.clone(),
+            schema,
+            input,
+            output: output.clone(),
+            max_attempts,
+            generation_step: TextGenerationStep::new(
+                name,
+                template,
+                llm,
+                output,
+                system_template,
+                max_tokens,
+                temperature,
+            ),
+        }
+    }
+}
+
+impl Step for RepairJsonStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+
+        let schema = resources
+            .templates
+            .render(self.schema.clone(), context.data.clone())?;
+        let schema_value = object_schema(&serde_json::from_str(&schema)?)?;
+        let validator = match jsonschema::validator_for(&schema_value) {
+            Ok(validator) => validator,
+            Err(e) => {
+                error!(target: "repair_json_step", "🐔 Failed to create JSON schema validator: {e}");
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let mut text = match context.get(&self.input) {
+            Some(Value::String(text)) => text.clone(),
+            Some(value) => value.to_string(),
+            None => {
+                error!(target: "repair_json_step", "🐔 Input {} not found", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        for attempt in 0..=self.max_attempts {
+            let (value, errors) = validate_json_text(&validator, &text);
+            if let (Some(value), true) = (value, errors.is_empty()) {
+                debug!(target: "repair_json_step", "JSON valid after {} repair attempts", attempt);
+                context.set(&self.output, value);
+                return Ok(context);
+            }
+            if attempt == self.max_attempts {
+                error!(target: "repair_json_step", "🐔 JSON still invalid after {} repair attempts: {}", attempt, errors.join("; "));
+                break;
+            }
+
+            let mut repair_context = context.clone();
+            repair_context.set(
+                "__repair",
+                json!({
+                    "json": text,
+                    "schema": serde_json::to_string_pretty(&schema_value)?,
+                    "errors": errors,
+                }),
+            );
+            text = match self
+                .generation_step
+                .generate(
+                    &resources.datasets.resources,
+                    &resources.templates,
+                    &resources.llms.resources,
+                    &resources.embeddings.resources,
+                    &repair_context,
+                    None,
+                    self.generation_step.max_tokens,
+                    self.generation_step.temperature,
+                )
+                .await?
+            {
+                Some(text) => text,
+                None => break,
+            };
+        }
+
+        context.set_status(StepStatus::Failed);
+        Ok(context)
+    }
+}
+
+/// Prompt template used when translating with an LLM.
+pub fn translate_template(input: &str, source_lang: &str, target_lang: &str) -> String {
+    format!(
+        "Translate the following text from {source_lang} to {target_lang}. \
+        Keep the formatting and reply with the translation only.\n\n{{{{ {input} }}}}"
+    )
+}
+
+pub enum TranslateBackend {
+    Llm(TextGenerationStep),
+    Seq2Seq(Seq2SeqSpec),
+}
+
+/// Translates the `input` field with a registered LLM or a local (m)T5 model.
+pub struct TranslateStep {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub source_lang: String,
+    pub target_lang: String,
+    pub backend: TranslateBackend,
+}
+
+impl TranslateStep {
+    pub fn new(
+        name: String,
+        input: String,
+        output: String,
+        source_lang: String,
+        target_lang: String,
+        backend: TranslateBackend,
+    ) -> Self {
+        Self {
+            name,
+            input,
+            output,
+            source_lang,
+            target_lang,
+            backend,
+        }
+    }
+}
+
+impl Step for TranslateStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "translate_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let translation = match &self.backend {
+            TranslateBackend::Llm(generation_step) => {
+                generation_step
+                    .generate(
+                        &resources.datasets.resources,
+                        &resources.templates,
+                        &resources.llms.resources,
+                        &resources.embeddings.resources,
+                        &context,
+                        None,
+                        generation_step.max_tokens,
+                        generation_step.temperature,
+                    )
+                    .await?
+            }
+            TranslateBackend::Seq2Seq(spec) => {
+                let spec = spec.clone();
+                let prompt = format!(
+                    "translate {} to {}: {}",
+                    self.source_lang, self.target_lang, text
+                );
+                let result = tokio::task::spawn_blocking(move || {
+                    let model = Seq2SeqModel::lazy(spec)?;
+                    let model = model.lock().map_anyhow_err()?;
+                    model.forward(prompt, None)
+                })
+                .await?;
+                match result {
+                    Ok(translation) => Some(translation),
+                    Err(e) => {
+                        error!(target: "translate_step", "🐔 Failed to translate: {}", e);
+                        None
+                    }
+                }
+            }
+        };
+
+        match translation {
+            Some(translation) => context.set(&self.output, translation.trim()),
+            None => context.set_status(StepStatus::Failed),
+        }
+        Ok(context)
+    }
+}
+
+/// Indices of the best and the worst scored candidate, `None` when the scores are less than
+/// `min_margin` apart (or there are fewer than two) so the pair would not be informative.
+pub fn preference_pair(scores: &[f64], min_margin: f64) -> Option<(usize, usize)> {
+    if scores.len() < 2 {
+        return None;
+    }
+    let mut best = 0;
+    let mut worst = 0;
+    for (i, score) in scores.iter().enumerate() {
+        if *score > scores[best] {
+            best = i;
+        }
+        if *score < scores[worst] {
+            worst = i;
+        }
+    }
+    (best != worst && scores[best] - scores[worst] >= min_margin).then_some((best, worst))
+}
+
+/// Builds a preference pair `{prompt, chosen, rejected}` for the rendered `template`.
+/// Without a judge the first candidate (e.g. a strong model) is chosen and the second (a weak
+/// model) rejected; with a judge every candidate is scored (the judge template sees `prompt`
+/// and `candidate`) and the best and worst scored ones form the pair, with their scores.
+pub struct PreferencePairStep {
+    pub name: String,
+    pub template: String,
+    pub output: String,
+    pub candidates: Vec<TextGenerationStep>,
+    pub judge: Option<JudgeStep>,
+    pub min_margin: f64,
+}
+
+impl PreferencePairStep {
+    pub fn new(
+        name: String,
+        template: String,
+        output: String,
+        candidates: Vec<TextGenerationStep>,
+        judge: Option<JudgeStep>,
+        min_margin: f64,
+    ) -> Result<Self> {
+        match &judge {
+            None if candidates.len() != 2 => {
+                bail!("🐔 Without a judge exactly two candidates (chosen, rejected) are required")
+            }
+            Some(_) if candidates.len() < 2 => {
+                bail!("🐔 At least two candidates are required")
+            }
+            _ => {}
+        }
+        Ok(Self {
+            name,
+            template,
+            output,
+            candidates,
+            judge,
+            min_margin,
+        })
+    }
+}
+
+impl Step for PreferencePairStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let prompt = resources
+            .templates
+            .render(self.template.clone(), context.data.clone())?;
+
+        let mut candidates = Vec::with_capacity(self.candidates.len());
+        for candidate in &self.candidates {
+            match candidate
+                .generate(
+                    &resources.datasets.resources,
+                    &resources.templates,
+                    &resources.llms.resources,
+                    &resources.embeddings.resources,
+                    &context,
+                    None,
+                    candidate.max_tokens,
+                    candidate.temperature,
+                )
+                .await?
+            {
+                Some(text) => candidates.push(text.trim().to_string()),
+                None => {
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        let Some(judge) = &self.judge else {
+            context.set(
+                &self.output,
+                json!({"prompt": prompt, "chosen": candidates[0], "rejected": candidates[1]}),
+            );
+            return Ok(context);
+        };
+
+        let mut scores = Vec::with_capacity(candidates.len());
+        for candidate in &candidates {
+            let mut judge_context = context.clone();
+            judge_context.set("prompt", &prompt);
+            judge_context.set("candidate", candidate);
+            match judge.score(resources, &judge_context).await? {
+                Some((score, _)) => scores.push(score),
+                None => {
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        match preference_pair(&scores, self.min_margin) {
+            Some((chosen, rejected)) => {
+                context.set(
+                    &self.output,
+                    json!({
+                        "prompt": prompt,
+                        "chosen": candidates[chosen],
+                        "rejected": candidates[rejected],
+                        "chosen_score": scores[chosen],
+                        "rejected_score": scores[rejected],
+                    }),
+                );
+            }
+            None => {
+                debug!(target: "preference_pair_step", "🐔 Candidate scores {:?} do not differ by {}", scores, self.min_margin);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_judge_response() {
+        let (score, rationale) =
+            parse_judge_response(r#"{"score": 4, "rationale": "Mostly correct"}"#).unwrap();
+        assert_eq!(score, 4.0);
+        assert_eq!(rationale.as_deref(), Some("Mostly correct"));
+
+        let (score, _) =
+            parse_judge_response("```json\n{\"score\": \"3.5\", \"rationale\": \"ok\"}\n```")
+                .unwrap();
+        assert_eq!(score, 3.5);
+
+        let (score, rationale) = parse_judge_response("Score: 2/5. The answer is vague.").unwrap();
+        assert_eq!(score, 2.0);
+        assert!(rationale.unwrap().contains("vague"));
+
+        assert!(parse_judge_response("no score here").is_none());
+    }
+
+    #[test]
+    fn test_pairwise_verdict() {
+        assert_eq!(
+            parse_pairwise_vote(r#"{"winner": "B", "rationale": "shorter"}"#),
+            Some(PairwiseVote::B)
+        );
+        assert_eq!(parse_pairwise_vote("A"), Some(PairwiseVote::A));
+        assert_eq!(parse_pairwise_vote("nope"), None);
+
+        // consistent across the swap
+        assert_eq!(
+            pairwise_verdict(&[PairwiseVote::A, PairwiseVote::B.swapped()]),
+            (PairwiseVote::A, 1.0)
+        );
+        // position bias, judge always picks the first candidate
+        assert_eq!(
+            pairwise_verdict(&[PairwiseVote::A, PairwiseVote::A.swapped()]),
+            (PairwiseVote::Tie, 0.5)
+        );
+        assert_eq!(
+            pairwise_verdict(&[PairwiseVote::B, PairwiseVote::Tie]),
+            (PairwiseVote::B, 0.75)
+        );
+    }
+
+    #[test]
+    fn test_self_consistency_answers() {
+        let re = Regex::new(r"answer is (\d+)").unwrap();
+        assert_eq!(
+            extract_answer("So the answer is 42.", None, Some(&re)),
+            Some(json!("42"))
+        );
+        assert_eq!(
+            extract_answer(r#"{"result": {"answer": 7}}"#, Some("result.answer"), None),
+            Some(json!(7))
+        );
+        assert_eq!(extract_answer("no json", Some("answer"), None), None);
+
+        let answers = vec![
+            json!("42"),
+            json!("41"),
+            json!(" 42 "),
+            json!("41"),
+            json!("42"),
+        ];
+        assert_eq!(majority_answer(&answers), Some((json!("42"), 3)));
+        assert_eq!(majority_answer(&[]), None);
+    }
+
+    #[test]
+    fn test_translate_template() {
+        assert_eq!(
+            translate_template("question", "English", "Polish"),
+            "Translate the following text from English to Polish. Keep the formatting and reply with the translation only.\n\n{{ question }}"
+        );
+        assert!("mt5-small".parse::<crate::seq2seq::Which>().is_ok());
+        assert!("gpt-4o".parse::<crate::seq2seq::Which>().is_err());
+    }
+
+    #[test]
+    fn test_preference_pair() {
+        assert_eq!(preference_pair(&[3.0, 8.0, 1.0, 8.0], 0.0), Some((1, 2)));
+        assert_eq!(preference_pair(&[3.0, 4.0], 2.0), None);
+        assert_eq!(preference_pair(&[5.0, 5.0], 0.0), None);
+        assert_eq!(preference_pair(&[5.0], 0.0), None);
+    }
+}
@@ -1,9 +1,15 @@
 use crate::{
+    common::df_to_values,
     steps::{Step, StepContext, StepStatus},
     PipelineResources,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use jaq_core::load::{Arena, File, Loader};
+use jaq_core::{Compiler, Ctx, Native, RcIter};
+use jaq_json::Val;
 use log::error;
+use polars::prelude::*;
+use serde_json::Value;
 
 pub struct FilterStep {
     pub name: String,
@@ -82,3 +88,584 @@ impl Step for MutateStep {
         Ok(context)
     }
 }
+
+/// Renames (or copies with `copy`) top-level context keys, `mapping` is `(from, to)`.
+pub struct MapKeysStep {
+    pub name: String,
+    pub mapping: Vec<(String, String)>,
+    pub copy: bool,
+}
+
+impl MapKeysStep {
+    pub fn new(name: String, mapping: Vec<(String, String)>, copy: bool) -> Self {
+        Self {
+            name,
+            mapping,
+            copy,
+        }
+    }
+
+    pub fn apply(&self, context: &StepContext) -> StepContext {
+        let mut context = context.clone();
+        // read every source first so swapping keys works
+        let mut values = Vec::with_capacity(self.mapping.len());
+        for (from, to) in &self.mapping {
+            match context.data.get(from) {
+                Some(value) => values.push((from, to, value.clone())),
+                None => {
+                    error!(target: "mapkeysstep", "🐔 Key '{}' not found in context data", from);
+                    context.set_status(StepStatus::Failed);
+                    return context;
+                }
+            }
+        }
+
+        if !self.copy {
+            if let Some(data) = context.data.as_object_mut() {
+                for (from, _, _) in &values {
+                    data.remove(*from);
+                }
+            }
+        }
+        for (_, to, value) in values {
+            context.set(to, value);
+        }
+        context
+    }
+}
+
+impl Step for MapKeysStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        Ok(self.apply(context))
+    }
+}
+
+/// Keeps only the listed top-level context keys.
+pub struct SelectKeysStep {
+    pub name: String,
+    pub keys: Vec<String>,
+}
+
+impl SelectKeysStep {
+    pub fn new(name: String, keys: Vec<String>) -> Self {
+        Self { name, keys }
+    }
+
+    pub fn apply(&self, context: &StepContext) -> StepContext {
+        let mut context = context.clone();
+        if let Some(data) = context.data.as_object_mut() {
+            data.retain(|key, _| self.keys.contains(key));
+        }
+        context
+    }
+}
+
+impl Step for SelectKeysStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        Ok(self.apply(context))
+    }
+}
+
+/// Removes the listed top-level context keys.
+pub struct DropKeysStep {
+    pub name: String,
+    pub keys: Vec<String>,
+}
+
+impl DropKeysStep {
+    pub fn new(name: String, keys: Vec<String>) -> Self {
+        Self { name, keys }
+    }
+
+    pub fn apply(&self, context: &StepContext) -> StepContext {
+        let mut context = context.clone();
+        if let Some(data) = context.data.as_object_mut() {
+            data.retain(|key, _| !self.keys.contains(key));
+        }
+        context
+    }
+}
+
+impl Step for DropKeysStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        Ok(self.apply(context))
+    }
+}
+
+/// Selects values from the JSON in the `input` field with a JSONPath expression
+/// (e.g. `$.items[*].name`). Writes the first match, or every match with `all`.
+/// String inputs are parsed as JSON.
+pub struct JsonPathStep {
+    pub name: String,
+    pub input: String,
+    pub path: String,
+    pub output: String,
+    pub all: bool,
+}
+
+impl JsonPathStep {
+    pub fn new(
+        name: String,
+        input: String,
+        path: String,
+        output: String,
+        all: bool,
+    ) -> Result<Self> {
+        // the compiled path is not Send, only validate it here
+        jsonpath_lib::PathCompiled::compile(&path)
+            .map_err(|e| anyhow::anyhow!("Invalid JSONPath {}: {:?}", path, e))?;
+        Ok(Self {
+            name,
+            input,
+            path,
+            output,
+            all,
+        })
+    }
+
+    pub fn select(&self, value: &Value) -> Result<Option<Value>> {
+        let value = match value {
+            Value::String(s) => serde_json::from_str(s).unwrap_or_else(|_| value.clone()),
+            _ => value.clone(),
+        };
+        let selected = jsonpath_lib::select(&value, &self.path)
+            .map_err(|e| anyhow::anyhow!("JSONPath {} failed: {:?}", self.path, e))?;
+        if self.all {
+            Ok(Some(Value::Array(selected.into_iter().cloned().collect())))
+        } else {
+            Ok(selected.first().map(|v| (*v).clone()))
+        }
+    }
+}
+
+impl Step for JsonPathStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let selected = match context.get(&self.input) {
+            Some(value) => self.select(value)?,
+            None => None,
+        };
+        match selected {
+            Some(value) => context.set(&self.output, value),
+            None => {
+                error!(target: "jsonpathstep", "🐔 JSONPath {} matched nothing in {}", self.path, self.input);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+/// Restructures JSON with a jq filter. Without `input` the filter runs over the
+/// whole context data.
+pub struct JqStep {
+    pub name: String,
+    pub input: Option<String>,
+    pub filter: jaq_core::Filter<Native<Val>>,
+    pub output: String,
+    pub all: bool,
+}
+
+impl JqStep {
+    pub fn new(
+        name: String,
+        input: Option<String>,
+        filter: String,
+        output: String,
+        all: bool,
+    ) -> Result<Self> {
+        Ok(Self {
+            name,
+            input,
+            filter: compile_jq(&filter)?,
+            output,
+            all,
+        })
+    }
+
+    pub fn transform(&self, value: &Value) -> Result<Option<Value>> {
+        let value = match value {
+            Value::String(s) => serde_json::from_str(s).unwrap_or_else(|_| value.clone()),
+            _ => value.clone(),
+        };
+        let inputs = RcIter::new(core::iter::empty());
+        let mut results = self
+            .filter
+            .run((Ctx::new([], &inputs), Val::from(value)))
+            .map(|result| result.map(Value::from).map_err(|e| anyhow!("🐔 {}", e)));
+        if self.all {
+            Ok(Some(Value::Array(results.collect::<Result<_>>()?)))
+        } else {
+            results.next().transpose()
+        }
+    }
+}
+
+/// Parses and compiles a jq filter with the jq standard library.
+fn compile_jq(filter: &str) -> Result<jaq_core::Filter<Native<Val>>> {
+    let arena = Arena::default();
+    let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+    let modules = loader
+        .load(
+            &arena,
+            File {
+                code: filter,
+                path: (),
+            },
+        )
+        .map_err(|errors| {
+            let reasons = errors
+                .into_iter()
+                .flat_map(|(_, e)| match e {
+                    jaq_core::load::Error::Io(e) => e.into_iter().map(|(_, e)| e).collect(),
+                    jaq_core::load::Error::Lex(e) => e
+                        .into_iter()
+                        .map(|(expect, _)| format!("expected {}", expect.as_str()))
+                        .collect(),
+                    jaq_core::load::Error::Parse(e) => e
+                        .into_iter()
+                        .map(|(expect, _)| format!("expected {}", expect.as_str()))
+                        .collect::<Vec<_>>(),
+                })
+                .collect::<Vec<_>>();
+            anyhow!("🐔 Invalid jq filter {}: {}", filter, reasons.join(", "))
+        })?;
+    Compiler::default()
+        .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+        .compile(modules)
+        .map_err(|errors| {
+            let names = errors
+                .into_iter()
+                .flat_map(|(_, e)| e.into_iter().map(|(name, _)| name))
+                .collect::<Vec<_>>();
+            anyhow!(
+                "🐔 Invalid jq filter {}: undefined {}",
+                filter,
+                names.join(", ")
+            )
+        })
+}
+
+impl Step for JqStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let input = match &self.input {
+            Some(input) => context.get(input).cloned(),
+            None => Some(context.data.clone()),
+        };
+        let transformed = match input.map(|v| self.transform(&v)) {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => {
+                error!(target: "jqstep", "🐔 jq filter failed: {}", e);
+                None
+            }
+            None => None,
+        };
+        match transformed {
+            Some(value) => context.set(&self.output, value),
+            None => {
+                error!(target: "jqstep", "🐔 jq filter produced no value");
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+/// Loads the list of objects in `input` into a DataFrame registered as table `input`
+/// and writes the rows returned by the `sql` query (Polars SQL) to `output`.
+pub struct SqlStep {
+    pub name: String,
+    pub input: String,
+    pub sql: String,
+    pub output: String,
+}
+
+impl SqlStep {
+    pub fn new(name: String, input: String, sql: String, output: String) -> Self {
+        Self {
+            name,
+            input,
+            sql,
+            output,
+        }
+    }
+
+    pub fn query(&self, items: &[Value]) -> Result<Vec<Value>> {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+        let json = serde_json::to_vec(items)?;
+        let df = JsonReader::new(std::io::Cursor::new(json)).finish()?;
+        let mut ctx = polars::sql::SQLContext::new();
+        ctx.register(&self.input, df.lazy());
+        let df = ctx.execute(&self.sql)?.collect()?;
+        df_to_values(&df)
+    }
+}
+
+impl Step for SqlStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let items = match context.get(&self.input).and_then(|v| v.as_array()) {
+            Some(items) => items.clone(),
+            None => {
+                error!(target: "sqlstep", "🐔 SQL input {} is missing or not a list", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+        match self.query(&items) {
+            Ok(rows) => context.set(&self.output, rows),
+            Err(e) => {
+                error!(target: "sqlstep", "🐔 SQL query over {} failed: {}", self.input, e);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context(data: serde_json::Value) -> StepContext {
+        let mut context = StepContext::new();
+        context.data = data;
+        context
+    }
+
+    #[test]
+    fn test_map_keys() {
+        let ctx = context(json!({"q": "2 + 2", "a": 4}));
+        let step = MapKeysStep::new(
+            "MAP".to_string(),
+            vec![
+                ("q".to_string(), "question".to_string()),
+                ("a".to_string(), "answer".to_string()),
+            ],
+            false,
+        );
+        let result = step.apply(&ctx);
+        assert_eq!(result.data, json!({"question": "2 + 2", "answer": 4}));
+
+        let step = MapKeysStep::new(
+            "COPY".to_string(),
+            vec![
+                ("q".to_string(), "a".to_string()),
+                ("a".to_string(), "q".to_string()),
+            ],
+            false,
+        );
+        assert_eq!(step.apply(&ctx).data, json!({"q": 4, "a": "2 + 2"}));
+
+        let step = MapKeysStep::new(
+            "COPY".to_string(),
+            vec![("q".to_string(), "question".to_string())],
+            true,
+        );
+        assert_eq!(
+            step.apply(&ctx).data,
+            json!({"q": "2 + 2", "a": 4, "question": "2 + 2"})
+        );
+
+        let step = MapKeysStep::new(
+            "MISSING".to_string(),
+            vec![("missing".to_string(), "x".to_string())],
+            false,
+        );
+        assert!(matches!(step.apply(&ctx).get_status(), StepStatus::Failed));
+    }
+
+    #[test]
+    fn test_select_drop_keys() {
+        let ctx = context(json!({"index": 1, "prompt": "...", "answer": "4"}));
+        let keys = vec!["index".to_string(), "answer".to_string()];
+
+        let selected = SelectKeysStep::new("SELECT".to_string(), keys.clone()).apply(&ctx);
+        assert_eq!(selected.data, json!({"index": 1, "answer": "4"}));
+
+        let dropped = DropKeysStep::new("DROP".to_string(), keys).apply(&ctx);
+        assert_eq!(dropped.data, json!({"prompt": "..."}));
+    }
+
+    #[test]
+    fn test_jsonpath() {
+        let doc = json!({"items": [{"name": "a", "price": 1}, {"name": "b", "price": 5}]});
+
+        let step = JsonPathStep::new(
+            "PATH".to_string(),
+            "doc".to_string(),
+            "$.items[*].name".to_string(),
+            "out".to_string(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(step.select(&doc).unwrap(), Some(json!(["a", "b"])));
+
+        let step = JsonPathStep::new(
+            "PATH".to_string(),
+            "doc".to_string(),
+            "$.items[?(@.price > 2)].name".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            step.select(&Value::String(doc.to_string())).unwrap(),
+            Some(json!("b"))
+        );
+
+        let step = JsonPathStep::new(
+            "PATH".to_string(),
+            "doc".to_string(),
+            "$.missing".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(step.select(&doc).unwrap(), None);
+
+        assert!(JsonPathStep::new(
+            "PATH".to_string(),
+            "doc".to_string(),
+            "$.[".to_string(),
+            "out".to_string(),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_jq() {
+        let step = JqStep::new(
+            "JQ".to_string(),
+            Some("doc".to_string()),
+            "{names: [.items[].name], total: (.items | map(.price) | add)}".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        let doc = json!({"items": [{"name": "a", "price": 1}, {"name": "b", "price": 5}]});
+        assert_eq!(
+            step.transform(&Value::String(doc.to_string())).unwrap(),
+            Some(json!({"names": ["a", "b"], "total": 6}))
+        );
+
+        let step = JqStep::new(
+            "JQ".to_string(),
+            None,
+            ".items[] | select(.price > 10)".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(step.transform(&doc).unwrap(), None);
+
+        let step = JqStep::new(
+            "JQ".to_string(),
+            None,
+            ".items[].name".to_string(),
+            "out".to_string(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(step.transform(&doc).unwrap(), Some(json!(["a", "b"])));
+
+        let step = JqStep::new(
+            "JQ".to_string(),
+            None,
+            "reduce .items[] as $i (0; . + $i.price) | \"total \\(.)\"".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(step.transform(&doc).unwrap(), Some(json!("total 6")));
+
+        let step = JqStep::new(
+            "JQ".to_string(),
+            None,
+            ".items[0].name[0]".to_string(),
+            "out".to_string(),
+            false,
+        )
+        .unwrap();
+        assert!(step.transform(&doc).is_err());
+        assert!(JqStep::new(
+            "JQ".to_string(),
+            None,
+            "unknown_fn".to_string(),
+            "out".to_string(),
+            false
+        )
+        .is_err());
+
+        assert!(JqStep::new(
+            "JQ".to_string(),
+            None,
+            ".[".to_string(),
+            "out".to_string(),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sql() {
+        let step = SqlStep::new(
+            "SQL".to_string(),
+            "items".to_string(),
+            "SELECT kind, SUM(price) AS total FROM items WHERE price > 1 GROUP BY kind ORDER BY kind"
+                .to_string(),
+            "out".to_string(),
+        );
+        let items = vec![
+            json!({"kind": "x", "price": 5}),
+            json!({"kind": "y", "price": 1}),
+            json!({"kind": "x", "price": 9}),
+            json!({"kind": "y", "price": 3}),
+        ];
+        assert_eq!(
+            step.query(&items).unwrap(),
+            vec![
+                json!({"kind": "x", "total": 14}),
+                json!({"kind": "y", "total": 3})
+            ]
+        );
+        assert_eq!(step.query(&[]).unwrap(), Vec::<Value>::new());
+
+        let step = SqlStep::new(
+            "SQL".to_string(),
+            "items".to_string(),
+            "SELECT * FROM missing".to_string(),
+            "out".to_string(),
+        );
+        assert!(step.query(&items).is_err());
+    }
+}
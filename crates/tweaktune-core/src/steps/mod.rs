@@ -1,41 +1,77 @@
 pub mod conversations;
+pub mod dag;
 pub mod embeddings;
 pub mod generators;
 pub mod logic;
+pub mod pii;
 pub mod py;
 pub mod quality;
+pub mod references;
+pub mod shell;
+pub mod text;
+pub mod tools;
 pub mod validators;
 pub mod writers;
 use crate::{
-    common::{df_to_values, OptionToResult},
+    common::{blake3_hash, dedup::hash_value, df_to_values, OptionToResult},
     datasets::{Dataset, DatasetType},
-    embeddings::EmbeddingsType,
+    embeddings::{cosine_similarity, embed_cached, EmbeddingsType},
     llms::LLMType,
     steps::{
         conversations::{
-            RenderConversationStep, RenderDPOStep, RenderGRPOStep, RenderToolCallStep,
+            AugmentConversationStep, ConvertFormatStep, DialogueStep, RenderConversationStep,
+            RenderDPOStep, RenderGRPOStep, RenderToolCallStep, TruncateConversationStep,
         },
-        embeddings::CheckEmbeddingStep,
-        generators::{JsonGenerationStep, JudgeConversationStep, TextGenerationStep},
-        logic::{FilterStep, MutateStep},
-        py::{PyStep, PyValidator},
-        quality::{CheckHashStep, CheckLanguageStep, CheckSimHashStep},
+        embeddings::{
+            CheckEmbeddingStep, EmbedStep, GroundedGenerationStep, RetrieveStep,
+            SimilarityFilterStep,
+        },
+        generators::{
+            JsonGenerationStep, JudgeConversationStep, JudgeStep, PairwiseJudgeStep,
+            PreferencePairStep, RepairJsonStep, SelfConsistencyStep, TextGenerationStep,
+            TranslateStep,
+        },
+        logic::{
+            DropKeysStep, FilterStep, JqStep, JsonPathStep, MapKeysStep, MutateStep,
+            SelectKeysStep, SqlStep,
+        },
+        pii::PiiRedactionStep,
+        py::{PyStep, PyValidator, PyWriterStep},
+        quality::{
+            CheckGroundingStep, CheckHashStep, CheckLanguageStep, CheckLengthStep,
+            CheckSimHashStep, ItemIdStep, RewardsStep, VerifyMathStep,
+        },
+        shell::{CodeTestsStep, ExecuteCodeStep, ShellStep},
+        text::{
+            semantic_chunks, split_sentences, CleanupStep, RegexExtractStep, RegexReplaceStep,
+            TokenCountStep, TruncateTokensStep,
+        },
+        tools::{NegativeToolSamplerStep, SimulateToolResponseStep},
         validators::{
-            ConversationValidateStep, ToolsNormalizeStep, ToolsValidateStep, ValidateJsonStep,
+            ConversationValidateStep, ExtractStructuredStep, ToolsNormalizeStep, ToolsValidateStep,
+            ValidateJsonStep,
+        },
+        writers::{
+            CsvWriterStep, EmbeddingsWriterStep, GroupByStep, HttpWriterStep, IpcWriterStep,
+            JsonlWriterStep, PostgresWriterStep, SqliteWriterStep, TeeStep,
         },
-        writers::{CsvWriterStep, JsonlWriterStep},
     },
     templates::Templates,
+    tokenizers::TokenizerWrapper,
     PipelineResources,
 };
 use anyhow::Result;
-use log::error;
+use log::{debug, error};
 use pyo3::prelude::*;
-use rand::RngCore;
+use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use text_splitter::{Characters, TextSplitter};
+use std::sync::Mutex;
+use text_splitter::{Characters, ChunkConfig, ChunkSizer, TextSplitter};
+#[cfg(feature = "kafka")]
+use writers::KafkaWriterStep;
 
 pub type StepContextData = serde_json::Value;
 
@@ -44,6 +80,10 @@ pub struct StepContext {
     pub id: uuid::Uuid,
     status: StepStatus,
     pub data: StepContextData,
+    #[serde(skip)]
+    failed_step: Option<String>,
+    #[serde(skip)]
+    error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,6 +100,8 @@ impl StepContext {
             id: uuid::Uuid::new_v4(),
             data: json!({}),
             status: StepStatus::Pending,
+            failed_step: None,
+            error: None,
         }
     }
 
@@ -71,6 +113,28 @@ impl StepContext {
         &self.status
     }
 
+    /// Records the step that failed the item, nested steps record it first.
+    pub fn set_failed_step(&mut self, step: &str) {
+        if self.failed_step.is_none() {
+            self.failed_step = Some(step.to_string());
+        }
+    }
+
+    pub fn failed_step(&self) -> Option<&str> {
+        self.failed_step.as_deref()
+    }
+
+    /// Records the error that failed the item, e.g. under the `quarantine` error policy.
+    pub fn set_error(&mut self, error: &str) {
+        if self.error.is_none() {
+            self.error = Some(error.to_string());
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
     pub fn set<T: serde::Serialize>(&mut self, key: &str, value: T) {
         self.data[key] = serde_json::to_value(value).unwrap();
     }
@@ -98,11 +162,20 @@ pub enum StepType {
     IfElse(IfElseStep),
     Py(PyStep),
     PyValidator(PyValidator),
+    PyWriter(PyWriterStep),
     TextGeneration(TextGenerationStep),
     JsonGeneration(JsonGenerationStep),
     JsonWriter(JsonlWriterStep),
     CsvWriter(CsvWriterStep),
+    IpcWriter(IpcWriterStep),
+    SqliteWriter(SqliteWriterStep),
+    PostgresWriter(PostgresWriterStep),
+    HttpWriter(HttpWriterStep),
+    #[cfg(feature = "kafka")]
+    KafkaWriter(KafkaWriterStep),
+    Tee(TeeStep),
     Print(PrintStep),
+    Dump(DumpStep),
     DataSampler(DataSamplerStep),
     Chunk(ChunkStep),
     Render(RenderStep),
@@ -119,9 +192,200 @@ pub enum StepType {
     CheckLanguage(CheckLanguageStep),
     RenderToolCall(RenderToolCallStep),
     CheckHash(CheckHashStep),
+    ItemId(ItemIdStep),
     CheckSimHash(CheckSimHashStep),
     CheckEmbedding(CheckEmbeddingStep),
+    Embed(EmbedStep),
+    SimilarityFilter(SimilarityFilterStep),
+    EmbeddingsWriter(EmbeddingsWriterStep),
+    Retrieve(RetrieveStep),
     JudgeConversation(JudgeConversationStep),
+    Judge(JudgeStep),
+    PairwiseJudge(PairwiseJudgeStep),
+    SelfConsistency(SelfConsistencyStep),
+    ForEach(ForEachStep),
+    SubPipeline(SubPipelineStep),
+    Loop(LoopStep),
+    Retry(RetryStep),
+    Parallel(ParallelStep),
+    Switch(SwitchStep),
+    MapKeys(MapKeysStep),
+    SelectKeys(SelectKeysStep),
+    DropKeys(DropKeysStep),
+    RegexExtract(RegexExtractStep),
+    RegexReplace(RegexReplaceStep),
+    JsonPath(JsonPathStep),
+    Jq(JqStep),
+    Sql(SqlStep),
+    Shell(ShellStep),
+    ExecuteCode(ExecuteCodeStep),
+    CodeTests(CodeTestsStep),
+    TokenCount(TokenCountStep),
+    TruncateTokens(TruncateTokensStep),
+    Cleanup(CleanupStep),
+    PiiRedaction(PiiRedactionStep),
+    Translate(TranslateStep),
+    CheckLength(CheckLengthStep),
+    CheckGrounding(CheckGroundingStep),
+    VerifyMath(VerifyMathStep),
+    RepairJson(RepairJsonStep),
+    ExtractStructured(ExtractStructuredStep),
+    Accumulate(AccumulateStep),
+    GroupBy(GroupByStep),
+    Zip(ZipStep),
+    Explode(ExplodeStep),
+    TruncateConversation(TruncateConversationStep),
+    ConvertFormat(ConvertFormatStep),
+    AugmentConversation(AugmentConversationStep),
+    SimulateToolResponse(SimulateToolResponseStep),
+    Persona(PersonaStep),
+    WeightedChoice(WeightedChoiceStep),
+    NegativeToolSampler(NegativeToolSamplerStep),
+    PreferencePair(PreferencePairStep),
+    Rewards(RewardsStep),
+    Cache(CacheStep),
+    GroundedGeneration(GroundedGenerationStep),
+    Dialogue(Box<DialogueStep>),
+}
+
+impl StepType {
+    /// Name the step was registered under, e.g. `JUDGE--3`.
+    pub fn name(&self) -> &str {
+        match self {
+            StepType::IfElse(step) => &step.name,
+            StepType::Py(step) => &step.name,
+            StepType::PyValidator(step) => &step.name,
+            StepType::PyWriter(step) => &step.name,
+            StepType::TextGeneration(step) => &step.name,
+            StepType::JsonGeneration(step) => &step.name,
+            StepType::JsonWriter(step) => &step.name,
+            StepType::CsvWriter(step) => &step.name,
+            StepType::IpcWriter(step) => &step.name,
+            StepType::SqliteWriter(step) => &step.name,
+            StepType::PostgresWriter(step) => &step.name,
+            StepType::HttpWriter(step) => &step.name,
+            #[cfg(feature = "kafka")]
+            StepType::KafkaWriter(step) => &step.name,
+            StepType::Tee(step) => &step.name,
+            StepType::Print(step) => &step.name,
+            StepType::Dump(step) => &step.name,
+            StepType::DataSampler(step) => &step.name,
+            StepType::Chunk(step) => &step.name,
+            StepType::Render(step) => &step.name,
+            StepType::ValidateJson(step) => &step.name,
+            StepType::ValidateTools(step) => &step.name,
+            StepType::NormalizeTools(step) => &step.name,
+            StepType::ConversationValidate(step) => &step.name,
+            StepType::IntoList(step) => &step.name,
+            StepType::RenderConversation(step) => &step.name,
+            StepType::RenderDPO(step) => &step.name,
+            StepType::RenderGRPO(step) => &step.name,
+            StepType::Filter(step) => &step.name,
+            StepType::Mutate(step) => &step.name,
+            StepType::CheckLanguage(step) => &step.name,
+            StepType::RenderToolCall(step) => &step.name,
+            StepType::CheckHash(step) => &step.name,
+            StepType::ItemId(step) => &step.name,
+            StepType::CheckSimHash(step) => &step.name,
+            StepType::CheckEmbedding(step) => &step.name,
+            StepType::Embed(step) => &step.name,
+            StepType::SimilarityFilter(step) => &step.name,
+            StepType::EmbeddingsWriter(step) => &step.name,
+            StepType::Retrieve(step) => &step.name,
+            StepType::JudgeConversation(step) => &step.name,
+            StepType::Judge(step) => &step.name,
+            StepType::PairwiseJudge(step) => &step.name,
+            StepType::SelfConsistency(step) => &step.name,
+            StepType::ForEach(step) => &step.name,
+            StepType::SubPipeline(step) => &step.name,
+            StepType::Loop(step) => &step.name,
+            StepType::Retry(step) => &step.name,
+            StepType::Parallel(step) => &step.name,
+            StepType::Switch(step) => &step.name,
+            StepType::MapKeys(step) => &step.name,
+            StepType::SelectKeys(step) => &step.name,
+            StepType::DropKeys(step) => &step.name,
+            StepType::RegexExtract(step) => &step.name,
+            StepType::RegexReplace(step) => &step.name,
+            StepType::JsonPath(step) => &step.name,
+            StepType::Jq(step) => &step.name,
+            StepType::Sql(step) => &step.name,
+            StepType::Shell(step) => &step.name,
+            StepType::ExecuteCode(step) => &step.name,
+            StepType::CodeTests(step) => &step.name,
+            StepType::TokenCount(step) => &step.name,
+            StepType::TruncateTokens(step) => &step.name,
+            StepType::Cleanup(step) => &step.name,
+            StepType::PiiRedaction(step) => &step.name,
+            StepType::Translate(step) => &step.name,
+            StepType::CheckLength(step) => &step.name,
+            StepType::CheckGrounding(step) => &step.name,
+            StepType::VerifyMath(step) => &step.name,
+            StepType::RepairJson(step) => &step.name,
+            StepType::ExtractStructured(step) => &step.name,
+            StepType::Accumulate(step) => &step.name,
+            StepType::GroupBy(step) => &step.name,
+            StepType::Zip(step) => &step.name,
+            StepType::Explode(step) => &step.name,
+            StepType::TruncateConversation(step) => &step.name,
+            StepType::ConvertFormat(step) => &step.name,
+            StepType::AugmentConversation(step) => &step.name,
+            StepType::SimulateToolResponse(step) => &step.name,
+            StepType::Persona(step) => &step.name,
+            StepType::WeightedChoice(step) => &step.name,
+            StepType::NegativeToolSampler(step) => &step.name,
+            StepType::PreferencePair(step) => &step.name,
+            StepType::Rewards(step) => &step.name,
+            StepType::Cache(step) => &step.name,
+            StepType::GroundedGeneration(step) => &step.name,
+            StepType::Dialogue(step) => &step.name,
+        }
+    }
+}
+
+/// Called once all items were processed, lets steps that buffer rows until the end
+/// of the run flush them.
+pub async fn finish_steps(steps: &[StepType]) -> Result<()> {
+    for step in steps {
+        match step {
+            StepType::IfElse(if_step) => {
+                Box::pin(finish_steps(&if_step.then_steps)).await?;
+                if let Some(else_steps) = &if_step.else_steps {
+                    Box::pin(finish_steps(else_steps)).await?;
+                }
+            }
+            StepType::ForEach(foreach_step) => Box::pin(finish_steps(&foreach_step.steps)).await?,
+            StepType::Loop(loop_step) => Box::pin(finish_steps(&loop_step.steps)).await?,
+            StepType::Retry(retry_step) => Box::pin(finish_steps(&retry_step.steps)).await?,
+            StepType::Cache(cache_step) => Box::pin(finish_steps(&cache_step.steps)).await?,
+            StepType::Parallel(parallel_step) => {
+                for branch in &parallel_step.branches {
+                    Box::pin(finish_steps(branch)).await?;
+                }
+            }
+            StepType::Switch(switch_step) => {
+                for (_, steps) in &switch_step.cases {
+                    Box::pin(finish_steps(steps)).await?;
+                }
+                if let Some(default) = &switch_step.default {
+                    Box::pin(finish_steps(default)).await?;
+                }
+            }
+            StepType::Tee(tee_step) => Box::pin(finish_steps(&tee_step.sinks)).await?,
+            StepType::EmbeddingsWriter(writer) => writer.finish()?,
+            StepType::JsonWriter(writer) => writer.finish().await?,
+            StepType::CsvWriter(writer) => writer.finish().await?,
+            StepType::IpcWriter(writer) => writer.finish().await?,
+            StepType::PyWriter(writer) => writer.finish()?,
+            StepType::PostgresWriter(writer) => writer.finish().await?,
+            StepType::HttpWriter(writer) => writer.finish().await?,
+            #[cfg(feature = "kafka")]
+            StepType::KafkaWriter(writer) => writer.finish().await?,
+            StepType::GroupBy(group_by) => group_by.finish()?,
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
 pub struct IfElseStep {
@@ -157,37 +421,51 @@ impl IfElseStep {
         _embeddings: &HashMap<String, EmbeddingsType>,
         context: &StepContext,
     ) -> Result<bool> {
-        let json = serde_json::to_string(context)?;
-
-        let result = if let Some(condition) = &self.py_condition {
-            let result: PyResult<bool> = Python::with_gil(|py| {
-                let result: bool = condition.call_method1(py, "check", (json,))?.extract(py)?;
-                Ok(result)
-            });
-
-            anyhow::Ok(result?)
-        } else if let Some(key) = &self.condition_key {
-            let rendered = templates.render(key.clone(), context.data.clone())?;
-            if let Ok(v) = serde_json::from_str::<bool>(&rendered) {
-                anyhow::Ok(v)
-            } else {
-                error!(target: "ifelsestep", "🐔 Condition is not a boolean: {}", rendered);
-                return Err(anyhow::anyhow!("Condition is not a boolean"));
-            }
+        check_condition(
+            self.py_condition.as_ref(),
+            self.condition_key.as_ref(),
+            templates,
+            context,
+        )
+    }
+}
+
+/// Evaluates a Python predicate or a template rendering to `true`/`false`, errors are
+/// logged and treated as `false`.
+pub fn check_condition(
+    py_condition: Option<&PyObject>,
+    condition_key: Option<&String>,
+    templates: &Templates,
+    context: &StepContext,
+) -> Result<bool> {
+    let json = serde_json::to_string(context)?;
+
+    let result = if let Some(condition) = py_condition {
+        let result: PyResult<bool> = Python::with_gil(|py| {
+            let result: bool = condition.call_method1(py, "check", (json,))?.extract(py)?;
+            Ok(result)
+        });
+
+        anyhow::Ok(result?)
+    } else if let Some(key) = condition_key {
+        let rendered = templates.render(key.clone(), context.data.clone())?;
+        if let Ok(v) = serde_json::from_str::<bool>(&rendered) {
+            anyhow::Ok(v)
         } else {
-            Err(anyhow::anyhow!(
-                "Either py_condition or condition_key must be provided"
-            ))
-        };
+            error!(target: "ifelsestep", "🐔 Condition is not a boolean: {}", rendered);
+            return Err(anyhow::anyhow!("Condition is not a boolean"));
+        }
+    } else {
+        Err(anyhow::anyhow!(
+            "Either py_condition or condition_key must be provided"
+        ))
+    };
 
-        match result {
-            Ok(result) => Ok(result),
-            Err(e) => {
-                error!(target: "ifelsestep", "🐔 {:?}", e);
-                let mut context = context.clone();
-                context.set_status(StepStatus::Failed);
-                Ok(false)
-            }
+    match result {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            error!(target: "ifelsestep", "🐔 {:?}", e);
+            Ok(false)
         }
     }
 }
@@ -202,6 +480,495 @@ impl Step for IfElseStep {
     }
 }
 
+/// Repeats the inner steps until the condition holds after a run or `max_iters` runs
+/// were made. Failed runs are retried from the context before the run; the context is
+/// marked failed if the condition was never met.
+pub struct LoopStep {
+    pub name: String,
+    pub py_condition: Option<PyObject>,
+    pub condition_key: Option<String>,
+    pub steps: Vec<StepType>,
+    pub max_iters: usize,
+}
+
+impl LoopStep {
+    pub fn new(
+        name: String,
+        py_condition: Option<PyObject>,
+        condition_key: Option<String>,
+        steps: Vec<StepType>,
+        max_iters: usize,
+    ) -> Self {
+        Self {
+            name,
+            py_condition,
+            condition_key,
+            steps,
+            max_iters,
+        }
+    }
+
+    pub fn check(&self, templates: &Templates, context: &StepContext) -> Result<bool> {
+        check_condition(
+            self.py_condition.as_ref(),
+            self.condition_key.as_ref(),
+            templates,
+            context,
+        )
+    }
+}
+
+impl Step for LoopStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+/// Routes the context to the steps of the case matching the rendered key template,
+/// falls back to `default`. Contexts matching no case pass through unchanged.
+pub struct SwitchStep {
+    pub name: String,
+    pub key_template: String,
+    pub cases: Vec<(String, Vec<StepType>)>,
+    pub default: Option<Vec<StepType>>,
+}
+
+impl SwitchStep {
+    pub fn new(
+        name: String,
+        key_template: String,
+        cases: Vec<(String, Vec<StepType>)>,
+        default: Option<Vec<StepType>>,
+    ) -> Self {
+        Self {
+            name,
+            key_template,
+            cases,
+            default,
+        }
+    }
+
+    pub fn branch(
+        &self,
+        templates: &Templates,
+        context: &StepContext,
+    ) -> Result<Option<&Vec<StepType>>> {
+        let rendered = templates.render(self.key_template.clone(), context.data.clone())?;
+        let key = rendered.trim();
+        Ok(self
+            .cases
+            .iter()
+            .find(|(value, _)| value == key)
+            .map(|(_, steps)| steps)
+            .or(self.default.as_ref()))
+    }
+}
+
+impl Step for SwitchStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+/// Runs the branches concurrently on clones of the context and copies the outputs
+/// declared for each branch back. The context fails if any branch failed.
+pub struct ParallelStep {
+    pub name: String,
+    pub branches: Vec<Vec<StepType>>,
+    pub outputs: Vec<Vec<String>>,
+}
+
+impl ParallelStep {
+    pub fn new(name: String, branches: Vec<Vec<StepType>>, outputs: Vec<Vec<String>>) -> Self {
+        Self {
+            name,
+            branches,
+            outputs,
+        }
+    }
+
+    pub fn merge(&self, context: &StepContext, results: Vec<StepContext>) -> StepContext {
+        let mut context = context.clone();
+        for (result, outputs) in results.iter().zip(&self.outputs) {
+            if matches!(result.get_status(), StepStatus::Failed) {
+                if let Some(step) = result.failed_step() {
+                    context.set_failed_step(step);
+                }
+                context.set_status(StepStatus::Failed);
+                return context;
+            }
+            for output in outputs {
+                match result.get(output) {
+                    Some(value) => context.set(output, value),
+                    None => {
+                        error!(target: "parallelstep", "🐔 Branch output {} not found", output);
+                        context.set_status(StepStatus::Failed);
+                        return context;
+                    }
+                }
+            }
+        }
+        context
+    }
+}
+
+impl Step for ParallelStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+/// Re-runs the inner steps when they return an error or mark the context failed, waiting
+/// an exponentially growing, jittered delay between attempts.
+pub struct RetryStep {
+    pub name: String,
+    pub steps: Vec<StepType>,
+    pub max_attempts: usize,
+    pub backoff_ms: u64,
+}
+
+impl RetryStep {
+    pub fn new(name: String, steps: Vec<StepType>, max_attempts: usize, backoff_ms: u64) -> Self {
+        Self {
+            name,
+            steps,
+            max_attempts: max_attempts.max(1),
+            backoff_ms,
+        }
+    }
+
+    pub fn delay(&self, attempt: usize) -> std::time::Duration {
+        backoff_delay(self.backoff_ms, attempt, &mut rand::rng())
+    }
+}
+
+/// `backoff_ms * 2^(attempt - 1)` scaled by a random factor in `[0.5, 1.5)`.
+pub fn backoff_delay(backoff_ms: u64, attempt: usize, rng: &mut impl Rng) -> std::time::Duration {
+    let exp = attempt.saturating_sub(1).min(16) as u32;
+    let base = backoff_ms.saturating_mul(2u64.pow(exp)) as f64;
+    let jitter: f64 = rng.random_range(0.5..1.5);
+    std::time::Duration::from_millis((base * jitter) as u64)
+}
+
+/// What the pipeline does when a step returns an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// The item fails with the error and the run reports it.
+    Fail,
+    /// The item continues with the context as it was before the step.
+    Skip,
+    /// The step is re-run up to `max_attempts` times, waiting as in [`backoff_delay`],
+    /// before the item fails.
+    Retry {
+        max_attempts: usize,
+        backoff_ms: u64,
+    },
+    /// The item is marked as failed with the error and goes to the quarantine.
+    Quarantine,
+}
+
+impl ErrorPolicy {
+    pub fn parse(policy: &str, max_attempts: usize, backoff_ms: u64) -> Result<Self> {
+        match policy.to_lowercase().as_str() {
+            "fail" => Ok(ErrorPolicy::Fail),
+            "skip" => Ok(ErrorPolicy::Skip),
+            "retry" => Ok(ErrorPolicy::Retry {
+                max_attempts: max_attempts.max(1),
+                backoff_ms,
+            }),
+            "quarantine" => Ok(ErrorPolicy::Quarantine),
+            _ => anyhow::bail!("🐔 Unsupported error policy: {}", policy),
+        }
+    }
+
+    /// Wait before the next attempt of the `retry` policy.
+    pub fn delay(&self, attempt: usize) -> std::time::Duration {
+        match self {
+            ErrorPolicy::Retry { backoff_ms, .. } => {
+                backoff_delay(*backoff_ms, attempt, &mut rand::rng())
+            }
+            _ => std::time::Duration::ZERO,
+        }
+    }
+}
+
+impl Step for RetryStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+fn cache_key(fingerprint: &str, inputs: &[String], context: &StepContext) -> String {
+    let inputs = if inputs.is_empty() {
+        context.data.clone()
+    } else {
+        json!(inputs
+            .iter()
+            .map(|input| (input.clone(), context.get(input).cloned()))
+            .collect::<HashMap<_, _>>())
+    };
+    blake3_hash(&format!("{}{}", fingerprint, hash_value(&inputs)))
+}
+
+/// Memoizes the inner steps: the values they add or change are stored in the state keyed
+/// by a hash of the steps `config`, the templates it references and the `inputs` (the whole
+/// item when empty), and restored instead of running the steps again on later runs.
+pub struct CacheStep {
+    pub name: String,
+    pub steps: Vec<StepType>,
+    pub config: String,
+    pub inputs: Vec<String>,
+    fingerprint: std::sync::OnceLock<String>,
+}
+
+impl CacheStep {
+    pub fn new(name: String, steps: Vec<StepType>, config: String, inputs: Vec<String>) -> Self {
+        // python callables print with their address, which changes between runs
+        let config = regex::Regex::new(r"0x[0-9a-f]+")
+            .expect("Address pattern")
+            .replace_all(&config, "")
+            .to_string();
+        Self {
+            name,
+            steps,
+            config,
+            inputs,
+            fingerprint: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Hash of the config and the content of the templates it mentions, so editing a
+    /// prompt invalidates the cache.
+    fn fingerprint(&self, templates: &Templates) -> &str {
+        self.fingerprint.get_or_init(|| {
+            let mut referenced = templates
+                .templates
+                .iter()
+                .filter(|(key, _)| self.config.contains(&format!("{:?}", key)))
+                .collect::<Vec<_>>();
+            referenced.sort();
+            blake3_hash(&format!("{}{:?}", self.config, referenced))
+        })
+    }
+
+    pub fn key(&self, templates: &Templates, context: &StepContext) -> String {
+        cache_key(self.fingerprint(templates), &self.inputs, context)
+    }
+
+    /// Top level values of `after` that are new or differ from `before`.
+    pub fn changes(before: &StepContext, after: &StepContext) -> serde_json::Value {
+        let changes = after
+            .data
+            .as_object()
+            .map(|after_data| {
+                after_data
+                    .iter()
+                    .filter(|(key, value)| before.data.get(key.as_str()) != Some(value))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<serde_json::Map<_, _>>()
+            })
+            .unwrap_or_default();
+        serde_json::Value::Object(changes)
+    }
+
+    pub fn restore(context: &mut StepContext, changes: &serde_json::Value) {
+        if let Some(changes) = changes.as_object() {
+            for (key, value) in changes {
+                context.set(key, value.clone());
+            }
+        }
+    }
+}
+
+impl Step for CacheStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+/// Runs the inner steps once per element of the `input` list. Each run sees the element
+/// as `item_key`, the value of `item_output` (defaults to `item_key`) after the run is
+/// collected into `output`. Elements whose run failed are skipped.
+pub struct ForEachStep {
+    pub name: String,
+    pub input: String,
+    pub item_key: String,
+    pub output: String,
+    pub item_output: Option<String>,
+    pub steps: Vec<StepType>,
+}
+
+impl ForEachStep {
+    pub fn new(
+        name: String,
+        input: String,
+        item_key: String,
+        output: String,
+        item_output: Option<String>,
+        steps: Vec<StepType>,
+    ) -> Self {
+        Self {
+            name,
+            input,
+            item_key,
+            output,
+            item_output,
+            steps,
+        }
+    }
+
+    pub fn items(&self, context: &StepContext) -> Option<Vec<serde_json::Value>> {
+        match context.get(&self.input) {
+            Some(serde_json::Value::Array(items)) => Some(items.clone()),
+            _ => {
+                error!(target: "foreachstep", "🐔 Input {} is missing or not a list", self.input);
+                None
+            }
+        }
+    }
+
+    pub fn item_context(
+        &self,
+        context: &StepContext,
+        index: usize,
+        item: serde_json::Value,
+    ) -> StepContext {
+        let mut item_context = context.clone();
+        item_context.set(&self.item_key, item);
+        item_context.set(&format!("{}_index", self.item_key), index);
+        item_context
+    }
+
+    pub fn collect(&self, item_context: &StepContext) -> Option<serde_json::Value> {
+        if matches!(item_context.get_status(), StepStatus::Failed) {
+            return None;
+        }
+        let key = self.item_output.as_ref().unwrap_or(&self.item_key);
+        item_context.get(key).cloned()
+    }
+}
+
+impl Step for ForEachStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Inner steps are run by the pipeline");
+    }
+}
+
+/// Runs a child pipeline for each item and copies its `outputs` back to the item, failing
+/// the item when the child failed it. The child pipeline is held and run by the pipeline.
+pub struct SubPipelineStep {
+    pub name: String,
+    pub outputs: Vec<String>,
+}
+
+impl SubPipelineStep {
+    pub fn new(name: String, outputs: Vec<String>) -> Self {
+        Self { name, outputs }
+    }
+}
+
+impl Step for SubPipelineStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("The child pipeline is run by the pipeline");
+    }
+}
+
+/// Collects items into batches of `size`. When a batch is full the remaining steps run
+/// once for the whole batch (a list under `output`) instead of once per item, the last
+/// partial batch is flushed when the run ends. `inputs` limits the collected keys.
+pub struct AccumulateStep {
+    pub name: String,
+    pub size: usize,
+    pub output: String,
+    pub inputs: Option<Vec<String>>,
+    buffer: Mutex<Vec<serde_json::Value>>,
+}
+
+impl AccumulateStep {
+    pub fn new(name: String, size: usize, output: String, inputs: Option<Vec<String>>) -> Self {
+        Self {
+            name,
+            size: size.max(1),
+            output,
+            inputs,
+            buffer: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds the item to the current batch and returns the batch context once it is full.
+    pub fn push(&self, context: &StepContext) -> Option<StepContext> {
+        let item = match &self.inputs {
+            Some(inputs) => serde_json::Value::Object(
+                inputs
+                    .iter()
+                    .map(|key| (key.clone(), context.get(key).cloned().unwrap_or_default()))
+                    .collect(),
+            ),
+            None => context.data.clone(),
+        };
+
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.push(item);
+        if buffer.len() < self.size {
+            return None;
+        }
+        let batch = std::mem::take(&mut *buffer);
+        Some(self.batch_context(batch))
+    }
+
+    /// Returns the context of the last partial batch, if any items are left.
+    pub fn flush(&self) -> Option<StepContext> {
+        let batch = std::mem::take(&mut *self.buffer.lock().unwrap());
+        (!batch.is_empty()).then(|| self.batch_context(batch))
+    }
+
+    fn batch_context(&self, batch: Vec<serde_json::Value>) -> StepContext {
+        let mut context = StepContext::new();
+        context.set("batch_size", batch.len());
+        context.set(&self.output, batch);
+        context.set_status(StepStatus::Running);
+        context
+    }
+}
+
+impl Step for AccumulateStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Batches are emitted by the pipeline");
+    }
+}
+
 pub struct RenderStep {
     pub name: String,
     pub template: String,
@@ -290,6 +1057,58 @@ impl Step for PrintStep {
     }
 }
 
+/// Writes the whole context as pretty JSON to `dir` for items matching `condition`.
+/// With `failed` items marked as failed are dumped too, otherwise without a condition
+/// every item is.
+pub struct DumpStep {
+    pub name: String,
+    pub dir: String,
+    pub condition: Option<String>,
+    pub failed: bool,
+}
+
+impl DumpStep {
+    pub fn new(name: String, dir: String, condition: Option<String>, failed: bool) -> Self {
+        Self {
+            name,
+            dir,
+            condition,
+            failed,
+        }
+    }
+
+    fn matches(&self, templates: &Templates, context: &StepContext) -> Result<bool> {
+        if matches!(context.get_status(), StepStatus::Failed) {
+            return Ok(self.failed);
+        }
+        match &self.condition {
+            Some(condition) => check_condition(None, Some(condition), templates, context),
+            None => Ok(!self.failed),
+        }
+    }
+
+    /// File of an item, `<step name>-<item id>.json`.
+    pub fn path(&self, context: &StepContext) -> std::path::PathBuf {
+        std::path::Path::new(&self.dir).join(format!("{}-{}.json", self.name, context.id))
+    }
+}
+
+impl Step for DumpStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        if self.matches(&resources.templates, context)? {
+            std::fs::create_dir_all(&self.dir)?;
+            let path = self.path(context);
+            std::fs::write(&path, serde_json::to_string_pretty(context)?)?;
+            debug!(target: "dump_step", "🐔 Dumped item to {}", path.display());
+        }
+        Ok(context.clone())
+    }
+}
+
 pub struct DataSamplerStep {
     pub name: String,
     pub dataset: String,
@@ -330,6 +1149,44 @@ impl DataSamplerStep {
     }
 }
 
+/// The frame behind a dataset, mixed datasets have none as they sample their sources.
+pub fn dataset_df(dataset_type: &DatasetType) -> Option<&polars::prelude::DataFrame> {
+    match dataset_type {
+        DatasetType::Polars(polars_dataset) => Some(polars_dataset.df()),
+        DatasetType::Json(json_dataset) => Some(json_dataset.df()),
+        DatasetType::JsonList(json_list_dataset) => Some(json_list_dataset.df()),
+        DatasetType::OpenApi(openapi_dataset) => Some(openapi_dataset.df()),
+        DatasetType::Ipc(ipc_dataset) => Some(ipc_dataset.df()),
+        DatasetType::Csv(csv_dataset) => Some(csv_dataset.df()),
+        DatasetType::Parquet(parquet_dataset) => Some(parquet_dataset.df()),
+        DatasetType::Jsonl(jsonl_dataset) => Some(jsonl_dataset.df()),
+        DatasetType::Mixed(_mixed_dataset) => None,
+        DatasetType::PhfSet(phf_set_dataset) => Some(phf_set_dataset.df()),
+    }
+}
+
+/// Random `size` rows of the dataset (all rows in random order by default), at most as
+/// many as it has.
+pub fn sample_dataset(
+    resources: &PipelineResources,
+    dataset: &str,
+    size: Option<usize>,
+    rng: &mut impl Rng,
+) -> Result<Vec<serde_json::Value>> {
+    let dataset_type = resources.datasets.get(dataset).ok_or_err(dataset)?;
+    match dataset_type {
+        DatasetType::Mixed(mixed_dataset) => {
+            mixed_dataset.sample(size, &resources.datasets.resources, rng)
+        }
+        _ => {
+            let df = dataset_df(dataset_type).ok_or_err(dataset)?;
+            let size = size.unwrap_or(df.height()).min(df.height());
+            let df = df.sample_n_literal(size, false, true, Some(rng.next_u64()))?;
+            df_to_values(&df)
+        }
+    }
+}
+
 impl Step for DataSamplerStep {
     async fn process(
         &self,
@@ -338,55 +1195,177 @@ impl Step for DataSamplerStep {
     ) -> Result<StepContext> {
         let mut context = context.clone();
 
-        let dataset_type = resources
-            .datasets
-            .get(&self.dataset)
-            .ok_or_err(&self.dataset)
-            .unwrap();
+        let mut rng = resources.rng(&self.name, None, &context);
+        let json_rows = sample_dataset(resources, &self.dataset, self.size, &mut rng)?;
 
-        let json_rows = if let DatasetType::Mixed(mixed_dataset) = dataset_type {
-            mixed_dataset.sample(self.size.unwrap(), &resources.datasets.resources)?
-        } else {
-            let df = match dataset_type {
-                DatasetType::Polars(polars_dataset) => polars_dataset.df(),
-                DatasetType::Json(json_dataset) => json_dataset.df(),
-                DatasetType::JsonList(json_list_dataset) => json_list_dataset.df(),
-                DatasetType::OpenApi(openapi_dataset) => openapi_dataset.df(),
-                DatasetType::Ipc(ipc_dataset) => ipc_dataset.df(),
-                DatasetType::Csv(csv_dataset) => csv_dataset.df(),
-                DatasetType::Parquet(parquet_dataset) => parquet_dataset.df(),
-                DatasetType::Jsonl(jsonl_dataset) => jsonl_dataset.df(),
-                DatasetType::Mixed(_mixed_dataset) => unreachable!(),
-                DatasetType::PhfSet(phf_set_dataset) => phf_set_dataset.df(),
-            };
-
-            let df = df
-                .sample_n_literal(
-                    self.size.unwrap_or(df.size()),
-                    false,
-                    false,
-                    Some(rand::rng().next_u64()),
-                )
-                .unwrap();
+        context.set(&self.output, json_rows);
+        Ok(context)
+    }
+}
+
+/// Samples a persona into `output` as an object with one value per attribute: the built-in
+/// [`crate::dictionaries::personas`] lists named in `attributes` (all when empty) plus the
+/// `custom` lists, which override built-ins of the same name. With a `seed` the persona is
+/// reproducible per item `index`.
+pub struct PersonaStep {
+    pub name: String,
+    pub output: String,
+    pub attributes: Vec<(String, Vec<String>)>,
+    pub seed: Option<u64>,
+}
 
-            df_to_values(&df)?
+impl PersonaStep {
+    pub fn new(
+        name: String,
+        output: String,
+        attributes: Vec<String>,
+        custom: Vec<(String, Vec<String>)>,
+        seed: Option<u64>,
+    ) -> Result<Self> {
+        let builtin = |name: &str| {
+            crate::dictionaries::personas::ATTRIBUTES
+                .iter()
+                .find(|(attribute, _)| *attribute == name)
+                .map(|(_, set)| set.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+        };
+        let names = if attributes.is_empty() && custom.is_empty() {
+            crate::dictionaries::personas::ATTRIBUTES
+                .iter()
+                .map(|(attribute, _)| attribute.to_string())
+                .collect()
+        } else {
+            attributes
         };
 
-        context.set(&self.output, json_rows);
+        let mut resolved = Vec::new();
+        for name in names {
+            if custom.iter().any(|(key, _)| *key == name) {
+                continue;
+            }
+            match builtin(&name) {
+                Some(values) => resolved.push((name, values)),
+                None => anyhow::bail!("🐔 Unknown persona attribute: {}", name),
+            }
+        }
+        for (name, values) in custom {
+            if values.is_empty() {
+                anyhow::bail!("🐔 Persona attribute {} has no values", name);
+            }
+            resolved.push((name, values));
+        }
+
+        Ok(Self {
+            name,
+            output,
+            attributes: resolved,
+            seed,
+        })
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.attributes
+                .iter()
+                .map(|(name, values)| {
+                    (
+                        name.clone(),
+                        json!(values[rng.random_range(0..values.len())]),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Step for PersonaStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let persona = self.sample(&mut resources.rng(&self.name, self.seed, &context));
+        context.set(&self.output, persona);
+        Ok(context)
+    }
+}
+
+/// Picks one of `options` into `output` with probability proportional to its weight, e.g. a
+/// template name, instruction or style. With a `seed` the choice is reproducible per item
+/// `index`.
+pub struct WeightedChoiceStep {
+    pub name: String,
+    pub output: String,
+    pub options: Vec<String>,
+    pub weights: WeightedIndex<f64>,
+    pub seed: Option<u64>,
+}
+
+impl WeightedChoiceStep {
+    pub fn new(
+        name: String,
+        output: String,
+        options: Vec<(String, f64)>,
+        seed: Option<u64>,
+    ) -> Result<Self> {
+        if options.is_empty() {
+            anyhow::bail!("🐔 Choice step {} has no options", name);
+        }
+        let weights = WeightedIndex::new(options.iter().map(|(_, weight)| *weight))
+            .map_err(|e| anyhow::anyhow!("🐔 Invalid weights of choice step {}: {}", name, e))?;
+        Ok(Self {
+            name,
+            output,
+            options: options.into_iter().map(|(option, _)| option).collect(),
+            weights,
+            seed,
+        })
+    }
+
+    pub fn choose(&self, rng: &mut impl Rng) -> &str {
+        &self.options[self.weights.sample(rng)]
+    }
+}
+
+impl Step for WeightedChoiceStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let choice = self.choose(&mut resources.rng(&self.name, self.seed, &context));
+        context.set(&self.output, choice);
         Ok(context)
     }
 }
 
+/// Splits the `input` text into chunks within `capacity`, measured in characters or, with
+/// `tokenizer`, in tokens of a registered tokenizer. With `embedding` chunks are cut on
+/// topic shifts, where the similarity of neighbouring sentence windows drops below
+/// `threshold`.
 pub struct ChunkStep {
     pub name: String,
     pub capacity: (usize, usize),
     pub input: String,
     pub output: String,
+    pub tokenizer: Option<String>,
+    pub embedding: Option<String>,
+    pub threshold: f32,
     pub text_splitter: TextSplitter<Characters>,
 }
 
 impl ChunkStep {
-    pub fn new(name: String, capacity: (usize, usize), input: String, output: String) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        capacity: (usize, usize),
+        input: String,
+        output: String,
+        tokenizer: Option<String>,
+        embedding: Option<String>,
+        threshold: f32,
+    ) -> Self {
         let range = capacity.0..capacity.1;
         let text_splitter = TextSplitter::new(range);
 
@@ -395,28 +1374,99 @@ impl ChunkStep {
             capacity,
             input,
             output,
+            tokenizer,
+            embedding,
+            threshold,
             text_splitter,
         }
     }
+
+    async fn semantic_chunks(
+        &self,
+        resources: &PipelineResources,
+        embedding: &str,
+        tokenizer: Option<&TokenizerWrapper>,
+        text: &str,
+    ) -> Result<Vec<String>> {
+        let embedding = resources
+            .embeddings
+            .get(embedding)
+            .ok_or_else(|| anyhow::anyhow!("Embedding not found: {}", embedding))?;
+
+        let sentences = split_sentences(text);
+        // each sentence is embedded together with its neighbours to smooth out noise
+        let windows = (0..sentences.len())
+            .map(|i| {
+                let start = sentences[i.saturating_sub(1)].0;
+                let end = sentences[(i + 1).min(sentences.len() - 1)].1;
+                text[start..end].trim().to_string()
+            })
+            .collect::<Vec<_>>();
+        let vectors = if windows.is_empty() {
+            Vec::new()
+        } else {
+            embed_cached(embedding, resources.state.as_ref(), windows).await?
+        };
+        let similarities = vectors
+            .windows(2)
+            .map(|pair| cosine_similarity(&pair[0], &pair[1]))
+            .collect::<Vec<_>>();
+
+        Ok(semantic_chunks(
+            text,
+            &sentences,
+            &similarities,
+            self.capacity,
+            self.threshold,
+            |chunk| match tokenizer {
+                Some(tokenizer) => tokenizer.size(chunk),
+                None => chunk.chars().count(),
+            },
+        ))
+    }
 }
 
 impl Step for ChunkStep {
     async fn process(
         &self,
-        _resources: &PipelineResources,
+        resources: &PipelineResources,
         context: &StepContext,
     ) -> Result<StepContext> {
         let mut context = context.clone();
-        let text = context
-            .get(&self.input)
-            .ok_or_else(|| anyhow::anyhow!("Input not found"))?;
-        let chunks: Vec<serde_json::Value> = self
-            .text_splitter
-            .chunks(&text.to_string())
-            .collect::<Vec<&str>>()
-            .iter()
-            .map(|s| serde_json::from_str(s).unwrap())
-            .collect();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "chunk_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let tokenizer = match &self.tokenizer {
+            Some(name) => Some(
+                resources
+                    .tokenizers
+                    .get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Tokenizer not found: {}", name))?,
+            ),
+            None => None,
+        };
+
+        let chunks: Vec<String> = match (&self.embedding, tokenizer) {
+            (Some(embedding), tokenizer) => {
+                self.semantic_chunks(resources, embedding, tokenizer, &text)
+                    .await?
+            }
+            (None, Some(tokenizer)) => {
+                let config =
+                    ChunkConfig::new(self.capacity.0..self.capacity.1).with_sizer(tokenizer);
+                TextSplitter::new(config)
+                    .chunks(&text)
+                    .map(String::from)
+                    .collect()
+            }
+            (None, None) => self.text_splitter.chunks(&text).map(String::from).collect(),
+        };
 
         context.set(&self.output, chunks);
         Ok(context)
@@ -456,8 +1506,126 @@ impl Step for IntoListStep {
     }
 }
 
+/// Fans the item out into one item per element of the `input` list, the remaining steps
+/// (writers included) run once for each of them. The element is stored under `item_key`
+/// and its position under `{item_key}_index`.
+pub struct ExplodeStep {
+    pub name: String,
+    pub input: String,
+    pub item_key: String,
+}
+
+impl ExplodeStep {
+    pub fn new(name: String, input: String, item_key: String) -> Self {
+        Self {
+            name,
+            input,
+            item_key,
+        }
+    }
+
+    pub fn explode(&self, context: &StepContext) -> Option<Vec<StepContext>> {
+        let items = match context.get(&self.input) {
+            Some(serde_json::Value::Array(items)) => items.clone(),
+            _ => {
+                error!(target: "explode_step", "🐔 Input {} is missing or not a list", self.input);
+                return None;
+            }
+        };
+
+        Some(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let mut item_context = context.clone();
+                    item_context.id = uuid::Uuid::new_v4();
+                    item_context.set(&self.item_key, item);
+                    item_context.set(&format!("{}_index", self.item_key), index);
+                    item_context
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Step for ExplodeStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Exploded items are run by the pipeline");
+    }
+}
+
+/// Zips equal-length lists into a list of objects, `inputs` pairs the object key with
+/// the list field it is taken from, e.g. `[("question", "questions"), ("answer", "answers")]`.
+pub struct ZipStep {
+    pub name: String,
+    pub inputs: Vec<(String, String)>,
+    pub output: String,
+}
+
+impl ZipStep {
+    pub fn new(name: String, inputs: Vec<(String, String)>, output: String) -> Self {
+        Self {
+            name,
+            inputs,
+            output,
+        }
+    }
+
+    pub fn zip(&self, context: &StepContext) -> Result<Vec<serde_json::Value>> {
+        let mut lists = Vec::with_capacity(self.inputs.len());
+        for (_, input) in &self.inputs {
+            match context.get(input) {
+                Some(serde_json::Value::Array(items)) => lists.push(items),
+                _ => anyhow::bail!("Input {} is missing or not a list", input),
+            }
+        }
+        let len = lists.first().map(|l| l.len()).unwrap_or_default();
+        if lists.iter().any(|l| l.len() != len) {
+            let lengths = lists.iter().map(|l| l.len()).collect::<Vec<_>>();
+            anyhow::bail!("Lists have different lengths: {:?}", lengths);
+        }
+
+        Ok((0..len)
+            .map(|i| {
+                serde_json::Value::Object(
+                    self.inputs
+                        .iter()
+                        .zip(&lists)
+                        .map(|((key, _), list)| (key.clone(), list[i].clone()))
+                        .collect(),
+                )
+            })
+            .collect())
+    }
+}
+
+impl Step for ZipStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        match self.zip(&context) {
+            Ok(items) => context.set(&self.output, items),
+            Err(e) => {
+                error!(target: "zip_step", "🐔 {}", e);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
 
     #[test]
     fn it_works() {
@@ -551,4 +1719,285 @@ mod tests {
         assert!(!jsonschema::is_valid(&full_schema, &instance));
         println!("hello");
     }
+
+    #[test]
+    fn test_backoff_delay() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for attempt in 1..=4 {
+            let base = 100 * 2u64.pow(attempt as u32 - 1);
+            let delay = super::backoff_delay(100, attempt, &mut rng).as_millis() as u64;
+            assert!(delay >= base / 2 && delay < base * 3 / 2);
+        }
+        assert_eq!(super::backoff_delay(0, 3, &mut rng).as_millis(), 0);
+    }
+
+    #[test]
+    fn test_error_policy() -> Result<()> {
+        assert_eq!(ErrorPolicy::parse("Skip", 3, 100)?, ErrorPolicy::Skip);
+        assert_eq!(
+            ErrorPolicy::parse("retry", 0, 100)?,
+            ErrorPolicy::Retry {
+                max_attempts: 1,
+                backoff_ms: 100
+            }
+        );
+        assert_eq!(
+            ErrorPolicy::parse("quarantine", 3, 100)?,
+            ErrorPolicy::Quarantine
+        );
+        assert!(ErrorPolicy::parse("ignore", 3, 100).is_err());
+
+        let mut context = StepContext::new();
+        context.set_error("first");
+        context.set_error("second");
+        assert_eq!(context.error(), Some("first"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let step = AccumulateStep::new(
+            "ACC".to_string(),
+            2,
+            "batch".to_string(),
+            Some(vec!["index".to_string()]),
+        );
+        let item = |i: usize| {
+            let mut context = StepContext::new();
+            context.set("index", i);
+            context.set("other", "x");
+            context
+        };
+
+        assert!(step.push(&item(0)).is_none());
+        let batch = step.push(&item(1)).unwrap();
+        assert_eq!(batch.data["batch"], json!([{"index": 0}, {"index": 1}]));
+        assert_eq!(batch.data["batch_size"], json!(2));
+        assert!(step.flush().is_none());
+
+        assert!(step.push(&item(2)).is_none());
+        assert_eq!(step.flush().unwrap().data["batch"], json!([{"index": 2}]));
+        assert!(step.flush().is_none());
+    }
+
+    #[test]
+    fn test_zip() {
+        let step = ZipStep::new(
+            "ZIP".to_string(),
+            vec![
+                ("question".to_string(), "questions".to_string()),
+                ("answer".to_string(), "answers".to_string()),
+            ],
+            "pairs".to_string(),
+        );
+        let mut context = StepContext::new();
+        context.set("questions", json!(["q1", "q2"]));
+        context.set("answers", json!(["a1", "a2"]));
+        assert_eq!(
+            step.zip(&context).unwrap(),
+            vec![
+                json!({"question": "q1", "answer": "a1"}),
+                json!({"question": "q2", "answer": "a2"})
+            ]
+        );
+
+        context.set("answers", json!(["a1"]));
+        assert!(step.zip(&context).is_err());
+        context.set("answers", json!("a1"));
+        assert!(step.zip(&context).is_err());
+    }
+
+    #[test]
+    fn test_explode() {
+        let step = ExplodeStep::new(
+            "EXPLODE".to_string(),
+            "chunks".to_string(),
+            "chunk".to_string(),
+        );
+        let mut context = StepContext::new();
+        context.set("chunks", json!(["a", "b"]));
+
+        let items = step.explode(&context).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].data["chunk"], json!("b"));
+        assert_eq!(items[1].data["chunk_index"], json!(1));
+        assert_ne!(items[0].id, items[1].id);
+
+        context.set("chunks", json!([]));
+        assert!(step.explode(&context).unwrap().is_empty());
+        context.set("chunks", json!("a"));
+        assert!(step.explode(&context).is_none());
+    }
+
+    #[test]
+    fn test_persona() {
+        let step = PersonaStep::new(
+            "PERSONA".to_string(),
+            "persona".to_string(),
+            vec!["tone".to_string(), "occupation".to_string()],
+            vec![("occupation".to_string(), vec!["pilot".to_string()])],
+            Some(7),
+        )
+        .unwrap();
+        let persona = step.sample(&mut StdRng::seed_from_u64(7));
+        assert_eq!(persona["occupation"], "pilot");
+        assert!(crate::dictionaries::personas::TONE.contains(persona["tone"].as_str().unwrap()));
+        assert_eq!(persona, step.sample(&mut StdRng::seed_from_u64(7)));
+
+        let all = PersonaStep::new("P".into(), "p".into(), vec![], vec![], None).unwrap();
+        assert_eq!(
+            all.attributes.len(),
+            crate::dictionaries::personas::ATTRIBUTES.len()
+        );
+        assert!(
+            PersonaStep::new("P".into(), "p".into(), vec!["mood".into()], vec![], None).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cache_changes() {
+        let mut before = StepContext::new();
+        before.set("index", 1);
+        before.set("question", "q");
+        let mut after = before.clone();
+        after.set("question", "q2");
+        after.set("answer", "a");
+
+        let changes = CacheStep::changes(&before, &after);
+        assert_eq!(changes, json!({"question": "q2", "answer": "a"}));
+
+        let mut restored = before.clone();
+        CacheStep::restore(&mut restored, &changes);
+        assert_eq!(restored.data, after.data);
+
+        let inputs = vec!["question".to_string()];
+        let mut other = before.clone();
+        other.set("index", 2);
+        assert_eq!(
+            cache_key("f", &inputs, &before),
+            cache_key("f", &inputs, &other)
+        );
+        assert_ne!(
+            cache_key("f", &inputs, &before),
+            cache_key("f", &inputs, &after)
+        );
+        assert_ne!(cache_key("f", &[], &before), cache_key("f", &[], &other));
+        assert_ne!(
+            cache_key("f", &inputs, &before),
+            cache_key("g", &inputs, &before)
+        );
+    }
+
+    #[test]
+    fn test_weighted_choice() {
+        let options = vec![
+            ("formal".to_string(), 3.0),
+            ("casual".to_string(), 1.0),
+            ("never".to_string(), 0.0),
+        ];
+        let step =
+            WeightedChoiceStep::new("CHOICE".to_string(), "style".to_string(), options, None)
+                .unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = HashMap::new();
+        for _ in 0..1000 {
+            *counts.entry(step.choose(&mut rng)).or_insert(0) += 1;
+        }
+        assert!(!counts.contains_key("never"));
+        assert!((650..850).contains(&counts["formal"]));
+        assert_eq!(
+            step.choose(&mut StdRng::seed_from_u64(1)),
+            step.choose(&mut StdRng::seed_from_u64(1))
+        );
+
+        let new = |options: Vec<(String, f64)>| {
+            WeightedChoiceStep::new("C".to_string(), "o".to_string(), options, None)
+        };
+        assert!(new(vec![]).is_err());
+        assert!(new(vec![("a".to_string(), 0.0)]).is_err());
+        assert!(new(vec![("a".to_string(), -1.0)]).is_err());
+    }
+
+    #[test]
+    fn test_sample_dataset() -> Result<()> {
+        let rows = (0..20)
+            .map(|i| format!("{{\"i\": {}}}", i))
+            .collect::<Vec<_>>();
+        let mut resources = PipelineResources::new(None);
+        resources.datasets.add(
+            "rows".to_string(),
+            DatasetType::JsonList(crate::datasets::JsonListDataset::new(
+                "rows".to_string(),
+                rows,
+                None,
+            )?),
+        );
+
+        let sample = |size, seed| {
+            sample_dataset(&resources, "rows", size, &mut StdRng::seed_from_u64(seed)).unwrap()
+        };
+        assert_eq!(sample(Some(5), 1).len(), 5);
+        assert_eq!(sample(Some(5), 1), sample(Some(5), 1));
+        assert_ne!(sample(Some(5), 1), sample(Some(5), 2));
+        assert_eq!(sample(Some(50), 1).len(), 20);
+        assert_eq!(sample(None, 1).len(), 20);
+        assert!(
+            sample_dataset(&resources, "missing", None, &mut StdRng::seed_from_u64(1)).is_err()
+        );
+
+        let mixed = crate::datasets::MixedDataset::new(
+            "mixed".to_string(),
+            vec!["rows".to_string(), "rows".to_string()],
+            &resources.datasets.resources,
+        )?;
+        resources
+            .datasets
+            .add("mixed".to_string(), DatasetType::Mixed(mixed));
+        let sample = |size| {
+            sample_dataset(&resources, "mixed", size, &mut StdRng::seed_from_u64(1)).unwrap()
+        };
+        assert_eq!(sample(Some(5)).len(), 5);
+        assert_eq!(sample(None).len(), 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipeline_seed() {
+        let options = (0..100).map(|i| (i.to_string(), 1.0)).collect::<Vec<_>>();
+        let step =
+            WeightedChoiceStep::new("CHOICE".to_string(), "o".to_string(), options, None).unwrap();
+        let choose = |resources: &PipelineResources, index: u64| {
+            let mut context = StepContext::new();
+            context.set("index", index);
+            let mut rng = resources.rng(&step.name, step.seed, &context);
+            step.choose(&mut rng).to_string()
+        };
+
+        let mut resources = PipelineResources::new(None);
+        resources.set_seed(42);
+        let first = (0..10).map(|i| choose(&resources, i)).collect::<Vec<_>>();
+        let second = (0..10).map(|i| choose(&resources, i)).collect::<Vec<_>>();
+        assert_eq!(first, second);
+
+        resources.set_seed(43);
+        let other = (0..10).map(|i| choose(&resources, i)).collect::<Vec<_>>();
+        assert_ne!(first, other);
+        assert_eq!(resources.step_seed("CHOICE", Some(1)), Some(1));
+        assert_ne!(
+            resources.step_seed("A", None),
+            resources.step_seed("B", None)
+        );
+    }
+
+    #[test]
+    fn test_failed_step() {
+        let mut context = StepContext::new();
+        assert_eq!(context.failed_step(), None);
+        context.set_failed_step("JUDGE--2");
+        context.set_failed_step("IF_ELSE--1");
+        assert_eq!(context.failed_step(), Some("JUDGE--2"));
+        // only tracked in memory, not part of the serialized context
+        let value = serde_json::to_value(&context).unwrap();
+        assert!(value.get("failed_step").is_none());
+    }
 }
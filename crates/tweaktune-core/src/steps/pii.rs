@@ -0,0 +1,302 @@
+use crate::{
+    steps::{Step, StepContext, StepStatus},
+    PipelineResources,
+};
+use anyhow::{bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+
+static EMAIL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}")
+        .expect("Failed to compile email regex")
+});
+static IBAN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b")
+        .expect("Failed to compile iban regex")
+});
+static CREDIT_CARD: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").expect("Failed to compile card regex"));
+static SSN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b\d{3}-\d{2}-\d{4}\b").expect("Failed to compile ssn regex"));
+static PESEL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b\d{11}\b").expect("Failed to compile pesel regex"));
+static IP: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(?:\d{1,3}\.){3}\d{1,3}\b").expect("Failed to compile ip regex"));
+static PHONE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{1,4}\)[ .-]?)?\d{2,4}(?:[ .-]?\d{2,4}){1,4}")
+        .expect("Failed to compile phone regex")
+});
+
+/// Kinds of personal data detected by [`PiiRedactionStep`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PiiEntity {
+    Email,
+    Iban,
+    CreditCard,
+    Ssn,
+    Pesel,
+    Ip,
+    Phone,
+}
+
+impl std::str::FromStr for PiiEntity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "email" => Ok(PiiEntity::Email),
+            "iban" => Ok(PiiEntity::Iban),
+            "credit_card" => Ok(PiiEntity::CreditCard),
+            "ssn" => Ok(PiiEntity::Ssn),
+            "pesel" => Ok(PiiEntity::Pesel),
+            "ip" => Ok(PiiEntity::Ip),
+            "phone" => Ok(PiiEntity::Phone),
+            _ => bail!("🐔 Unsupported PII entity: {}", s),
+        }
+    }
+}
+
+impl PiiEntity {
+    /// Detection order, more specific patterns go first so e.g. card numbers are not
+    /// redacted as phones.
+    pub const ALL: [PiiEntity; 7] = [
+        PiiEntity::Email,
+        PiiEntity::Iban,
+        PiiEntity::CreditCard,
+        PiiEntity::Ssn,
+        PiiEntity::Pesel,
+        PiiEntity::Ip,
+        PiiEntity::Phone,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PiiEntity::Email => "email",
+            PiiEntity::Iban => "iban",
+            PiiEntity::CreditCard => "credit_card",
+            PiiEntity::Ssn => "ssn",
+            PiiEntity::Pesel => "pesel",
+            PiiEntity::Ip => "ip",
+            PiiEntity::Phone => "phone",
+        }
+    }
+
+    fn pattern(&self) -> &'static Regex {
+        match self {
+            PiiEntity::Email => &EMAIL,
+            PiiEntity::Iban => &IBAN,
+            PiiEntity::CreditCard => &CREDIT_CARD,
+            PiiEntity::Ssn => &SSN,
+            PiiEntity::Pesel => &PESEL,
+            PiiEntity::Ip => &IP,
+            PiiEntity::Phone => &PHONE,
+        }
+    }
+
+    /// Checksum and plausibility checks that cut down false positives of the patterns.
+    fn is_valid(&self, candidate: &str) -> bool {
+        let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+        match self {
+            PiiEntity::Iban => iban_checksum(candidate),
+            PiiEntity::CreditCard => luhn_checksum(&digits),
+            PiiEntity::Pesel => pesel_checksum(&digits),
+            PiiEntity::Ip => candidate.split('.').all(|o| o.parse::<u8>().is_ok()),
+            // plain numbers need a country code or at least 9 digits to count as phones
+            PiiEntity::Phone => {
+                (9..=15).contains(&digits.len())
+                    || (candidate.starts_with('+') && digits.len() >= 7)
+            }
+            PiiEntity::Email | PiiEntity::Ssn => true,
+        }
+    }
+}
+
+fn luhn_checksum(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match i % 2 {
+            1 if d * 2 > 9 => d * 2 - 9,
+            1 => d * 2,
+            _ => *d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn iban_checksum(candidate: &str) -> bool {
+    let iban: String = candidate.chars().filter(|c| !c.is_whitespace()).collect();
+    if iban.len() < 15 {
+        return false;
+    }
+    let rearranged = iban[4..].chars().chain(iban[..4].chars());
+    let mut remainder = 0u64;
+    for c in rearranged {
+        let value = match c.to_digit(36) {
+            Some(value) => value as u64,
+            None => return false,
+        };
+        remainder = if value > 9 {
+            (remainder * 100 + value) % 97
+        } else {
+            (remainder * 10 + value) % 97
+        };
+    }
+    remainder == 1
+}
+
+fn pesel_checksum(digits: &[u32]) -> bool {
+    const WEIGHTS: [u32; 10] = [1, 3, 7, 9, 1, 3, 7, 9, 1, 3];
+    if digits.len() != 11 {
+        return false;
+    }
+    let sum: u32 = WEIGHTS.iter().zip(digits).map(|(w, d)| w * d).sum();
+    (10 - sum % 10) % 10 == digits[10]
+}
+
+/// Replaces personal data (emails, phones, IBANs, card numbers, national IDs, IPs) in
+/// the `input` field with replacement tokens such as `[EMAIL]`.
+pub struct PiiRedactionStep {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub entities: Vec<PiiEntity>,
+    pub replacements: HashMap<String, String>,
+}
+
+impl PiiRedactionStep {
+    /// Empty `entities` enables all of them, `national_id` stands for `ssn` and `pesel`.
+    pub fn new(
+        name: String,
+        input: String,
+        output: String,
+        entities: Vec<String>,
+        replacements: HashMap<String, String>,
+    ) -> Result<Self> {
+        let mut selected = Vec::new();
+        for entity in &entities {
+            if entity == "national_id" {
+                selected.extend([PiiEntity::Ssn, PiiEntity::Pesel]);
+            } else {
+                selected.push(entity.parse::<PiiEntity>()?);
+            }
+        }
+        let entities = PiiEntity::ALL
+            .into_iter()
+            .filter(|e| selected.is_empty() || selected.contains(e))
+            .collect();
+
+        Ok(Self {
+            name,
+            input,
+            output,
+            entities,
+            replacements,
+        })
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for entity in &self.entities {
+            let token = self
+                .replacements
+                .get(entity.name())
+                .cloned()
+                .unwrap_or_else(|| format!("[{}]", entity.name().to_uppercase()));
+            text = entity
+                .pattern()
+                .replace_all(&text, |caps: &regex::Captures| {
+                    if entity.is_valid(&caps[0]) {
+                        token.clone()
+                    } else {
+                        caps[0].to_string()
+                    }
+                })
+                .to_string();
+        }
+        text
+    }
+}
+
+impl Step for PiiRedactionStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "pii_redaction_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let redacted = self.redact(&text);
+        context.set(&self.output, redacted);
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(entities: &[&str], replacements: &[(&str, &str)]) -> PiiRedactionStep {
+        PiiRedactionStep::new(
+            "PII".to_string(),
+            "text".to_string(),
+            "out".to_string(),
+            entities.iter().map(|e| e.to_string()).collect(),
+            replacements
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_redact_pii() {
+        let text = "Mail jan.kowalski@example.com or call +48 601 234 567. \
+            IBAN GB82 WEST 1234 5698 7654 32, card 4111 1111 1111 1111, \
+            SSN 123-45-6789, PESEL 44051401359, server 192.168.0.12. \
+            Order 2024-01-15 costs 1999 and ships in 3 days.";
+        assert_eq!(
+            step(&[], &[]).redact(text),
+            "Mail [EMAIL] or call [PHONE]. \
+            IBAN [IBAN], card [CREDIT_CARD], \
+            SSN [SSN], PESEL [PESEL], server [IP]. \
+            Order 2024-01-15 costs 1999 and ships in 3 days."
+        );
+
+        // failing checksums are left untouched
+        let text = "card 4111 1111 1111 1112, PESEL 44051401358, IBAN GB82 WEST 1234 5698 7654 33";
+        assert_eq!(
+            step(&["credit_card", "pesel", "iban"], &[]).redact(text),
+            text
+        );
+
+        assert_eq!(
+            step(&["email"], &[("email", "<redacted>")]).redact("a@b.io, 123-45-6789"),
+            "<redacted>, 123-45-6789"
+        );
+        assert_eq!(
+            step(&["national_id"], &[]).entities,
+            vec![PiiEntity::Ssn, PiiEntity::Pesel]
+        );
+        assert!(PiiRedactionStep::new(
+            "PII".to_string(),
+            "text".to_string(),
+            "out".to_string(),
+            vec!["passport".to_string()],
+            HashMap::new()
+        )
+        .is_err());
+    }
+}
@@ -60,6 +60,61 @@ impl PyValidator {
     }
 }
 
+/// Hands every item that reaches it to a Python sink, the `columns` of it or all of its
+/// data. The sink gets `process(record_json)` per item and `finish()` once the run is done.
+pub struct PyWriterStep {
+    pub name: String,
+    pub py_func: PyObject,
+    pub columns: Option<Vec<String>>,
+}
+
+impl PyWriterStep {
+    pub fn new(name: String, py_func: PyObject, columns: Option<Vec<String>>) -> Self {
+        Self {
+            name,
+            py_func,
+            columns,
+        }
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        Python::with_gil(|py| self.py_func.call_method0(py, "finish"))?;
+        Ok(())
+    }
+}
+
+impl Step for PyWriterStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let record = match &self.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|column| Some((column.clone(), context.get(column)?.clone())))
+                .collect::<Option<serde_json::Map<_, _>>>()
+                .map(serde_json::Value::Object),
+            None => Some(context.data.clone()),
+        };
+        let mut context = context.clone();
+        let Some(record) = record else {
+            error!(target: "py_writer_step", "🐔 Columns {:?} missing in context", self.columns);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        };
+
+        let json = serde_json::to_string(&record)?;
+        let result = Python::with_gil(|py| self.py_func.call_method1(py, "process", (json,)));
+        if let Err(e) = result {
+            error!(target: "py_writer_step", "🐔 {:?}", e);
+            context.set_status(StepStatus::Failed);
+        }
+
+        Ok(context)
+    }
+}
+
 impl Step for PyValidator {
     async fn process(
         &self,
@@ -1,11 +1,24 @@
 use crate::{
-    common::dedup::{hash_value, simhash_value},
-    steps::{Step, StepContext, StepStatus},
+    common::{
+        dedup::{hash_value, simhash_value},
+        math::{answer_value, approx_eq, evaluate, final_answer},
+        ResultExt,
+    },
+    steps::{generators::JudgeStep, text::split_sentences, Step, StepContext, StepStatus},
     PipelineResources,
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use lingua::{LanguageDetector, LanguageDetectorBuilder};
 use log::error;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+static CITATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[([^\[\]]+)\]").expect("valid citation regex"));
+static QUOTE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#""([^"]+)"|“([^”]+)”"#).expect("valid quote regex"));
 
 pub struct CheckLanguageStep {
     pub name: String,
@@ -113,6 +126,63 @@ impl Step for CheckHashStep {
     }
 }
 
+pub struct ItemIdStep {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub output: String,
+    pub unique: bool,
+}
+
+impl ItemIdStep {
+    pub fn new(name: String, inputs: Vec<String>, output: String, unique: bool) -> Result<Self> {
+        if inputs.is_empty() {
+            bail!("🐔 Item id step {} needs at least one input", name);
+        }
+        Ok(Self {
+            name,
+            inputs,
+            output,
+            unique,
+        })
+    }
+}
+
+/// Deterministic id of an item: hash of the canonical JSON of the `inputs` fields.
+fn item_key(context: &StepContext, inputs: &[String]) -> Option<String> {
+    let mut fields = Map::new();
+    for input in inputs {
+        fields.insert(input.clone(), context.get(input)?.clone());
+    }
+    Some(hash_value(&Value::Object(fields)))
+}
+
+impl Step for ItemIdStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+
+        let Some(key) = item_key(&context, &self.inputs) else {
+            error!(target: "steps_quality", "🐔 Item id inputs {:?} not found", self.inputs);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        };
+
+        if let Some(state) = resources.state.as_ref() {
+            let seen = state.set_item_key(&context.id.to_string(), &key).await?;
+            if seen && self.unique {
+                error!(target: "steps_quality", "🐔 Item {} was already processed", key);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+
+        context.set(&self.output, key);
+        Ok(context)
+    }
+}
+
 pub struct CheckSimHashStep {
     pub name: String,
     pub input: String,
@@ -170,3 +240,679 @@ impl Step for CheckSimHashStep {
         Ok(context)
     }
 }
+
+/// Character, word and token bounds checked by [`CheckLengthStep`].
+#[derive(Debug, Clone, Default)]
+pub struct LengthBounds {
+    pub min_chars: Option<usize>,
+    pub max_chars: Option<usize>,
+    pub min_words: Option<usize>,
+    pub max_words: Option<usize>,
+    pub min_tokens: Option<usize>,
+    pub max_tokens: Option<usize>,
+}
+
+impl LengthBounds {
+    pub fn counts_tokens(&self) -> bool {
+        self.min_tokens.is_some() || self.max_tokens.is_some()
+    }
+
+    /// Returns a description of the first violated bound.
+    pub fn check(&self, text: &str, tokens: Option<usize>) -> Option<String> {
+        let within =
+            |kind: &str, count: usize, min: Option<usize>, max: Option<usize>| match (min, max) {
+                (Some(min), _) if count < min => Some(format!("{} {} < {}", kind, count, min)),
+                (_, Some(max)) if count > max => Some(format!("{} {} > {}", kind, count, max)),
+                _ => None,
+            };
+        within(
+            "chars",
+            text.chars().count(),
+            self.min_chars,
+            self.max_chars,
+        )
+        .or_else(|| {
+            within(
+                "words",
+                text.split_whitespace().count(),
+                self.min_words,
+                self.max_words,
+            )
+        })
+        .or_else(|| {
+            tokens.and_then(|tokens| within("tokens", tokens, self.min_tokens, self.max_tokens))
+        })
+    }
+}
+
+/// Drops items whose `inputs` fields fall outside the length bounds. Token bounds need
+/// a tokenizer registered with `with_tokenizer`.
+pub struct CheckLengthStep {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub bounds: LengthBounds,
+    pub tokenizer: Option<String>,
+}
+
+impl CheckLengthStep {
+    pub fn new(
+        name: String,
+        inputs: Vec<String>,
+        bounds: LengthBounds,
+        tokenizer: Option<String>,
+    ) -> Result<Self> {
+        if bounds.counts_tokens() && tokenizer.is_none() {
+            bail!("🐔 Token bounds require a tokenizer");
+        }
+        Ok(Self {
+            name,
+            inputs,
+            bounds,
+            tokenizer,
+        })
+    }
+}
+
+impl Step for CheckLengthStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let tokenizer = match &self.tokenizer {
+            Some(name) if self.bounds.counts_tokens() => Some(
+                resources
+                    .tokenizers
+                    .get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Tokenizer not found: {}", name))?,
+            ),
+            _ => None,
+        };
+
+        for input in &self.inputs {
+            let text = match context.get(input).and_then(|v| v.as_str()) {
+                Some(text) => text,
+                None => {
+                    error!(target: "steps_quality", "🐔 Length check input {} is missing or not a string", input);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            };
+            let tokens = match tokenizer {
+                Some(tokenizer) => Some(tokenizer.count(text).map_anyhow_err()?),
+                None => None,
+            };
+            if let Some(violation) = self.bounds.check(text, tokens) {
+                error!(target: "steps_quality", "🐔 Length check failed for {}: {}", input, violation);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        }
+
+        Ok(context)
+    }
+}
+
+/// Heuristic reward of [`RewardsStep`].
+#[derive(Debug, Clone)]
+pub enum Reward {
+    /// Numeric value (or boolean) at a dotted path of the context, e.g. a judge score.
+    Field(String),
+    /// `0` up to `max` characters of the field, `-(len - max) / max` above.
+    LengthPenalty(String, usize),
+    /// `1` when the field matches the pattern, `0` otherwise.
+    Regex(String, Regex),
+}
+
+impl std::str::FromStr for Reward {
+    type Err = anyhow::Error;
+
+    /// Parses `field:<path>`, `length_penalty:<field>:<max>` and `regex:<field>:<pattern>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.splitn(3, ':').collect();
+        match parts.as_slice() {
+            ["field", path] => Ok(Reward::Field(path.to_string())),
+            ["length_penalty", field, max] => {
+                let max = max.parse::<usize>()?;
+                if max == 0 {
+                    bail!("🐔 Length penalty max must be positive: {}", s);
+                }
+                Ok(Reward::LengthPenalty(field.to_string(), max))
+            }
+            ["regex", field, pattern] => Ok(Reward::Regex(field.to_string(), Regex::new(pattern)?)),
+            _ => bail!("🐔 Unsupported reward: {}", s),
+        }
+    }
+}
+
+fn lookup<'a>(context: &'a StepContext, path: &str) -> Option<&'a Value> {
+    let mut keys = path.split('.');
+    let mut value = context.get(keys.next()?)?;
+    for key in keys {
+        value = match value {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+            _ => value.get(key)?,
+        };
+    }
+    Some(value)
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl Reward {
+    /// The reward for the item, `None` when the field is missing or not numeric.
+    pub fn score(&self, context: &StepContext) -> Option<f64> {
+        match self {
+            Reward::Field(path) => match lookup(context, path)? {
+                Value::Number(n) => n.as_f64(),
+                Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+                Value::String(s) => s.trim().parse::<f64>().ok(),
+                _ => None,
+            },
+            Reward::LengthPenalty(field, max) => {
+                let len = text(lookup(context, field)?).chars().count();
+                Some((*max as f64 - len as f64).min(0.0) / *max as f64)
+            }
+            Reward::Regex(field, pattern) => {
+                let matched = pattern.is_match(&text(lookup(context, field)?));
+                Some(if matched { 1.0 } else { 0.0 })
+            }
+        }
+    }
+}
+
+/// Sum of the rewards multiplied by their weights, rewards without a weight are skipped.
+pub fn weighted_total(rewards: &Map<String, Value>, weights: &[(String, f64)]) -> f64 {
+    weights
+        .iter()
+        .filter_map(|(name, weight)| Some(rewards.get(name)?.as_f64()? * weight))
+        .sum()
+}
+
+/// Attaches scalar rewards to the item as an `output` object: heuristic `rewards` and
+/// scores of `judges` (rubric templates), plus a `total` when `weights` are given. Items
+/// missing a reward fail.
+pub struct RewardsStep {
+    pub name: String,
+    pub output: String,
+    pub rewards: Vec<(String, Reward)>,
+    pub judges: Vec<(String, JudgeStep)>,
+    pub weights: Vec<(String, f64)>,
+}
+
+impl RewardsStep {
+    pub fn new(
+        name: String,
+        output: String,
+        rewards: Vec<(String, Reward)>,
+        judges: Vec<(String, JudgeStep)>,
+        weights: Vec<(String, f64)>,
+    ) -> Self {
+        Self {
+            name,
+            output,
+            rewards,
+            judges,
+            weights,
+        }
+    }
+}
+
+impl Step for RewardsStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let mut rewards = Map::new();
+
+        for (name, reward) in &self.rewards {
+            match reward.score(&context) {
+                Some(score) => {
+                    rewards.insert(name.clone(), score.into());
+                }
+                None => {
+                    error!(target: "rewards_step", "🐔 Failed to compute reward {}", name);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+        for (name, judge) in &self.judges {
+            match judge.score(resources, &context).await? {
+                Some((score, _)) => {
+                    rewards.insert(name.clone(), score.into());
+                }
+                None => {
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+        if !self.weights.is_empty() {
+            let total = weighted_total(&rewards, &self.weights);
+            rewards.insert("total".to_string(), total.into());
+        }
+
+        context.set(&self.output, rewards);
+        Ok(context)
+    }
+}
+
+fn content_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= 3 || w.chars().any(|c| c.is_ascii_digit()))
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Share of the claims of `answer` backed by the `sources` (`(id, text)` pairs) and the
+/// claims that are not. A claim is a quoted span, which has to appear verbatim, or a
+/// sentence, at least `min_overlap` of whose words have to appear in the sources.
+/// Sentences citing (`[id]`) a source that was not provided are not grounded.
+pub fn grounding(
+    answer: &str,
+    sources: &[(Option<String>, String)],
+    min_overlap: f64,
+) -> (f64, Vec<String>) {
+    let ids = sources
+        .iter()
+        .filter_map(|(id, _)| id.as_deref())
+        .collect::<HashSet<_>>();
+    let corpus = normalize(
+        &sources
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+    let vocabulary = content_words(&corpus).into_iter().collect::<HashSet<_>>();
+
+    let (mut claims, mut ungrounded) = (0, Vec::new());
+    for (start, end) in split_sentences(answer) {
+        let sentence = answer[start..end].trim();
+        let cited = CITATION
+            .captures_iter(sentence)
+            .filter_map(|c| c.get(1))
+            .map(|id| id.as_str().trim())
+            .collect::<Vec<_>>();
+        let claim = CITATION.replace_all(sentence, "");
+
+        let quotes = QUOTE
+            .captures_iter(&claim)
+            .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+            .map(|q| q.as_str().to_string())
+            .collect::<Vec<_>>();
+        for span in &quotes {
+            claims += 1;
+            if !corpus.contains(&normalize(span)) {
+                ungrounded.push(span.clone());
+            }
+        }
+
+        let words = content_words(&QUOTE.replace_all(&claim, ""));
+        if words.is_empty() {
+            continue;
+        }
+        claims += 1;
+        let found = words.iter().filter(|w| vocabulary.contains(*w)).count();
+        let unknown_citation = !ids.is_empty() && cited.iter().any(|id| !ids.contains(id));
+        if unknown_citation || (found as f64) < min_overlap * words.len() as f64 {
+            ungrounded.push(sentence.to_string());
+        }
+    }
+
+    let ratio = if claims == 0 {
+        1.0
+    } else {
+        (claims - ungrounded.len()) as f64 / claims as f64
+    };
+    (ratio, ungrounded)
+}
+
+/// Source texts (with ids when present) of a context value: a string, a list of strings
+/// or a list of chunks (`{"id": ..., "text": ...}`, e.g. from grounded generation).
+fn source_texts(value: &Value) -> Vec<(Option<String>, String)> {
+    match value {
+        Value::String(s) => vec![(None, s.clone())],
+        Value::Array(items) => items.iter().flat_map(source_texts).collect(),
+        Value::Object(chunk) => match chunk.get("text") {
+            Some(text) => vec![(chunk.get("id").map(self::text), self::text(text))],
+            None => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+/// Checks that the claims of the `input` answer appear in the `sources` fields, failing
+/// the item when the grounding ratio is below `min_ratio`. The ratio and the ungrounded
+/// claims are written to `output`.
+pub struct CheckGroundingStep {
+    pub name: String,
+    pub input: String,
+    pub sources: Vec<String>,
+    pub min_ratio: f64,
+    pub min_overlap: f64,
+    pub output: Option<String>,
+}
+
+impl CheckGroundingStep {
+    pub fn new(
+        name: String,
+        input: String,
+        sources: Vec<String>,
+        min_ratio: f64,
+        min_overlap: f64,
+        output: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            input,
+            sources,
+            min_ratio,
+            min_overlap,
+            output,
+        }
+    }
+}
+
+impl Step for CheckGroundingStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let answer = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(answer) => answer.to_string(),
+            None => {
+                error!(target: "steps_quality", "🐔 Grounding input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+        let mut sources = Vec::new();
+        for key in &self.sources {
+            match context.get(key) {
+                Some(value) => sources.extend(source_texts(value)),
+                None => {
+                    error!(target: "steps_quality", "🐔 Grounding source {} is missing", key);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        let (ratio, ungrounded) = grounding(&answer, &sources, self.min_overlap);
+        if let Some(output) = &self.output {
+            context.set(output, json!({"ratio": ratio, "ungrounded": ungrounded}));
+        }
+        if ratio < self.min_ratio {
+            error!(target: "steps_quality", "🐔 Grounding ratio {:.2} below {}", ratio, self.min_ratio);
+            context.set_status(StepStatus::Failed);
+        }
+        Ok(context)
+    }
+}
+
+/// Verifies the final answer of a worked solution in `input` against the `expected`
+/// field (a number or a reference solution) or the value of the arithmetic `expression`
+/// field. Wrong or unparsable answers fail the item, the extracted answer and the verdict
+/// are written to `output`.
+pub struct VerifyMathStep {
+    pub name: String,
+    pub input: String,
+    pub expected: Option<String>,
+    pub expression: Option<String>,
+    pub tolerance: f64,
+    pub output: Option<String>,
+}
+
+impl VerifyMathStep {
+    pub fn new(
+        name: String,
+        input: String,
+        expected: Option<String>,
+        expression: Option<String>,
+        tolerance: f64,
+        output: Option<String>,
+    ) -> Result<Self> {
+        if expected.is_some() == expression.is_some() {
+            bail!("🐔 Math verification needs either an expected field or an expression field");
+        }
+        Ok(Self {
+            name,
+            input,
+            expected,
+            expression,
+            tolerance,
+            output,
+        })
+    }
+
+    fn expected_value(&self, context: &StepContext) -> Result<f64> {
+        match (&self.expected, &self.expression) {
+            (Some(field), _) => match context.get(field) {
+                Some(Value::Number(n)) => n.as_f64().ok_or_else(|| anyhow!("🐔 Invalid number")),
+                Some(Value::String(s)) => final_answer(s)
+                    .and_then(|answer| answer_value(&answer))
+                    .ok_or_else(|| anyhow!("🐔 No answer in expected field {}", field)),
+                _ => bail!("🐔 Expected field {} is missing", field),
+            },
+            (None, Some(field)) => match context.get(field).and_then(|v| v.as_str()) {
+                Some(expression) => evaluate(expression),
+                None => bail!("🐔 Expression field {} is missing or not a string", field),
+            },
+            (None, None) => unreachable!("checked in new"),
+        }
+    }
+}
+
+impl Step for VerifyMathStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let answer = context
+            .get(&self.input)
+            .and_then(|v| v.as_str())
+            .and_then(final_answer);
+        let Some(answer) = answer else {
+            error!(target: "steps_quality", "🐔 No final answer in {}", self.input);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        };
+        let expected = match self.expected_value(&context) {
+            Ok(expected) => expected,
+            Err(e) => {
+                error!(target: "steps_quality", "{}", e);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let value = answer_value(&answer);
+        let correct = value.is_some_and(|value| approx_eq(value, expected, self.tolerance));
+        if let Some(output) = &self.output {
+            context.set(
+                output,
+                json!({"answer": answer, "value": value, "expected": expected, "correct": correct}),
+            );
+        }
+        if !correct {
+            error!(target: "steps_quality", "🐔 Answer {} does not match {}", answer, expected);
+            context.set_status(StepStatus::Failed);
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_bounds() {
+        let bounds = LengthBounds {
+            min_chars: Some(3),
+            max_words: Some(4),
+            max_tokens: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(bounds.check("the quick fox", Some(3)), None);
+        assert_eq!(bounds.check("", Some(0)), Some("chars 0 < 3".to_string()));
+        assert_eq!(
+            bounds.check("a a a a a a", Some(6)),
+            Some("words 6 > 4".to_string())
+        );
+        assert_eq!(
+            bounds.check("the quick fox", Some(9)),
+            Some("tokens 9 > 5".to_string())
+        );
+        assert!(CheckLengthStep::new("LEN".to_string(), vec![], bounds, None).is_err());
+    }
+
+    #[test]
+    fn test_item_key() {
+        let inputs = vec!["question".to_string(), "answer".to_string()];
+        let mut a = StepContext::new();
+        a.set("index", 1);
+        a.set("question", "q");
+        a.set("answer", json!({"x": 1, "y": 2}));
+        let mut b = StepContext::new();
+        b.set("answer", json!({"y": 2, "x": 1}));
+        b.set("question", "q");
+        b.set("index", 7);
+
+        let key = item_key(&a, &inputs).unwrap();
+        assert_eq!(key.len(), 64);
+        assert_eq!(item_key(&b, &inputs), Some(key.clone()));
+        b.set("question", "q2");
+        assert_ne!(item_key(&b, &inputs), Some(key));
+        assert_eq!(item_key(&a, &["missing".to_string()]), None);
+        assert!(ItemIdStep::new("ID".to_string(), vec![], "id".to_string(), false).is_err());
+    }
+
+    #[test]
+    fn test_rewards() {
+        let mut context = StepContext::new();
+        context.set(
+            "judge",
+            serde_json::json!({"scores": [4, "3.5"], "ok": true}),
+        );
+        context.set("answer", "x".repeat(150));
+
+        let score = |reward: &str| reward.parse::<Reward>().unwrap().score(&context);
+        assert_eq!(score("field:judge.scores.0"), Some(4.0));
+        assert_eq!(score("field:judge.scores.1"), Some(3.5));
+        assert_eq!(score("field:judge.ok"), Some(1.0));
+        assert_eq!(score("field:judge.missing"), None);
+        assert_eq!(score("length_penalty:answer:100"), Some(-0.5));
+        assert_eq!(score("length_penalty:answer:200"), Some(0.0));
+        assert_eq!(score("regex:answer:^x{3}"), Some(1.0));
+        assert!("length_penalty:answer:0".parse::<Reward>().is_err());
+        assert!("bleu:answer".parse::<Reward>().is_err());
+
+        let rewards = serde_json::json!({"a": 2.0, "b": -1.0, "c": 5.0});
+        let weights = vec![("a".to_string(), 0.5), ("b".to_string(), 2.0)];
+        assert_eq!(weighted_total(rewards.as_object().unwrap(), &weights), -1.0);
+    }
+
+    #[test]
+    fn test_grounding() {
+        let sources = vec![
+            (
+                Some("a".to_string()),
+                "The Eiffel Tower is 330 metres tall.\nIt was completed in 1889.".to_string(),
+            ),
+            (
+                Some("b".to_string()),
+                "Paris is the capital of France.".to_string(),
+            ),
+        ];
+        let (ratio, ungrounded) = grounding(
+            "The Eiffel Tower is 330 metres tall [a]. Paris is the capital of France [b].",
+            &sources,
+            0.8,
+        );
+        assert_eq!(ratio, 1.0);
+        assert!(ungrounded.is_empty());
+
+        let (ratio, ungrounded) = grounding(
+            "It was completed in 1889 [c]. The tower is painted bright green! Paris is the capital of France. \"completed in   1889\"",
+            &sources,
+            0.8,
+        );
+        assert_eq!(ratio, 0.5);
+        assert_eq!(
+            ungrounded,
+            vec![
+                "It was completed in 1889 [c].",
+                "The tower is painted bright green!"
+            ]
+        );
+
+        let (ratio, ungrounded) = grounding("\"built in 1900\". Ok.", &sources, 0.8);
+        assert_eq!(ratio, 0.0);
+        assert_eq!(ungrounded, vec!["built in 1900"]);
+        assert_eq!(grounding("", &sources, 0.8).0, 1.0);
+
+        let chunks = json!([{"id": 1, "text": "x"}, "y", {"other": 1}]);
+        assert_eq!(
+            source_texts(&chunks),
+            vec![
+                (Some("1".to_string()), "x".to_string()),
+                (None, "y".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_math_expected() {
+        let step = |expected: Option<&str>, expression: Option<&str>| {
+            VerifyMathStep::new(
+                "MATH".to_string(),
+                "solution".to_string(),
+                expected.map(String::from),
+                expression.map(String::from),
+                1e-6,
+                None,
+            )
+        };
+        assert!(step(None, None).is_err());
+        assert!(step(Some("a"), Some("b")).is_err());
+
+        let mut context = StepContext::new();
+        context.set("gold", "She sold 48/2 = <<48/2=24>>24 clips.\n#### 72");
+        context.set("number", 72);
+        context.set("equation", "48 + 48 / 2");
+        for (expected, expression) in [
+            (Some("gold"), None),
+            (Some("number"), None),
+            (None, Some("equation")),
+        ] {
+            let step = step(expected, expression).unwrap();
+            assert_eq!(step.expected_value(&context).unwrap(), 72.0);
+        }
+        assert!(step(Some("missing"), None)
+            .unwrap()
+            .expected_value(&context)
+            .is_err());
+    }
+}
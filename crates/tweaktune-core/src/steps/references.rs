@@ -0,0 +1,337 @@
+use crate::steps::generators::{JsonGenerationStep, TextGenerationStep, TranslateBackend};
+use crate::steps::StepType;
+use crate::PipelineResources;
+use std::path::Path;
+
+/// Resources a step uses by name and the local paths it writes to.
+#[derive(Debug, Default)]
+struct StepReferences {
+    llms: Vec<String>,
+    datasets: Vec<String>,
+    embeddings: Vec<String>,
+    tokenizers: Vec<String>,
+    templates: Vec<String>,
+    /// Files written, their directory has to exist.
+    files: Vec<String>,
+    /// Directories created when missing.
+    dirs: Vec<String>,
+}
+
+impl StepReferences {
+    fn template(&mut self, template: &Option<String>) {
+        self.templates.extend(template.iter().cloned());
+    }
+
+    fn generation(&mut self, step: &TextGenerationStep) {
+        self.llms.push(step.llm.clone());
+        self.templates.push(step.template.clone());
+    }
+
+    fn json_generation(&mut self, step: &JsonGenerationStep) {
+        self.generation(&step.generation_step);
+        self.template(&step.schema_key);
+    }
+}
+
+/// Checks that the LLMs, datasets, embeddings, tokenizers and templates the `steps` name
+/// are registered and that the paths they write to are writable, returning every problem.
+pub fn check(steps: &[StepType], resources: &PipelineResources) -> Vec<String> {
+    let mut problems = Vec::new();
+    for step in steps {
+        let refs = references(step);
+        let mut missing = |kind: &str, names: &[String], known: &dyn Fn(&str) -> bool| {
+            for name in names {
+                if !known(name) {
+                    problems.push(format!(
+                        "🐔 Step {} uses {} {} which is not registered",
+                        step.name(),
+                        kind,
+                        name
+                    ));
+                }
+            }
+        };
+        missing("LLM", &refs.llms, &|name| {
+            resources.llms.get(name).is_some()
+        });
+        missing("dataset", &refs.datasets, &|name| {
+            resources.datasets.get(name).is_some() || resources.pending_datasets.contains(name)
+        });
+        missing("embeddings", &refs.embeddings, &|name| {
+            resources.embeddings.get(name).is_some()
+        });
+        missing("tokenizer", &refs.tokenizers, &|name| {
+            resources.tokenizers.get(name).is_some()
+        });
+        missing("template", &refs.templates, &|name| {
+            resources.templates.templates.contains_key(name)
+        });
+        for file in &refs.files {
+            let dir = match Path::new(file).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if let Err(e) = check_writable(dir, false) {
+                problems.push(format!(
+                    "🐔 Step {} can't write {}: {}",
+                    step.name(),
+                    file,
+                    e
+                ));
+            }
+        }
+        for dir in &refs.dirs {
+            if let Err(e) = check_writable(Path::new(dir), true) {
+                problems.push(format!(
+                    "🐔 Step {} can't write to {}: {}",
+                    step.name(),
+                    dir,
+                    e
+                ));
+            }
+        }
+        for nested in nested(step) {
+            problems.extend(check(nested, resources));
+        }
+    }
+    problems
+}
+
+/// Creates and removes a file in `dir`, or in its closest existing parent when it's
+/// `created` on demand.
+fn check_writable(dir: &Path, created: bool) -> Result<(), String> {
+    let mut dir = dir;
+    if created {
+        while !dir.exists() {
+            match dir.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => dir = parent,
+                _ => return Ok(()),
+            }
+        }
+    }
+    if !dir.is_dir() {
+        return Err(format!("directory {} does not exist", dir.display()));
+    }
+    tempfile::NamedTempFile::new_in(dir)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Steps run by `step`, e.g. the branches of an `ifelse`.
+fn nested(step: &StepType) -> Vec<&[StepType]> {
+    match step {
+        StepType::IfElse(step) => {
+            let mut nested = vec![step.then_steps.as_slice()];
+            nested.extend(step.else_steps.as_deref());
+            nested
+        }
+        StepType::Loop(step) => vec![&step.steps],
+        StepType::Retry(step) => vec![&step.steps],
+        StepType::Cache(step) => vec![&step.steps],
+        StepType::ForEach(step) => vec![&step.steps],
+        StepType::Tee(step) => vec![&step.sinks],
+        StepType::Parallel(step) => step.branches.iter().map(Vec::as_slice).collect(),
+        StepType::Switch(step) => {
+            let mut nested = step
+                .cases
+                .iter()
+                .map(|(_, steps)| steps.as_slice())
+                .collect::<Vec<_>>();
+            nested.extend(step.default.as_deref());
+            nested
+        }
+        _ => vec![],
+    }
+}
+
+fn references(step: &StepType) -> StepReferences {
+    let mut refs = StepReferences::default();
+    match step {
+        StepType::IfElse(step) => refs.template(&step.condition_key),
+        StepType::Loop(step) => refs.template(&step.condition_key),
+        StepType::Switch(step) => refs.templates.push(step.key_template.clone()),
+        StepType::TextGeneration(step) => refs.generation(step),
+        StepType::JsonGeneration(step) => refs.json_generation(step),
+        StepType::JudgeConversation(step) => refs.json_generation(&step.json_generation_step),
+        StepType::Judge(step) => refs.generation(&step.generation_step),
+        StepType::PairwiseJudge(step) => refs.generation(&step.generation_step),
+        StepType::SelfConsistency(step) => refs.generation(&step.generation_step),
+        StepType::RepairJson(step) => {
+            refs.generation(&step.generation_step);
+            refs.templates.push(step.schema.clone());
+        }
+        StepType::Translate(step) => {
+            if let TranslateBackend::Llm(generation) = &step.backend {
+                refs.generation(generation);
+            }
+        }
+        StepType::PreferencePair(step) => {
+            refs.templates.push(step.template.clone());
+            for candidate in &step.candidates {
+                refs.generation(candidate);
+            }
+            if let Some(judge) = &step.judge {
+                refs.generation(&judge.generation_step);
+            }
+        }
+        StepType::Rewards(step) => {
+            for (_, judge) in &step.judges {
+                refs.generation(&judge.generation_step);
+            }
+        }
+        StepType::AugmentConversation(step) => {
+            refs.templates.extend(step.system_prompts.iter().cloned());
+            if let Some(paraphrase) = &step.paraphrase {
+                refs.generation(paraphrase);
+            }
+        }
+        StepType::Dialogue(step) => {
+            refs.generation(&step.user);
+            refs.generation(&step.assistant);
+            refs.template(&step.system_template);
+            if let Some(generation) = step
+                .tool_responses
+                .as_ref()
+                .and_then(|tools| tools.generation_step.as_ref())
+            {
+                refs.generation(generation);
+            }
+        }
+        StepType::SimulateToolResponse(step) => {
+            if let Some(generation) = &step.generation_step {
+                refs.generation(generation);
+            }
+        }
+        StepType::GroundedGeneration(step) => {
+            refs.datasets.push(step.dataset.clone());
+            refs.embeddings.push(step.embedding.clone());
+            refs.generation(&step.generation);
+        }
+        StepType::JsonWriter(step) => {
+            refs.template(&step.template);
+            refs.files.extend(step.local_path().map(str::to_string));
+        }
+        StepType::CsvWriter(step) => {
+            refs.templates.extend(step.templates.values().cloned());
+            refs.files.extend(step.local_path().map(str::to_string));
+        }
+        StepType::IpcWriter(step) if step.operator.is_none() => refs.files.push(step.path.clone()),
+        StepType::SqliteWriter(step) => refs.files.push(step.path.clone()),
+        StepType::EmbeddingsWriter(step) => refs.files.push(step.path.clone()),
+        StepType::GroupBy(step) => refs.files.push(step.path.clone()),
+        StepType::Dump(step) => refs.dirs.push(step.dir.clone()),
+        StepType::HttpWriter(step) => refs.template(&step.template),
+        #[cfg(feature = "kafka")]
+        StepType::KafkaWriter(step) => {
+            refs.template(&step.template);
+            refs.template(&step.key_template);
+        }
+        StepType::Print(step) => refs.template(&step.template),
+        StepType::Render(step) => refs.templates.push(step.template.clone()),
+        StepType::RenderToolCall(step) => refs.template(&step.additional_template),
+        StepType::RenderDPO(step) => {
+            refs.templates.extend([
+                step.messages.clone(),
+                step.chosen.clone(),
+                step.rejected.clone(),
+                step.tool_call_template_key.clone(),
+            ]);
+            refs.template(&step.tools);
+        }
+        StepType::RenderGRPO(step) => {
+            refs.templates
+                .extend([step.messages.clone(), step.solution.clone()]);
+            refs.template(&step.tools);
+        }
+        StepType::Filter(step) => refs.templates.push(step.condition.clone()),
+        StepType::Mutate(step) => refs.templates.push(step.condition.clone()),
+        StepType::Shell(step) => refs.templates.push(step.command.clone()),
+        StepType::ValidateJson(step) => {
+            refs.templates
+                .extend([step.schema.clone(), step.instance.clone()]);
+        }
+        StepType::ExtractStructured(step) => refs.templates.push(step.schema.clone()),
+        StepType::Chunk(step) => {
+            refs.tokenizers.extend(step.tokenizer.iter().cloned());
+            refs.embeddings.extend(step.embedding.iter().cloned());
+        }
+        StepType::TruncateConversation(step) => {
+            refs.tokenizers.extend(step.tokenizer.iter().cloned())
+        }
+        StepType::CheckLength(step) => refs.tokenizers.extend(step.tokenizer.iter().cloned()),
+        StepType::TokenCount(step) => refs.tokenizers.push(step.tokenizer.clone()),
+        StepType::TruncateTokens(step) => refs.tokenizers.push(step.tokenizer.clone()),
+        StepType::CheckEmbedding(step) => refs.embeddings.push(step.embedding.clone()),
+        StepType::Embed(step) => refs.embeddings.push(step.embedding.clone()),
+        StepType::SimilarityFilter(step) => refs.embeddings.push(step.embedding.clone()),
+        StepType::Retrieve(step) => refs.embeddings.push(step.embedding.clone()),
+        StepType::DataSampler(step) => refs.datasets.push(step.dataset.clone()),
+        StepType::NegativeToolSampler(step) => refs.datasets.push(step.dataset.clone()),
+        _ => {}
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::steps::writers::SqliteWriterStep;
+    use crate::steps::{DataSamplerStep, RenderStep};
+
+    #[test]
+    fn test_check() {
+        let mut resources = PipelineResources::new(None);
+        resources
+            .templates
+            .add("question".to_string(), "{{topic}}?".to_string());
+        let dir = tempfile::tempdir().unwrap();
+        let steps = vec![
+            StepType::Render(RenderStep::new(
+                "R".to_string(),
+                "question".to_string(),
+                "q".to_string(),
+            )),
+            StepType::TextGeneration(TextGenerationStep::new(
+                "G".to_string(),
+                "answer".to_string(),
+                "gpt".to_string(),
+                "a".to_string(),
+                None,
+                None,
+                None,
+            )),
+            StepType::DataSampler(DataSamplerStep::new(
+                "S".to_string(),
+                "topics".to_string(),
+                None,
+                "t".to_string(),
+            )),
+            StepType::SqliteWriter(
+                SqliteWriterStep::new(
+                    "W".to_string(),
+                    dir.path().join("missing/out.db").display().to_string(),
+                    "items".to_string(),
+                    vec![("q".to_string(), "q".to_string())],
+                )
+                .unwrap(),
+            ),
+        ];
+        let problems = check(&steps, &resources);
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].contains("LLM gpt"));
+        assert!(problems[1].contains("template answer"));
+        assert!(problems[2].contains("dataset topics"));
+        assert!(problems[3].contains("does not exist"));
+
+        let steps = vec![StepType::SqliteWriter(
+            SqliteWriterStep::new(
+                "W".to_string(),
+                dir.path().join("out.db").display().to_string(),
+                "items".to_string(),
+                vec![("q".to_string(), "q".to_string())],
+            )
+            .unwrap(),
+        )];
+        assert!(check(&steps, &resources).is_empty());
+    }
+}
@@ -0,0 +1,690 @@
+use crate::{
+    steps::{Step, StepContext, StepStatus},
+    PipelineResources,
+};
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::json;
+use std::{process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+static FENCE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)```[\w+#-]*[ \t]*\n(.*?)```").expect("valid fence regex"));
+/// Whether `unshare -rn` can create the user and network namespaces here, probed once.
+static USER_NAMESPACES: Lazy<bool> = Lazy::new(|| {
+    std::process::Command::new("unshare")
+        .args(["-rn", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+});
+
+/// Runs the rendered `command` template with `sh -c`, feeding the `stdin` field to the
+/// process and writing its stdout to `output`. A non-zero exit code fails the item
+/// unless `fail_on_error` is disabled.
+pub struct ShellStep {
+    pub name: String,
+    pub command: String,
+    pub stdin: Option<String>,
+    pub output: String,
+    pub exit_code_output: Option<String>,
+    pub timeout_secs: u64,
+    pub fail_on_error: bool,
+}
+
+impl ShellStep {
+    pub fn new(
+        name: String,
+        command: String,
+        stdin: Option<String>,
+        output: String,
+        exit_code_output: Option<String>,
+        timeout_secs: u64,
+        fail_on_error: bool,
+    ) -> Self {
+        Self {
+            name,
+            command,
+            stdin,
+            output,
+            exit_code_output,
+            timeout_secs,
+            fail_on_error,
+        }
+    }
+}
+
+/// Returns the stdout and exit code of `sh -c command`. The process is killed when
+/// it runs longer than `timeout`.
+pub async fn run_command(
+    command: &str,
+    stdin: Option<&str>,
+    timeout: Duration,
+) -> Result<(String, i32)> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        let input = input.to_string();
+        // write in the background so a process that does not read stdin cannot block us
+        tokio::spawn(async move {
+            let _ = pipe.write_all(input.as_bytes()).await;
+        });
+    }
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| anyhow!("Command timed out after {:?}", timeout))??;
+
+    if !output.stderr.is_empty() {
+        log::debug!(target: "shell_step", "{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    Ok((
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        output.status.code().unwrap_or(-1),
+    ))
+}
+
+impl Step for ShellStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let command = resources
+            .templates
+            .render(self.command.clone(), context.data.clone())?;
+
+        let stdin = match &self.stdin {
+            Some(key) => match context.get(key) {
+                Some(serde_json::Value::String(s)) => Some(s.clone()),
+                Some(value) => Some(value.to_string()),
+                None => {
+                    error!(target: "shell_step", "🐔 Stdin input {} not found", key);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            },
+            None => None,
+        };
+
+        let (stdout, code) = match run_command(
+            &command,
+            stdin.as_deref(),
+            Duration::from_secs(self.timeout_secs),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                error!(target: "shell_step", "🐔 Command failed to run: {}", e);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        context.set(&self.output, stdout);
+        if let Some(exit_code_output) = &self.exit_code_output {
+            context.set(exit_code_output, code);
+        }
+        if code != 0 && self.fail_on_error {
+            error!(target: "shell_step", "🐔 Command exited with code {}", code);
+            context.set_status(StepStatus::Failed);
+        }
+        Ok(context)
+    }
+}
+
+/// The first fenced code block of `text`, or the whole text when there is none.
+pub fn code_block(text: &str) -> String {
+    match FENCE.captures(text) {
+        Some(captures) => captures[1].to_string(),
+        None => text.trim().to_string(),
+    }
+}
+
+/// File name and command used to run code in `language`.
+pub fn interpreter(language: &str) -> Result<(&'static str, &'static str)> {
+    Ok(match language.to_lowercase().as_str() {
+        "python" | "py" => ("main.py", "python3 -I main.py"),
+        "javascript" | "js" | "node" => ("main.js", "node main.js"),
+        "bash" | "sh" | "shell" => ("main.sh", "bash main.sh"),
+        _ => bail!("🐔 Unsupported language: {}", language),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub timed_out: bool,
+}
+
+/// Limits of a sandboxed run. Without `network` the process runs in its own network
+/// namespace (`unshare -rn`), `memory_mb` caps its virtual memory.
+#[derive(Debug, Clone)]
+pub struct SandboxLimits {
+    pub timeout: Duration,
+    pub memory_mb: Option<u64>,
+    pub network: bool,
+}
+
+impl SandboxLimits {
+    /// Fails when the network has to be cut off but user namespaces are unavailable, so a
+    /// step doesn't fail every item at run time instead.
+    pub fn check(&self) -> Result<()> {
+        if !self.network && !*USER_NAMESPACES {
+            bail!("🐔 Running code without network needs user namespaces (unshare -rn), enable them or set network=True");
+        }
+        Ok(())
+    }
+}
+
+/// Writes the `(name, content)` files to a fresh temporary directory and runs `command`
+/// there with a minimal environment. The directory is removed afterwards.
+pub async fn run_sandboxed(
+    files: &[(&str, &str)],
+    command: &str,
+    stdin: Option<&str>,
+    limits: &SandboxLimits,
+) -> Result<Execution> {
+    let dir = tempfile::tempdir()?;
+    for (name, content) in files {
+        tokio::fs::write(dir.path().join(name), content).await?;
+    }
+
+    let script = match limits.memory_mb {
+        Some(mb) => format!("ulimit -v {}; exec {}", mb * 1024, command),
+        None => format!("exec {}", command),
+    };
+    let mut process = if limits.network {
+        Command::new("sh")
+    } else {
+        let mut unshare = Command::new("unshare");
+        unshare.arg("-rn").arg("sh");
+        unshare
+    };
+    let mut child = process
+        .arg("-c")
+        .arg(script)
+        .current_dir(dir.path())
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("HOME", dir.path())
+        .env("TMPDIR", dir.path())
+        .env("LANG", "C.UTF-8")
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        let input = input.to_string();
+        tokio::spawn(async move {
+            let _ = pipe.write_all(input.as_bytes()).await;
+        });
+    }
+
+    match tokio::time::timeout(limits.timeout, child.wait_with_output()).await {
+        Ok(output) => {
+            let output = output?;
+            Ok(Execution {
+                stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                exit_code: output.status.code().unwrap_or(-1),
+                timed_out: false,
+            })
+        }
+        Err(_) => Ok(Execution {
+            stdout: String::new(),
+            stderr: format!("Timed out after {:?}", limits.timeout),
+            exit_code: -1,
+            timed_out: true,
+        }),
+    }
+}
+
+/// Runs the code in the `input` field (the first fenced block when present) in a
+/// sandbox and writes its stdout, stderr, exit code and timeout flag to `output`.
+/// Code that exits with an error or times out fails the item unless `fail_on_error`
+/// is disabled. `command` overrides the interpreter of `language`, e.g. to run tests.
+pub struct ExecuteCodeStep {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub file_name: String,
+    pub command: String,
+    pub stdin: Option<String>,
+    pub limits: SandboxLimits,
+    pub fail_on_error: bool,
+}
+
+impl ExecuteCodeStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        input: String,
+        output: String,
+        language: &str,
+        command: Option<String>,
+        stdin: Option<String>,
+        limits: SandboxLimits,
+        fail_on_error: bool,
+    ) -> Result<Self> {
+        let (file_name, default_command) = interpreter(language)?;
+        limits.check()?;
+        Ok(Self {
+            name,
+            input,
+            output,
+            file_name: file_name.to_string(),
+            command: command.unwrap_or_else(|| default_command.to_string()),
+            stdin,
+            limits,
+            fail_on_error,
+        })
+    }
+}
+
+impl Step for ExecuteCodeStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let code = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(code) => code_block(code),
+            None => {
+                error!(target: "shell_step", "🐔 Code input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+        let stdin = match &self.stdin {
+            Some(key) => match context.get(key) {
+                Some(serde_json::Value::String(s)) => Some(s.clone()),
+                Some(value) => Some(value.to_string()),
+                None => {
+                    error!(target: "shell_step", "🐔 Stdin input {} not found", key);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            },
+            None => None,
+        };
+
+        let execution = match run_sandboxed(
+            &[(&self.file_name, &code)],
+            &self.command,
+            stdin.as_deref(),
+            &self.limits,
+        )
+        .await
+        {
+            Ok(execution) => execution,
+            Err(e) => {
+                error!(target: "shell_step", "🐔 Code failed to run: {}", e);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let failed = execution.timed_out || execution.exit_code != 0;
+        if failed && self.fail_on_error {
+            error!(target: "shell_step", "🐔 Code exited with code {}: {}", execution.exit_code, execution.stderr.trim());
+            context.set_status(StepStatus::Failed);
+        }
+        context.set(
+            &self.output,
+            json!({
+                "stdout": execution.stdout,
+                "stderr": execution.stderr,
+                "exit_code": execution.exit_code,
+                "timed_out": execution.timed_out,
+            }),
+        );
+        Ok(context)
+    }
+}
+
+const TEST_RESULTS_MARKER: &str = "__TEST_RESULTS__";
+
+/// Runs `main.py` and `test_main.py` in one namespace (so tests may call the code
+/// directly or import it from `main`), then every `test*` function and `TestCase`.
+const PYTHON_TEST_RUNNER: &str = r#"import json, sys, traceback, unittest
+sys.path.insert(0, ".")
+namespace, results = {"__name__": "main"}, {}
+try:
+    for path in ("main.py", "test_main.py"):
+        exec(compile(open(path).read(), path, "exec"), namespace)
+except BaseException:
+    traceback.print_exc()
+    results["<module>"] = False
+else:
+    for name, test in list(namespace.items()):
+        if not name.lower().startswith("test"):
+            continue
+        if isinstance(test, type) and issubclass(test, unittest.TestCase):
+            for case in unittest.defaultTestLoader.loadTestsFromTestCase(test):
+                results[case.id().split(".", 1)[-1]] = case.run().wasSuccessful()
+        elif callable(test) and not isinstance(test, type):
+            try:
+                test()
+                results[name] = True
+            except BaseException:
+                traceback.print_exc()
+                results[name] = False
+print("__TEST_RESULTS__" + json.dumps(results))
+sys.exit(0 if all(results.values()) else 1)
+"#;
+
+/// Splits the per test results printed by the python runner off `stdout`.
+pub fn test_results(stdout: &str) -> (String, Option<serde_json::Value>) {
+    match stdout.rfind(TEST_RESULTS_MARKER) {
+        Some(start) => {
+            let line = stdout[start + TEST_RESULTS_MARKER.len()..].lines().next();
+            let results = line.and_then(|line| serde_json::from_str(line).ok());
+            (stdout[..start].to_string(), results)
+        }
+        None => (stdout.to_string(), None),
+    }
+}
+
+/// Runs the generated `code` against the `tests` field in the sandbox and keeps the
+/// item only when they pass. Python tests may be top level asserts, `test*` functions
+/// or `TestCase`s and get per test results, other languages run the code followed by
+/// the tests and pass on a zero exit code. `command` runs `main.<ext>` and
+/// `test_main.<ext>` with a test runner of choice instead. `output` gets
+/// `{passed, results, stdout, stderr, exit_code, timed_out}`.
+pub struct CodeTestsStep {
+    pub name: String,
+    pub code: String,
+    pub tests: String,
+    pub output: String,
+    pub language: String,
+    pub command: Option<String>,
+    pub limits: SandboxLimits,
+}
+
+impl CodeTestsStep {
+    pub fn new(
+        name: String,
+        code: String,
+        tests: String,
+        output: String,
+        language: String,
+        command: Option<String>,
+        limits: SandboxLimits,
+    ) -> Result<Self> {
+        interpreter(&language)?;
+        limits.check()?;
+        Ok(Self {
+            name,
+            code,
+            tests,
+            output,
+            language,
+            command,
+            limits,
+        })
+    }
+
+    fn is_python(&self) -> bool {
+        interpreter(&self.language).is_ok_and(|(file_name, _)| file_name == "main.py")
+    }
+
+    async fn run(&self, code: &str, tests: &str) -> Result<Execution> {
+        let (file_name, default_command) = interpreter(&self.language)?;
+        let test_file_name = format!("test_{}", file_name);
+        match &self.command {
+            Some(command) => {
+                let files = [(file_name, code), (test_file_name.as_str(), tests)];
+                run_sandboxed(&files, command, None, &self.limits).await
+            }
+            None if self.is_python() => {
+                let files = [
+                    (file_name, code),
+                    (test_file_name.as_str(), tests),
+                    ("run_tests.py", PYTHON_TEST_RUNNER),
+                ];
+                run_sandboxed(&files, "python3 -I run_tests.py", None, &self.limits).await
+            }
+            None => {
+                let program = format!("{}\n\n{}\n", code, tests);
+                run_sandboxed(
+                    &[(file_name, &program)],
+                    default_command,
+                    None,
+                    &self.limits,
+                )
+                .await
+            }
+        }
+    }
+}
+
+impl Step for CodeTestsStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let mut sources = Vec::with_capacity(2);
+        for key in [&self.code, &self.tests] {
+            match context.get(key).and_then(|v| v.as_str()) {
+                Some(text) => sources.push(code_block(text)),
+                None => {
+                    error!(target: "shell_step", "🐔 Code input {} is missing or not a string", key);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            }
+        }
+
+        let execution = match self.run(&sources[0], &sources[1]).await {
+            Ok(execution) => execution,
+            Err(e) => {
+                error!(target: "shell_step", "🐔 Tests failed to run: {}", e);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let (stdout, results) = test_results(&execution.stdout);
+        let passed = !execution.timed_out && execution.exit_code == 0;
+        if !passed {
+            error!(target: "shell_step", "🐔 Tests failed with code {}: {}", execution.exit_code, execution.stderr.trim());
+            context.set_status(StepStatus::Failed);
+        }
+        context.set(
+            &self.output,
+            json!({
+                "passed": passed,
+                "results": results,
+                "stdout": stdout,
+                "stderr": execution.stderr,
+                "exit_code": execution.exit_code,
+                "timed_out": execution.timed_out,
+            }),
+        );
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let timeout = Duration::from_secs(5);
+
+        let (stdout, code) = run_command("tr a-z A-Z", Some("hello"), timeout)
+            .await
+            .unwrap();
+        assert_eq!((stdout.as_str(), code), ("HELLO", 0));
+
+        let (stdout, code) = run_command("echo out; exit 3", None, timeout)
+            .await
+            .unwrap();
+        assert_eq!((stdout.as_str(), code), ("out\n", 3));
+
+        assert!(run_command("sleep 5", None, Duration::from_millis(100))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_sandboxed() {
+        let limits = SandboxLimits {
+            timeout: Duration::from_secs(5),
+            memory_mb: None,
+            network: true,
+        };
+        let code = code_block(
+            "Here you go:\n```bash\nread name\necho \"hi $name\" > out.txt\ncat out.txt; pwd\n```",
+        );
+        let execution = run_sandboxed(
+            &[("main.sh", &code)],
+            "bash main.sh",
+            Some("Ann\n"),
+            &limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(execution.exit_code, 0);
+        let mut lines = execution.stdout.lines();
+        assert_eq!(lines.next(), Some("hi Ann"));
+        let dir = lines.next().unwrap();
+        assert_ne!(dir, std::env::current_dir().unwrap().to_str().unwrap());
+        assert!(!std::path::Path::new(dir).exists());
+
+        let execution = run_sandboxed(
+            &[("main.sh", "echo oops >&2; exit 2")],
+            "bash main.sh",
+            None,
+            &limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (execution.exit_code, execution.stderr.as_str()),
+            (2, "oops\n")
+        );
+
+        let limits = SandboxLimits {
+            timeout: Duration::from_millis(200),
+            ..limits
+        };
+        let execution = run_sandboxed(&[("main.sh", "sleep 5")], "bash main.sh", None, &limits)
+            .await
+            .unwrap();
+        assert!(execution.timed_out);
+
+        assert_eq!(code_block("print(1)\n"), "print(1)");
+        assert!(interpreter("cobol").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_without_network() {
+        let limits = SandboxLimits {
+            timeout: Duration::from_secs(5),
+            memory_mb: None,
+            network: false,
+        };
+        let step = ExecuteCodeStep::new(
+            "EXEC".to_string(),
+            "code".to_string(),
+            "result".to_string(),
+            "bash",
+            None,
+            None,
+            limits.clone(),
+            true,
+        );
+        if !*USER_NAMESPACES {
+            assert!(step.is_err());
+            return;
+        }
+        let step = step.unwrap();
+        let mut context = StepContext::new();
+        context.set("code", "grep -c : /proc/net/dev");
+        let result = step
+            .process(&PipelineResources::new(None), &context)
+            .await
+            .unwrap();
+        assert!(!matches!(result.get_status(), StepStatus::Failed));
+        // only the loopback interface is left in the namespace
+        assert_eq!(result.get("result").unwrap()["stdout"], json!("1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_python_test_runner() {
+        let step = |language: &str| {
+            CodeTestsStep::new(
+                "TEST".to_string(),
+                "code".to_string(),
+                "tests".to_string(),
+                "result".to_string(),
+                language.to_string(),
+                None,
+                SandboxLimits {
+                    timeout: Duration::from_secs(10),
+                    memory_mb: None,
+                    network: true,
+                },
+            )
+        };
+        assert!(step("cobol").is_err());
+
+        let code = "def add(a, b):\n    return a + b\n";
+        let tests = "import unittest\nfrom main import add\nprint('checking')\nassert add(1, 1) == 2\n\ndef test_add():\n    assert add(2, 3) == 5\n\ndef test_wrong():\n    assert add(2, 2) == 5\n\nclass TestAdd(unittest.TestCase):\n    def test_zero(self):\n        self.assertEqual(add(0, 0), 0)\n";
+        let execution = step("python").unwrap().run(code, tests).await.unwrap();
+        assert_eq!(execution.exit_code, 1);
+        let (stdout, results) = test_results(&execution.stdout);
+        assert_eq!(stdout, "checking\n");
+        assert_eq!(
+            results,
+            Some(json!({"test_add": true, "test_wrong": false, "TestAdd.test_zero": true}))
+        );
+
+        let execution = step("python")
+            .unwrap()
+            .run(code, "assert add(1, 2) == 3")
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, 0);
+        assert_eq!(test_results(&execution.stdout).1, Some(json!({})));
+
+        let execution = step("bash")
+            .unwrap()
+            .run("add() { echo $(($1 + $2)); }", "[ \"$(add 2 3)\" = 5 ]")
+            .await
+            .unwrap();
+        assert_eq!(execution.exit_code, 0);
+    }
+}
@@ -0,0 +1,658 @@
+use crate::{
+    common::ResultExt,
+    steps::{Step, StepContext, StepStatus},
+    tokenizers::TruncateStrategy,
+    PipelineResources,
+};
+use anyhow::{bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// Extracts the first match of `pattern` from the `input` field. Named groups are written
+/// as an object, a single unnamed group as its text, otherwise the whole match.
+pub struct RegexExtractStep {
+    pub name: String,
+    pub input: String,
+    pub pattern: Regex,
+    pub output: String,
+}
+
+impl RegexExtractStep {
+    pub fn new(name: String, input: String, pattern: &str, output: String) -> Result<Self> {
+        Ok(Self {
+            name,
+            input,
+            pattern: Regex::new(pattern)?,
+            output,
+        })
+    }
+
+    pub fn extract(&self, text: &str) -> Option<Value> {
+        let captures = self.pattern.captures(text)?;
+        let names: Vec<&str> = self.pattern.capture_names().flatten().collect();
+        if !names.is_empty() {
+            let mut groups = Map::new();
+            for name in names {
+                let value = captures
+                    .name(name)
+                    .map(|m| Value::String(m.as_str().to_string()))
+                    .unwrap_or(Value::Null);
+                groups.insert(name.to_string(), value);
+            }
+            return Some(Value::Object(groups));
+        }
+
+        let m = if self.pattern.captures_len() > 1 {
+            captures.get(1)?
+        } else {
+            captures.get(0)?
+        };
+        Some(Value::String(m.as_str().to_string()))
+    }
+}
+
+impl Step for RegexExtractStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "regex_extract_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        match self.extract(&text) {
+            Some(value) => context.set(&self.output, value),
+            None => {
+                error!(target: "regex_extract_step", "🐔 Pattern {} not found in {}", self.pattern, self.input);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
+/// Applies regex substitutions (presets first, then custom ones, in order) to the
+/// `input` field and writes the result to `output` (defaults to `input`).
+pub struct RegexReplaceStep {
+    pub name: String,
+    pub input: String,
+    pub output: Option<String>,
+    pub replacements: Vec<(Regex, String)>,
+}
+
+impl RegexReplaceStep {
+    pub fn new(
+        name: String,
+        input: String,
+        output: Option<String>,
+        replacements: Vec<(String, String)>,
+        presets: Vec<String>,
+    ) -> Result<Self> {
+        let mut compiled = Vec::new();
+        for preset in &presets {
+            compiled.extend(replace_preset(preset)?);
+        }
+        for (pattern, replacement) in replacements {
+            compiled.push((Regex::new(&pattern)?, replacement));
+        }
+        Ok(Self {
+            name,
+            input,
+            output,
+            replacements: compiled,
+        })
+    }
+
+    pub fn replace(&self, text: &str) -> String {
+        self.replacements
+            .iter()
+            .fold(text.to_string(), |text, (pattern, replacement)| {
+                pattern
+                    .replace_all(&text, replacement.as_str())
+                    .into_owned()
+            })
+    }
+}
+
+/// Common cleanups:
+/// - `code_fences` drops markdown fence lines (```json ... ```)
+/// - `chat_tokens` drops special tokens like `<|im_end|>`
+/// - `whitespace` collapses runs of spaces and blank lines and trims the text
+pub fn replace_preset(name: &str) -> Result<Vec<(Regex, String)>> {
+    let rules: &[(&str, &str)] = match name {
+        "code_fences" => &[(r"(?m)^[ \t]*```[\w+-]*[ \t]*$\n?", "")],
+        "chat_tokens" => &[(r"<\|[^|<>]+\|>", "")],
+        "whitespace" => &[
+            (r"[ \t]+", " "),
+            (r"[ \t]*\n[ \t]*", "\n"),
+            (r"\n{3,}", "\n\n"),
+            (r"^\s+|\s+$", ""),
+        ],
+        _ => bail!("🐔 Unknown regex replace preset: {}", name),
+    };
+    rules
+        .iter()
+        .map(|(pattern, replacement)| Ok((Regex::new(pattern)?, replacement.to_string())))
+        .collect()
+}
+
+impl Step for RegexReplaceStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "regex_replace_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let output = self.output.as_ref().unwrap_or(&self.input);
+        context.set(output, self.replace(&text));
+        Ok(context)
+    }
+}
+
+/// Writes the number of tokens in the `input` field, as counted by a registered tokenizer.
+pub struct TokenCountStep {
+    pub name: String,
+    pub tokenizer: String,
+    pub input: String,
+    pub output: String,
+}
+
+impl TokenCountStep {
+    pub fn new(name: String, tokenizer: String, input: String, output: String) -> Self {
+        Self {
+            name,
+            tokenizer,
+            input,
+            output,
+        }
+    }
+}
+
+impl Step for TokenCountStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let tokenizer = resources
+            .tokenizers
+            .get(&self.tokenizer)
+            .ok_or_else(|| anyhow::anyhow!("Tokenizer not found: {}", self.tokenizer))?;
+
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "token_count_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let count = tokenizer.count(&text).map_anyhow_err()?;
+        context.set(&self.output, count);
+        Ok(context)
+    }
+}
+
+/// Truncates the `input` field to `max_tokens` tokens of a registered tokenizer, keeping
+/// the head, the tail or both ends (`middle`). Writes to `output` or back to `input`.
+pub struct TruncateTokensStep {
+    pub name: String,
+    pub tokenizer: String,
+    pub input: String,
+    pub output: Option<String>,
+    pub max_tokens: usize,
+    pub strategy: TruncateStrategy,
+    pub marker: String,
+}
+
+impl TruncateTokensStep {
+    pub fn new(
+        name: String,
+        tokenizer: String,
+        input: String,
+        output: Option<String>,
+        max_tokens: usize,
+        strategy: TruncateStrategy,
+        marker: String,
+    ) -> Self {
+        Self {
+            name,
+            tokenizer,
+            input,
+            output,
+            max_tokens,
+            strategy,
+            marker,
+        }
+    }
+}
+
+impl Step for TruncateTokensStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let tokenizer = resources
+            .tokenizers
+            .get(&self.tokenizer)
+            .ok_or_else(|| anyhow::anyhow!("Tokenizer not found: {}", self.tokenizer))?;
+
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "truncate_tokens_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let truncated = tokenizer.truncate(&text, self.max_tokens, &self.strategy, &self.marker)?;
+        context.set(self.output.as_ref().unwrap_or(&self.input), truncated);
+        Ok(context)
+    }
+}
+
+/// Byte ranges of the sentences (or paragraphs) in `text`.
+pub fn split_sentences(text: &str) -> Vec<(usize, usize)> {
+    let boundary = Regex::new(r"[.!?]+\s+|\n\s*\n").expect("valid sentence regex");
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for m in boundary.find_iter(text) {
+        ranges.push((start, m.end()));
+        start = m.end();
+    }
+    ranges.push((start, text.len()));
+    ranges.retain(|(start, end)| !text[*start..*end].trim().is_empty());
+    ranges
+}
+
+/// Groups sentences into chunks, starting a new chunk where the similarity between
+/// neighbouring windows (`similarities[i]` is between sentence `i` and `i + 1`) drops
+/// below `threshold` once the chunk has reached `capacity.0`, or where adding the next
+/// sentence would exceed `capacity.1`.
+pub fn semantic_chunks(
+    text: &str,
+    sentences: &[(usize, usize)],
+    similarities: &[f32],
+    capacity: (usize, usize),
+    threshold: f32,
+    size: impl Fn(&str) -> usize,
+) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut end = 0;
+    for (i, (s, e)) in sentences.iter().enumerate() {
+        if let Some(chunk_start) = start {
+            let current = text[chunk_start..end].trim();
+            let topic_shift = similarities.get(i - 1).is_some_and(|sim| *sim < threshold)
+                && size(current) >= capacity.0;
+            let too_big = size(text[chunk_start..*e].trim()) > capacity.1;
+            if topic_shift || too_big {
+                chunks.push(current.to_string());
+                start = Some(*s);
+            }
+        } else {
+            start = Some(*s);
+        }
+        end = *e;
+    }
+    if let Some(chunk_start) = start {
+        chunks.push(text[chunk_start..end].trim().to_string());
+    }
+    chunks
+}
+
+static HTML_DROP: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?is)<!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<head\b.*?</head\s*>",
+    )
+    .expect("Failed to compile html drop regex")
+});
+static HTML_HEADING: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)<h([1-6])\b[^>]*>").expect("Failed to compile heading regex"));
+static HTML_LIST_ITEM: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)<li\b[^>]*>").expect("Failed to compile list item regex"));
+static HTML_BLOCK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)<br\s*/?>|</?(p|div|section|article|header|footer|ul|ol|table|tr|blockquote|pre|h[1-6])\b[^>]*>")
+        .expect("Failed to compile block regex")
+});
+static HTML_TAG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"<[^>]*>").expect("Failed to compile tag regex"));
+static HTML_ENTITY: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").expect("Failed to compile entity regex")
+});
+
+static MD_FENCE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^[ \t]*(```|~~~).*$\n?").expect("Failed to compile fence regex"));
+static MD_IMAGE_LINK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").expect("Failed to compile link regex"));
+static MD_HEADING: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^[ \t]*#{1,6}[ \t]+").expect("Failed to compile heading regex"));
+static MD_LIST: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^([ \t]*)([-*+]|\d+[.)])[ \t]+").expect("Failed to compile list regex")
+});
+static MD_QUOTE_RULE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^[ \t]*(>[ \t]?)+|^[ \t]*([-*_][ \t]*){3,}$")
+        .expect("Failed to compile quote regex")
+});
+static MD_EMPHASIS: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(\*\*|__)(\S(?:.*?\S)?)(\*\*|__)|\*(\S(?:[^*]*?\S)?)\*|`([^`]+)`|~~(.+?)~~")
+        .expect("Failed to compile emphasis regex")
+});
+
+fn decode_entity(entity: &str) -> Option<String> {
+    if let Some(code) = entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+    {
+        return u32::from_str_radix(code, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .map(String::from);
+    }
+    if let Some(code) = entity.strip_prefix('#') {
+        return code.parse().ok().and_then(char::from_u32).map(String::from);
+    }
+    let decoded = match entity {
+        "amp" => "&",
+        "lt" => "<",
+        "gt" => ">",
+        "quot" => "\"",
+        "apos" => "'",
+        "nbsp" => " ",
+        "ndash" => "–",
+        "mdash" => "—",
+        "hellip" => "…",
+        "lsquo" => "‘",
+        "rsquo" => "’",
+        "ldquo" => "“",
+        "rdquo" => "”",
+        "laquo" => "«",
+        "raquo" => "»",
+        "copy" => "©",
+        "reg" => "®",
+        "trade" => "™",
+        "euro" => "€",
+        _ => return None,
+    };
+    Some(decoded.to_string())
+}
+
+/// Converts HTML to Markdown-flavoured plain text: drops scripts, styles and comments,
+/// turns headings and list items into Markdown markers, strips tags and decodes entities.
+pub fn strip_html(html: &str) -> String {
+    let text = HTML_DROP.replace_all(html, "");
+    let text = HTML_HEADING.replace_all(&text, |caps: &regex::Captures| {
+        let level = caps[1].parse().unwrap_or(1);
+        format!("\n\n{} ", "#".repeat(level))
+    });
+    let text = HTML_LIST_ITEM.replace_all(&text, "\n- ");
+    let text = HTML_BLOCK.replace_all(&text, "\n");
+    let text = HTML_TAG.replace_all(&text, "");
+    HTML_ENTITY
+        .replace_all(&text, |caps: &regex::Captures| {
+            decode_entity(&caps[1]).unwrap_or_else(|| caps[0].to_string())
+        })
+        .to_string()
+}
+
+/// Flattens Markdown to plain text, optionally keeping heading and list markers.
+pub fn flatten_markdown(markdown: &str, keep_headings: bool, keep_lists: bool) -> String {
+    let text = MD_FENCE.replace_all(markdown, "");
+    let text = MD_IMAGE_LINK.replace_all(&text, "$1");
+    let text = MD_QUOTE_RULE.replace_all(&text, "");
+    let text = if keep_headings {
+        text
+    } else {
+        MD_HEADING.replace_all(&text, "").into_owned().into()
+    };
+    let text = if keep_lists {
+        text
+    } else {
+        MD_LIST.replace_all(&text, "$1").into_owned().into()
+    };
+    MD_EMPHASIS
+        .replace_all(&text, |caps: &regex::Captures| {
+            (2..=6)
+                .filter(|i| *i != 3)
+                .find_map(|i| caps.get(i))
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        })
+        .to_string()
+}
+
+/// Cleans scraped text in the `input` field: strips HTML (`html`), flattens Markdown
+/// (`markdown`) and normalizes whitespace. Writes to `output` or back to `input`.
+pub struct CleanupStep {
+    pub name: String,
+    pub input: String,
+    pub output: Option<String>,
+    pub html: bool,
+    pub markdown: bool,
+    pub keep_headings: bool,
+    pub keep_lists: bool,
+    whitespace: Vec<(Regex, String)>,
+}
+
+impl CleanupStep {
+    pub fn new(
+        name: String,
+        input: String,
+        output: Option<String>,
+        html: bool,
+        markdown: bool,
+        keep_headings: bool,
+        keep_lists: bool,
+    ) -> Self {
+        Self {
+            name,
+            input,
+            output,
+            html,
+            markdown,
+            keep_headings,
+            keep_lists,
+            whitespace: replace_preset("whitespace").expect("whitespace preset"),
+        }
+    }
+
+    pub fn clean(&self, text: &str) -> String {
+        let mut text = if self.html {
+            strip_html(text)
+        } else {
+            text.to_string()
+        };
+        if self.markdown {
+            text = flatten_markdown(&text, self.keep_headings, self.keep_lists);
+        }
+        for (pattern, replacement) in &self.whitespace {
+            text = pattern.replace_all(&text, replacement.as_str()).to_string();
+        }
+        text
+    }
+}
+
+impl Step for CleanupStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let text = match context.get(&self.input).and_then(|v| v.as_str()) {
+            Some(text) => text.to_string(),
+            None => {
+                error!(target: "cleanup_step", "🐔 Input {} is missing or not a string", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let cleaned = self.clean(&text);
+        context.set(self.output.as_ref().unwrap_or(&self.input), cleaned);
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_regex_extract() {
+        let step = RegexExtractStep::new(
+            "EXTRACT".to_string(),
+            "text".to_string(),
+            r"(?P<name>\w+) is (?P<age>\d+)",
+            "out".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            step.extract("Alice is 30 years old"),
+            Some(json!({"name": "Alice", "age": "30"}))
+        );
+
+        let step = RegexExtractStep::new(
+            "EXTRACT".to_string(),
+            "text".to_string(),
+            r"answer:\s*(\d+)",
+            "out".to_string(),
+        )
+        .unwrap();
+        assert_eq!(step.extract("The answer: 42"), Some(json!("42")));
+        assert_eq!(step.extract("no answer"), None);
+
+        let step = RegexExtractStep::new(
+            "EXTRACT".to_string(),
+            "text".to_string(),
+            r"\d+",
+            "out".to_string(),
+        )
+        .unwrap();
+        assert_eq!(step.extract("in 2024"), Some(json!("2024")));
+    }
+
+    #[test]
+    fn test_regex_replace() {
+        let step = RegexReplaceStep::new(
+            "REPLACE".to_string(),
+            "text".to_string(),
+            None,
+            vec![(r"(?i)sure, here is.*\n".to_string(), "".to_string())],
+            vec![
+                "chat_tokens".to_string(),
+                "code_fences".to_string(),
+                "whitespace".to_string(),
+            ],
+        )
+        .unwrap();
+        let text = "Sure, here is the JSON:\n```json\n{\"a\":   1}\n```\n\n\n<|im_end|>";
+        assert_eq!(step.replace(text), "{\"a\": 1}");
+
+        assert!(replace_preset("unknown").is_err());
+    }
+
+    #[test]
+    fn test_semantic_chunks() {
+        let text = "Cats purr. Cats sleep a lot.\n\nRust has traits. Rust compiles fast! Done?";
+        let sentences = split_sentences(text);
+        let parts: Vec<&str> = sentences.iter().map(|(s, e)| text[*s..*e].trim()).collect();
+        assert_eq!(
+            parts,
+            vec![
+                "Cats purr.",
+                "Cats sleep a lot.",
+                "Rust has traits.",
+                "Rust compiles fast!",
+                "Done?"
+            ]
+        );
+
+        let similarities = [0.9, 0.2, 0.9, 0.8];
+        let size = |chunk: &str| chunk.chars().count();
+        assert_eq!(
+            semantic_chunks(text, &sentences, &similarities, (0, 100), 0.5, size),
+            vec![
+                "Cats purr. Cats sleep a lot.",
+                "Rust has traits. Rust compiles fast! Done?"
+            ]
+        );
+        // the chunk is too short to split on the topic shift
+        assert_eq!(
+            semantic_chunks(text, &sentences, &similarities, (50, 100), 0.5, size).len(),
+            1
+        );
+        // capacity limits still apply without a topic shift
+        assert_eq!(
+            semantic_chunks(text, &sentences, &[1.0; 4], (0, 40), 0.5, size),
+            vec![
+                "Cats purr. Cats sleep a lot.",
+                "Rust has traits. Rust compiles fast!",
+                "Done?"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let html = r#"<html><head><title>x</title></head><body>
+            <script>alert(1)</script><!-- nav -->
+            <h2>Fish &amp; Chips</h2>
+            <p>Price:&nbsp;5&euro; &mdash; <b>cheap</b> &#39;n&#x27; tasty</p>
+            <ul><li>cod</li><li><a href="/h">haddock</a></li></ul>
+        </body></html>"#;
+
+        let step = CleanupStep::new(
+            "CLEAN".to_string(),
+            "text".to_string(),
+            None,
+            true,
+            true,
+            false,
+            true,
+        );
+        assert_eq!(
+            step.clean(html),
+            "Fish & Chips\n\nPrice: 5€ — cheap 'n' tasty\n\n- cod\n- haddock"
+        );
+
+        let markdown = "# Title\n\nSome **bold**, *italic* and `code` with a [link](http://x).\n\n> quoted\n\n1. first\n2. second\n\n```python\nprint(1)\n```\n---\n![img](a.png)";
+        let step = CleanupStep::new(
+            "CLEAN".to_string(),
+            "text".to_string(),
+            None,
+            false,
+            true,
+            true,
+            false,
+        );
+        assert_eq!(
+            step.clean(markdown),
+            "# Title\n\nSome bold, italic and code with a link.\n\nquoted\n\nfirst\nsecond\n\nprint(1)\n\nimg"
+        );
+    }
+}
@@ -0,0 +1,724 @@
+use crate::{
+    common::{df_to_values, extract_json},
+    steps::{dataset_df, generators::TextGenerationStep, Step, StepContext, StepStatus},
+    PipelineResources,
+};
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+/// How [`SimulateToolResponseStep`] produces tool responses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolResponseMode {
+    /// Random data conforming to the response schema.
+    Fake,
+    /// Plausible data written by an LLM for the call and response schema.
+    Llm,
+    /// A real request to the API under `base_url`.
+    Http,
+}
+
+impl std::str::FromStr for ToolResponseMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fake" => Ok(ToolResponseMode::Fake),
+            "llm" => Ok(ToolResponseMode::Llm),
+            "http" => Ok(ToolResponseMode::Http),
+            _ => bail!("🐔 Unsupported tool response mode: {}", s),
+        }
+    }
+}
+
+/// An OpenAPI operation behind a tool, keyed by the same function name the OpenAPI
+/// dataset produces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolOperation {
+    pub method: String,
+    pub path: String,
+    /// (name, location) pairs, location is `path`, `query` or `header`.
+    pub parameters: Vec<(String, String)>,
+    pub response_schema: Option<Value>,
+}
+
+/// Inlines local `#/components/...` references.
+fn resolve_refs(schema: &Value, spec: &Value, depth: usize) -> Value {
+    if depth > 8 {
+        return json!({});
+    }
+    match schema {
+        Value::Object(obj) => {
+            if let Some(Value::String(reference)) = obj.get("$ref") {
+                let target = reference
+                    .strip_prefix('#')
+                    .and_then(|pointer| spec.pointer(pointer))
+                    .cloned()
+                    .unwrap_or_else(|| json!({}));
+                return resolve_refs(&target, spec, depth + 1);
+            }
+            Value::Object(
+                obj.iter()
+                    .map(|(k, v)| (k.clone(), resolve_refs(v, spec, depth + 1)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|v| resolve_refs(v, spec, depth + 1))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Collects the operations of an OpenAPI spec with their JSON success response schema.
+pub fn openapi_operations(spec: &Value) -> HashMap<String, ToolOperation> {
+    let mut operations = HashMap::new();
+    let Some(paths) = spec["paths"].as_object() else {
+        return operations;
+    };
+    for (path, item) in paths {
+        for method in ["get", "post", "put", "delete", "patch"] {
+            let operation = &item[method];
+            if !operation.is_object() {
+                continue;
+            }
+            let name = match (
+                operation["summary"].as_str(),
+                operation["operationId"].as_str(),
+            ) {
+                (Some(summary), _) => summary.replace(' ', "_").to_lowercase(),
+                (None, Some(operation_id)) => operation_id.to_string(),
+                _ => continue,
+            };
+            let parameters = operation["parameters"]
+                .as_array()
+                .map(|params| {
+                    params
+                        .iter()
+                        .map(|p| resolve_refs(p, spec, 0))
+                        .filter_map(|p| {
+                            Some((
+                                p["name"].as_str()?.to_string(),
+                                p["in"].as_str()?.to_string(),
+                            ))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            let response_schema = ["200", "201", "default"]
+                .iter()
+                .find_map(|code| {
+                    operation["responses"][code]["content"]["application/json"].get("schema")
+                })
+                .map(|schema| resolve_refs(schema, spec, 0));
+
+            operations.insert(
+                name,
+                ToolOperation {
+                    method: method.to_uppercase(),
+                    path: path.clone(),
+                    parameters,
+                    response_schema,
+                },
+            );
+        }
+    }
+    operations
+}
+
+const WORDS: [&str; 12] = [
+    "alpha", "delta", "orbit", "maple", "harbor", "nova", "cedar", "pixel", "summit", "river",
+    "ember", "quartz",
+];
+
+/// Random value conforming to a (resolved) JSON schema, examples and enums are preferred.
+pub fn fake_value(schema: &Value, rng: &mut impl Rng, depth: usize) -> Value {
+    if let Some(example) = schema.get("example") {
+        return example.clone();
+    }
+    if let Some(value) = schema["examples"].as_array().and_then(|e| e.choose(rng)) {
+        return value.clone();
+    }
+    if let Some(value) = schema["enum"].as_array().and_then(|e| e.choose(rng)) {
+        return value.clone();
+    }
+    if let Some(value) = schema.get("const") {
+        return value.clone();
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        if let Some(variant) = schema[key]
+            .as_array()
+            .and_then(|v| v.iter().find(|s| s["type"] != "null"))
+        {
+            return fake_value(variant, rng, depth);
+        }
+    }
+
+    let schema_type = match &schema["type"] {
+        Value::Array(types) => types
+            .iter()
+            .filter_map(|t| t.as_str())
+            .find(|t| *t != "null")
+            .unwrap_or("null"),
+        Value::String(t) => t.as_str(),
+        _ if schema.get("properties").is_some() => "object",
+        _ => "string",
+    };
+    match schema_type {
+        "object" if depth < 6 => Value::Object(
+            schema["properties"]
+                .as_object()
+                .map(|properties| {
+                    properties
+                        .iter()
+                        .map(|(k, v)| (k.clone(), fake_value(v, rng, depth + 1)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        "array" if depth < 6 => {
+            let min = schema["minItems"].as_u64().unwrap_or(1) as usize;
+            let max = schema["maxItems"].as_u64().unwrap_or(3).max(min as u64) as usize;
+            Value::Array(
+                (0..rng.random_range(min..=max))
+                    .map(|_| fake_value(&schema["items"], rng, depth + 1))
+                    .collect(),
+            )
+        }
+        "integer" => {
+            let min = schema["minimum"].as_i64().unwrap_or(1);
+            let max = schema["maximum"]
+                .as_i64()
+                .unwrap_or(min.max(0) + 999)
+                .max(min);
+            json!(rng.random_range(min..=max))
+        }
+        "number" => {
+            let min = schema["minimum"].as_f64().unwrap_or(0.0);
+            let max = schema["maximum"].as_f64().unwrap_or(min + 1000.0).max(min);
+            json!((rng.random_range(min..=max) * 100.0).round() / 100.0)
+        }
+        "boolean" => json!(rng.random_bool(0.5)),
+        "null" | "object" | "array" => Value::Null,
+        _ => {
+            let word = WORDS.choose(rng).unwrap_or(&"alpha");
+            json!(match schema["format"].as_str() {
+                Some("date") => format!(
+                    "2024-{:02}-{:02}",
+                    rng.random_range(1..=12),
+                    rng.random_range(1..=28)
+                ),
+                Some("date-time") => format!(
+                    "2024-{:02}-{:02}T{:02}:{:02}:00Z",
+                    rng.random_range(1..=12),
+                    rng.random_range(1..=28),
+                    rng.random_range(0..24),
+                    rng.random_range(0..60)
+                ),
+                Some("email") => format!("{}@example.com", word),
+                Some("uuid") => uuid::Builder::from_random_bytes(rng.random())
+                    .into_uuid()
+                    .to_string(),
+                Some("uri") | Some("url") => format!("https://example.com/{}", word),
+                _ => format!("{} {}", word, rng.random_range(1..100)),
+            })
+        }
+    }
+}
+
+/// Tool calls of the last assistant message that are not yet followed by tool messages.
+pub fn pending_tool_calls(messages: &[Value]) -> Vec<Value> {
+    let Some(position) = messages
+        .iter()
+        .rposition(|m| m["role"] == "assistant" && m.get("tool_calls").is_some())
+    else {
+        return vec![];
+    };
+    let answered = messages[position + 1..]
+        .iter()
+        .take_while(|m| m["role"] == "tool")
+        .count();
+    if position + 1 + answered < messages.len() {
+        return vec![];
+    }
+    messages[position]["tool_calls"]
+        .as_array()
+        .map(|calls| calls.iter().skip(answered).cloned().collect())
+        .unwrap_or_default()
+}
+
+fn call_arguments(call: &Value) -> Value {
+    match &call["function"]["arguments"] {
+        Value::String(arguments) => serde_json::from_str(arguments).unwrap_or_default(),
+        arguments => arguments.clone(),
+    }
+}
+
+/// Completes function-calling conversations: answers the pending tool calls of the last
+/// assistant message with `tool` messages, faked from the OpenAPI response schema, written
+/// by an LLM or fetched from the real API under `base_url`.
+pub struct SimulateToolResponseStep {
+    pub name: String,
+    pub input: String,
+    pub output: Option<String>,
+    pub mode: ToolResponseMode,
+    pub operations: HashMap<String, ToolOperation>,
+    pub base_url: Option<String>,
+    pub generation_step: Option<TextGenerationStep>,
+    pub seed: Option<u64>,
+    pub timeout_secs: u64,
+}
+
+impl SimulateToolResponseStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        input: String,
+        output: Option<String>,
+        mode: ToolResponseMode,
+        operations: HashMap<String, ToolOperation>,
+        base_url: Option<String>,
+        generation_step: Option<TextGenerationStep>,
+        seed: Option<u64>,
+        timeout_secs: u64,
+    ) -> Result<Self> {
+        match mode {
+            ToolResponseMode::Http if base_url.is_none() || operations.is_empty() => {
+                bail!("🐔 The http mode requires an OpenAPI spec and a base_url")
+            }
+            ToolResponseMode::Llm if generation_step.is_none() => {
+                bail!("🐔 The llm mode requires an llm")
+            }
+            ToolResponseMode::Fake if operations.is_empty() => {
+                bail!("🐔 The fake mode requires an OpenAPI spec")
+            }
+            _ => {}
+        }
+        Ok(Self {
+            name,
+            input,
+            output,
+            mode,
+            operations,
+            base_url,
+            generation_step,
+            seed,
+            timeout_secs,
+        })
+    }
+
+    async fn http_response(&self, operation: &ToolOperation, arguments: &Value) -> Result<String> {
+        let base_url = self.base_url.as_deref().unwrap_or_default();
+        let mut path = operation.path.clone();
+        let mut query = Vec::new();
+        for (name, location) in &operation.parameters {
+            let value = match &arguments[name] {
+                Value::Null => continue,
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            match location.as_str() {
+                "path" => path = path.replace(&format!("{{{}}}", name), &value),
+                "query" => query.push((name.clone(), value)),
+                _ => {}
+            }
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout_secs))
+            .build()?;
+        let mut request = client
+            .request(
+                operation.method.parse()?,
+                format!("{}{}", base_url.trim_end_matches('/'), path),
+            )
+            .query(&query);
+        if let Some(body) = arguments.get("request_body") {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            bail!("{} returned {}: {}", path, status, text);
+        }
+        Ok(text)
+    }
+
+    async fn respond(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+        call: &Value,
+        rng: &mut StdRng,
+    ) -> Result<String> {
+        let tool_name = call["function"]["name"].as_str().unwrap_or_default();
+        let arguments = call_arguments(call);
+        let operation = self.operations.get(tool_name);
+
+        match (&self.mode, operation) {
+            (ToolResponseMode::Http, Some(operation)) => {
+                self.http_response(operation, &arguments).await
+            }
+            (
+                ToolResponseMode::Fake,
+                Some(ToolOperation {
+                    response_schema: Some(schema),
+                    ..
+                }),
+            ) => Ok(fake_value(schema, rng, 0).to_string()),
+            (ToolResponseMode::Llm, _) => {
+                let generation_step = self
+                    .generation_step
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("LLM not set"))?;
+                let schema = operation.and_then(|o| o.response_schema.as_ref());
+                let mut tool_context = context.clone();
+                tool_context.set(
+                    "tool_call",
+                    json!({"name": tool_name, "arguments": serde_json::to_string_pretty(&arguments)?}),
+                );
+                tool_context.set(
+                    "response_schema",
+                    schema.map(serde_json::to_string_pretty).transpose()?,
+                );
+                let text = generation_step
+                    .generate(
+                        &resources.datasets.resources,
+                        &resources.templates,
+                        &resources.llms.resources,
+                        &resources.embeddings.resources,
+                        &tool_context,
+                        None,
+                        generation_step.max_tokens,
+                        generation_step.temperature,
+                    )
+                    .await?
+                    .ok_or_else(|| anyhow!("No response generated"))?;
+                let value = extract_json(&text)?;
+                if let Some(schema) = schema {
+                    if !jsonschema::is_valid(schema, &value) {
+                        bail!("Generated response does not match the schema: {}", value);
+                    }
+                }
+                Ok(value.to_string())
+            }
+            _ => bail!(
+                "No OpenAPI operation with a response schema for tool {}",
+                tool_name
+            ),
+        }
+    }
+
+    /// Appends a `tool` message for every pending tool call of `messages`.
+    pub async fn answer(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+        messages: &mut Vec<Value>,
+        rng: &mut StdRng,
+    ) -> Result<()> {
+        for call in pending_tool_calls(messages) {
+            let content = self.respond(resources, context, &call, rng).await?;
+            let mut message = json!({"role": "tool", "content": content});
+            if let Some(id) = call.get("id") {
+                message["tool_call_id"] = id.clone();
+            }
+            messages.push(message);
+        }
+        Ok(())
+    }
+
+    pub fn rng(&self, resources: &PipelineResources, context: &StepContext) -> StdRng {
+        resources.rng(&self.name, self.seed, context)
+    }
+}
+
+impl Step for SimulateToolResponseStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let mut rng = self.rng(resources, &context);
+
+        let mut conversation = context.get(&self.input).cloned().unwrap_or_default();
+        let messages = match &mut conversation {
+            Value::Array(messages) => messages,
+            Value::Object(obj) => match obj.get_mut("messages") {
+                Some(Value::Array(messages)) => messages,
+                _ => {
+                    error!(target: "simulate_tool_response_step", "🐔 Conversation {} has no messages", self.input);
+                    context.set_status(StepStatus::Failed);
+                    return Ok(context);
+                }
+            },
+            _ => {
+                error!(target: "simulate_tool_response_step", "🐔 Conversation {} is missing or invalid", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        if let Err(e) = self.answer(resources, &context, messages, &mut rng).await {
+            error!(target: "simulate_tool_response_step", "🐔 Failed to simulate tool response: {}", e);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        }
+
+        context.set(self.output.as_ref().unwrap_or(&self.input), conversation);
+        Ok(context)
+    }
+}
+
+/// Name and parameter names of a tool definition or tool call, along with the required
+/// parameters of a definition.
+struct ToolSignature {
+    name: Option<String>,
+    parameters: BTreeSet<String>,
+    required: BTreeSet<String>,
+    is_call: bool,
+}
+
+fn tool_signature(value: &Value) -> ToolSignature {
+    let tool = value.get("function").unwrap_or(value);
+    let keys = |value: &Value| match value {
+        Value::Object(obj) => obj.keys().cloned().collect(),
+        Value::String(text) => serde_json::from_str::<Value>(text)
+            .ok()
+            .and_then(|v| v.as_object().map(|obj| obj.keys().cloned().collect()))
+            .unwrap_or_default(),
+        _ => BTreeSet::new(),
+    };
+    let strings = |value: &Value| match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        Value::String(text) => serde_json::from_str::<Vec<String>>(text)
+            .map(|v| v.into_iter().collect())
+            .unwrap_or_default(),
+        _ => BTreeSet::new(),
+    };
+
+    let is_call = tool.get("arguments").is_some();
+    ToolSignature {
+        name: tool["name"].as_str().map(|s| s.to_string()),
+        parameters: if is_call {
+            keys(&tool["arguments"])
+        } else {
+            keys(&tool["parameters"]["properties"])
+        },
+        required: strings(&tool["parameters"]["required"]),
+        is_call,
+    }
+}
+
+/// Whether the tool definition matches one of the gold tool calls or definitions: by name,
+/// by a call it could serve (arguments within its parameters, covering the required ones)
+/// or by an identical parameter set.
+pub fn matches_gold(tool: &Value, gold: &[Value]) -> bool {
+    let tool = tool_signature(tool);
+    gold.iter().map(tool_signature).any(|gold| {
+        if tool.name.is_some() && tool.name == gold.name {
+            return true;
+        }
+        if gold.parameters.is_empty() {
+            return false;
+        }
+        if gold.is_call {
+            gold.parameters.is_subset(&tool.parameters) && tool.required.is_subset(&gold.parameters)
+        } else {
+            gold.parameters == tool.parameters
+        }
+    })
+}
+
+/// Gold tool calls or definitions of a context value: a single one, a list of them or an
+/// assistant message with `tool_calls`.
+pub fn gold_tools(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(items) => items.iter().flat_map(gold_tools).collect(),
+        Value::Object(obj) => match obj.get("tool_calls") {
+            Some(calls) => gold_tools(calls),
+            None => vec![value.clone()],
+        },
+        Value::String(text) => serde_json::from_str::<Value>(text)
+            .map(|v| gold_tools(&v))
+            .unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+/// Samples `size` tools from `dataset` none of which match the `gold` tool calls, giving hard
+/// negatives (distractors) for function calling. Items with too few candidates fail.
+pub struct NegativeToolSamplerStep {
+    pub name: String,
+    pub dataset: String,
+    pub size: usize,
+    pub gold: String,
+    pub output: String,
+    pub seed: Option<u64>,
+}
+
+impl NegativeToolSamplerStep {
+    pub fn new(
+        name: String,
+        dataset: String,
+        size: usize,
+        gold: String,
+        output: String,
+        seed: Option<u64>,
+    ) -> Self {
+        Self {
+            name,
+            dataset,
+            size,
+            gold,
+            output,
+            seed,
+        }
+    }
+}
+
+impl Step for NegativeToolSamplerStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        let dataset = resources
+            .datasets
+            .get(&self.dataset)
+            .ok_or_else(|| anyhow!("Dataset not found: {}", self.dataset))?;
+        let df = dataset_df(dataset).ok_or_else(|| {
+            anyhow!("🐔 Negative tool sampling is not supported for mixed datasets")
+        })?;
+
+        let gold = context.get(&self.gold).map(gold_tools).unwrap_or_default();
+        if gold.is_empty() {
+            error!(target: "negative_tool_sampler_step", "🐔 No gold tool calls in {}", self.gold);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        }
+
+        let candidates = df_to_values(df)?
+            .into_iter()
+            .filter(|tool| !matches_gold(tool, &gold))
+            .collect::<Vec<_>>();
+        if candidates.len() < self.size {
+            error!(target: "negative_tool_sampler_step", "🐔 Only {} negative tools available, {} requested", candidates.len(), self.size);
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        }
+
+        let mut rng = resources.rng(&self.name, self.seed, &context);
+        let negatives = candidates
+            .choose_multiple(&mut rng, self.size)
+            .cloned()
+            .collect::<Vec<_>>();
+        context.set(&self.output, negatives);
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn spec() -> Value {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../tweaktune-python/tests/openapi.json"
+        );
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_openapi_operations_and_fake_values() {
+        let operations = openapi_operations(&spec());
+        let get_item = &operations["get_item"];
+        assert_eq!(
+            (get_item.method.as_str(), get_item.path.as_str()),
+            ("GET", "/items/{item_id}")
+        );
+        assert_eq!(
+            get_item.parameters,
+            vec![("item_id".to_string(), "path".to_string())]
+        );
+
+        let schema = get_item.response_schema.as_ref().unwrap();
+        assert!(schema.get("$ref").is_none());
+        let mut rng = StdRng::seed_from_u64(7);
+        let value = fake_value(schema, &mut rng, 0);
+        assert!(jsonschema::is_valid(schema, &value), "{value}");
+        assert_eq!(value, fake_value(schema, &mut StdRng::seed_from_u64(7), 0));
+    }
+
+    #[test]
+    fn test_pending_tool_calls() {
+        let call = |name: &str| json!({"function": {"name": name, "arguments": {}}});
+        let mut messages = vec![
+            json!({"role": "user", "content": "u"}),
+            json!({"role": "assistant", "tool_calls": [call("a"), call("b")]}),
+        ];
+        assert_eq!(pending_tool_calls(&messages).len(), 2);
+        messages.push(json!({"role": "tool", "content": "{}"}));
+        assert_eq!(pending_tool_calls(&messages), vec![call("b")]);
+        messages.push(json!({"role": "tool", "content": "{}"}));
+        assert!(pending_tool_calls(&messages).is_empty());
+        messages.push(json!({"role": "assistant", "content": "done"}));
+        assert!(pending_tool_calls(&messages).is_empty());
+    }
+
+    #[test]
+    fn test_matches_gold() {
+        let tool = |name: &str, properties: &[&str], required: &[&str]| {
+            let properties: serde_json::Map<String, Value> = properties
+                .iter()
+                .map(|p| (p.to_string(), json!({"type": "string"})))
+                .collect();
+            json!({"name": name, "parameters": {"type": "object", "properties": properties, "required": required}})
+        };
+        let gold = gold_tools(&json!({"role": "assistant", "tool_calls": [
+            {"function": {"name": "get_weather", "arguments": {"city": "Oslo"}}}
+        ]}));
+        assert_eq!(gold.len(), 1);
+
+        assert!(matches_gold(
+            &tool("get_weather", &["location"], &[]),
+            &gold
+        ));
+        // another tool able to serve the same call
+        assert!(matches_gold(
+            &tool("weather_now", &["city", "units"], &["city"]),
+            &gold
+        ));
+        assert!(!matches_gold(
+            &tool("weather_at", &["city", "date"], &["city", "date"]),
+            &gold
+        ));
+        assert!(!matches_gold(
+            &tool("get_stock", &["ticker"], &["ticker"]),
+            &gold
+        ));
+
+        let definitions = [tool("search", &["query"], &["query"])];
+        assert!(matches_gold(&tool("find", &["query"], &[]), &definitions));
+        assert!(!matches_gold(
+            &tool("find", &["query", "page"], &[]),
+            &definitions
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::common::coerce::{coerce_to_schema, fix_json_syntax};
+use crate::common::extract_json;
 use crate::common::validators::{
     normalize_tool, validate_function_call_conversation, validate_function_call_format,
     validate_tool_format_messages,
@@ -8,6 +10,43 @@ use anyhow::Result;
 use log::error;
 use serde_json::{json, Value};
 
+/// Builds the strict object schema used for validation from a schema with `properties`
+/// (possibly JSON encoded) and `required`.
+pub fn object_schema(full_schema: &Value) -> Result<Value> {
+    let properties = if let Value::String(v) = &full_schema["properties"] {
+        serde_json::from_str(v)?
+    } else {
+        full_schema["properties"].clone()
+    };
+
+    Ok(json!({
+        "type": "object",
+        "properties": properties,
+        "required": full_schema["required"],
+        "additionalProperties": false,
+    }))
+}
+
+/// Extracts JSON from `text` and returns it together with the schema violations, a
+/// parse failure is reported as the only error.
+pub fn validate_json_text(
+    validator: &jsonschema::Validator,
+    text: &str,
+) -> (Option<Value>, Vec<String>) {
+    let value = match extract_json(text) {
+        Ok(value) => value,
+        Err(e) => return (None, vec![e.to_string()]),
+    };
+    let errors = validator
+        .iter_errors(&value)
+        .map(|e| match e.instance_path.to_string() {
+            path if path.is_empty() => e.to_string(),
+            path => format!("{}: {}", path, e),
+        })
+        .collect();
+    (Some(value), errors)
+}
+
 pub struct ValidateJsonStep {
     pub name: String,
     pub schema: String,
@@ -35,20 +74,8 @@ impl Step for ValidateJsonStep {
         let schema = resources
             .templates
             .render(self.schema.clone(), context.data.clone())?;
-        let full_schema: Value = serde_json::from_str(&schema).unwrap();
-
-        let properties = if let Value::String(v) = full_schema["properties"].clone() {
-            serde_json::from_str(&v).unwrap()
-        } else {
-            full_schema["properties"].clone()
-        };
-
-        let schema_value = json!({
-            "type": "object",
-            "properties": properties,
-            "required": full_schema["required"],
-            "additionalProperties": false,
-        });
+        let full_schema: Value = serde_json::from_str(&schema)?;
+        let schema_value = object_schema(&full_schema)?;
 
         let instance_json = resources
             .templates
@@ -82,6 +109,103 @@ impl Step for ValidateJsonStep {
     }
 }
 
+/// Parses the JSON in `input`, fixing syntax slips and coercing near-miss values to
+/// the types of `schema` before validating. The object goes to `output` and the list
+/// of applied coercions to `coercions_output`.
+pub struct ExtractStructuredStep {
+    pub name: String,
+    pub schema: String,
+    pub input: String,
+    pub output: String,
+    pub coercions_output: Option<String>,
+}
+
+impl ExtractStructuredStep {
+    pub fn new(
+        name: String,
+        schema: String,
+        input: String,
+        output: String,
+        coercions_output: Option<String>,
+    ) -> Self {
+        Self {
+            name,
+            schema,
+            input,
+            output,
+            coercions_output,
+        }
+    }
+
+    /// Returns the coerced value and the applied coercions, or the validation errors.
+    /// Text around the JSON (e.g. code fences) is ignored.
+    pub fn extract(
+        &self,
+        schema: &Value,
+        input: &Value,
+    ) -> Result<(Value, Vec<String>), Vec<String>> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| vec![e.to_string()])?;
+        let mut applied = Vec::new();
+        let mut value = match input {
+            Value::String(text) => {
+                let (fixed, fixes) = fix_json_syntax(text);
+                applied.extend(fixes);
+                serde_json::from_str(&fixed).map_err(|e| vec![e.to_string()])?
+            }
+            value => value.clone(),
+        };
+
+        coerce_to_schema(&mut value, schema, "", &mut applied);
+        let errors: Vec<String> = validator
+            .iter_errors(&value)
+            .map(|e| e.to_string())
+            .collect();
+        if errors.is_empty() {
+            Ok((value, applied))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Step for ExtractStructuredStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+
+        let schema = resources
+            .templates
+            .render(self.schema.clone(), context.data.clone())?;
+        let schema_value = object_schema(&serde_json::from_str(&schema)?)?;
+
+        let input = match context.get(&self.input) {
+            Some(input) => input.clone(),
+            None => {
+                error!(target: "extract_structured_step", "🐔 Input {} not found", self.input);
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        match self.extract(&schema_value, &input) {
+            Ok((value, applied)) => {
+                context.set(&self.output, value);
+                if let Some(coercions_output) = &self.coercions_output {
+                    context.set(coercions_output, applied);
+                }
+            }
+            Err(errors) => {
+                error!(target: "extract_structured_step", "🐔 Failed to extract structured output: {}", errors.join("; "));
+                context.set_status(StepStatus::Failed);
+            }
+        }
+        Ok(context)
+    }
+}
+
 pub struct ToolsValidateStep {
     pub name: String,
     pub instances: String,
@@ -225,3 +349,71 @@ impl Step for ConversationValidateStep {
         Ok(context)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_json_text() {
+        let schema = object_schema(&json!({
+            "properties": "{\"age\": {\"type\": \"integer\"}, \"name\": {\"type\": \"string\"}}",
+            "required": ["age", "name"]
+        }))
+        .unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+
+        let (value, errors) = validate_json_text(&validator, r#"{"age": 5, "name": "Ann"}"#);
+        assert_eq!(value, Some(json!({"age": 5, "name": "Ann"})));
+        assert!(errors.is_empty());
+
+        let (value, errors) = validate_json_text(&validator, "```json\n{\"age\": \"5\"}\n```");
+        assert!(value.is_some());
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|e| e.starts_with("/age: ")));
+
+        let (value, errors) = validate_json_text(&validator, "no json here");
+        assert_eq!((value, errors.len()), (None, 1));
+    }
+
+    #[test]
+    fn test_extract_structured() {
+        let step = ExtractStructuredStep::new(
+            "EXTRACT".to_string(),
+            "schema".to_string(),
+            "raw".to_string(),
+            "out".to_string(),
+            None,
+        );
+        let schema = object_schema(&json!({
+            "properties": {"age": {"type": "integer"}, "name": {"type": "string"}},
+            "required": ["age", "name"]
+        }))
+        .unwrap();
+
+        let (value, applied) = step
+            .extract(
+                &schema,
+                &json!("```json\n{'age': '5', 'name': 'Ann',}\n```"),
+            )
+            .unwrap();
+        assert_eq!(value, json!({"age": 5, "name": "Ann"}));
+        assert_eq!(
+            applied,
+            vec![
+                "single_quotes",
+                "trailing_commas",
+                "/age: string -> integer"
+            ]
+        );
+
+        let (_, applied) = step
+            .extract(&schema, &json!({"age": 5, "name": "Ann"}))
+            .unwrap();
+        assert!(applied.is_empty());
+
+        assert!(step
+            .extract(&schema, &json!({"age": "five", "name": "Ann"}))
+            .is_err());
+    }
+}
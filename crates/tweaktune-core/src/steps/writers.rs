@@ -1,43 +1,353 @@
 use crate::{
-    steps::{Step, StepContext, StepStatus},
+    common::coerce::type_name,
+    common::dedup::{hash_value, simhash_value},
+    common::sink::{object_name, partial_path, Compression, LineSink, WriteMode},
+    readers::build_operator,
+    state::State,
+    steps::{backoff_delay, Step, StepContext, StepStatus, StepType},
     PipelineResources,
 };
-use anyhow::Result;
-use log::error;
+use anyhow::{bail, Result};
+use log::{debug, error, info};
+use opendal::Operator;
+use polars::prelude::*;
+use rand::Rng;
+#[cfg(feature = "kafka")]
+use rdkafka::config::ClientConfig;
+#[cfg(feature = "kafka")]
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+#[cfg(feature = "kafka")]
+use rdkafka::util::Timeout;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{PgPool, SqlitePool};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 
+/// Writes a JSON line per item to `path`. With `max_lines` or `max_bytes` the output rolls
+/// over to numbered shards: `out.jsonl` becomes `out-00001.jsonl`, `out-00002.jsonl`, ...
+/// Lines are buffered and flushed at least every `flush_interval`, see [`LineSink`].
+/// With `manifest` the finished output is described in `<path>.manifest.json`.
 pub struct JsonlWriterStep {
     pub name: String,
     pub path: String,
     pub template: Option<String>,
     pub value: Option<String>,
+    pub max_lines: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub manifest: bool,
+    shard: tokio::sync::Mutex<Option<Shard>>,
+    sink: LineSink,
+}
+
+struct Shard {
+    index: usize,
+    lines: usize,
+    bytes: u64,
+}
+
+impl Shard {
+    /// Continues an existing local shard file when appending, otherwise the shard starts
+    /// empty. Sizes of compressed shards are counted before compression.
+    fn open(sink: &LineSink, path: &str, index: usize) -> Result<Self> {
+        if sink.mode != WriteMode::Append || sink.operator.is_some() {
+            return Ok(Self {
+                index,
+                lines: 0,
+                bytes: 0,
+            });
+        }
+        let (lines, bytes) = match sink.compression.read(&shard_path(path, index)) {
+            Ok(content) => (
+                content.iter().filter(|b| **b == b'\n').count(),
+                content.len() as u64,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            index,
+            lines,
+            bytes,
+        })
+    }
+}
+
+/// The operator of a remote destination, `None` writes to the local `path`.
+fn remote_operator(path: &str, op_config: Option<String>) -> Result<Option<Operator>> {
+    op_config
+        .map(|config| build_operator(path, Some(config)))
+        .transpose()
+}
+
+fn flush_interval_from_secs(seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| anyhow::anyhow!("🐔 Invalid flush interval {}", seconds))
+}
+
+/// `out.jsonl` with index 2 is `out-00002.jsonl`, the index goes before all extensions.
+pub fn shard_path(path: &str, index: usize) -> String {
+    suffixed_path(path, &format!("-{:05}", index))
+}
+
+/// `path` with `suffix` added to the file name before its extensions.
+pub fn suffixed_path(path: &str, suffix: &str) -> String {
+    let path = std::path::Path::new(path);
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem, extension) = match file_name.find('.') {
+        Some(dot) if dot > 0 => file_name.split_at(dot),
+        _ => (file_name.as_str(), ""),
+    };
+    path.with_file_name(format!("{}{}{}", stem, suffix, extension))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Points the file writers of `steps`, those nested in other steps included, at the paths `to`
+/// makes of their current ones and returns the current ones, in the order the writers are
+/// visited. Used between runs, e.g. by the runs of a sweep.
+pub fn redirect(steps: &mut [StepType], to: &mut dyn FnMut(&str) -> String) -> Vec<String> {
+    let mut paths = Vec::new();
+    for step in steps {
+        let current = match step {
+            StepType::IfElse(step) => {
+                paths.extend(redirect(&mut step.then_steps, to));
+                if let Some(else_steps) = &mut step.else_steps {
+                    paths.extend(redirect(else_steps, to));
+                }
+                continue;
+            }
+            StepType::ForEach(step) => {
+                paths.extend(redirect(&mut step.steps, to));
+                continue;
+            }
+            StepType::Loop(step) => {
+                paths.extend(redirect(&mut step.steps, to));
+                continue;
+            }
+            StepType::Retry(step) => {
+                paths.extend(redirect(&mut step.steps, to));
+                continue;
+            }
+            StepType::Cache(step) => {
+                paths.extend(redirect(&mut step.steps, to));
+                continue;
+            }
+            StepType::Parallel(step) => {
+                for branch in &mut step.branches {
+                    paths.extend(redirect(branch, to));
+                }
+                continue;
+            }
+            StepType::Switch(step) => {
+                for (_, steps) in &mut step.cases {
+                    paths.extend(redirect(steps, to));
+                }
+                if let Some(default) = &mut step.default {
+                    paths.extend(redirect(default, to));
+                }
+                continue;
+            }
+            StepType::Tee(step) => {
+                paths.extend(redirect(&mut step.sinks, to));
+                continue;
+            }
+            StepType::JsonWriter(step) => {
+                *step.shard.get_mut() = None;
+                &mut step.path
+            }
+            StepType::CsvWriter(step) => &mut step.path,
+            StepType::IpcWriter(step) => &mut step.path,
+            StepType::SqliteWriter(step) => {
+                step.pool = OnceCell::new();
+                &mut step.path
+            }
+            StepType::EmbeddingsWriter(step) => &mut step.path,
+            StepType::GroupBy(step) => &mut step.path,
+            _ => continue,
+        };
+        let path = to(current);
+        paths.push(std::mem::replace(current, path));
+    }
+    paths
 }
 
 impl JsonlWriterStep {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         path: String,
         template: Option<String>,
         value: Option<String>,
-    ) -> Self {
-        Self {
+        max_lines: Option<usize>,
+        max_bytes: Option<u64>,
+        compression: Option<String>,
+        flush_interval: f64,
+        op_config: Option<String>,
+        mode: WriteMode,
+        manifest: bool,
+    ) -> Result<Self> {
+        let compression = Compression::resolve(&path, compression.as_deref())?;
+        let flush_interval = flush_interval_from_secs(flush_interval)?;
+        let operator = remote_operator(&path, op_config)?;
+        Ok(Self {
             name,
             path,
             template,
             value,
+            max_lines,
+            max_bytes,
+            manifest,
+            shard: tokio::sync::Mutex::new(None),
+            sink: LineSink::new(compression, flush_interval, operator).with_mode(mode),
+        })
+    }
+
+    /// File written, `None` for a remote destination.
+    pub fn local_path(&self) -> Option<&str> {
+        self.sink.operator.is_none().then_some(self.path.as_str())
+    }
+
+    fn sharded(&self) -> bool {
+        self.max_lines.is_some() || self.max_bytes.is_some()
+    }
+
+    async fn write_line(&self, line: &str) -> Result<()> {
+        if !self.sharded() {
+            return self.sink.write_line(&self.path, line).await;
+        }
+
+        // the lock is held while writing, so shards are filled one after another
+        let mut shard = self.shard.lock().await;
+        let mut current = match shard.take() {
+            Some(current) => current,
+            None => Shard::open(&self.sink, &self.path, 1)?,
+        };
+        let size = line.len() as u64 + 1;
+        while current.lines > 0
+            && (self.max_lines.is_some_and(|max| current.lines + 1 > max)
+                || self.max_bytes.is_some_and(|max| current.bytes + size > max))
+        {
+            current = Shard::open(&self.sink, &self.path, current.index + 1)?;
+        }
+
+        self.sink
+            .write_line(&shard_path(&self.path, current.index), line)
+            .await?;
+        current.lines += 1;
+        current.bytes += size;
+        *shard = Some(current);
+        Ok(())
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        self.sink.finish().await?;
+        let last = self.shard.lock().await.as_ref().map(|shard| shard.index);
+        if let (WriteMode::Overwrite, Some(last)) = (self.sink.mode, last) {
+            // shards left over from an earlier, longer run
+            let mut index = last + 1;
+            while self.sink.exists(&shard_path(&self.path, index)).await? {
+                self.sink.remove(&shard_path(&self.path, index)).await?;
+                index += 1;
+            }
+        }
+        if self.manifest {
+            self.write_manifest().await?;
+        }
+        Ok(())
+    }
+
+    async fn outputs(&self) -> Result<Vec<String>> {
+        if !self.sharded() {
+            return Ok(vec![self.path.clone()]);
+        }
+        let mut outputs = Vec::new();
+        while self
+            .sink
+            .exists(&shard_path(&self.path, outputs.len() + 1))
+            .await?
+        {
+            outputs.push(shard_path(&self.path, outputs.len() + 1));
+        }
+        Ok(outputs)
+    }
+
+    /// Row counts, the union of the top level fields with their types and hashes of the
+    /// finished files, so consumers can check they got the complete output.
+    async fn write_manifest(&self) -> Result<()> {
+        let mut files = Vec::new();
+        let mut rows = 0;
+        let mut schema: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+        let mut fingerprint = blake3::Hasher::new();
+        for output in self.outputs().await? {
+            let Some(content) = self.sink.read(&output).await? else {
+                continue;
+            };
+            let lines = self.sink.compression.decode(content.as_slice())?;
+            let mut count = 0;
+            for line in lines.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                count += 1;
+                if let Ok(serde_json::Value::Object(fields)) = serde_json::from_slice(line) {
+                    for (field, value) in fields {
+                        schema.entry(field).or_default().insert(type_name(&value));
+                    }
+                }
+            }
+            let hash = blake3::hash(&content).to_hex().to_string();
+            fingerprint.update(hash.as_bytes());
+            rows += count;
+            files.push(serde_json::json!({
+                "path": object_name(&output),
+                "rows": count,
+                "bytes": content.len(),
+                "blake3": hash,
+            }));
+        }
+        if files.is_empty() {
+            return Ok(());
         }
+
+        let manifest = serde_json::json!({
+            "path": object_name(&self.path),
+            "compression": self.sink.compression.name(),
+            "rows": rows,
+            "files": files,
+            "schema": schema,
+            "fingerprint": fingerprint.finalize().to_hex().to_string(),
+            "created_at": std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        });
+        self.sink
+            .write(
+                &manifest_path(&self.path),
+                serde_json::to_vec_pretty(&manifest)?,
+            )
+            .await?;
+        info!(target: "json_writer_step", "✅ Written manifest of {} rows in {} files", rows, files.len());
+        Ok(())
     }
 }
 
+/// `out.jsonl` is described by `out.jsonl.manifest.json`.
+pub fn manifest_path(path: &str) -> String {
+    format!("{}.manifest.json", path)
+}
+
 impl Step for JsonlWriterStep {
     async fn process(
         &self,
         resources: &PipelineResources,
         context: &StepContext,
     ) -> Result<StepContext> {
-        let file = File::options().append(true).create(true).open(&self.path)?;
-        let mut writer = std::io::BufWriter::new(file);
         let row = if let Some(template) = &self.template {
             resources
                 .templates
@@ -66,8 +376,7 @@ impl Step for JsonlWriterStep {
         match row {
             Ok(r) => {
                 let r = r.replace("\\n", "\n").replace('\n', "\\n");
-                writeln!(writer, "{}", r)?;
-                writer.flush()?;
+                self.write_line(&r).await?;
             }
             Err(e) => {
                 error!(target: "json_writer_step", "🐔 Failed to render template: {}", e);
@@ -79,46 +388,1909 @@ impl Step for JsonlWriterStep {
     }
 }
 
+/// How CSV fields are delimited, quoted and escaped.
+#[derive(Debug, Clone)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    pub quoting: csv::QuoteStyle,
+    pub quote: u8,
+    pub escape: Option<u8>,
+}
+
+impl CsvFormat {
+    /// `quoting` is one of `necessary`, `always`, `non_numeric` or `never`. Without an
+    /// `escape` character quotes inside fields are doubled.
+    pub fn new(delimiter: &str, quoting: &str, quote: &str, escape: Option<&str>) -> Result<Self> {
+        let byte = |name: &str, value: &str| match value.as_bytes() {
+            [byte] => Ok(*byte),
+            _ => Err(anyhow::anyhow!(
+                "🐔 CSV {} must be a single ASCII character, got {:?}",
+                name,
+                value
+            )),
+        };
+        let quoting = match quoting.to_lowercase().as_str() {
+            "necessary" => csv::QuoteStyle::Necessary,
+            "always" => csv::QuoteStyle::Always,
+            "non_numeric" => csv::QuoteStyle::NonNumeric,
+            "never" => csv::QuoteStyle::Never,
+            _ => bail!(
+                "🐔 Unknown CSV quoting {}, use necessary, always, non_numeric or never",
+                quoting
+            ),
+        };
+        Ok(Self {
+            delimiter: byte("delimiter", delimiter)?,
+            quoting,
+            quote: byte("quote", quote)?,
+            escape: escape.map(|escape| byte("escape", escape)).transpose()?,
+        })
+    }
+
+    /// One CSV line without the line terminator.
+    pub fn line(&self, fields: &[String]) -> Result<String> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote_style(self.quoting)
+            .quote(self.quote)
+            .double_quote(self.escape.is_none())
+            .escape(self.escape.unwrap_or(b'\\'))
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(Vec::new());
+        writer.write_record(fields)?;
+        let mut line = String::from_utf8(writer.into_inner()?)?;
+        line.pop();
+        if matches!(self.quoting, csv::QuoteStyle::Never) {
+            // unquoted fields can't hold line breaks
+            line = line.replace("\\n", "\n").replace('\n', "\\n");
+        }
+        Ok(line)
+    }
+}
+
+/// Writes the `columns` of every item as a CSV line. A column is rendered with its template
+/// from `templates` when it has one, otherwise taken from the context; strings are written
+/// as they are and other values as JSON.
 pub struct CsvWriterStep {
     pub name: String,
     pub path: String,
     pub columns: Vec<String>,
-    pub delimeter: String,
+    pub format: CsvFormat,
+    pub templates: HashMap<String, String>,
+    sink: LineSink,
 }
 
 impl CsvWriterStep {
-    pub fn new(name: String, path: String, columns: Vec<String>, delimeter: String) -> Self {
-        Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        path: String,
+        columns: Vec<String>,
+        format: CsvFormat,
+        header: bool,
+        templates: HashMap<String, String>,
+        mode: WriteMode,
+        compression: Option<String>,
+        flush_interval: f64,
+        op_config: Option<String>,
+    ) -> Result<Self> {
+        let compression = Compression::resolve(&path, compression.as_deref())?;
+        let flush_interval = flush_interval_from_secs(flush_interval)?;
+        let operator = remote_operator(&path, op_config)?;
+        let header = if header {
+            Some(format.line(&columns)?)
+        } else {
+            None
+        };
+        Ok(Self {
             name,
             path,
             columns,
-            delimeter,
-        }
+            format,
+            templates,
+            sink: LineSink::new(compression, flush_interval, operator)
+                .with_mode(mode)
+                .with_header(header),
+        })
+    }
+
+    /// File written, `None` for a remote destination.
+    pub fn local_path(&self) -> Option<&str> {
+        self.sink.operator.is_none().then_some(self.path.as_str())
+    }
+
+    fn fields(&self, resources: &PipelineResources, context: &StepContext) -> Result<Vec<String>> {
+        self.columns
+            .iter()
+            .map(|column| {
+                if let Some(template) = self.templates.get(column) {
+                    return resources
+                        .templates
+                        .render(template.clone(), context.data.clone());
+                }
+                Ok(match context.get(column) {
+                    Some(serde_json::Value::String(value)) => value.clone(),
+                    Some(serde_json::Value::Null) | None => String::new(),
+                    Some(value) => value.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        self.sink.finish().await
     }
 }
 
 impl Step for CsvWriterStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let mut context = context.clone();
+        match self.fields(resources, &context) {
+            Ok(fields) => {
+                let line = self.format.line(&fields)?;
+                self.sink.write_line(&self.path, &line).await?;
+            }
+            Err(e) => {
+                error!(target: "csv_writer_step", "🐔 Failed to render CSV column: {}", e);
+                context.set_status(StepStatus::Failed);
+            }
+        }
+
+        Ok(context)
+    }
+}
+
+/// Collects rows and writes them as an Arrow IPC (Feather v2) file once the run is finished.
+/// A row holds the `columns` of the context, or all of it without them.
+pub struct IpcWriterStep {
+    pub name: String,
+    pub path: String,
+    pub columns: Option<Vec<String>>,
+    pub operator: Option<Operator>,
+    rows: Mutex<Vec<serde_json::Value>>,
+}
+
+impl IpcWriterStep {
+    pub fn new(
+        name: String,
+        path: String,
+        columns: Option<Vec<String>>,
+        op_config: Option<String>,
+    ) -> Result<Self> {
+        let operator = remote_operator(&path, op_config)?;
+        Ok(Self {
+            name,
+            path,
+            columns,
+            operator,
+            rows: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn row(&self, context: &StepContext) -> Option<serde_json::Value> {
+        match &self.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|column| Some((column.clone(), context.get(column)?.clone())))
+                .collect::<Option<serde_json::Map<_, _>>>()
+                .map(serde_json::Value::Object),
+            None => Some(context.data.clone()),
+        }
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?);
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        // the schema is inferred from all rows, columns missing in some rows are null there
+        let mut df = JsonReader::new(std::io::Cursor::new(serde_json::to_vec(&rows)?))
+            .infer_schema_len(None)
+            .finish()?;
+        if let Some(operator) = &self.operator {
+            let mut buffer = Vec::new();
+            IpcWriter::new(&mut buffer).finish(&mut df)?;
+            operator.write(&object_name(&self.path), buffer).await?;
+        } else {
+            let partial = partial_path(&self.path);
+            let mut file = File::create(&partial)?;
+            IpcWriter::new(&mut file).finish(&mut df)?;
+            file.sync_all()?;
+            std::fs::rename(&partial, &self.path)?;
+        }
+
+        info!(target: "ipc_writer_step", "✅ Written {} rows to {}", rows.len(), self.path);
+        Ok(())
+    }
+}
+
+impl Step for IpcWriterStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let Some(row) = self.row(context) else {
+            error!(target: "ipc_writer_step", "🐔 Columns {:?} missing in context", self.columns);
+            let mut context = context.clone();
+            context.set_status(StepStatus::Failed);
+            return Ok(context);
+        };
+        self.rows
+            .lock()
+            .map_err(|e| anyhow::anyhow!("{e}"))?
+            .push(row);
+        Ok(context.clone())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingsFormat {
+    /// Parquet with `item_id`, `text` and `vector` (list of f32) columns, readable by LanceDB.
+    Parquet,
+    /// Arrow IPC file with the same columns as parquet.
+    Ipc,
+    /// FAISS/texmex `.fvecs` vectors plus a `<path>.ids.jsonl` sidecar with ids and texts.
+    Fvecs,
+}
+
+impl std::str::FromStr for EmbeddingsFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "parquet" => Ok(EmbeddingsFormat::Parquet),
+            "ipc" | "arrow" => Ok(EmbeddingsFormat::Ipc),
+            "fvecs" | "faiss" => Ok(EmbeddingsFormat::Fvecs),
+            _ => bail!("🐔 Unsupported embeddings format: {}", s),
+        }
+    }
+}
+
+/// Collects (item_id, text, vector) rows and writes them once the run is finished.
+pub struct EmbeddingsWriterStep {
+    pub name: String,
+    pub path: String,
+    pub format: EmbeddingsFormat,
+    pub input: String,
+    pub vector: String,
+    rows: Mutex<Vec<(String, String, Vec<f32>)>>,
+}
+
+impl EmbeddingsWriterStep {
+    pub fn new(
+        name: String,
+        path: String,
+        format: EmbeddingsFormat,
+        input: String,
+        vector: String,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            format,
+            input,
+            vector,
+            rows: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?);
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        match self.format {
+            EmbeddingsFormat::Parquet | EmbeddingsFormat::Ipc => {
+                let ids: Vec<&str> = rows.iter().map(|r| r.0.as_str()).collect();
+                let texts: Vec<&str> = rows.iter().map(|r| r.1.as_str()).collect();
+                let vectors: ListChunked = rows
+                    .iter()
+                    .map(|r| Some(Series::new("".into(), &r.2)))
+                    .collect();
+
+                let mut df = DataFrame::new(vec![
+                    Series::new("item_id".into(), ids).into(),
+                    Series::new("text".into(), texts).into(),
+                    vectors.with_name("vector".into()).into_series().into(),
+                ])?;
+
+                let mut file = File::create(&self.path)?;
+                if self.format == EmbeddingsFormat::Parquet {
+                    ParquetWriter::new(&mut file).finish(&mut df)?;
+                } else {
+                    IpcWriter::new(&mut file).finish(&mut df)?;
+                }
+            }
+            EmbeddingsFormat::Fvecs => {
+                let mut vectors = std::io::BufWriter::new(File::create(&self.path)?);
+                let mut ids =
+                    std::io::BufWriter::new(File::create(format!("{}.ids.jsonl", self.path))?);
+                for (item_id, text, vector) in rows.iter() {
+                    vectors.write_all(&(vector.len() as i32).to_le_bytes())?;
+                    for v in vector {
+                        vectors.write_all(&v.to_le_bytes())?;
+                    }
+                    writeln!(
+                        ids,
+                        "{}",
+                        serde_json::json!({"item_id": item_id, "text": text})
+                    )?;
+                }
+                vectors.flush()?;
+                ids.flush()?;
+            }
+        }
+
+        info!(target: "embeddings_writer_step", "✅ Written {} embeddings to {}", rows.len(), self.path);
+        Ok(())
+    }
+}
+
+impl Step for EmbeddingsWriterStep {
     async fn process(
         &self,
         _resources: &PipelineResources,
         context: &StepContext,
     ) -> Result<StepContext> {
-        let file = File::options().append(true).create(true).open(&self.path)?;
-        let mut writer = std::io::BufWriter::new(file);
-        let mut row = String::new();
-        for (i, column) in self.columns.iter().enumerate() {
-            if let Some(value) = context.get(column) {
-                if i > 0 {
-                    row.push_str(&self.delimeter);
+        let text = context.get(&self.input).and_then(|v| v.as_str());
+        let vector: Option<Vec<f32>> = context
+            .get(&self.vector)
+            .and_then(|v| serde_json::from_value(v.clone()).ok());
+
+        let (text, vector) = match (text, vector) {
+            (Some(text), Some(vector)) => (text.to_string(), vector),
+            _ => {
+                error!(target: "embeddings_writer_step", "🐔 Text '{}' or vector '{}' missing in context", self.input, self.vector);
+                let mut context = context.clone();
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?.push((
+            context.id.to_string(),
+            text,
+            vector,
+        ));
+
+        Ok(context.clone())
+    }
+}
+
+/// Aggregate computed for every group of [`GroupByStep`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregation {
+    Count,
+    Concat(String),
+    Sample(String, usize),
+}
+
+impl std::str::FromStr for Aggregation {
+    type Err = anyhow::Error;
+
+    /// Parses `count`, `concat:<field>` and `sample:<field>[:<size>]`.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["count"] => Ok(Aggregation::Count),
+            ["concat", field] => Ok(Aggregation::Concat(field.to_string())),
+            ["sample", field] => Ok(Aggregation::Sample(field.to_string(), 3)),
+            ["sample", field, size] => Ok(Aggregation::Sample(field.to_string(), size.parse()?)),
+            _ => bail!("🐔 Unsupported aggregation: {}", s),
+        }
+    }
+}
+
+struct Group {
+    key: serde_json::Value,
+    values: Vec<Vec<serde_json::Value>>,
+    seen: Vec<usize>,
+    count: usize,
+}
+
+/// Groups items by the `key` field and aggregates them (count, concat or a random sample
+/// of a field) over the whole run. One JSON line per group is written to `path` once the
+/// run is finished. Items pass through unchanged.
+pub struct GroupByStep {
+    pub name: String,
+    pub key: String,
+    pub path: String,
+    pub aggregations: Vec<(String, Aggregation)>,
+    groups: Mutex<std::collections::BTreeMap<String, Group>>,
+}
+
+impl GroupByStep {
+    pub fn new(
+        name: String,
+        key: String,
+        path: String,
+        aggregations: Vec<(String, Aggregation)>,
+    ) -> Self {
+        Self {
+            name,
+            key,
+            path,
+            aggregations,
+            groups: Mutex::new(std::collections::BTreeMap::new()),
+        }
+    }
+
+    pub fn add(&self, context: &StepContext) -> Result<()> {
+        let key = context.get(&self.key).cloned().unwrap_or_default();
+        let mut groups = self.groups.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+        let group = groups.entry(key.to_string()).or_insert_with(|| Group {
+            key,
+            values: vec![Vec::new(); self.aggregations.len()],
+            seen: vec![0; self.aggregations.len()],
+            count: 0,
+        });
+        group.count += 1;
+
+        for (i, (_, aggregation)) in self.aggregations.iter().enumerate() {
+            let (field, size) = match aggregation {
+                Aggregation::Count => continue,
+                Aggregation::Concat(field) => (field, None),
+                Aggregation::Sample(field, size) => (field, Some(*size)),
+            };
+            let Some(value) = context.get(field).cloned() else {
+                continue;
+            };
+            group.seen[i] += 1;
+            match size {
+                // reservoir sampling keeps every item equally likely
+                Some(size) if group.values[i].len() >= size => {
+                    let j = rand::rng().random_range(0..group.seen[i]);
+                    if j < size {
+                        group.values[i][j] = value;
+                    }
                 }
-                row.push_str(&value.to_string());
+                _ => group.values[i].push(value),
             }
         }
+        Ok(())
+    }
+
+    /// Aggregated rows, one per group ordered by key.
+    pub fn rows(&self) -> Result<Vec<serde_json::Value>> {
+        let groups = self.groups.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+        Ok(groups
+            .values()
+            .map(|group| {
+                let mut row = serde_json::Map::new();
+                row.insert(self.key.clone(), group.key.clone());
+                for (i, (output, aggregation)) in self.aggregations.iter().enumerate() {
+                    let value = match aggregation {
+                        Aggregation::Count => serde_json::json!(group.count),
+                        _ => serde_json::json!(group.values[i]),
+                    };
+                    row.insert(output.clone(), value);
+                }
+                serde_json::Value::Object(row)
+            })
+            .collect())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        let rows = self.rows()?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = std::io::BufWriter::new(File::create(&self.path)?);
+        for row in &rows {
+            writeln!(file, "{}", row)?;
+        }
+        file.flush()?;
+
+        info!(target: "group_by_step", "✅ Written {} groups to {}", rows.len(), self.path);
+        Ok(())
+    }
+}
+
+impl Step for GroupByStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        self.add(context)?;
+        Ok(context.clone())
+    }
+}
+
+/// Quotes a table or column name for SQL.
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Inserts a row per item into `table` of the SQLite database at `path`. `columns` maps
+/// table columns to context keys, missing keys are inserted as NULL and nested values as
+/// JSON text. The database and table are created on first use.
+pub struct SqliteWriterStep {
+    pub name: String,
+    pub path: String,
+    pub table: String,
+    pub columns: Vec<(String, String)>,
+    pool: OnceCell<SqlitePool>,
+}
+
+impl SqliteWriterStep {
+    pub fn new(
+        name: String,
+        path: String,
+        table: String,
+        columns: Vec<(String, String)>,
+    ) -> Result<Self> {
+        if columns.is_empty() {
+            bail!("🐔 SQLite writer {} needs at least one column", name);
+        }
+        Ok(Self {
+            name,
+            path,
+            table,
+            columns,
+            pool: OnceCell::new(),
+        })
+    }
+
+    async fn pool(&self) -> Result<&SqlitePool> {
+        self.pool
+            .get_or_try_init(|| async {
+                let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", self.path))?
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal)
+                    .busy_timeout(std::time::Duration::from_secs(5));
+                let pool = SqlitePoolOptions::new().connect_with(options).await?;
+                let columns = self
+                    .columns
+                    .iter()
+                    .map(|(column, _)| quote_ident(column))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} ({})",
+                    quote_ident(&self.table),
+                    columns
+                ))
+                .execute(&pool)
+                .await?;
+                anyhow::Ok(pool)
+            })
+            .await
+    }
 
-        let row = row.replace("\\n", "\n").replace('\n', "\\n");
-        writeln!(writer, "{}", row)?;
-        writer.flush()?;
+    pub async fn insert(&self, context: &StepContext) -> Result<()> {
+        let pool = self.pool().await?;
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_ident(&self.table),
+            self.columns
+                .iter()
+                .map(|(column, _)| quote_ident(column))
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; self.columns.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for (_, key) in &self.columns {
+            query = match context.get(key) {
+                None | Some(serde_json::Value::Null) => query.bind(None::<String>),
+                Some(serde_json::Value::Bool(value)) => query.bind(*value),
+                Some(serde_json::Value::Number(value)) => match value.as_i64() {
+                    Some(value) => query.bind(value),
+                    None => query.bind(value.as_f64()),
+                },
+                Some(serde_json::Value::String(value)) => query.bind(value.clone()),
+                Some(value) => query.bind(value.to_string()),
+            };
+        }
+        query.execute(pool).await?;
+        Ok(())
+    }
+}
 
+impl Step for SqliteWriterStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        self.insert(context).await?;
         Ok(context.clone())
     }
 }
+
+/// What an insert of [`PostgresWriterStep`] does with rows conflicting on the conflict columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictAction {
+    Nothing,
+    Update,
+}
+
+impl std::str::FromStr for ConflictAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "nothing" | "ignore" => Ok(ConflictAction::Nothing),
+            "update" => Ok(ConflictAction::Update),
+            _ => bail!("🐔 Unsupported conflict action: {}", s),
+        }
+    }
+}
+
+/// Inserts items into an existing PostgreSQL `table` in batches of `batch_size` rows,
+/// the last partial batch once the run is finished. `columns` maps table columns to
+/// context keys, values are converted to the column types by `jsonb_populate_recordset`.
+/// With `conflict` columns conflicting rows are skipped or updated.
+pub struct PostgresWriterStep {
+    pub name: String,
+    pub conn_str: String,
+    pub table: String,
+    pub columns: Vec<(String, String)>,
+    pub batch_size: usize,
+    pub conflict: Option<(Vec<String>, ConflictAction)>,
+    rows: Mutex<Vec<serde_json::Value>>,
+    pool: OnceCell<PgPool>,
+}
+
+impl PostgresWriterStep {
+    pub fn new(
+        name: String,
+        conn_str: String,
+        table: String,
+        columns: Vec<(String, String)>,
+        batch_size: usize,
+        conflict: Option<(Vec<String>, ConflictAction)>,
+    ) -> Result<Self> {
+        if columns.is_empty() {
+            bail!("🐔 PostgreSQL writer {} needs at least one column", name);
+        }
+        if let Some((conflict_columns, _)) = &conflict {
+            if conflict_columns.is_empty() {
+                bail!("🐔 PostgreSQL writer {} needs conflict columns", name);
+            }
+        }
+        Ok(Self {
+            name,
+            conn_str,
+            table,
+            columns,
+            batch_size: batch_size.max(1),
+            conflict,
+            rows: Mutex::new(Vec::new()),
+            pool: OnceCell::new(),
+        })
+    }
+
+    fn row(&self, context: &StepContext) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.columns
+                .iter()
+                .map(|(column, key)| {
+                    (
+                        column.clone(),
+                        context.get(key).cloned().unwrap_or_default(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn insert_sql(&self) -> String {
+        // schema qualified names are quoted part by part
+        let table = self
+            .table
+            .split('.')
+            .map(quote_ident)
+            .collect::<Vec<_>>()
+            .join(".");
+        let columns = self
+            .columns
+            .iter()
+            .map(|(column, _)| quote_ident(column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = format!(
+            "INSERT INTO {table} ({columns}) SELECT {columns} FROM jsonb_populate_recordset(NULL::{table}, $1)"
+        );
+        if let Some((conflict_columns, action)) = &self.conflict {
+            let updates = self
+                .columns
+                .iter()
+                .filter(|(column, _)| !conflict_columns.contains(column))
+                .map(|(column, _)| format!("{0} = EXCLUDED.{0}", quote_ident(column)))
+                .collect::<Vec<_>>();
+            sql.push_str(&format!(
+                " ON CONFLICT ({})",
+                conflict_columns
+                    .iter()
+                    .map(|column| quote_ident(column))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+            if *action == ConflictAction::Nothing || updates.is_empty() {
+                sql.push_str(" DO NOTHING");
+            } else {
+                sql.push_str(&format!(" DO UPDATE SET {}", updates.join(", ")));
+            }
+        }
+        sql
+    }
+
+    async fn insert(&self, rows: Vec<serde_json::Value>) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let pool = self
+            .pool
+            .get_or_try_init(|| PgPoolOptions::new().connect(&self.conn_str))
+            .await?;
+        sqlx::query(&self.insert_sql())
+            .bind(serde_json::Value::Array(rows))
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?);
+        self.insert(rows).await
+    }
+}
+
+impl Step for PostgresWriterStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let batch = {
+            let mut rows = self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+            rows.push(self.row(context));
+            if rows.len() >= self.batch_size {
+                std::mem::take(&mut *rows)
+            } else {
+                Vec::new()
+            }
+        };
+        self.insert(batch).await?;
+        Ok(context.clone())
+    }
+}
+
+/// The rendered `template` parsed as JSON, the `columns` of the context or all of it.
+/// `None` when some of the columns are missing.
+fn json_row(
+    resources: &PipelineResources,
+    context: &StepContext,
+    template: &Option<String>,
+    columns: &Option<Vec<String>>,
+) -> Result<Option<serde_json::Value>> {
+    if let Some(template) = template {
+        let rendered = resources
+            .templates
+            .render(template.clone(), context.data.clone())?;
+        return Ok(Some(serde_json::from_str(&rendered)?));
+    }
+    Ok(match columns {
+        Some(columns) => columns
+            .iter()
+            .map(|column| Some((column.clone(), context.get(column)?.clone())))
+            .collect::<Option<serde_json::Map<_, _>>>()
+            .map(serde_json::Value::Object),
+        None => Some(context.data.clone()),
+    })
+}
+
+/// POSTs items as JSON to `url`: the rendered `template`, the `columns` of the context or
+/// all of it. With `batch_size` above one the items are sent as arrays, the last partial
+/// batch once the run is finished. Requests failing with a connection error, 429 or 5xx are
+/// retried up to `max_attempts` times, waiting as in [`backoff_delay`].
+pub struct HttpWriterStep {
+    pub name: String,
+    pub url: String,
+    pub template: Option<String>,
+    pub columns: Option<Vec<String>>,
+    pub batch_size: usize,
+    pub max_attempts: usize,
+    pub backoff_ms: u64,
+    client: reqwest::Client,
+    rows: Mutex<Vec<serde_json::Value>>,
+}
+
+impl HttpWriterStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        url: String,
+        template: Option<String>,
+        columns: Option<Vec<String>>,
+        headers: Vec<(String, String)>,
+        batch_size: usize,
+        max_attempts: usize,
+        backoff_ms: u64,
+        timeout_secs: u64,
+    ) -> Result<Self> {
+        let mut header_map = reqwest::header::HeaderMap::new();
+        for (header, value) in headers {
+            header_map.insert(
+                reqwest::header::HeaderName::from_str(&header)?,
+                reqwest::header::HeaderValue::from_str(&value)?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(header_map)
+            .timeout(Duration::from_secs(timeout_secs))
+            .build()?;
+        Ok(Self {
+            name,
+            url,
+            template,
+            columns,
+            batch_size: batch_size.max(1),
+            max_attempts: max_attempts.max(1),
+            backoff_ms,
+            client,
+            rows: Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn send(&self, mut rows: Vec<serde_json::Value>) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let body = if self.batch_size == 1 {
+            rows.remove(0)
+        } else {
+            serde_json::Value::Array(rows)
+        };
+
+        for attempt in 1..=self.max_attempts {
+            let error = match self.client.post(&self.url).json(&body).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+                    {
+                        bail!("🐔 {} returned {}: {}", self.url, status, text);
+                    }
+                    format!("{}: {}", status, text)
+                }
+                Err(e) => e.to_string(),
+            };
+            if attempt == self.max_attempts {
+                bail!(
+                    "🐔 {} failed after {} attempts: {}",
+                    self.url,
+                    self.max_attempts,
+                    error
+                );
+            }
+            debug!(target: "http_writer_step", "🐔 Attempt {} of {} failed: {}", attempt, self.max_attempts, error);
+            tokio::time::sleep(backoff_delay(self.backoff_ms, attempt, &mut rand::rng())).await;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?);
+        self.send(rows).await
+    }
+}
+
+impl Step for HttpWriterStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let row = match json_row(resources, context, &self.template, &self.columns) {
+            Ok(Some(row)) => row,
+            Ok(None) => {
+                error!(target: "http_writer_step", "🐔 Columns {:?} missing in context", self.columns);
+                let mut context = context.clone();
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+            Err(e) => {
+                error!(target: "http_writer_step", "🐔 Failed to render JSON body: {}", e);
+                let mut context = context.clone();
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+        let batch = {
+            let mut rows = self.rows.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+            rows.push(row);
+            if rows.len() >= self.batch_size {
+                std::mem::take(&mut *rows)
+            } else {
+                Vec::new()
+            }
+        };
+        self.send(batch).await?;
+        Ok(context.clone())
+    }
+}
+
+/// Publishes items as JSON messages to a Kafka `topic`: the rendered `template`, the
+/// `columns` of the context or all of it, keyed by the rendered `key_template`. The producer
+/// batches up to `batch_size` messages waiting at most `linger_ms`, an item is done once its
+/// message was delivered. `config` holds further librdkafka settings, e.g. SASL credentials.
+/// Needs the `kafka` feature.
+#[cfg(feature = "kafka")]
+pub struct KafkaWriterStep {
+    pub name: String,
+    pub topic: String,
+    pub template: Option<String>,
+    pub columns: Option<Vec<String>>,
+    pub key_template: Option<String>,
+    producer: FutureProducer,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaWriterStep {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        brokers: String,
+        topic: String,
+        template: Option<String>,
+        columns: Option<Vec<String>>,
+        key_template: Option<String>,
+        batch_size: usize,
+        linger_ms: u64,
+        config: Vec<(String, String)>,
+    ) -> Result<Self> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &brokers)
+            .set("batch.num.messages", batch_size.max(1).to_string())
+            .set("linger.ms", linger_ms.to_string());
+        for (key, value) in config {
+            client_config.set(key, value);
+        }
+        let producer = client_config.create()?;
+        Ok(Self {
+            name,
+            topic,
+            template,
+            columns,
+            key_template,
+            producer,
+        })
+    }
+
+    pub async fn finish(&self) -> Result<()> {
+        self.producer.flush(Duration::from_secs(30))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl Step for KafkaWriterStep {
+    async fn process(
+        &self,
+        resources: &PipelineResources,
+        context: &StepContext,
+    ) -> Result<StepContext> {
+        let key = self
+            .key_template
+            .as_ref()
+            .map(|key| {
+                resources
+                    .templates
+                    .render(key.clone(), context.data.clone())
+            })
+            .transpose();
+        let (row, key) = match (
+            json_row(resources, context, &self.template, &self.columns),
+            key,
+        ) {
+            (Ok(Some(row)), Ok(key)) => (row, key),
+            (Ok(None), _) => {
+                error!(target: "kafka_writer_step", "🐔 Columns {:?} missing in context", self.columns);
+                let mut context = context.clone();
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                error!(target: "kafka_writer_step", "🐔 Failed to render message: {}", e);
+                let mut context = context.clone();
+                context.set_status(StepStatus::Failed);
+                return Ok(context);
+            }
+        };
+
+        let payload = row.to_string();
+        let mut record = FutureRecord::to(&self.topic).payload(&payload);
+        if let Some(key) = &key {
+            record = record.key(key.trim());
+        }
+        self.producer
+            .send(record, Timeout::Never)
+            .await
+            .map_err(|(e, _)| anyhow::anyhow!("🐔 Failed to publish to {}: {}", self.topic, e))?;
+        Ok(context.clone())
+    }
+}
+
+/// Hands the item to each of the `sinks` (writers, `print` or `dump`), so a single step fans
+/// it out to several outputs. The sinks run concurrently on clones of the item, which passes
+/// through unchanged unless a sink fails it.
+pub struct TeeStep {
+    pub name: String,
+    pub sinks: Vec<StepType>,
+}
+
+impl TeeStep {
+    pub fn new(name: String, sinks: Vec<StepType>) -> Result<Self> {
+        if sinks.is_empty() {
+            bail!("Tee step {} needs at least one sink", name);
+        }
+        if let Some(step) = sinks.iter().find(|step| !is_sink(step)) {
+            bail!(
+                "Tee step {} only accepts writer, print or dump steps, got {}",
+                name,
+                step.name()
+            );
+        }
+        Ok(Self { name, sinks })
+    }
+
+    pub fn merge(&self, context: &StepContext, results: Vec<StepContext>) -> StepContext {
+        let mut context = context.clone();
+        if let Some(failed) = results
+            .iter()
+            .find(|result| matches!(result.get_status(), StepStatus::Failed))
+        {
+            if let Some(step) = failed.failed_step() {
+                context.set_failed_step(step);
+            }
+            context.set_status(StepStatus::Failed);
+        }
+        context
+    }
+}
+
+fn is_sink(step: &StepType) -> bool {
+    is_writer(step)
+        || matches!(
+            step,
+            StepType::GroupBy(_) | StepType::Print(_) | StepType::Dump(_) | StepType::Tee(_)
+        )
+}
+
+/// Steps writing a record per item.
+pub fn is_writer(step: &StepType) -> bool {
+    #[cfg(feature = "kafka")]
+    if matches!(step, StepType::KafkaWriter(_)) {
+        return true;
+    }
+    matches!(
+        step,
+        StepType::JsonWriter(_)
+            | StepType::CsvWriter(_)
+            | StepType::IpcWriter(_)
+            | StepType::SqliteWriter(_)
+            | StepType::PostgresWriter(_)
+            | StepType::HttpWriter(_)
+            | StepType::PyWriter(_)
+            | StepType::EmbeddingsWriter(_)
+    )
+}
+
+impl Step for TeeStep {
+    async fn process(
+        &self,
+        _resources: &PipelineResources,
+        _context: &StepContext,
+    ) -> Result<StepContext> {
+        unreachable!("Sinks are run by the pipeline");
+    }
+}
+
+/// Keeps a writer from writing records an earlier run, or another item, already wrote: the
+/// hash of the `inputs` fields is claimed in the state under `key` before the write, and with
+/// a `simhash_threshold` records within that Hamming distance of a written one count as
+/// written too.
+#[derive(Debug, Clone)]
+pub struct WriterDedup {
+    pub key: String,
+    pub inputs: Vec<String>,
+    pub simhash_threshold: Option<u32>,
+}
+
+impl WriterDedup {
+    pub fn new(
+        inputs: Vec<String>,
+        key: Option<String>,
+        simhash_threshold: Option<u32>,
+    ) -> Result<Self> {
+        if inputs.is_empty() {
+            bail!("🐔 Deduplication needs at least one input");
+        }
+        Ok(Self {
+            key: key.unwrap_or_else(|| format!("written:{}", inputs.join(","))),
+            inputs,
+            simhash_threshold,
+        })
+    }
+
+    fn record(&self, context: &StepContext) -> Option<serde_json::Value> {
+        let fields = self
+            .inputs
+            .iter()
+            .map(|input| Some((input.clone(), context.get(input)?.clone())))
+            .collect::<Option<serde_json::Map<_, _>>>()?;
+        Some(serde_json::Value::Object(fields))
+    }
+
+    /// Claims the record of the item, `false` when it was already written. Items missing
+    /// some of the inputs are always written.
+    pub async fn claim(&self, state: &State, context: &StepContext) -> Result<bool> {
+        let Some(record) = self.record(context) else {
+            return Ok(true);
+        };
+        let hash = hash_value(&record);
+        if !state
+            .claim_hash(&context.id.to_string(), &self.key, &hash)
+            .await?
+        {
+            return Ok(false);
+        }
+        if let Some(threshold) = self.simhash_threshold {
+            let similar = state
+                .knn_simhash(&self.key, simhash_value(&record), 1)
+                .await?;
+            if similar
+                .first()
+                .is_some_and(|(_, dist, _)| *dist <= threshold)
+            {
+                state.remove_hash(&self.key, &hash).await?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Remembers the simhash of a record once written.
+    pub async fn written(&self, state: &State, context: &StepContext) -> Result<()> {
+        if self.simhash_threshold.is_none() {
+            return Ok(());
+        }
+        if let Some(record) = self.record(context) {
+            state
+                .add_simhash(
+                    &context.id.to_string(),
+                    &self.key,
+                    simhash_value(&record) as i64,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Gives up the claim of a record whose write failed.
+    pub async fn release(&self, state: &State, context: &StepContext) -> Result<()> {
+        if let Some(record) = self.record(context) {
+            state.remove_hash(&self.key, &hash_value(&record)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_rows(step: &EmbeddingsWriterStep) -> Result<()> {
+        step.rows.lock().unwrap().extend([
+            ("id1".to_string(), "a".to_string(), vec![1.0, 0.0]),
+            ("id2".to_string(), "b".to_string(), vec![0.0, 1.0]),
+        ]);
+        step.finish()
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_shards() -> Result<()> {
+        assert_eq!(shard_path("out/data.jsonl", 2), "out/data-00002.jsonl");
+        assert_eq!(shard_path("data.jsonl.gz", 1), "data-00001.jsonl.gz");
+        assert_eq!(shard_path("data", 10), "data-00010");
+
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.jsonl").to_string_lossy().to_string();
+        let step = |max_lines, max_bytes| {
+            JsonlWriterStep::new(
+                "w".to_string(),
+                path.clone(),
+                None,
+                None,
+                max_lines,
+                max_bytes,
+                None,
+                1.0,
+                None,
+                WriteMode::Append,
+                false,
+            )
+        };
+        let by_lines = step(Some(2), None)?;
+        for i in 0..5 {
+            by_lines.write_line(&format!("{{\"i\": {}}}", i)).await?;
+        }
+        by_lines.finish().await?;
+        let lines = |index| -> Result<usize> {
+            Ok(std::fs::read_to_string(shard_path(&path, index))?
+                .lines()
+                .count())
+        };
+        assert_eq!((lines(1)?, lines(2)?, lines(3)?), (2, 2, 1));
+
+        // a rerun continues the last shard that has room
+        let by_bytes = step(None, Some(12))?;
+        by_bytes.write_line("{\"i\": 5}").await?;
+        by_bytes.write_line("{\"i\": 6}").await?;
+        by_bytes.finish().await?;
+        assert_eq!(lines(1)?, 2);
+        assert_eq!(
+            std::fs::read_to_string(shard_path(&path, 3))?,
+            "{\"i\": 4}\n"
+        );
+        assert!(std::path::Path::new(&shard_path(&path, 5)).exists());
+        assert!(step(None, None).is_ok());
+        assert!(JsonlWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1.0,
+            None,
+            WriteMode::Append,
+            false
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redirect() -> Result<()> {
+        assert_eq!(
+            suffixed_path("out/data.jsonl.gz", "_t-0.2"),
+            "out/data_t-0.2.jsonl.gz"
+        );
+
+        let tmp = TempDir::new()?;
+        let path = |name: &str| tmp.path().join(name).to_string_lossy().to_string();
+        let step = StepType::JsonWriter(JsonlWriterStep::new(
+            "w".to_string(),
+            path("out.jsonl"),
+            None,
+            None,
+            Some(10),
+            None,
+            None,
+            1.0,
+            None,
+            WriteMode::Append,
+            false,
+        )?);
+        let StepType::JsonWriter(writer) = &step else {
+            unreachable!()
+        };
+        writer.write_line("{\"run\": 1}").await?;
+        writer.finish().await?;
+
+        let mut steps = vec![step];
+        assert_eq!(
+            redirect(&mut steps, &mut |current| suffixed_path(current, "_b")),
+            vec![path("out.jsonl")]
+        );
+        let StepType::JsonWriter(writer) = &steps[0] else {
+            unreachable!()
+        };
+        writer.write_line("{\"run\": 2}").await?;
+        writer.finish().await?;
+        assert_eq!(
+            std::fs::read_to_string(shard_path(&path("out_b.jsonl"), 1))?,
+            "{\"run\": 2}\n"
+        );
+
+        // writers of a tee are redirected too
+        let writer = |name: &str| -> Result<StepType> {
+            Ok(StepType::JsonWriter(JsonlWriterStep::new(
+                "w".to_string(),
+                path(name),
+                None,
+                None,
+                None,
+                None,
+                None,
+                1.0,
+                None,
+                WriteMode::Append,
+                false,
+            )?))
+        };
+        let print = || StepType::Print(crate::steps::PrintStep::new("p".to_string(), None, None));
+        let mut steps = vec![
+            print(),
+            StepType::Tee(TeeStep::new(
+                "t".to_string(),
+                vec![writer("a.jsonl")?, print(), writer("b.jsonl")?],
+            )?),
+        ];
+        assert_eq!(
+            redirect(&mut steps, &mut |current| suffixed_path(current, "_c")),
+            vec![path("a.jsonl"), path("b.jsonl")]
+        );
+        let StepType::Tee(tee) = &steps[1] else {
+            unreachable!()
+        };
+        let StepType::JsonWriter(sink) = &tee.sinks[2] else {
+            unreachable!()
+        };
+        sink.write_line("{\"run\": 3}").await?;
+        sink.finish().await?;
+        assert_eq!(
+            std::fs::read_to_string(path("b_c.jsonl"))?,
+            "{\"run\": 3}\n"
+        );
+        assert!(redirect(&mut [print()], &mut |current| current.to_string()).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finish_cached_writer() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.jsonl").to_string_lossy().to_string();
+        let writer = JsonlWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            1.0,
+            None,
+            WriteMode::Append,
+            false,
+        )?;
+        writer.write_line("{\"a\": 1}").await?;
+        let steps = vec![StepType::Cache(crate::steps::CacheStep::new(
+            "c".to_string(),
+            vec![StepType::JsonWriter(writer)],
+            String::new(),
+            vec![],
+        ))];
+        crate::steps::finish_steps(&steps).await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "{\"a\": 1}\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_manifest() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.jsonl").to_string_lossy().to_string();
+        std::fs::write(shard_path(&path, 4), "{\"stale\": true}\n")?;
+        let step = |mode| {
+            JsonlWriterStep::new(
+                "w".to_string(),
+                path.clone(),
+                None,
+                None,
+                Some(2),
+                None,
+                None,
+                1.0,
+                None,
+                mode,
+                true,
+            )
+        };
+
+        let overwrite = step(WriteMode::Overwrite)?;
+        for i in 0..5 {
+            let line = if i == 0 {
+                "{\"i\": 0, \"tag\": null}".to_string()
+            } else {
+                format!("{{\"i\": {}, \"tag\": \"t{}\"}}", i, i)
+            };
+            overwrite.write_line(&line).await?;
+        }
+        overwrite.finish().await?;
+        assert!(!std::path::Path::new(&shard_path(&path, 4)).exists());
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(manifest_path(&path))?)?;
+        assert_eq!(manifest["rows"], 5);
+        assert_eq!(manifest["files"].as_array().map(|f| f.len()), Some(3));
+        assert_eq!(manifest["files"][0]["path"], "out-00001.jsonl");
+        assert_eq!(manifest["files"][2]["rows"], 1);
+        assert_eq!(
+            manifest["schema"],
+            serde_json::json!({"i": ["integer"], "tag": ["null", "string"]})
+        );
+        assert_eq!(manifest["fingerprint"].as_str().map(|f| f.len()), Some(64));
+
+        let refused = step(WriteMode::ErrorIfExists)?;
+        assert!(refused.write_line("{\"i\": 5}").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_format() -> Result<()> {
+        let fields = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        let row = fields(&["a;b", "say \"hi\"", "12", "x\ny"]);
+        let necessary = CsvFormat::new(";", "necessary", "\"", None)?;
+        assert_eq!(
+            necessary.line(&row)?,
+            "\"a;b\";\"say \"\"hi\"\"\";12;\"x\ny\""
+        );
+        let escaped = CsvFormat::new(",", "non_numeric", "'", Some("\\"))?;
+        assert_eq!(escaped.line(&fields(&["it's", "12"]))?, "'it\\'s',12");
+        let never = CsvFormat::new("\t", "never", "\"", None)?;
+        assert_eq!(never.line(&row)?, "a;b\tsay \"hi\"\t12\tx\\ny");
+        assert!(CsvFormat::new(";;", "necessary", "\"", None).is_err());
+        assert!(CsvFormat::new(";", "sometimes", "\"", None).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_writer() -> Result<()> {
+        let tmp = TempDir::new()?;
+        for (file, compression) in [
+            ("out.jsonl.gz", Compression::Gzip),
+            ("out.jsonl.zst", Compression::Zstd),
+        ] {
+            let path = tmp.path().join(file).to_string_lossy().to_string();
+            // two runs, the second appends to the finished file of the first
+            for run in 0..2 {
+                let step = JsonlWriterStep::new(
+                    "w".to_string(),
+                    path.clone(),
+                    None,
+                    None,
+                    Some(3),
+                    None,
+                    None,
+                    1.0,
+                    None,
+                    WriteMode::Append,
+                    false,
+                )?;
+                for i in 0..2 {
+                    step.write_line(&format!("{{\"run\": {}, \"i\": {}}}", run, i))
+                        .await?;
+                }
+                step.finish().await?;
+            }
+            let first = String::from_utf8(compression.read(&shard_path(&path, 1))?)?;
+            let second = String::from_utf8(compression.read(&shard_path(&path, 2))?)?;
+            assert_eq!(first.lines().count(), 3);
+            assert_eq!(second, "{\"run\": 1, \"i\": 1}\n");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_writer() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.db").to_string_lossy().to_string();
+        let step = SqliteWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            "my \"rows\"".to_string(),
+            vec![
+                ("question".to_string(), "q".to_string()),
+                ("score".to_string(), "score".to_string()),
+                ("meta".to_string(), "meta".to_string()),
+            ],
+        )?;
+        let mut context = StepContext::new();
+        context.set("q", "what?");
+        context.set("score", 0.5);
+        context.set("meta", serde_json::json!({"tags": ["a"]}));
+        step.insert(&context).await?;
+        step.insert(&StepContext::new()).await?;
+
+        let rows: Vec<(Option<String>, Option<f64>, Option<String>)> =
+            sqlx::query_as("SELECT question, score, meta FROM \"my \"\"rows\"\"\" ORDER BY rowid")
+                .fetch_all(step.pool().await?)
+                .await?;
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    Some("what?".to_string()),
+                    Some(0.5),
+                    Some(r#"{"tags":["a"]}"#.to_string())
+                ),
+                (None, None, None)
+            ]
+        );
+        assert!(SqliteWriterStep::new("w".into(), path, "t".into(), vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_postgres_insert_sql() -> Result<()> {
+        let columns = vec![
+            ("id".to_string(), "item_id".to_string()),
+            ("question".to_string(), "question".to_string()),
+        ];
+        let new = |conflict| {
+            PostgresWriterStep::new(
+                "w".to_string(),
+                "postgres://localhost/db".to_string(),
+                "public.samples".to_string(),
+                columns.clone(),
+                100,
+                conflict,
+            )
+        };
+        assert_eq!(
+            new(None)?.insert_sql(),
+            r#"INSERT INTO "public"."samples" ("id", "question") SELECT "id", "question" FROM jsonb_populate_recordset(NULL::"public"."samples", $1)"#
+        );
+        assert!(new(Some((vec!["id".to_string()], ConflictAction::Update)))?
+            .insert_sql()
+            .ends_with(r#" ON CONFLICT ("id") DO UPDATE SET "question" = EXCLUDED."question""#));
+        assert!(
+            new(Some((vec!["id".to_string()], ConflictAction::Nothing)))?
+                .insert_sql()
+                .ends_with(r#" ON CONFLICT ("id") DO NOTHING"#)
+        );
+        assert!(new(Some((vec![], ConflictAction::Nothing))).is_err());
+
+        let mut context = StepContext::new();
+        context.set("item_id", 7);
+        assert_eq!(
+            new(None)?.row(&context),
+            serde_json::json!({"id": 7, "question": null})
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ipc_writer() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("out.arrow").to_string_lossy().to_string();
+        let step = IpcWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            Some(vec!["question".to_string(), "meta".to_string()]),
+            None,
+        )?;
+        let mut context = StepContext::new();
+        context.set("question", "q1");
+        context.set("meta", serde_json::json!({"score": 1}));
+        context.set("ignored", true);
+        assert!(step.row(&context).is_some());
+        step.rows.lock().unwrap().push(step.row(&context).unwrap());
+        context.set("question", "q2");
+        context.set("meta", serde_json::json!({"score": 2, "tag": "x"}));
+        step.rows.lock().unwrap().push(step.row(&context).unwrap());
+        assert!(step.row(&StepContext::new()).is_none());
+        step.finish().await?;
+
+        let df = IpcReader::new(File::open(&path)?).finish()?;
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.get_column_names(), vec!["question", "meta"]);
+        assert_eq!(df.column("question")?.str()?.get(1), Some("q2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_embeddings_writer_parquet() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("emb.parquet").to_string_lossy().to_string();
+        let step = EmbeddingsWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            EmbeddingsFormat::Parquet,
+            "text".to_string(),
+            "vector".to_string(),
+        );
+        write_rows(&step)?;
+
+        let df = ParquetReader::new(File::open(&path)?).finish()?;
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.get_column_names(), vec!["item_id", "text", "vector"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_embeddings_writer_fvecs() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp.path().join("emb.fvecs").to_string_lossy().to_string();
+        let step = EmbeddingsWriterStep::new(
+            "w".to_string(),
+            path.clone(),
+            EmbeddingsFormat::Fvecs,
+            "text".to_string(),
+            "vector".to_string(),
+        );
+        write_rows(&step)?;
+
+        // two records of 4 byte dim + 2 floats
+        assert_eq!(std::fs::metadata(&path)?.len(), 2 * (4 + 2 * 4));
+        let ids = std::fs::read_to_string(format!("{}.ids.jsonl", path))?;
+        assert_eq!(ids.lines().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let path = tmp
+            .path()
+            .join("groups.jsonl")
+            .to_string_lossy()
+            .to_string();
+        let aggregations = ["count", "concat:question", "sample:question:2"]
+            .iter()
+            .zip(["items", "questions", "examples"])
+            .map(|(a, o)| Ok((o.to_string(), a.parse()?)))
+            .collect::<Result<Vec<_>>>()?;
+        let step = GroupByStep::new(
+            "g".to_string(),
+            "topic".to_string(),
+            path.clone(),
+            aggregations,
+        );
+
+        for (topic, question) in [
+            ("math", "q1"),
+            ("art", "q2"),
+            ("math", "q3"),
+            ("math", "q4"),
+        ] {
+            let mut context = StepContext::new();
+            context.set("topic", topic);
+            context.set("question", question);
+            step.add(&context)?;
+        }
+        step.finish()?;
+
+        let rows: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            serde_json::json!({"topic": "art", "items": 1, "questions": ["q2"], "examples": ["q2"]})
+        );
+        assert_eq!(rows[1]["items"], 3);
+        assert_eq!(rows[1]["questions"], serde_json::json!(["q1", "q3", "q4"]));
+        assert_eq!(rows[1]["examples"].as_array().unwrap().len(), 2);
+        assert!("median:question".parse::<Aggregation>().is_err());
+        Ok(())
+    }
+
+    /// Answers one request per status with `Connection: close`, returns the request bodies.
+    async fn serve(
+        statuses: Vec<u16>,
+    ) -> Result<(String, tokio::task::JoinHandle<Vec<(String, String)>>)> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/items", listener.local_addr()?);
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                loop {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|v| v.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            requests.push((head.to_string(), body.to_string()));
+                            break;
+                        }
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        Ok((url, handle))
+    }
+
+    #[tokio::test]
+    async fn test_http_writer() -> Result<()> {
+        let resources = PipelineResources::new(None);
+        let mut context = StepContext::new();
+        context.set("question", "q1");
+        context.set("answer", "a1");
+
+        let (url, server) = serve(vec![503, 200, 200]).await?;
+        let step = HttpWriterStep::new(
+            "h".to_string(),
+            url.clone(),
+            None,
+            Some(vec!["question".to_string()]),
+            vec![("Authorization".to_string(), "Bearer secret".to_string())],
+            2,
+            2,
+            0,
+            5,
+        )?;
+        for _ in 0..3 {
+            step.process(&resources, &context).await?;
+        }
+        step.finish().await?;
+        let requests = server.await?;
+        assert!(requests[0].0.starts_with("POST /items"));
+        assert!(requests[0].0.contains("authorization: Bearer secret"));
+        assert_eq!(requests[0].1, requests[1].1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[1].1)?,
+            serde_json::json!([{"question": "q1"}, {"question": "q1"}])
+        );
+        assert_eq!(requests[2].1, "[{\"question\":\"q1\"}]");
+
+        let missing = HttpWriterStep::new(
+            "h".to_string(),
+            url,
+            None,
+            Some(vec!["missing".to_string()]),
+            vec![],
+            1,
+            1,
+            0,
+            5,
+        )?;
+        let result = missing.process(&resources, &context).await?;
+        assert!(matches!(result.get_status(), StepStatus::Failed));
+
+        // client errors are not retried
+        let (url, server) = serve(vec![400]).await?;
+        let step = HttpWriterStep::new("h".to_string(), url, None, None, vec![], 1, 3, 0, 5)?;
+        assert!(step.process(&resources, &context).await.is_err());
+        assert_eq!(server.await?.len(), 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "kafka")]
+    #[tokio::test]
+    async fn test_kafka_writer() -> Result<()> {
+        let step = |config: Vec<(&str, &str)>| {
+            KafkaWriterStep::new(
+                "k".to_string(),
+                "127.0.0.1:1".to_string(),
+                "items".to_string(),
+                None,
+                Some(vec!["question".to_string()]),
+                None,
+                100,
+                5,
+                config
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+        };
+        assert!(step(vec![("linger.ms", "soon")]).is_err());
+
+        let resources = PipelineResources::new(None);
+        let mut context = StepContext::new();
+        let writer = step(vec![("message.timeout.ms", "100")])?;
+        let result = writer.process(&resources, &context).await?;
+        assert!(matches!(result.get_status(), StepStatus::Failed));
+
+        // nothing listens on the broker port, so the delivery times out
+        context.set("question", "q1");
+        assert!(writer.process(&resources, &context).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tee_sinks() -> Result<()> {
+        let print = || StepType::Print(crate::steps::PrintStep::new("p".to_string(), None, None));
+        let tee = TeeStep::new("t".to_string(), vec![print(), print()])?;
+        assert!(TeeStep::new("t".to_string(), vec![]).is_err());
+        assert!(TeeStep::new(
+            "t".to_string(),
+            vec![
+                print(),
+                StepType::Render(crate::steps::RenderStep::new(
+                    "r".to_string(),
+                    "template".to_string(),
+                    "output".to_string(),
+                )),
+            ],
+        )
+        .is_err());
+
+        let mut context = StepContext::new();
+        context.set("question", "q1");
+        let mut failed = context.clone();
+        failed.set_status(StepStatus::Failed);
+        failed.set_failed_step("p");
+        let merged = tee.merge(&context, vec![context.clone(), failed]);
+        assert!(matches!(merged.get_status(), StepStatus::Failed));
+        assert_eq!(merged.failed_step(), Some("p"));
+        assert_eq!(merged.data, context.data);
+        assert!(matches!(
+            tee.merge(&context, vec![context.clone()]).get_status(),
+            StepStatus::Pending
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writer_dedup() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let state = State::new(tmp.path().to_str().unwrap()).await?;
+        state.add_run("run1", "/tmp/log", None).await?;
+        let item = |question: &str| {
+            let mut context = StepContext::new();
+            context.set("question", question);
+            context
+        };
+        let mut items = Vec::new();
+        let questions = [
+            "What is the capital of France?",
+            "What is the capital of France?",
+            "what is the  Capital of France?",
+            "Why is the sky blue?",
+        ];
+        for (i, question) in questions.iter().enumerate() {
+            let context = item(question);
+            state
+                .add_item(&context.id.to_string(), "run1", i as i64, None)
+                .await?;
+            items.push(context);
+        }
+
+        let dedup = WriterDedup::new(vec!["question".to_string()], None, None)?;
+        assert!(dedup.claim(&state, &items[0]).await?);
+        assert!(!dedup.claim(&state, &items[1]).await?);
+        assert!(dedup.claim(&state, &items[2]).await?);
+        dedup.release(&state, &items[2]).await?;
+        assert!(dedup.claim(&state, &items[2]).await?);
+        // items without the inputs aren't deduplicated
+        assert!(dedup.claim(&state, &StepContext::new()).await?);
+
+        let dedup = WriterDedup::new(
+            vec!["question".to_string()],
+            Some("near".to_string()),
+            Some(3),
+        )?;
+        assert!(dedup.claim(&state, &items[0]).await?);
+        dedup.written(&state, &items[0]).await?;
+        assert!(!dedup.claim(&state, &items[2]).await?);
+        assert!(dedup.claim(&state, &items[3]).await?);
+        Ok(())
+    }
+}
@@ -23,3 +23,27 @@ pub fn judge_templates(name: &str, language: &str) -> Option<&'static str> {
         .get_file(format!("judges/{name}_{language}.j2"))
         .and_then(|f| f.contents_utf8())
 }
+
+pub fn repair_templates(name: &str) -> Option<&'static str> {
+    TEMPLATES_DIR
+        .get_file(format!("repairs/{name}.j2"))
+        .and_then(|f| f.contents_utf8())
+}
+
+pub fn augment_templates(name: &str) -> Option<&'static str> {
+    TEMPLATES_DIR
+        .get_file(format!("augment/{name}.j2"))
+        .and_then(|f| f.contents_utf8())
+}
+
+pub fn tool_templates(name: &str) -> Option<&'static str> {
+    TEMPLATES_DIR
+        .get_file(format!("tools/{name}.j2"))
+        .and_then(|f| f.contents_utf8())
+}
+
+pub fn dialogue_templates(name: &str) -> Option<&'static str> {
+    TEMPLATES_DIR
+        .get_file(format!("dialogue/{name}.j2"))
+        .and_then(|f| f.contents_utf8())
+}
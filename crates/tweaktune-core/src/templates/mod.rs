@@ -1,12 +1,13 @@
 pub mod embed;
-use crate::common::{kthash, OptionToResult, ResultExt};
+use crate::common::{derive_seed, item_rng, kthash, OptionToResult, ResultExt};
 use crate::readers::build_reader;
 use crate::steps::StepContextData;
 use anyhow::{bail, Result};
 use log::{debug, error};
-use minijinja::Environment;
+use minijinja::{Environment, State};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::{rng, Rng};
+use rand::Rng;
 use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
@@ -16,11 +17,23 @@ use std::sync::{OnceLock, RwLock};
 
 static ENVIRONMENT: RwLock<OnceLock<Environment>> = RwLock::new(OnceLock::new());
 
+/// Rng of a random filter for the item being rendered, taken from its `index`.
+fn filter_rng(seed: Option<u64>, filter: &str, state: &State) -> StdRng {
+    let index = state
+        .lookup("index")
+        .and_then(|index| u64::try_from(index).ok())
+        .unwrap_or(0);
+    item_rng(seed.map(|seed| derive_seed(seed, filter)), index)
+}
+
 static CHATTEMPLATE_ENVIRONMENT: RwLock<OnceLock<Environment>> = RwLock::new(OnceLock::new());
 
 #[derive(Default, Clone, Deserialize)]
 pub struct Templates {
     pub templates: HashMap<String, String>,
+    /// Makes `shuffle` and `random_range` reproducible per item `index`.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 impl Templates {
@@ -88,11 +101,12 @@ impl Templates {
             }
         });
 
-        e.add_filter("shuffle", |value: String| {
+        let seed = self.seed;
+        e.add_filter("shuffle", move |state: &State, value: String| {
             match serde_json::from_str::<Vec<serde_json::Value>>(&value) {
                 Ok(arr) => {
                     let mut arr = arr;
-                    arr.shuffle(&mut rng());
+                    arr.shuffle(&mut filter_rng(seed, "shuffle", state));
                     let val = serde_json::to_string(&arr);
                     match val {
                         Ok(v) => v,
@@ -109,7 +123,7 @@ impl Templates {
             }
         });
 
-        e.add_filter("random_range", |value: String| {
+        e.add_filter("random_range", move |state: &State, value: String| {
             let bounds: Vec<&str> = value.split(',').collect();
             if bounds.len() != 2 {
                 error!(target: "templates_err", "🐔 random_range filter requires two comma-separated arguments");
@@ -133,7 +147,7 @@ impl Templates {
                 error!(target: "templates_err", "🐔 random_int filter requires start < end");
                 return value;
             }
-            let rand_int = rand::rng().random_range(start..end);
+            let rand_int = filter_rng(seed, "random_range", state).random_range(start..end);
             rand_int.to_string()
         });
 
@@ -1,5 +1,30 @@
+use crate::common::{hf_hub_get, ResultExt};
+use anyhow::{bail, Result};
+use std::path::Path;
+use text_splitter::ChunkSizer;
 use tokenizers::{Encoding, Tokenizer};
 
+/// Which part of the text is kept when truncating to a token budget.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TruncateStrategy {
+    Head,
+    Tail,
+    Middle,
+}
+
+impl std::str::FromStr for TruncateStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "head" => Ok(TruncateStrategy::Head),
+            "tail" => Ok(TruncateStrategy::Tail),
+            "middle" => Ok(TruncateStrategy::Middle),
+            _ => bail!("🐔 Unsupported truncate strategy: {}", s),
+        }
+    }
+}
+
 pub struct TokenizerWrapper {
     pub tokenizer: Tokenizer,
 }
@@ -8,6 +33,17 @@ impl TokenizerWrapper {
         Self { tokenizer }
     }
 
+    /// Loads a `tokenizer.json` from a local path or from a Hugging Face model repo.
+    pub fn load(path_or_repo: &str, hf_token: Option<String>) -> Result<Self> {
+        let tokenizer = if Path::new(path_or_repo).is_file() {
+            Tokenizer::from_file(path_or_repo).map_anyhow_err()?
+        } else {
+            let bytes = hf_hub_get(path_or_repo, "tokenizer.json", hf_token, None)?;
+            Tokenizer::from_bytes(&bytes).map_anyhow_err()?
+        };
+        Ok(Self::new(tokenizer))
+    }
+
     pub fn encode(&self, text: &str) -> Result<Encoding, tokenizers::Error> {
         self.tokenizer.encode(text, true)
     }
@@ -16,4 +52,124 @@ impl TokenizerWrapper {
         let encoding = self.encode(text)?;
         Ok(encoding.len())
     }
+
+    /// Cuts `text` down to `max_tokens` tokens (special tokens excluded) on token
+    /// boundaries of the original text. `Head` keeps the beginning, `Tail` the end and
+    /// `Middle` keeps both ends joined with `marker`.
+    pub fn truncate(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        strategy: &TruncateStrategy,
+        marker: &str,
+    ) -> Result<String> {
+        let encoding = self.tokenizer.encode(text, false).map_anyhow_err()?;
+        let offsets = encoding.get_offsets();
+        if offsets.len() <= max_tokens {
+            return Ok(text.to_string());
+        }
+
+        let head = |n: usize| match n {
+            0 => "",
+            n => &text[..offsets[n - 1].1],
+        };
+        let tail = |n: usize| match n {
+            0 => "",
+            n => &text[offsets[offsets.len() - n].0..],
+        };
+        Ok(match strategy {
+            TruncateStrategy::Head => head(max_tokens).to_string(),
+            TruncateStrategy::Tail => tail(max_tokens).to_string(),
+            TruncateStrategy::Middle => {
+                let kept_tail = max_tokens / 2;
+                format!(
+                    "{}{}{}",
+                    head(max_tokens - kept_tail),
+                    marker,
+                    tail(kept_tail)
+                )
+            }
+        })
+    }
+}
+
+// text-splitter's own tokenizers sizer needs the onig regex backend, so size chunks here
+impl ChunkSizer for TokenizerWrapper {
+    fn size(&self, chunk: &str) -> usize {
+        self.tokenizer
+            .encode(chunk, false)
+            .map(|encoding| encoding.len())
+            .unwrap_or_else(|_| chunk.chars().count())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// Whitespace word-level tokenizer over a tiny vocabulary.
+    pub(crate) fn word_tokenizer() -> TokenizerWrapper {
+        let words = [
+            "[UNK]", "the", "quick", "brown", "fox", "jumps", "over", "lazy", "dog", ".",
+        ];
+        let vocab = words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.to_string(), serde_json::json!(i)))
+            .collect::<serde_json::Map<_, _>>();
+        let config = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]"}
+        });
+        TokenizerWrapper::new(Tokenizer::from_str(&config.to_string()).unwrap())
+    }
+
+    #[test]
+    fn test_count() {
+        let tokenizer = word_tokenizer();
+        assert_eq!(tokenizer.count("the quick brown fox.").unwrap(), 5);
+        assert_eq!(tokenizer.count("").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let tokenizer = word_tokenizer();
+        let text = "the quick brown fox jumps over the lazy dog.";
+
+        let truncate = |max_tokens, strategy| {
+            tokenizer
+                .truncate(text, max_tokens, &strategy, " ... ")
+                .unwrap()
+        };
+        assert_eq!(truncate(3, TruncateStrategy::Head), "the quick brown");
+        assert_eq!(truncate(3, TruncateStrategy::Tail), "lazy dog.");
+        assert_eq!(
+            truncate(5, TruncateStrategy::Middle),
+            "the quick brown ... dog."
+        );
+        assert_eq!(truncate(20, TruncateStrategy::Middle), text);
+        assert_eq!(truncate(0, TruncateStrategy::Head), "");
+        assert!("sideways".parse::<TruncateStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_chunk_sizer() {
+        let tokenizer = word_tokenizer();
+        let text = "the quick brown fox jumps over the lazy dog. the lazy dog jumps over the quick brown fox.";
+        let splitter = text_splitter::TextSplitter::new(
+            text_splitter::ChunkConfig::new(2..5).with_sizer(&tokenizer),
+        );
+        let chunks: Vec<&str> = splitter.chunks(text).collect();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| tokenizer.size(chunk) <= 5));
+        assert_eq!(chunks.join(" "), text);
+    }
 }
@@ -0,0 +1,41 @@
+#![cfg(feature = "integration-tests")]
+//RUN_BERT_INTEGRATION=1 cargo test -p tweaktune-core --features integration-tests -- --nocapture
+use std::env;
+use tweaktune_core::embeddings::bert::{BertEmbeddingsModel, BertSpec, Pooling, BERT_MODEL_REPO};
+use tweaktune_core::embeddings::{cosine_similarity, Embeddings};
+
+#[test]
+fn bert_integration_test() -> Result<(), anyhow::Error> {
+    if env::var("RUN_BERT_INTEGRATION").unwrap_or_default() != "1" {
+        eprintln!("Skipping bert integration test because RUN_BERT_INTEGRATION != 1");
+        return Ok(());
+    }
+
+    let spec = BertSpec {
+        name: "test".to_string(),
+        model_repo: Some(BERT_MODEL_REPO.to_string()),
+        device: None,
+        hf_token: env::var("HF_TOKEN").ok(),
+        pooling: Some(Pooling::Mean),
+        normalize: Some(true),
+    };
+
+    let instance = BertEmbeddingsModel::lazy(spec)?;
+    let guard = instance
+        .lock()
+        .map_err(|e| anyhow::anyhow!("lock error: {:?}", e))?;
+
+    let emb = guard.embed(vec![
+        "How do I reset my password?".to_string(),
+        "I forgot my password, how can I change it?".to_string(),
+        "The weather is sunny today.".to_string(),
+    ])?;
+
+    assert_eq!(emb.len(), 3);
+    assert_eq!(emb[0].len(), 384);
+    let norm: f32 = emb[0].iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-3);
+    assert!(cosine_similarity(&emb[0], &emb[1]) > cosine_similarity(&emb[0], &emb[2]));
+
+    Ok(())
+}
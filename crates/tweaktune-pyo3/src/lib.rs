@@ -2,4 +2,6 @@ pub mod chat_template;
 pub mod common;
 pub mod logging;
 pub mod pipeline;
+pub mod profiling;
 pub mod steps;
+pub mod telemetry;
@@ -1,36 +1,73 @@
+use crate::profiling::Allocations;
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::{Cell, ContentArrangement, Table};
 use log::{Level, Log, Metadata, Record};
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use pyo3::{pyclass, pymethods};
+use serde_json::{json, Value};
 use simplelog::{Config, LevelFilter, SharedLogger};
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BusEvent {
-    pub event_type: String,
-    pub data: Value,
+/// Event of a run delivered to the `bus` of `run`, a queue (`put`) or a callback.
+#[pyclass(frozen)]
+#[derive(Debug, Clone)]
+pub enum PipelineEvent {
+    /// A dataset read, before the run starts.
+    DatasetLoaded {
+        name: String,
+        rows: usize,
+        /// Seconds.
+        duration: f64,
+    },
+    RunStarted {
+        run_id: String,
+        name: String,
+        /// Number of items when known upfront.
+        total: Option<usize>,
+    },
+    ItemCompleted {
+        index: Option<i64>,
+        /// Seconds.
+        duration: f64,
+        /// Tokens reported by the API LLM calls of the item.
+        tokens: u64,
+    },
+    ItemFailed {
+        index: Option<i64>,
+        step: Option<String>,
+        error: String,
+    },
+    RunFinished {
+        /// `completed`, `interrupted` or `failed`.
+        status: String,
+        processed: usize,
+        failed: usize,
+        /// Seconds.
+        elapsed: f64,
+        error: Option<String>,
+    },
+    Log {
+        message: String,
+    },
 }
 
-impl BusEvent {
-    pub fn build(event_type: &str, data: Value) -> String {
-        serde_json::to_string(&BusEvent {
-            event_type: event_type.to_string(),
-            data,
-        })
-        .unwrap()
+#[pymethods]
+impl PipelineEvent {
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
     }
 }
 
 pub struct ChannelWriter {
-    pub sender: Arc<mpsc::Sender<String>>,
+    pub sender: Arc<mpsc::Sender<PipelineEvent>>,
     buffer: Mutex<String>,
 }
 
 impl ChannelWriter {
-    pub fn new(sender: Arc<mpsc::Sender<String>>) -> Self {
+    pub fn new(sender: Arc<mpsc::Sender<PipelineEvent>>) -> Self {
         ChannelWriter {
             sender,
             buffer: Mutex::new(String::new()),
@@ -44,9 +81,8 @@ impl Write for ChannelWriter {
         buffer.push_str(&String::from_utf8_lossy(buf));
         while let Some(pos) = buffer.find('\n') {
             let line = buffer.drain(..=pos).collect::<String>();
-            let line = BusEvent::build("log", Value::String(line));
             self.sender
-                .send(line)
+                .send(PipelineEvent::Log { message: line })
                 .map_err(|_| std::io::Error::other("Failed to send message"))?;
         }
         Ok(buf.len())
@@ -96,8 +132,6 @@ impl LogsCollector {
         let entries = self.entries.lock().unwrap();
 
         // Build counts grouped by (level, message)
-        use std::collections::BTreeMap;
-
         let mut grouped: BTreeMap<(String, String), usize> = BTreeMap::new();
         let mut total = 0usize;
         let mut per_level: std::collections::BTreeMap<String, usize> = BTreeMap::new();
@@ -127,7 +161,7 @@ impl LogsCollector {
 
         // Sort grouped by count desc
         let mut items: Vec<((String, String), usize)> = grouped.into_iter().collect();
-        items.sort_by(|a, b| b.1.cmp(&a.1));
+        items.sort_by_key(|item| std::cmp::Reverse(item.1));
 
         for ((level, msg), count) in items.iter() {
             table.add_row(vec![
@@ -191,3 +225,298 @@ impl SharedLogger for LogsCollector {
         self
     }
 }
+
+#[derive(Debug, Default, Clone, Copy)]
+struct StepTiming {
+    count: usize,
+    total: Duration,
+    max: Duration,
+    allocations: Allocations,
+}
+
+/// StepTimings aggregates the wall-clock duration of steps by step name and
+/// renders them as a summary table.
+#[derive(Clone, Default)]
+pub struct StepTimings {
+    timings: Arc<Mutex<BTreeMap<String, StepTiming>>>,
+}
+
+impl StepTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, step: &str, elapsed: Duration, allocations: Allocations) {
+        if let Ok(mut timings) = self.timings.lock() {
+            let timing = timings.entry(step.to_string()).or_default();
+            timing.count += 1;
+            timing.total += elapsed;
+            timing.max = timing.max.max(elapsed);
+            timing.allocations.bytes += allocations.bytes;
+            timing.allocations.count += allocations.count;
+        }
+    }
+
+    pub fn reset(&self) {
+        self.timings.lock().unwrap().clear();
+    }
+
+    /// Steps sorted by total time, slowest first. Durations of steps wrapping
+    /// other steps (retry, ifelse, ...) include the wrapped steps.
+    pub fn summary_table(&self) -> String {
+        let timings = self.timings.lock().unwrap();
+        let mut items: Vec<(&String, &StepTiming)> = timings.iter().collect();
+        items.sort_by_key(|(_, timing)| std::cmp::Reverse(timing.total));
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic);
+        table.set_header(vec![
+            Cell::from("Step"),
+            Cell::from("Count"),
+            Cell::from("Total [s]"),
+            Cell::from("Mean [ms]"),
+            Cell::from("Max [ms]"),
+        ]);
+        for (step, timing) in items {
+            let mean = timing.total.as_secs_f64() * 1000.0 / timing.count.max(1) as f64;
+            table.add_row(vec![
+                Cell::from(step.clone()),
+                Cell::from(timing.count.to_string()),
+                Cell::from(format!("{:.3}", timing.total.as_secs_f64())),
+                Cell::from(format!("{:.1}", mean)),
+                Cell::from(format!("{:.1}", timing.max.as_secs_f64() * 1000.0)),
+            ]);
+        }
+        table.to_string()
+    }
+
+    /// Profile of a run that took `wall` with up to `workers` items at once: the share of the
+    /// worker time each step took and its allocations per call, followed by a hint on where
+    /// to look first.
+    pub fn profile_table(&self, wall: Duration, workers: usize) -> String {
+        let timings = self.timings.lock().unwrap();
+        let mut items: Vec<(&String, &StepTiming)> = timings.iter().collect();
+        items.sort_by_key(|(_, timing)| std::cmp::Reverse(timing.total));
+        let capacity = wall.as_secs_f64() * workers.max(1) as f64;
+        let share = |timing: &StepTiming| timing.total.as_secs_f64() * 100.0 / capacity.max(1e-9);
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic);
+        table.set_header(vec![
+            Cell::from("Step"),
+            Cell::from("Count"),
+            Cell::from("Total [s]"),
+            Cell::from("Share [%]"),
+            Cell::from("Mean [ms]"),
+            Cell::from("Max [ms]"),
+            Cell::from("Alloc/call [KB]"),
+            Cell::from("Allocs/call"),
+        ]);
+        for (step, timing) in &items {
+            let count = timing.count.max(1) as f64;
+            table.add_row(vec![
+                Cell::from(step.to_string()),
+                Cell::from(timing.count.to_string()),
+                Cell::from(format!("{:.3}", timing.total.as_secs_f64())),
+                Cell::from(format!("{:.1}", share(timing))),
+                Cell::from(format!(
+                    "{:.1}",
+                    timing.total.as_secs_f64() * 1000.0 / count
+                )),
+                Cell::from(format!("{:.1}", timing.max.as_secs_f64() * 1000.0)),
+                Cell::from(format!(
+                    "{:.1}",
+                    timing.allocations.bytes as f64 / 1024.0 / count
+                )),
+                Cell::from(format!("{:.0}", timing.allocations.count as f64 / count)),
+            ]);
+        }
+
+        let hint = match items.first() {
+            None => "No steps ran.".to_string(),
+            Some((step, timing)) if share(timing) < 50.0 => format!(
+                "The slowest step, {}, kept the {} workers busy {:.0}% of the {:.1}s run: \
+                 reading the input or writing the outputs holds it back, try larger chunks.",
+                step,
+                workers,
+                share(timing),
+                wall.as_secs_f64(),
+            ),
+            Some((step, timing)) => format!(
+                "{} kept the {} workers busy {:.0}% of the {:.1}s run: add workers if it \
+                 waits on LLM calls or requests, or cache it if items repeat.",
+                step,
+                workers,
+                share(timing),
+                wall.as_secs_f64(),
+            ),
+        };
+        format!("{}\n{}", table, hint)
+    }
+}
+
+/// Latencies kept per step for the percentiles of the run report, the most recent ones.
+const LATENCY_SAMPLES: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct StepStats {
+    succeeded: usize,
+    failed: usize,
+    /// Milliseconds, a ring of the last `LATENCY_SAMPLES`.
+    latencies: Vec<f64>,
+    errors: BTreeMap<&'static str, usize>,
+}
+
+/// RunStats collects the per-step outcomes, errors and token usage of a run for the
+/// machine-readable report.
+#[derive(Default)]
+pub struct RunStats {
+    steps: Mutex<BTreeMap<String, StepStats>>,
+    tokens: std::sync::atomic::AtomicU64,
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&self) {
+        self.steps.lock().unwrap().clear();
+        self.tokens.store(0, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn record_step(&self, step: &str, elapsed: Duration, error: Option<&str>) {
+        let mut steps = self.steps.lock().unwrap();
+        let stats = steps.entry(step.to_string()).or_default();
+        let runs = stats.succeeded + stats.failed;
+        match error {
+            Some(error) => {
+                stats.failed += 1;
+                *stats.errors.entry(error_category(error)).or_insert(0) += 1;
+            }
+            None => stats.succeeded += 1,
+        }
+        let latency = elapsed.as_secs_f64() * 1000.0;
+        if stats.latencies.len() < LATENCY_SAMPLES {
+            stats.latencies.push(latency);
+        } else {
+            stats.latencies[runs % LATENCY_SAMPLES] = latency;
+        }
+    }
+
+    pub fn record_tokens(&self, tokens: u64) {
+        self.tokens
+            .fetch_add(tokens, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.tokens.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Per-step counts, latency percentiles in milliseconds and error categories, the errors
+    /// of all steps by category and the tokens used.
+    pub fn report(&self) -> Value {
+        let steps = self.steps.lock().unwrap();
+        let mut errors = BTreeMap::new();
+        let steps = steps
+            .iter()
+            .map(|(name, stats)| {
+                for (category, count) in &stats.errors {
+                    *errors.entry(*category).or_insert(0) += count;
+                }
+                let mut latencies = stats.latencies.clone();
+                latencies.sort_by(f64::total_cmp);
+                let percentile = |p: f64| {
+                    let rank = (p * latencies.len() as f64).ceil() as usize;
+                    latencies.get(rank.saturating_sub(1)).copied()
+                };
+                let stats = json!({
+                    "succeeded": stats.succeeded,
+                    "failed": stats.failed,
+                    "latency_ms": {
+                        "p50": percentile(0.5),
+                        "p90": percentile(0.9),
+                        "p99": percentile(0.99),
+                        "max": latencies.last(),
+                    },
+                    "errors": stats.errors,
+                });
+                (name.clone(), stats)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "steps": steps,
+            "errors": errors,
+            "tokens": self.tokens.load(std::sync::atomic::Ordering::SeqCst),
+        })
+    }
+}
+
+/// Comparison of the runs of a sweep, one row per run of its report.
+pub fn sweep_table(runs: &[Value]) -> String {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::Dynamic);
+    table.set_header(vec![
+        Cell::from("Run"),
+        Cell::from("Params"),
+        Cell::from("Status"),
+        Cell::from("Processed"),
+        Cell::from("Failed"),
+        Cell::from("Tokens"),
+        Cell::from("Cost"),
+        Cell::from("Elapsed [s]"),
+    ]);
+    let text = |value: &Value| match value {
+        Value::Null => "-".to_string(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    };
+    for run in runs {
+        table.add_row(vec![
+            Cell::from(text(&run["run"])),
+            Cell::from(text(&run["params"])),
+            Cell::from(text(&run["status"])),
+            Cell::from(text(&run["processed"])),
+            Cell::from(text(&run["failed"])),
+            Cell::from(text(&run["tokens"])),
+            Cell::from(match run["cost"].as_f64() {
+                Some(cost) => format!("{:.4}", cost),
+                None => "-".to_string(),
+            }),
+            Cell::from(match run["elapsed"].as_f64() {
+                Some(elapsed) => format!("{:.1}", elapsed),
+                None => "-".to_string(),
+            }),
+        ]);
+    }
+    table.to_string()
+}
+
+/// Coarse category of a step error for the run report.
+fn error_category(error: &str) -> &'static str {
+    let error = error.to_lowercase();
+    if error.contains("429") || error.contains("rate limit") {
+        "rate_limited"
+    } else if error.contains("timeout") || error.contains("timed out") {
+        "timeout"
+    } else if error.contains("valid") || error.contains("schema") {
+        "validation"
+    } else if error.contains("json") || error.contains("parse") {
+        "parse"
+    } else if error.contains("llm") || error.contains("status") || error.contains("connect") {
+        "llm"
+    } else if error.contains("pyerr") || error.contains("traceback") {
+        "python"
+    } else {
+        "other"
+    }
+}
@@ -1,52 +1,111 @@
 use crate::common::ResultExt;
-use crate::logging::{BusEvent, ChannelWriter, LogsCollector};
+use crate::logging::{
+    sweep_table, ChannelWriter, LogsCollector, PipelineEvent, RunStats, StepTimings,
+};
+use crate::profiling::{track_allocations, Allocations};
+use crate::telemetry::{end_span, in_span, Telemetry};
 use anyhow::{bail, Result};
 use chrono::Local;
 use core::fmt;
 use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, error, info};
-use pyo3::{pyclass, pymethods, PyObject, PyRef, PyResult, Python};
-use serde_json::json;
+use log::{debug, error, info, warn};
+use pyo3::types::PyAnyMethods;
+use pyo3::{pyclass, pymethods, Py, PyObject, PyRef, PyRefMut, PyResult, Python};
+use serde_json::{json, Value};
 use simplelog::*;
+use std::collections::HashMap;
 use std::fs::{create_dir_all, File};
+use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Once};
 use std::thread;
-use tweaktune_core::common::{blake3_hash, deserialize, run_async, SerializationType};
+use std::time::Duration;
+use tweaktune_core::common::{
+    blake3_hash, create_rows_stream, deserialize, item_rng, khash, run_async, serialize,
+    workers::{in_worker, WorkerIds},
+    SerializationType,
+};
 use tweaktune_core::datasets::{
-    CsvDataset, Dataset as DatasetTrait, IpcDataset, JsonlDataset, MixedDataset, ParquetDataset,
-    PhfSetDataset, PolarsDataset,
+    epoch_rows, fingerprint_df, product_rows, zip_rows, CsvDataset, DatasetLoader, IpcDataset,
+    JsonlDataset, LoadedDatasets, ParquetDataset, PhfSetDataset, PolarsDataset,
+};
+use tweaktune_core::embeddings::{
+    bert::{BertSpec, Pooling},
+    cluster::cluster_embeddings,
+    e5::E5Spec,
 };
-use tweaktune_core::embeddings::e5::E5Spec;
 use tweaktune_core::llms::{ApiLLMMode, MistralrsLLM, UnslothLLM};
 use tweaktune_core::readers::read_to_string;
+use tweaktune_core::seq2seq::{Seq2SeqSpec, Which};
 use tweaktune_core::steps::conversations::{
-    RenderConversationStep, RenderDPOStep, RenderGRPOStep, RenderToolCallStep,
+    AugmentConversationStep, ConvertFormatStep, DialogueStep, RenderConversationStep,
+    RenderDPOStep, RenderGRPOStep, RenderToolCallStep, TruncateConversationStep,
+};
+use tweaktune_core::steps::dag::{self, StepIo};
+use tweaktune_core::steps::embeddings::{
+    CheckEmbeddingStep, EmbedStep, GroundedGenerationStep, RetrieveStep, SimilarityFilterStep,
+};
+use tweaktune_core::steps::generators::{
+    translate_template, JudgeConversationStep, JudgeStep, JudgeType as JudgeTypeCore,
+    PairwiseJudgeStep, PreferencePairStep, RepairJsonStep, SelfConsistencyStep, TranslateBackend,
+    TranslateStep,
+};
+use tweaktune_core::steps::pii::PiiRedactionStep;
+use tweaktune_core::steps::quality::{
+    CheckGroundingStep, CheckHashStep, CheckLanguageStep, CheckLengthStep, CheckSimHashStep,
+    ItemIdStep, LengthBounds, Reward, RewardsStep, VerifyMathStep,
+};
+use tweaktune_core::steps::references;
+use tweaktune_core::steps::shell::{CodeTestsStep, ExecuteCodeStep, SandboxLimits, ShellStep};
+use tweaktune_core::steps::text::{
+    CleanupStep, RegexExtractStep, RegexReplaceStep, TokenCountStep, TruncateTokensStep,
+};
+use tweaktune_core::steps::tools::{
+    openapi_operations, NegativeToolSamplerStep, SimulateToolResponseStep, ToolResponseMode,
 };
-use tweaktune_core::steps::embeddings::CheckEmbeddingStep;
-use tweaktune_core::steps::generators::{JudgeConversationStep, JudgeType as JudgeTypeCore};
-use tweaktune_core::steps::quality::{CheckHashStep, CheckLanguageStep, CheckSimHashStep};
+#[cfg(feature = "kafka")]
+use tweaktune_core::steps::writers::KafkaWriterStep;
 use tweaktune_core::steps::{
-    logic::{FilterStep, MutateStep},
+    dataset_df,
+    logic::{
+        DropKeysStep, FilterStep, JqStep, JsonPathStep, MapKeysStep, MutateStep, SelectKeysStep,
+        SqlStep,
+    },
+    sample_dataset,
     validators::{
-        ConversationValidateStep, ToolsNormalizeStep, ToolsValidateStep, ValidateJsonStep,
+        ConversationValidateStep, ExtractStructuredStep, ToolsNormalizeStep, ToolsValidateStep,
+        ValidateJsonStep,
     },
-    ChunkStep, IfElseStep, IntoListStep, RenderStep,
+    AccumulateStep, CacheStep, ChunkStep, ErrorPolicy, ExplodeStep, ForEachStep, IfElseStep,
+    IntoListStep, LoopStep, ParallelStep, RenderStep, RetryStep, SubPipelineStep, SwitchStep,
+    ZipStep,
 };
+use tweaktune_core::tokenizers::{TokenizerWrapper, TruncateStrategy};
 use tweaktune_core::PipelineResources;
 use tweaktune_core::{
     common::OptionToResult,
+    config::{read_config, redact_secrets, ReplaceTokens},
     datasets::{DatasetType, JsonDataset, JsonListDataset, OpenApiDataset},
-    embeddings::{EmbeddingsType, OpenAIEmbeddings},
-    llms::{ApiLLM, LLMType},
+    embeddings::{
+        embed_cached, CohereEmbeddings, EmbeddingPrecision, EmbeddingsType, JinaEmbeddings,
+        OpenAIEmbeddings,
+    },
+    llms::{track_llm_usage, ApiLLM, LLMType, LlmPressure, LlmUsage, LLM_PRESSURE},
     state::State,
     steps::{
+        finish_steps,
         generators::{JsonGenerationStep, TextGenerationStep},
-        py::{PyStep, PyValidator},
-        writers::{CsvWriterStep, JsonlWriterStep},
-        DataSamplerStep, PrintStep, Step as StepCore, StepContext, StepStatus, StepType,
+        py::{PyStep, PyValidator, PyWriterStep},
+        writers::{
+            is_writer, redirect, suffixed_path, Aggregation, ConflictAction, CsvFormat,
+            CsvWriterStep, EmbeddingsFormat, EmbeddingsWriterStep, GroupByStep, HttpWriterStep,
+            IpcWriterStep, JsonlWriterStep, PostgresWriterStep, SqliteWriterStep, TeeStep,
+            WriterDedup,
+        },
+        DataSamplerStep, DumpStep, PersonaStep, PrintStep, Step as StepCore, StepContext,
+        StepStatus, StepType, WeightedChoiceStep,
     },
     templates::Templates,
 };
@@ -55,12 +114,14 @@ use tweaktune_core::{
 #[derive(Debug, Clone)]
 pub enum InternalDatasetType {
     Openings,
+    Personas,
 }
 
 impl fmt::Display for InternalDatasetType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             InternalDatasetType::Openings => "openings",
+            InternalDatasetType::Personas => "personas",
         };
         write!(f, "{}", s)
     }
@@ -127,13 +188,40 @@ pub struct PipelineBuilder {
     #[allow(dead_code)]
     name: String,
     workers: usize,
+    worker_ids: WorkerIds,
     resources: PipelineResources,
     steps: Vec<StepType>,
     iter_by: IterBy,
+    params: serde_json::Map<String, Value>,
     running: Arc<AtomicBool>,
+    paused: AtomicBool,
     logs_collector: Arc<LogsCollector>,
     log_path: Option<String>,
     metadata: Metadata,
+    allow_shell: bool,
+    quarantine: Option<String>,
+    /// The open quarantine file and its path, shared by the items of the run.
+    quarantine_file: std::sync::Mutex<Option<(String, File)>>,
+    error_policies: HashMap<String, ErrorPolicy>,
+    writer_dedup: HashMap<String, WriterDedup>,
+    failures: FailureBudget,
+    stop_condition: StopCondition,
+    until: Option<RunUntil>,
+    shutdown_timeout: Duration,
+    autoscaler: Option<Autoscaler>,
+    epochs: usize,
+    shuffle_epochs: bool,
+    dag: bool,
+    step_io: HashMap<String, StepIo>,
+    dag_waves: std::sync::RwLock<Vec<Vec<usize>>>,
+    subpipelines: HashMap<String, PipelineBuilder>,
+    step_timings: Option<String>,
+    timings: StepTimings,
+    profiling: std::sync::atomic::AtomicBool,
+    processed: Arc<std::sync::atomic::AtomicUsize>,
+    events: std::sync::RwLock<Option<Arc<mpsc::Sender<PipelineEvent>>>>,
+    stats: RunStats,
+    telemetry: Option<Telemetry>,
 }
 
 #[pymethods]
@@ -160,6 +248,7 @@ impl PipelineBuilder {
             id: uuid::Uuid::new_v4(),
             name,
             workers: 1,
+            worker_ids: WorkerIds::default(),
             resources: PipelineResources::new(state),
             steps: vec![],
             iter_by: IterBy::Range {
@@ -167,10 +256,35 @@ impl PipelineBuilder {
                 stop: 0,
                 step: 1,
             },
+            params: serde_json::Map::new(),
             running: Arc::new(AtomicBool::new(false)),
+            paused: AtomicBool::new(false),
             logs_collector: Arc::new(LogsCollector::new()),
             log_path: None,
             metadata,
+            allow_shell: false,
+            quarantine: None,
+            quarantine_file: std::sync::Mutex::new(None),
+            error_policies: HashMap::new(),
+            writer_dedup: HashMap::new(),
+            failures: FailureBudget::default(),
+            stop_condition: StopCondition::default(),
+            until: None,
+            shutdown_timeout: Duration::from_secs(30),
+            autoscaler: None,
+            epochs: 1,
+            shuffle_epochs: true,
+            dag: false,
+            step_io: HashMap::new(),
+            dag_waves: std::sync::RwLock::new(Vec::new()),
+            subpipelines: HashMap::new(),
+            step_timings: None,
+            timings: StepTimings::new(),
+            profiling: std::sync::atomic::AtomicBool::new(false),
+            processed: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            events: std::sync::RwLock::new(None),
+            stats: RunStats::new(),
+            telemetry: None,
         }
     }
 
@@ -179,12 +293,294 @@ impl PipelineBuilder {
         debug!("Setting workers to {}", workers);
     }
 
+    /// Adjusts the number of items processed at once between `min_workers` and `max_workers`,
+    /// starting from the workers: halved when LLM calls are rate limited, fail or take longer
+    /// than `target_latency_ms`, raised by one while the endpoint is healthy.
+    #[pyo3(signature = (min_workers=1, max_workers=32, target_latency_ms=None))]
+    pub fn with_autoscaling(
+        &mut self,
+        min_workers: usize,
+        max_workers: usize,
+        target_latency_ms: Option<u64>,
+    ) -> PyResult<()> {
+        if min_workers == 0 || min_workers > max_workers {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Autoscaling needs 0 < min_workers <= max_workers",
+            ));
+        }
+        self.autoscaler = Some(Autoscaler::new(
+            min_workers,
+            max_workers,
+            target_latency_ms.map(Duration::from_millis),
+            &LLM_PRESSURE,
+        ));
+        Ok(())
+    }
+
+    pub fn with_shell_commands(&mut self, enabled: bool) {
+        self.allow_shell = enabled;
+        debug!("Setting shell commands enabled to {}", enabled);
+    }
+
+    pub fn with_quarantine(&mut self, path: String) {
+        debug!("Writing failed items to quarantine: {}", &path);
+        self.quarantine = Some(path);
+    }
+
+    /// Sets what happens when the last added step returns an error, see [`ErrorPolicy`].
+    #[pyo3(signature = (policy, max_attempts=3, backoff_ms=500))]
+    pub fn set_error_policy(
+        &mut self,
+        policy: String,
+        max_attempts: usize,
+        backoff_ms: u64,
+    ) -> PyResult<()> {
+        let policy = ErrorPolicy::parse(&policy, max_attempts, backoff_ms).map_pyerr()?;
+        let Some(step) = self.steps.last() else {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "An error policy needs a step to apply to",
+            ));
+        };
+        debug!("Error policy of {}: {:?}", step.name(), policy);
+        self.error_policies.insert(step.name().to_string(), policy);
+        Ok(())
+    }
+
+    /// Keeps the last added writer from writing records earlier runs already wrote, see
+    /// [`WriterDedup`].
+    #[pyo3(signature = (inputs, key=None, simhash_threshold=None))]
+    pub fn set_writer_dedup(
+        &mut self,
+        inputs: Vec<String>,
+        key: Option<String>,
+        simhash_threshold: Option<u32>,
+    ) -> PyResult<()> {
+        let Some(step) = self.steps.last().filter(|step| is_writer(step)) else {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Deduplication needs a writer step to apply to",
+            ));
+        };
+        if self.resources.state.is_none() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Deduplication across runs needs the metadata to be enabled",
+            ));
+        }
+        let dedup = WriterDedup::new(inputs, key, simhash_threshold).map_pyerr()?;
+        debug!("Deduplication of {}: {:?}", step.name(), dedup);
+        self.writer_dedup.insert(step.name().to_string(), dedup);
+        Ok(())
+    }
+
+    /// Declares the context keys the last added step reads and writes, see [`dag::plan`].
+    pub fn set_step_io(&mut self, inputs: Vec<String>, outputs: Vec<String>) -> PyResult<()> {
+        let Some(step) = self.steps.last() else {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Inputs and outputs need a step to apply to",
+            ));
+        };
+        self.step_io
+            .insert(step.name().to_string(), StepIo { inputs, outputs });
+        Ok(())
+    }
+
+    /// Runs the steps of an item as a DAG of their declared inputs and outputs, steps that
+    /// don't depend on each other run concurrently.
+    #[pyo3(signature = (enabled=true))]
+    pub fn with_dag(&mut self, enabled: bool) {
+        self.dag = enabled;
+    }
+
+    /// Aborts the run once more than `count` items, or more than the `ratio` of at least
+    /// `min_items` processed items, failed with an error. Below the limit errors don't fail the run.
+    #[pyo3(signature = (count=None, ratio=None, min_items=10))]
+    pub fn with_max_failures(
+        &mut self,
+        count: Option<usize>,
+        ratio: Option<f64>,
+        min_items: usize,
+    ) -> PyResult<()> {
+        let limit = match (count, ratio) {
+            (Some(count), None) => MaxFailures::Count(count),
+            (None, Some(ratio)) if (0.0..1.0).contains(&ratio) => {
+                MaxFailures::Ratio(ratio, min_items.max(1))
+            }
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Max failures must be a count or a ratio between 0 and 1",
+                ))
+            }
+        };
+        debug!("Aborting the run after failures over: {:?}", limit);
+        self.failures.limit = Some(limit);
+        Ok(())
+    }
+
+    /// Stops starting new items once `max_items` were started, `max_tokens` used by the API LLMs,
+    /// `max_cost` spent at `cost_per_1k_tokens` or `max_duration` seconds elapsed. The run
+    /// finishes the items in progress and ends as `stopped`.
+    #[pyo3(signature = (max_items=None, max_tokens=None, max_cost=None, max_duration=None, cost_per_1k_tokens=None))]
+    pub fn with_stop_condition(
+        &mut self,
+        max_items: Option<usize>,
+        max_tokens: Option<u64>,
+        max_cost: Option<f64>,
+        max_duration: Option<f64>,
+        cost_per_1k_tokens: Option<f64>,
+    ) -> PyResult<()> {
+        let max_cost = match (max_cost, cost_per_1k_tokens) {
+            (Some(max_cost), Some(price)) => Some((max_cost, price)),
+            (Some(_), None) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "A max cost needs the cost per 1k tokens",
+                ))
+            }
+            (None, _) => None,
+        };
+        let max_duration = max_duration
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+        if max_items.is_none()
+            && max_tokens.is_none()
+            && max_cost.is_none()
+            && max_duration.is_none()
+        {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "A stop condition needs at least one limit",
+            ));
+        }
+        self.stop_condition = StopCondition {
+            max_items,
+            max_tokens,
+            max_cost,
+            max_duration,
+            ..Default::default()
+        };
+        debug!(
+            "Stopping the run at items: {:?}, tokens: {:?}, cost: {:?}, duration: {:?}",
+            max_items, max_tokens, max_cost, max_duration
+        );
+        Ok(())
+    }
+
+    /// Seeds sampling, random choices, template filters and API LLM requests, so a run can be
+    /// reproduced. Steps given their own `seed` keep it.
+    pub fn with_seed(&mut self, seed: u64) {
+        self.resources.set_seed(seed);
+    }
+
+    /// Sets the run parameter `key` to the JSON `value`, `${VAR}` in its strings are replaced
+    /// with environment variables. Every item gets the parameters as `params`.
+    pub fn with_param(&mut self, key: String, value: String) -> PyResult<()> {
+        let mut value = serde_json::from_str::<Value>(&value).map_pyerr()?;
+        ReplaceTokens::replace_value(&mut value).map_err(|e| {
+            pyo3::exceptions::PyValueError::new_err(format!("🐔 Param {}: {}", key, e))
+        })?;
+        debug!("Setting the param {} to {}", key, value);
+        self.params.insert(key, value);
+        Ok(())
+    }
+
+    /// Seeded steps draw from a random stream of each worker instead of one of each item.
+    pub fn with_worker_rng(&mut self) {
+        self.resources.worker_rng = true;
+    }
+
+    /// How long the running items may take to finish once the run is interrupted.
+    pub fn with_shutdown_timeout(&mut self, seconds: f64) -> PyResult<()> {
+        self.shutdown_timeout = Duration::try_from_secs_f64(seconds).map_pyerr()?;
+        Ok(())
+    }
+
+    /// Exports a span per item and per step, with the LLMs used, their tokens and the outcome,
+    /// to an OTLP/HTTP collector such as Jaeger or Tempo.
+    #[pyo3(signature = (endpoint="http://localhost:4318/v1/traces", service_name="tweaktune"))]
+    pub fn with_otlp_tracing(&mut self, endpoint: &str, service_name: &str) -> PyResult<()> {
+        debug!("Exporting traces to: {}", endpoint);
+        self.telemetry = Some(Telemetry::new(endpoint, service_name).map_pyerr()?);
+        Ok(())
+    }
+
+    /// Measures the duration of every step, `output` is the context key the
+    /// durations of an item are written to.
+    pub fn with_step_timings(&mut self, output: String) {
+        debug!("Recording step timings to: {}", &output);
+        self.step_timings = Some(output);
+    }
+
+    /// Serializes the pipeline, the `graph` of configured resources and steps recorded by
+    /// the Python API along with the run settings, to `yaml` or `json`. Secrets are redacted.
+    #[pyo3(signature = (graph, format="yaml"))]
+    pub fn to_config(&self, graph: String, format: &str) -> PyResult<String> {
+        let serialization_type = match format {
+            "yaml" | "yml" => SerializationType::YAML,
+            "json" => SerializationType::JSON,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Unsupported config format: {}, use yaml or json",
+                    format
+                )))
+            }
+        };
+        let max_failures = self.failures.limit.map(|limit| match limit {
+            MaxFailures::Count(count) => json!({ "count": count }),
+            MaxFailures::Ratio(ratio, min_items) => {
+                json!({ "ratio": ratio, "min_items": min_items })
+            }
+        });
+        let autoscaling = self.autoscaler.as_ref().map(|autoscaler| {
+            json!({
+                "min_workers": autoscaler.min,
+                "max_workers": autoscaler.max,
+                "target_latency_ms": autoscaler.target_latency.map(|latency| latency.as_millis() as u64),
+            })
+        });
+        let stop_condition = &self.stop_condition;
+        let stop_condition = (stop_condition.max_items.is_some()
+            || stop_condition.max_tokens.is_some()
+            || stop_condition.max_cost.is_some()
+            || stop_condition.max_duration.is_some())
+        .then(|| {
+            json!({
+                "max_items": stop_condition.max_items,
+                "max_tokens": stop_condition.max_tokens,
+                "max_cost": stop_condition.max_cost.map(|(max, _)| max),
+                "cost_per_1k_tokens": stop_condition.max_cost.map(|(_, price)| price),
+                "max_duration": stop_condition.max_duration.map(|max| max.as_secs_f64()),
+            })
+        });
+        let mut config = json!({
+            "name": self.name,
+            "settings": {
+                "workers": self.workers,
+                "seed": self.resources.seed,
+                "worker_rng": self.resources.worker_rng,
+                "params": self.params,
+                "epochs": self.epochs,
+                "shuffle_epochs": self.shuffle_epochs,
+                "dag": self.dag,
+                "max_failures": max_failures,
+                "autoscaling": autoscaling,
+                "stop_condition": stop_condition,
+                "shutdown_timeout": self.shutdown_timeout.as_secs_f64(),
+                "quarantine": self.quarantine,
+                "shell_commands": self.allow_shell,
+                "step_timings": self.step_timings,
+            },
+            "graph": serde_json::from_str::<Value>(&graph).map_pyerr()?,
+        });
+        redact_secrets(&mut config);
+        serialize(config, serialization_type).map_pyerr()
+    }
+
     pub fn with_openapi_dataset(&mut self, name: String, path_or_url: String) -> PyResult<()> {
         debug!("Added OPEN_API dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::OpenApi(OpenApiDataset::new(name, path_or_url)?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::OpenApi(OpenApiDataset::new(
+                name,
+                path_or_url,
+            )?))
+        });
         Ok(())
     }
 
@@ -196,10 +592,11 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added JSON_LIST dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::JsonList(JsonListDataset::new(name, json_list, sql)?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::JsonList(JsonListDataset::new(
+                name, json_list, sql,
+            )?))
+        });
         Ok(())
     }
 
@@ -211,19 +608,21 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added JSONL dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Jsonl(JsonlDataset::new(name, path, sql)?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Jsonl(JsonlDataset::new(name, path, sql)?))
+        });
         Ok(())
     }
 
     pub fn with_polars_dataset(&mut self, name: String, path: String, sql: String) -> PyResult<()> {
         debug!("Added POLARS dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Polars(PolarsDataset::new(name, path, Some(sql))?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Polars(PolarsDataset::new(
+                name,
+                path,
+                Some(sql),
+            )?))
+        });
         Ok(())
     }
 
@@ -235,23 +634,15 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added JSON dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Json(JsonDataset::new(name, path, sql)?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Json(JsonDataset::new(name, path, sql)?))
+        });
         Ok(())
     }
 
     pub fn with_mixed_dataset(&mut self, name: String, datasets: Vec<String>) -> PyResult<()> {
         debug!("Added MIXED dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Mixed(MixedDataset::new(
-                name,
-                datasets,
-                &self.resources.datasets.resources,
-            )?),
-        );
+        self.resources.pending_datasets.add_mixed(name, datasets);
         Ok(())
     }
 
@@ -282,6 +673,17 @@ impl PipelineBuilder {
                     )?),
                 );
             }
+            InternalDatasetType::Personas => {
+                for (attribute, set) in tweaktune_core::dictionaries::personas::ATTRIBUTES.iter() {
+                    self.resources.datasets.add(
+                        format!("{dataset}::{attribute}"),
+                        DatasetType::PhfSet(PhfSetDataset::new(
+                            format!("{dataset}::{attribute}"),
+                            set,
+                        )?),
+                    );
+                }
+            }
         }
         Ok(())
     }
@@ -294,10 +696,9 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added Parquet dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Parquet(ParquetDataset::new(name, path, sql)?),
-        );
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Parquet(ParquetDataset::new(name, path, sql)?))
+        });
         Ok(())
     }
 
@@ -309,11 +710,10 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added Ipc dataset: {}", &name);
-
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Ipc(IpcDataset::new(name, ipc_data, sql)?),
-        );
+        let ipc_data = ipc_data.to_vec();
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Ipc(IpcDataset::new(name, &ipc_data, sql)?))
+        });
         Ok(())
     }
 
@@ -327,90 +727,85 @@ impl PipelineBuilder {
         sql: Option<String>,
     ) -> PyResult<()> {
         debug!("Added CSV dataset: {}", &name);
-        self.resources.datasets.add(
-            name.clone(),
-            DatasetType::Csv(CsvDataset::new(
-                name,
-                path,
-                delimiter.as_bytes()[0],
-                has_header,
-                sql,
-            )?),
-        );
+        let delimiter = delimiter.as_bytes()[0];
+        self.resources.pending_datasets.add(name.clone(), move || {
+            Ok(DatasetType::Csv(CsvDataset::new(
+                name, path, delimiter, has_header, sql,
+            )?))
+        });
         Ok(())
     }
 
+    /// Adds an OpenAI-compatible LLM, several `api_keys` or `base_urls` are rotated through
+    /// by the workers.
     pub fn with_llm_api(
         &mut self,
         name: String,
-        base_url: String,
-        api_key: String,
+        base_urls: Vec<String>,
+        api_keys: Vec<String>,
         model: String,
         max_tokens: u32,
         temperature: f32,
-    ) {
+    ) -> PyResult<()> {
         debug!("Added LLM API: {}", &name);
-        self.resources.llms.add(
-            name.clone(),
-            LLMType::Api(ApiLLM::new(
-                name,
-                ApiLLMMode::Api {
-                    base_url,
-                    api_key,
-                    model,
-                },
-                max_tokens,
-                temperature,
-            )),
-        );
+        let modes = pair_endpoints(&name, api_keys, base_urls)?
+            .into_iter()
+            .map(|(api_key, base_url)| ApiLLMMode::Api {
+                base_url,
+                api_key,
+                model: model.clone(),
+            })
+            .collect();
+        self.add_api_llm(name, modes, max_tokens, temperature);
+        Ok(())
     }
 
+    /// Adds an OpenAI LLM, several `api_keys` are rotated through by the workers.
     pub fn with_llm_openai(
         &mut self,
         name: String,
-        api_key: String,
+        api_keys: Vec<String>,
         model: String,
         max_tokens: u32,
         temperature: f32,
-    ) {
+    ) -> PyResult<()> {
         debug!("Added LLM API: {}", &name);
-        self.resources.llms.add(
-            name.clone(),
-            LLMType::Api(ApiLLM::new(
-                name,
-                ApiLLMMode::OpenAI { api_key, model },
-                max_tokens,
-                temperature,
-            )),
-        );
+        let modes = pair_endpoints(&name, api_keys, vec![String::new()])?
+            .into_iter()
+            .map(|(api_key, _)| ApiLLMMode::OpenAI {
+                api_key,
+                model: model.clone(),
+            })
+            .collect();
+        self.add_api_llm(name, modes, max_tokens, temperature);
+        Ok(())
     }
 
+    /// Adds an Azure OpenAI deployment, several `api_keys` or `endpoints` are rotated through
+    /// by the workers.
     #[allow(clippy::too_many_arguments)]
     pub fn with_llm_azure_openai(
         &mut self,
         name: String,
-        api_key: String,
-        endpoint: String,
+        api_keys: Vec<String>,
+        endpoints: Vec<String>,
         deployment_name: String,
         api_version: String,
         max_tokens: u32,
         temperature: f32,
-    ) {
+    ) -> PyResult<()> {
         debug!("Added LLM API: {}", &name);
-        self.resources.llms.add(
-            name.clone(),
-            LLMType::Api(ApiLLM::new(
-                name,
-                ApiLLMMode::AzureOpenAI {
-                    api_key,
-                    endpoint,
-                    deployment_name,
-                    api_version,
-                },
-                max_tokens,
-                temperature,
-            )),
-        );
+        let modes = pair_endpoints(&name, api_keys, endpoints)?
+            .into_iter()
+            .map(|(api_key, endpoint)| ApiLLMMode::AzureOpenAI {
+                api_key,
+                endpoint,
+                deployment_name: deployment_name.clone(),
+                api_version: api_version.clone(),
+            })
+            .collect();
+        self.add_api_llm(name, modes, max_tokens, temperature);
+        Ok(())
     }
 
     pub fn with_llm_unsloth(&mut self, name: String, py_func: PyObject) {
@@ -443,6 +838,55 @@ impl PipelineBuilder {
         );
     }
 
+    #[pyo3(signature = (name, api_key, model, input_type=None, base_url=None))]
+    pub fn with_embeddings_cohere(
+        &mut self,
+        name: String,
+        api_key: String,
+        model: String,
+        input_type: Option<String>,
+        base_url: Option<String>,
+    ) {
+        debug!("Added Cohere embeddings: {}", &name);
+        let base_url = base_url.unwrap_or_else(|| "https://api.cohere.com".to_string());
+        self.resources.embeddings.add(
+            name.clone(),
+            EmbeddingsType::Cohere(CohereEmbeddings::new(
+                name, base_url, api_key, model, input_type,
+            )),
+        );
+    }
+
+    #[pyo3(signature = (name, api_key, model, task=None, base_url=None))]
+    pub fn with_embeddings_jina(
+        &mut self,
+        name: String,
+        api_key: String,
+        model: String,
+        task: Option<String>,
+        base_url: Option<String>,
+    ) {
+        debug!("Added Jina embeddings: {}", &name);
+        let base_url = base_url.unwrap_or_else(|| "https://api.jina.ai".to_string());
+        self.resources.embeddings.add(
+            name.clone(),
+            EmbeddingsType::Jina(JinaEmbeddings::new(name, base_url, api_key, model, task)),
+        );
+    }
+
+    #[pyo3(signature = (name, path_or_repo, hf_token=None))]
+    pub fn with_tokenizer(
+        &mut self,
+        name: String,
+        path_or_repo: String,
+        hf_token: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added tokenizer: {}", &name);
+        let tokenizer = TokenizerWrapper::load(&path_or_repo, hf_token).map_pyerr()?;
+        self.resources.tokenizers.add(name, tokenizer);
+        Ok(())
+    }
+
     pub fn with_embeddings_e5(&mut self, name: String, model_repo: String) {
         debug!("Added E5 embeddings: {}", &name);
 
@@ -457,6 +901,118 @@ impl PipelineBuilder {
             .add(name.clone(), EmbeddingsType::E5(spec));
     }
 
+    #[pyo3(signature = (name, model_repo, pooling=None, normalize=None, device=None))]
+    pub fn with_embeddings_bert(
+        &mut self,
+        name: String,
+        model_repo: String,
+        pooling: Option<String>,
+        normalize: Option<bool>,
+        device: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added BERT embeddings: {}", &name);
+        let pooling = pooling
+            .map(|p| p.parse::<Pooling>())
+            .transpose()
+            .map_pyerr()?;
+
+        let spec = BertSpec {
+            name: name.clone(),
+            model_repo: Some(model_repo),
+            device,
+            hf_token: None,
+            pooling,
+            normalize,
+        };
+        self.resources
+            .embeddings
+            .add(name.clone(), EmbeddingsType::Bert(spec));
+        Ok(())
+    }
+
+    pub fn with_embeddings_dimensions(&mut self, key: String, dimensions: usize) -> PyResult<()> {
+        debug!(
+            "Embeddings stored for {} truncated to {} dimensions",
+            &key, dimensions
+        );
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        state.set_embedding_dimensions(&key, dimensions);
+        Ok(())
+    }
+
+    pub fn with_embeddings_precision(&mut self, key: String, precision: String) -> PyResult<()> {
+        debug!("Embeddings stored for {} quantized to {}", &key, &precision);
+        let precision = precision.parse::<EmbeddingPrecision>().map_pyerr()?;
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        state.set_embedding_precision(&key, precision);
+        Ok(())
+    }
+
+    pub fn warm_embeddings_cache(&self, embeddings: String, texts: Vec<String>) -> PyResult<()> {
+        let embeddings = self
+            .resources
+            .embeddings
+            .get(&embeddings)
+            .ok_or_err(&embeddings)
+            .map_pyerr()?;
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        debug!("Warming embeddings cache: {} texts", texts.len());
+        run_async(embed_cached(embeddings, Some(state), texts)).map_pyerr()?;
+        Ok(())
+    }
+
+    #[pyo3(signature = (embeddings=None))]
+    pub fn flush_embeddings_cache(&self, embeddings: Option<String>) -> PyResult<u64> {
+        let model = match embeddings {
+            Some(name) => Some(
+                self.resources
+                    .embeddings
+                    .get(&name)
+                    .ok_or_err(&name)
+                    .map_pyerr()?
+                    .model_id(),
+            ),
+            None => None,
+        };
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        let flushed = run_async(state.flush_embeddings_cache(model.as_deref())).map_pyerr()?;
+        debug!("Flushed {} cached embeddings", flushed);
+        Ok(flushed)
+    }
+
+    #[pyo3(signature = (step=None))]
+    pub fn flush_step_cache(&self, step: Option<String>) -> PyResult<u64> {
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        let flushed = run_async(state.flush_step_cache(step.as_deref())).map_pyerr()?;
+        debug!("Flushed {} cached step results", flushed);
+        Ok(flushed)
+    }
+
     pub fn with_jinja_template(&mut self, name: String, template: String) {
         debug!("Added Jinja template: {}", &name);
         self.resources.templates.add(name, template);
@@ -521,8 +1077,57 @@ impl PipelineBuilder {
         self.iter_by = IterBy::Range { start, stop, step };
     }
 
-    pub fn iter_by_dataset(&mut self, name: String) {
-        self.iter_by = IterBy::Dataset { name };
+    /// Iterates over the rows of the dataset, `skip` and `limit` select a slice of them, e.g.
+    /// to try a pipeline on the first rows or resume at an offset.
+    #[pyo3(signature = (name, limit=None, skip=None))]
+    pub fn iter_by_dataset(&mut self, name: String, limit: Option<usize>, skip: Option<usize>) {
+        self.iter_by = IterBy::Dataset { name, limit, skip };
+    }
+
+    /// Iterates over the dataset rows `epochs` times, each item gets its `epoch`. With `shuffle`
+    /// every epoch comes in a new order (reproducible with the pipeline seed). The rows are
+    /// loaded in memory.
+    #[pyo3(signature = (epochs, shuffle=true))]
+    pub fn with_epochs(&mut self, epochs: usize, shuffle: bool) -> PyResult<()> {
+        if epochs == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "At least one epoch is needed",
+            ));
+        }
+        self.epochs = epochs;
+        self.shuffle_epochs = shuffle;
+        Ok(())
+    }
+
+    /// Iterates over the rows of the datasets side by side, each item gets the row of every
+    /// dataset under the dataset name.
+    pub fn iter_by_zip(&mut self, names: Vec<String>) -> PyResult<()> {
+        if names.is_empty() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Zip iteration needs at least one dataset",
+            ));
+        }
+        self.iter_by = IterBy::Zip { names };
+        Ok(())
+    }
+
+    /// Iterates over `n` random rows of the dataset, reproducible with a `seed` or the
+    /// pipeline seed.
+    #[pyo3(signature = (name, n, seed=None))]
+    pub fn iter_by_dataset_sample(&mut self, name: String, n: usize, seed: Option<u64>) {
+        self.iter_by = IterBy::Sample { name, n, seed };
+    }
+
+    /// Iterates over every combination of rows of the datasets, each item gets the row of
+    /// every dataset under the dataset name.
+    pub fn iter_by_product(&mut self, names: Vec<String>) -> PyResult<()> {
+        if names.is_empty() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Product iteration needs at least one dataset",
+            ));
+        }
+        self.iter_by = IterBy::Product { names };
+        Ok(())
     }
 
     pub fn add_py_step(&mut self, name: String, py_func: PyObject) {
@@ -577,10 +1182,199 @@ impl PipelineBuilder {
         )));
     }
 
-    pub fn add_py_validator_step(&mut self, name: String, py_func: PyObject) {
-        debug!("Added Python validator step: {}", &name);
+    #[pyo3(signature = (name, key_template, cases, default=None))]
+    pub fn add_switch_step(
+        &mut self,
+        name: String,
+        key_template: String,
+        cases: HashMap<String, PyRef<StepsChain>>,
+        default: Option<PyRef<StepsChain>>,
+    ) {
+        debug!("Added Switch step: {}", &name);
+
+        // plain expressions are wrapped like ifelse conditions
+        let key = if key_template.contains("{{") {
+            let key = khash("switch", &name, &key_template);
+            self.resources
+                .templates
+                .templates
+                .insert(key.clone(), key_template);
+            key
+        } else {
+            self.resources
+                .templates
+                .add_inline("switch", &name, &key_template)
+        };
+
+        let cases = cases
+            .into_iter()
+            .map(|(value, chain)| {
+                let steps = chain
+                    .steps
+                    .iter()
+                    .map(|step| map_step(step, &mut self.resources.templates))
+                    .collect::<Vec<_>>();
+                (value, steps)
+            })
+            .collect::<Vec<_>>();
+
+        let default = default.map(|chain| {
+            chain
+                .steps
+                .iter()
+                .map(|step| map_step(step, &mut self.resources.templates))
+                .collect::<Vec<_>>()
+        });
+
         self.steps
-            .push(StepType::PyValidator(PyValidator::new(name, py_func)));
+            .push(StepType::Switch(SwitchStep::new(name, key, cases, default)));
+    }
+
+    pub fn add_parallel_step(
+        &mut self,
+        name: String,
+        branches: Vec<PyRef<StepsChain>>,
+        outputs: Vec<Vec<String>>,
+    ) -> PyResult<()> {
+        debug!("Added Parallel step: {}", &name);
+        if branches.len() != outputs.len() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Outputs must be declared for every branch",
+            ));
+        }
+
+        let branches = branches
+            .iter()
+            .map(|branch| {
+                branch
+                    .steps
+                    .iter()
+                    .map(|step| map_step(step, &mut self.resources.templates))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        self.steps.push(StepType::Parallel(ParallelStep::new(
+            name, branches, outputs,
+        )));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, steps, max_attempts=3, backoff_ms=500))]
+    pub fn add_retry_step(
+        &mut self,
+        name: String,
+        steps: PyRef<StepsChain>,
+        max_attempts: usize,
+        backoff_ms: u64,
+    ) {
+        debug!("Added Retry step: {}", &name);
+
+        let steps = steps
+            .steps
+            .iter()
+            .map(|step| map_step(step, &mut self.resources.templates))
+            .collect::<Vec<_>>();
+
+        self.steps.push(StepType::Retry(RetryStep::new(
+            name,
+            steps,
+            max_attempts,
+            backoff_ms,
+        )));
+    }
+
+    #[pyo3(signature = (name, steps, inputs=vec![]))]
+    pub fn add_cache_step(&mut self, name: String, steps: PyRef<StepsChain>, inputs: Vec<String>) {
+        debug!("Added Cache step: {}", &name);
+
+        let config = format!("{:?}", steps.steps);
+        let steps = steps
+            .steps
+            .iter()
+            .map(|step| map_step(step, &mut self.resources.templates))
+            .collect::<Vec<_>>();
+
+        self.steps
+            .push(StepType::Cache(CacheStep::new(name, steps, config, inputs)));
+    }
+
+    pub fn add_loop_step(
+        &mut self,
+        name: String,
+        py_condition: Option<PyObject>,
+        condition: Option<String>,
+        steps: PyRef<StepsChain>,
+        max_iters: usize,
+    ) {
+        debug!("Added Loop step: {}", &name);
+
+        let steps = steps
+            .steps
+            .iter()
+            .map(|step| map_step(step, &mut self.resources.templates))
+            .collect::<Vec<_>>();
+
+        let condition_key = condition.as_ref().map(|condition| {
+            self.resources
+                .templates
+                .add_inline("loop", &name, condition)
+        });
+
+        self.steps.push(StepType::Loop(LoopStep::new(
+            name,
+            py_condition,
+            condition_key,
+            steps,
+            max_iters,
+        )));
+    }
+
+    #[pyo3(signature = (name, input, item_key, steps, output, item_output=None))]
+    pub fn add_foreach_step(
+        &mut self,
+        name: String,
+        input: String,
+        item_key: String,
+        steps: PyRef<StepsChain>,
+        output: String,
+        item_output: Option<String>,
+    ) {
+        debug!("Added ForEach step: {}", &name);
+
+        let steps = steps
+            .steps
+            .iter()
+            .map(|step| map_step(step, &mut self.resources.templates))
+            .collect::<Vec<_>>();
+
+        self.steps.push(StepType::ForEach(ForEachStep::new(
+            name,
+            input,
+            item_key,
+            output,
+            item_output,
+            steps,
+        )));
+    }
+
+    pub fn add_py_validator_step(&mut self, name: String, py_func: PyObject) {
+        debug!("Added Python validator step: {}", &name);
+        self.steps
+            .push(StepType::PyValidator(PyValidator::new(name, py_func)));
+    }
+
+    #[pyo3(signature = (name, py_func, columns=None))]
+    pub fn add_py_writer_step(
+        &mut self,
+        name: String,
+        py_func: PyObject,
+        columns: Option<Vec<String>>,
+    ) {
+        debug!("Added Python writer step: {}", &name);
+        self.steps.push(StepType::PyWriter(PyWriterStep::new(
+            name, py_func, columns,
+        )));
     }
 
     pub fn add_into_list_step(&mut self, name: String, inputs: Vec<String>, output: String) {
@@ -589,6 +1383,32 @@ impl PipelineBuilder {
             .push(StepType::IntoList(IntoListStep::new(name, inputs, output)));
     }
 
+    pub fn add_explode_step(&mut self, name: String, input: String, item_key: String) {
+        debug!("Added Explode step: {}", &name);
+        self.steps
+            .push(StepType::Explode(ExplodeStep::new(name, input, item_key)));
+    }
+
+    pub fn add_zip_step(&mut self, name: String, inputs: Vec<(String, String)>, output: String) {
+        debug!("Added Zip step: {}", &name);
+        self.steps
+            .push(StepType::Zip(ZipStep::new(name, inputs, output)));
+    }
+
+    #[pyo3(signature = (name, size, output, inputs=None))]
+    pub fn add_accumulate_step(
+        &mut self,
+        name: String,
+        size: usize,
+        output: String,
+        inputs: Option<Vec<String>>,
+    ) {
+        debug!("Added Accumulate step: {} with size: {}", &name, size);
+        self.steps.push(StepType::Accumulate(AccumulateStep::new(
+            name, size, output, inputs,
+        )));
+    }
+
     pub fn add_validate_conversation_step(&mut self, name: String, conversation: String) {
         debug!("Added conversation validation step: {}", &name);
         self.steps.push(StepType::ConversationValidate(
@@ -732,792 +1552,3696 @@ impl PipelineBuilder {
             )));
     }
 
-    #[pyo3(signature = (name, path, template=None, value=None))]
-    pub fn add_write_jsonl_step(
-        &mut self,
-        name: String,
-        path: String,
-        template: Option<String>,
-        value: Option<String>,
-    ) {
-        debug!("Added JSONL writer step: {}", &name);
-        self.steps.push(StepType::JsonWriter(JsonlWriterStep::new(
-            name, path, template, value,
-        )));
-    }
-
-    #[pyo3(signature = (name, template=None, columns=None))]
-    pub fn add_print_step(
-        &mut self,
-        name: String,
-        template: Option<String>,
-        columns: Option<Vec<String>>,
-    ) {
-        debug!("Added print step: {}", &name);
-        self.steps
-            .push(StepType::Print(PrintStep::new(name, template, columns)));
-    }
-
-    pub fn add_write_csv_step(
-        &mut self,
-        name: String,
-        path: String,
-        columns: Vec<String>,
-        delimiter: String,
-    ) {
-        debug!("Added CSV writer step: {}", &name);
-        self.steps.push(StepType::CsvWriter(CsvWriterStep::new(
-            name, path, columns, delimiter,
-        )));
-    }
-
-    pub fn add_data_sampler_step(
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, template, llm, output, rationale_output=None, threshold=None, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_judge_step(
         &mut self,
         name: String,
-        dataset: String,
-        size: usize,
+        template: String,
+        llm: String,
         output: String,
+        rationale_output: Option<String>,
+        threshold: Option<f64>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
     ) {
         debug!(
-            "Added data sampler on dataset: {} with size: {}",
-            &dataset, &size
+            "Added judge step with llm: {}, template: {}",
+            &llm, &template
         );
-        self.steps.push(StepType::DataSampler(DataSamplerStep::new(
+        self.steps.push(StepType::Judge(JudgeStep::new(
             name,
-            dataset,
-            Some(size),
+            template,
+            llm,
             output,
+            rationale_output,
+            threshold,
+            system_template,
+            max_tokens,
+            temperature,
         )));
     }
 
-    pub fn add_tool_sampler_step(
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, output, rewards=vec![], judge_llm=None, judges=vec![], weights=vec![], max_tokens=None, temperature=None))]
+    pub fn add_rewards_step(
         &mut self,
         name: String,
-        dataset: String,
-        size: usize,
         output: String,
-    ) {
-        self.add_data_sampler_step(name.clone(), dataset, size, output.clone());
-        self.add_normalizetools_step(name.clone(), output.clone(), output.clone());
-        self.add_validatetools_step(name, output);
-    }
-
-    pub fn add_data_read_step(&mut self, name: String, dataset: String, output: String) {
-        debug!("Added data read on dataset: {}", &dataset);
-        self.steps.push(StepType::DataSampler(DataSamplerStep::new(
-            name, dataset, None, output,
+        rewards: Vec<(String, String)>,
+        judge_llm: Option<String>,
+        judges: Vec<(String, String)>,
+        weights: Vec<(String, f64)>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!("Added rewards step with output: {}", &output);
+        let rewards = rewards
+            .into_iter()
+            .map(|(reward_name, reward)| Ok((reward_name, reward.parse::<Reward>()?)))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_pyerr()?;
+        if !judges.is_empty() && judge_llm.is_none() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "🐔 Judge rewards require a judge_llm",
+            ));
+        }
+        let judges = judges
+            .into_iter()
+            .map(|(reward_name, template)| {
+                let judge = JudgeStep::new(
+                    name.clone(),
+                    template,
+                    judge_llm.clone().unwrap_or_default(),
+                    reward_name.clone(),
+                    None,
+                    None,
+                    None,
+                    max_tokens,
+                    temperature,
+                );
+                (reward_name, judge)
+            })
+            .collect();
+        self.steps.push(StepType::Rewards(RewardsStep::new(
+            name, output, rewards, judges, weights,
         )));
+        Ok(())
     }
 
-    pub fn add_chunk_step(
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, template, llms, output, samples=1, judge_llm=None, judge_template=None, language=None, min_margin=0.0, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_preference_pair_step(
         &mut self,
         name: String,
-        capacity: (usize, usize),
-        input: String,
+        template: String,
+        llms: Vec<String>,
         output: String,
-    ) {
-        debug!("Added data chunking step");
-        self.steps.push(StepType::Chunk(ChunkStep::new(
-            name, capacity, input, output,
-        )));
+        samples: usize,
+        judge_llm: Option<String>,
+        judge_template: Option<String>,
+        language: Option<String>,
+        min_margin: f64,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!("Added preference pair step with llms: {:?}", &llms);
+        let candidates = llms
+            .iter()
+            .flat_map(|llm| std::iter::repeat_n(llm, samples.max(1)))
+            .map(|llm| {
+                TextGenerationStep::new(
+                    name.clone(),
+                    template.clone(),
+                    llm.clone(),
+                    output.clone(),
+                    system_template.clone(),
+                    max_tokens,
+                    temperature,
+                )
+            })
+            .collect();
+        let judge = judge_llm.map(|judge_llm| {
+            let template = judge_template.unwrap_or_else(|| {
+                let language = language.unwrap_or("en".to_string());
+                let tmpl = blake3_hash(&format!("{}_{}_response", &name, &language));
+                self.resources.templates.templates.insert(
+                    tmpl.clone(),
+                    tweaktune_core::templates::embed::judge_templates("response", &language)
+                        .expect("Response judge template")
+                        .to_string(),
+                );
+                tmpl
+            });
+            JudgeStep::new(
+                name.clone(),
+                template,
+                judge_llm,
+                output.clone(),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        });
+        self.steps.push(StepType::PreferencePair(
+            PreferencePairStep::new(name, template, output, candidates, judge, min_margin)
+                .map_pyerr()?,
+        ));
+        Ok(())
     }
 
-    pub fn add_render_step(&mut self, name: String, template: String, output: String) {
-        debug!("Added render step");
-        self.steps
-            .push(StepType::Render(RenderStep::new(name, template, output)));
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, template, llm, output, samples, json_path=None, pattern=None, agreement_output=None, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_self_consistency_step(
+        &mut self,
+        name: String,
+        template: String,
+        llm: String,
+        output: String,
+        samples: usize,
+        json_path: Option<String>,
+        pattern: Option<String>,
+        agreement_output: Option<String>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!(
+            "Added self-consistency step with llm: {}, samples: {}",
+            &llm, samples
+        );
+        self.steps.push(StepType::SelfConsistency(
+            SelfConsistencyStep::new(
+                name,
+                template,
+                llm,
+                output,
+                samples,
+                json_path,
+                pattern,
+                agreement_output,
+                system_template,
+                max_tokens,
+                temperature,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
     }
 
-    #[pyo3(signature = (name, conversation, output, tools=None, separator=None))]
-    pub fn add_render_conversation_step(
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, template, llm, candidate_a, candidate_b, output, confidence_output=None, swap=true, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_pairwise_judge_step(
         &mut self,
         name: String,
-        conversation: String,
+        template: String,
+        llm: String,
+        candidate_a: String,
+        candidate_b: String,
         output: String,
-        tools: Option<String>,
-        separator: Option<String>,
+        confidence_output: Option<String>,
+        swap: bool,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
     ) {
-        debug!("Added render conversation step");
+        debug!(
+            "Added pairwise judge step with llm: {}, template: {}",
+            &llm, &template
+        );
         self.steps
-            .push(StepType::RenderConversation(RenderConversationStep::new(
+            .push(StepType::PairwiseJudge(PairwiseJudgeStep::new(
                 name,
-                conversation,
-                tools,
-                separator,
+                template,
+                llm,
+                candidate_a,
+                candidate_b,
                 output,
+                confidence_output,
+                swap,
+                system_template,
+                max_tokens,
+                temperature,
             )));
     }
 
-    #[pyo3(signature = (name, conversation, output, tools=None, separator=None))]
-    pub fn add_render_sft_step(
+    #[pyo3(signature = (name, path, template=None, value=None, max_lines=None, max_bytes=None, compression=None, flush_interval=1.0, op_config=None, mode="append".to_string(), manifest=false))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_write_jsonl_step(
         &mut self,
         name: String,
-        conversation: String,
-        output: String,
-        tools: Option<String>,
-        separator: Option<String>,
+        path: String,
+        template: Option<String>,
+        value: Option<String>,
+        max_lines: Option<usize>,
+        max_bytes: Option<u64>,
+        compression: Option<String>,
+        flush_interval: f64,
+        op_config: Option<String>,
+        mode: String,
+        manifest: bool,
+    ) -> PyResult<()> {
+        debug!("Added JSONL writer step: {}", &name);
+        self.steps.push(StepType::JsonWriter(
+            JsonlWriterStep::new(
+                name,
+                path,
+                template,
+                value,
+                max_lines,
+                max_bytes,
+                compression,
+                flush_interval,
+                op_config,
+                mode.parse().map_pyerr()?,
+                manifest,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    /// Runs the `child` pipeline, with its own datasets and LLMs, for each item and copies its
+    /// `outputs` back to the item. The child is taken over and left empty, its templates are
+    /// added to this pipeline.
+    pub fn add_subpipeline_step(
+        &mut self,
+        name: String,
+        mut child: PyRefMut<'_, PipelineBuilder>,
+        outputs: Vec<String>,
+    ) -> PyResult<()> {
+        debug!("Added SubPipeline step: {}", &name);
+        if self.subpipelines.contains_key(&name) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "A sub-pipeline named {} was already added",
+                name
+            )));
+        }
+        let placeholder = PipelineBuilder::new(
+            child.name.clone(),
+            Some(Metadata::new(String::new(), false)),
+        );
+        let child = std::mem::replace(&mut *child, placeholder);
+        for (key, template) in &child.resources.templates.templates {
+            match self.resources.templates.templates.get(key) {
+                Some(existing) if existing != template => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                        "Template {} of sub-pipeline {} differs from the parent template",
+                        key, name
+                    )));
+                }
+                Some(_) => {}
+                None => self.resources.templates.add(key.clone(), template.clone()),
+            }
+        }
+        if child.dag {
+            *child.dag_waves.write().unwrap() =
+                dag::plan(&child.steps, &child.step_io).map_pyerr()?;
+        }
+        self.subpipelines.insert(name.clone(), child);
+        self.steps
+            .push(StepType::SubPipeline(SubPipelineStep::new(name, outputs)));
+        Ok(())
+    }
+
+    pub fn steps_count(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Moves the steps added since `start` into a tee step writing each item to all of them.
+    pub fn add_tee_step(&mut self, name: String, start: usize) -> PyResult<()> {
+        debug!("Added Tee step: {}", &name);
+        if start > self.steps.len() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Tee sinks start after the last step",
+            ));
+        }
+        let sinks = self.steps.split_off(start);
+        self.steps
+            .push(StepType::Tee(TeeStep::new(name, sinks).map_pyerr()?));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, template=None, columns=None))]
+    pub fn add_print_step(
+        &mut self,
+        name: String,
+        template: Option<String>,
+        columns: Option<Vec<String>>,
     ) {
-        self.add_render_conversation_step(name, conversation, output, tools, separator);
+        debug!("Added print step: {}", &name);
+        self.steps
+            .push(StepType::Print(PrintStep::new(name, template, columns)));
+    }
+
+    #[pyo3(signature = (name, dir, condition=None, failed=false))]
+    pub fn add_dump_step(
+        &mut self,
+        name: String,
+        dir: String,
+        condition: Option<String>,
+        failed: bool,
+    ) {
+        debug!("Added dump step: {}", &name);
+        let condition = condition.map(|condition| {
+            self.resources
+                .templates
+                .add_inline("dump", &name, &condition)
+        });
+        self.steps
+            .push(StepType::Dump(DumpStep::new(name, dir, condition, failed)));
     }
 
+    #[pyo3(signature = (name, path, columns, delimiter=",".to_string(), header=false, quoting="necessary".to_string(), quote="\"".to_string(), escape=None, mode="append".to_string(), templates=HashMap::new(), compression=None, flush_interval=1.0, op_config=None))]
     #[allow(clippy::too_many_arguments)]
-    pub fn add_render_dpo_step(
+    pub fn add_write_csv_step(
         &mut self,
         name: String,
-        conversation: String,
+        path: String,
+        columns: Vec<String>,
+        delimiter: String,
+        header: bool,
+        quoting: String,
+        quote: String,
+        escape: Option<String>,
+        mode: String,
+        templates: HashMap<String, String>,
+        compression: Option<String>,
+        flush_interval: f64,
+        op_config: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added CSV writer step: {}", &name);
+        let format = CsvFormat::new(&delimiter, &quoting, &quote, escape.as_deref()).map_pyerr()?;
+        self.steps.push(StepType::CsvWriter(
+            CsvWriterStep::new(
+                name,
+                path,
+                columns,
+                format,
+                header,
+                templates,
+                mode.parse().map_pyerr()?,
+                compression,
+                flush_interval,
+                op_config,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, path, columns=None, op_config=None))]
+    pub fn add_write_ipc_step(
+        &mut self,
+        name: String,
+        path: String,
+        columns: Option<Vec<String>>,
+        op_config: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added IPC writer step: {}", &name);
+        self.steps.push(StepType::IpcWriter(
+            IpcWriterStep::new(name, path, columns, op_config).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_write_sqlite_step(
+        &mut self,
+        name: String,
+        path: String,
+        table: String,
+        columns: Vec<(String, String)>,
+    ) -> PyResult<()> {
+        debug!("Added SQLite writer step: {}", &name);
+        self.steps.push(StepType::SqliteWriter(
+            SqliteWriterStep::new(name, path, table, columns).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, conn_str, table, columns, batch_size=100, conflict_columns=None, conflict_action="update".to_string()))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_write_postgres_step(
+        &mut self,
+        name: String,
+        conn_str: String,
+        table: String,
+        columns: Vec<(String, String)>,
+        batch_size: usize,
+        conflict_columns: Option<Vec<String>>,
+        conflict_action: String,
+    ) -> PyResult<()> {
+        debug!("Added PostgreSQL writer step: {}", &name);
+        let conflict = match conflict_columns {
+            Some(conflict_columns) => Some((
+                conflict_columns,
+                conflict_action.parse::<ConflictAction>().map_pyerr()?,
+            )),
+            None => None,
+        };
+        self.steps.push(StepType::PostgresWriter(
+            PostgresWriterStep::new(name, conn_str, table, columns, batch_size, conflict)
+                .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, url, template=None, columns=None, headers=vec![], batch_size=1, max_attempts=3, backoff_ms=500, timeout_secs=30))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_write_http_step(
+        &mut self,
+        name: String,
+        url: String,
+        template: Option<String>,
+        columns: Option<Vec<String>>,
+        headers: Vec<(String, String)>,
+        batch_size: usize,
+        max_attempts: usize,
+        backoff_ms: u64,
+        timeout_secs: u64,
+    ) -> PyResult<()> {
+        debug!("Added HTTP writer step: {}", &name);
+        self.steps.push(StepType::HttpWriter(
+            HttpWriterStep::new(
+                name,
+                url,
+                template,
+                columns,
+                headers,
+                batch_size,
+                max_attempts,
+                backoff_ms,
+                timeout_secs,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, brokers, topic, template=None, columns=None, key=None, batch_size=10000, linger_ms=5, config=vec![]))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_write_kafka_step(
+        &mut self,
+        name: String,
+        brokers: String,
+        topic: String,
+        template: Option<String>,
+        columns: Option<Vec<String>>,
+        key: Option<String>,
+        batch_size: usize,
+        linger_ms: u64,
+        config: Vec<(String, String)>,
+    ) -> PyResult<()> {
+        #[cfg(feature = "kafka")]
+        {
+            debug!("Added Kafka writer step: {}", &name);
+            let key_template = key.map(|key| {
+                self.resources
+                    .templates
+                    .add_inline("kafka_key", &name, &key)
+            });
+            self.steps.push(StepType::KafkaWriter(
+                KafkaWriterStep::new(
+                    name,
+                    brokers,
+                    topic,
+                    template,
+                    columns,
+                    key_template,
+                    batch_size,
+                    linger_ms,
+                    config,
+                )
+                .map_pyerr()?,
+            ));
+            Ok(())
+        }
+        #[cfg(not(feature = "kafka"))]
+        {
+            let _ = (
+                name, brokers, topic, template, columns, key, batch_size, linger_ms, config,
+            );
+            Err(pyo3::exceptions::PyValueError::new_err(
+                "🐔 write_kafka needs tweaktune built with the kafka feature",
+            ))
+        }
+    }
+
+    pub fn add_data_sampler_step(
+        &mut self,
+        name: String,
+        dataset: String,
+        size: usize,
         output: String,
-        chosen: String,
-        rejected: String,
-        tools: Option<String>,
-        separator: Option<String>,
     ) {
-        debug!("Added render DPO step");
+        debug!(
+            "Added data sampler on dataset: {} with size: {}",
+            &dataset, &size
+        );
+        self.steps.push(StepType::DataSampler(DataSamplerStep::new(
+            name,
+            dataset,
+            Some(size),
+            output,
+        )));
+    }
+
+    pub fn add_tool_sampler_step(
+        &mut self,
+        name: String,
+        dataset: String,
+        size: usize,
+        output: String,
+    ) {
+        self.add_data_sampler_step(name.clone(), dataset, size, output.clone());
+        self.add_normalizetools_step(name.clone(), output.clone(), output.clone());
+        self.add_validatetools_step(name, output);
+    }
+
+    #[pyo3(signature = (name, dataset, size, gold, output, seed=None))]
+    pub fn add_negative_tool_sampler_step(
+        &mut self,
+        name: String,
+        dataset: String,
+        size: usize,
+        gold: String,
+        output: String,
+        seed: Option<u64>,
+    ) {
+        debug!("Added negative tool sampler step on dataset: {}", &dataset);
+        self.steps
+            .push(StepType::NegativeToolSampler(NegativeToolSamplerStep::new(
+                name.clone(),
+                dataset,
+                size,
+                gold,
+                output.clone(),
+                seed,
+            )));
+        self.add_normalizetools_step(name.clone(), output.clone(), output.clone());
+        self.add_validatetools_step(name, output);
+    }
+
+    #[pyo3(signature = (name, output, attributes=vec![], custom=vec![], seed=None))]
+    pub fn add_persona_step(
+        &mut self,
+        name: String,
+        output: String,
+        attributes: Vec<String>,
+        custom: Vec<(String, Vec<String>)>,
+        seed: Option<u64>,
+    ) -> PyResult<()> {
+        debug!("Added persona step with output: {}", &output);
+        self.steps.push(StepType::Persona(
+            PersonaStep::new(name, output, attributes, custom, seed).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, output, options, seed=None))]
+    pub fn add_weighted_choice_step(
+        &mut self,
+        name: String,
+        output: String,
+        options: Vec<(String, f64)>,
+        seed: Option<u64>,
+    ) -> PyResult<()> {
+        debug!("Added weighted choice step with output: {}", &output);
+        self.steps.push(StepType::WeightedChoice(
+            WeightedChoiceStep::new(name, output, options, seed).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_data_read_step(&mut self, name: String, dataset: String, output: String) {
+        debug!("Added data read on dataset: {}", &dataset);
+        self.steps.push(StepType::DataSampler(DataSamplerStep::new(
+            name, dataset, None, output,
+        )));
+    }
+
+    #[pyo3(signature = (name, mapping, copy=false))]
+    pub fn add_map_keys_step(&mut self, name: String, mapping: Vec<(String, String)>, copy: bool) {
+        debug!("Added map keys step");
+        self.steps
+            .push(StepType::MapKeys(MapKeysStep::new(name, mapping, copy)));
+    }
+
+    pub fn add_select_keys_step(&mut self, name: String, keys: Vec<String>) {
+        debug!("Added select keys step");
+        self.steps
+            .push(StepType::SelectKeys(SelectKeysStep::new(name, keys)));
+    }
+
+    pub fn add_drop_keys_step(&mut self, name: String, keys: Vec<String>) {
+        debug!("Added drop keys step");
+        self.steps
+            .push(StepType::DropKeys(DropKeysStep::new(name, keys)));
+    }
+
+    pub fn add_regex_extract_step(
+        &mut self,
+        name: String,
+        input: String,
+        pattern: String,
+        output: String,
+    ) -> PyResult<()> {
+        debug!("Added regex extract step with pattern: {}", &pattern);
+        self.steps.push(StepType::RegexExtract(
+            RegexExtractStep::new(name, input, &pattern, output).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, input, replacements, presets=vec![], output=None))]
+    pub fn add_regex_replace_step(
+        &mut self,
+        name: String,
+        input: String,
+        replacements: Vec<(String, String)>,
+        presets: Vec<String>,
+        output: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added regex replace step on: {}", &input);
+        self.steps.push(StepType::RegexReplace(
+            RegexReplaceStep::new(name, input, output, replacements, presets).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, input, output=None, html=true, markdown=true, keep_headings=false, keep_lists=true))]
+    pub fn add_cleanup_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: Option<String>,
+        html: bool,
+        markdown: bool,
+        keep_headings: bool,
+        keep_lists: bool,
+    ) {
+        debug!("Added cleanup step for input: {}", &input);
+        self.steps.push(StepType::Cleanup(CleanupStep::new(
+            name,
+            input,
+            output,
+            html,
+            markdown,
+            keep_headings,
+            keep_lists,
+        )));
+    }
+
+    #[pyo3(signature = (name, input, output, entities=vec![], replacements=HashMap::new()))]
+    pub fn add_pii_redaction_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: String,
+        entities: Vec<String>,
+        replacements: HashMap<String, String>,
+    ) -> PyResult<()> {
+        debug!("Added PII redaction step for input: {}", &input);
+        self.steps.push(StepType::PiiRedaction(
+            PiiRedactionStep::new(name, input, output, entities, replacements).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, input, output, target_lang, backend, source_lang="English".to_string(), max_tokens=None, temperature=None))]
+    pub fn add_translate_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: String,
+        target_lang: String,
+        backend: String,
+        source_lang: String,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!(
+            "Added translate step to {} with backend: {}",
+            &target_lang, &backend
+        );
+        // model names like `mt5-small` select the local seq2seq model, anything else is an LLM
+        let backend = match backend.parse::<Which>() {
+            Ok(which) => TranslateBackend::Seq2Seq(Seq2SeqSpec {
+                name: backend,
+                which: Some(which),
+                ..Default::default()
+            }),
+            Err(_) => {
+                let template = translate_template(&input, &source_lang, &target_lang);
+                let key = khash("translate", &name, &template);
+                self.resources
+                    .templates
+                    .templates
+                    .insert(key.clone(), template);
+                TranslateBackend::Llm(TextGenerationStep::new(
+                    name.clone(),
+                    key,
+                    backend,
+                    output.clone(),
+                    None,
+                    max_tokens,
+                    temperature,
+                ))
+            }
+        };
+        self.steps.push(StepType::Translate(TranslateStep::new(
+            name,
+            input,
+            output,
+            source_lang,
+            target_lang,
+            backend,
+        )));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, input, path, output, all=false))]
+    pub fn add_jsonpath_step(
+        &mut self,
+        name: String,
+        input: String,
+        path: String,
+        output: String,
+        all: bool,
+    ) -> PyResult<()> {
+        debug!("Added JSONPath step with path: {}", &path);
+        self.steps.push(StepType::JsonPath(
+            JsonPathStep::new(name, input, path, output, all).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, filter, output, input=None, all=false))]
+    pub fn add_jq_step(
+        &mut self,
+        name: String,
+        filter: String,
+        output: String,
+        input: Option<String>,
+        all: bool,
+    ) -> PyResult<()> {
+        debug!("Added jq step with filter: {}", &filter);
+        self.steps.push(StepType::Jq(
+            JqStep::new(name, input, filter, output, all).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_sql_step(&mut self, name: String, input: String, sql: String, output: String) {
+        debug!("Added SQL step with query: {}", &sql);
+        self.steps
+            .push(StepType::Sql(SqlStep::new(name, input, sql, output)));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, command, output, stdin=None, exit_code_output=None, timeout_secs=60, fail_on_error=true))]
+    pub fn add_shell_step(
+        &mut self,
+        name: String,
+        command: String,
+        output: String,
+        stdin: Option<String>,
+        exit_code_output: Option<String>,
+        timeout_secs: u64,
+        fail_on_error: bool,
+    ) -> PyResult<()> {
+        if !self.allow_shell {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Shell steps are disabled, enable them with with_shell_commands()",
+            ));
+        }
+        debug!("Added shell step with command: {}", &command);
+        let command_key = khash("shell", &name, &command);
+        self.resources
+            .templates
+            .templates
+            .insert(command_key.clone(), command);
+        self.steps.push(StepType::Shell(ShellStep::new(
+            name,
+            command_key,
+            stdin,
+            output,
+            exit_code_output,
+            timeout_secs,
+            fail_on_error,
+        )));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, input, output, language="python".to_string(), command=None, stdin=None, timeout_secs=10, memory_mb=None, network=false, fail_on_error=true))]
+    pub fn add_execute_code_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: String,
+        language: String,
+        command: Option<String>,
+        stdin: Option<String>,
+        timeout_secs: u64,
+        memory_mb: Option<u64>,
+        network: bool,
+        fail_on_error: bool,
+    ) -> PyResult<()> {
+        if !self.allow_shell {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Code execution steps are disabled, enable them with with_shell_commands()",
+            ));
+        }
+        debug!("Added execute code step for language: {}", &language);
+        let limits = SandboxLimits {
+            timeout: Duration::from_secs(timeout_secs),
+            memory_mb,
+            network,
+        };
+        self.steps.push(StepType::ExecuteCode(
+            ExecuteCodeStep::new(
+                name,
+                input,
+                output,
+                &language,
+                command,
+                stdin,
+                limits,
+                fail_on_error,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, code, tests, output, language="python".to_string(), command=None, timeout_secs=30, memory_mb=None, network=false))]
+    pub fn add_code_tests_step(
+        &mut self,
+        name: String,
+        code: String,
+        tests: String,
+        output: String,
+        language: String,
+        command: Option<String>,
+        timeout_secs: u64,
+        memory_mb: Option<u64>,
+        network: bool,
+    ) -> PyResult<()> {
+        if !self.allow_shell {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "Code execution steps are disabled, enable them with with_shell_commands()",
+            ));
+        }
+        debug!("Added code tests step for language: {}", &language);
+        let limits = SandboxLimits {
+            timeout: Duration::from_secs(timeout_secs),
+            memory_mb,
+            network,
+        };
+        self.steps.push(StepType::CodeTests(
+            CodeTestsStep::new(name, code, tests, output, language, command, limits).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_token_count_step(
+        &mut self,
+        name: String,
+        tokenizer: String,
+        input: String,
+        output: String,
+    ) {
+        debug!("Added token count step with tokenizer: {}", &tokenizer);
+        self.steps.push(StepType::TokenCount(TokenCountStep::new(
+            name, tokenizer, input, output,
+        )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, tokenizer, input, max_tokens, strategy="head".to_string(), output=None, marker=" ... ".to_string()))]
+    pub fn add_truncate_tokens_step(
+        &mut self,
+        name: String,
+        tokenizer: String,
+        input: String,
+        max_tokens: usize,
+        strategy: String,
+        output: Option<String>,
+        marker: String,
+    ) -> PyResult<()> {
+        debug!("Added truncate tokens step with max tokens: {}", max_tokens);
+        let strategy = strategy.parse::<TruncateStrategy>().map_pyerr()?;
+        self.steps
+            .push(StepType::TruncateTokens(TruncateTokensStep::new(
+                name, tokenizer, input, output, max_tokens, strategy, marker,
+            )));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, capacity, input, output, tokenizer=None, embedding=None, threshold=0.75))]
+    pub fn add_chunk_step(
+        &mut self,
+        name: String,
+        capacity: (usize, usize),
+        input: String,
+        output: String,
+        tokenizer: Option<String>,
+        embedding: Option<String>,
+        threshold: f32,
+    ) {
+        debug!("Added data chunking step");
+        self.steps.push(StepType::Chunk(ChunkStep::new(
+            name, capacity, input, output, tokenizer, embedding, threshold,
+        )));
+    }
+
+    pub fn add_render_step(&mut self, name: String, template: String, output: String) {
+        debug!("Added render step");
+        self.steps
+            .push(StepType::Render(RenderStep::new(name, template, output)));
+    }
+
+    pub fn add_convert_format_step(
+        &mut self,
+        name: String,
+        input: String,
+        from_format: String,
+        to_format: String,
+        output: String,
+    ) -> PyResult<()> {
+        debug!(
+            "Added convert format step from {} to {}",
+            &from_format, &to_format
+        );
+        self.steps.push(StepType::ConvertFormat(
+            ConvertFormatStep::new(name, input, &from_format, &to_format, output).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, input, output=None, max_turns=None, max_tokens=None, tokenizer=None))]
+    pub fn add_truncate_conversation_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: Option<String>,
+        max_turns: Option<usize>,
+        max_tokens: Option<usize>,
+        tokenizer: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added truncate conversation step for input: {}", &input);
+        self.steps.push(StepType::TruncateConversation(
+            TruncateConversationStep::new(name, input, output, max_turns, max_tokens, tokenizer)
+                .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, input, output, system_prompts=vec![], shuffle_tools=false, llm=None, paraphrase_template=None, paraphrase_prob=1.0, seed=None, max_tokens=None, temperature=None))]
+    pub fn add_augment_conversation_step(
+        &mut self,
+        name: String,
+        input: String,
+        output: String,
+        system_prompts: Vec<String>,
+        shuffle_tools: bool,
+        llm: Option<String>,
+        paraphrase_template: Option<String>,
+        paraphrase_prob: f64,
+        seed: Option<u64>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) {
+        debug!("Added augment conversation step for input: {}", &input);
+        let paraphrase = llm.map(|llm| {
+            let template = paraphrase_template.unwrap_or_else(|| {
+                let template = tweaktune_core::templates::embed::augment_templates("paraphrase")
+                    .expect("Paraphrase template")
+                    .to_string();
+                let key = khash("paraphrase", &name, &template);
+                self.resources
+                    .templates
+                    .templates
+                    .insert(key.clone(), template);
+                key
+            });
+            TextGenerationStep::new(
+                name.clone(),
+                template,
+                llm,
+                output.clone(),
+                None,
+                max_tokens,
+                temperature,
+            )
+        });
+        self.steps
+            .push(StepType::AugmentConversation(AugmentConversationStep::new(
+                name,
+                input,
+                output,
+                system_prompts,
+                shuffle_tools,
+                paraphrase,
+                paraphrase_prob,
+                seed,
+            )));
+    }
+
+    #[pyo3(signature = (name, conversation, output, tools=None, separator=None))]
+    pub fn add_render_conversation_step(
+        &mut self,
+        name: String,
+        conversation: String,
+        output: String,
+        tools: Option<String>,
+        separator: Option<String>,
+    ) {
+        debug!("Added render conversation step");
+        self.steps
+            .push(StepType::RenderConversation(RenderConversationStep::new(
+                name,
+                conversation,
+                tools,
+                separator,
+                output,
+            )));
+    }
+
+    #[pyo3(signature = (name, conversation, output, tools=None, separator=None))]
+    pub fn add_render_sft_step(
+        &mut self,
+        name: String,
+        conversation: String,
+        output: String,
+        tools: Option<String>,
+        separator: Option<String>,
+    ) {
+        self.add_render_conversation_step(name, conversation, output, tools, separator);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_render_dpo_step(
+        &mut self,
+        name: String,
+        conversation: String,
+        output: String,
+        chosen: String,
+        rejected: String,
+        tools: Option<String>,
+        separator: Option<String>,
+    ) {
+        debug!("Added render DPO step");
+
+        let conversation_key = format!("{}_dpo_conversation", output);
+        self.add_render_conversation_step(
+            name.clone(),
+            conversation,
+            conversation_key.to_string(),
+            tools.clone(),
+            separator,
+        );
+
+        let messages_key = self.resources.templates.add_inline(
+            "render_dpo",
+            &name,
+            &format!("{}.messages|tojson", &conversation_key),
+        );
+        let chosen_key = self
+            .resources
+            .templates
+            .add_inline("render_dpo", &name, &chosen);
+        let rejected_key = self
+            .resources
+            .templates
+            .add_inline("render_dpo", &name, &rejected);
+
+        let tools_key = if tools.is_some() {
+            Some(self.resources.templates.add_inline(
+                "render_dpo",
+                &name,
+                &format!("{}.tools|tojson", &conversation_key),
+            ))
+        } else {
+            None
+        };
+
+        let tool_call_template =
+            r#"<tool_call>{"name":"{{name}}","arguments":{{arguments | tojson}}}</tool_call>"#;
+
+        let tool_call_template_key = format!("{}_tool_call_template", &name);
+        self.resources.templates.add(
+            tool_call_template_key.clone(),
+            tool_call_template.to_string(),
+        );
+
+        self.steps.push(StepType::RenderDPO(RenderDPOStep::new(
+            name,
+            messages_key,
+            chosen_key,
+            rejected_key,
+            tool_call_template_key,
+            tools_key,
+            output,
+        )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_render_grpo_step(
+        &mut self,
+        name: String,
+        conversation: String,
+        output: String,
+        solution: String,
+        validator_id: String,
+        tools: Option<String>,
+        separator: Option<String>,
+    ) {
+        debug!("Added render GRPO step");
+
+        let conversation_key = format!("{}_grpo_conversation", output);
+        self.add_render_conversation_step(
+            name.clone(),
+            conversation,
+            conversation_key.to_string(),
+            tools.clone(),
+            separator,
+        );
+
+        let messages_key = self.resources.templates.add_inline(
+            "render_grpo",
+            &name,
+            &format!("{}.messages|tojson", &conversation_key),
+        );
+        let solution_key = self
+            .resources
+            .templates
+            .add_inline("render_grpo", &name, &solution);
+
+        let tools_key = if tools.is_some() {
+            Some(self.resources.templates.add_inline(
+                "render_grpo",
+                &name,
+                &format!("{}.tools|tojson", &conversation_key),
+            ))
+        } else {
+            None
+        };
+
+        self.steps.push(StepType::RenderGRPO(RenderGRPOStep::new(
+            name,
+            messages_key,
+            solution_key,
+            validator_id,
+            tools_key,
+            output,
+        )));
+    }
+
+    pub fn add_render_tool_call_step(
+        &mut self,
+        name: String,
+        tool_name: String,
+        arguments: String,
+        output: String,
+        additional_template: Option<String>,
+    ) {
+        debug!("Added render tool call step");
+        self.steps
+            .push(StepType::RenderToolCall(RenderToolCallStep::new(
+                name,
+                tool_name,
+                arguments,
+                output,
+                additional_template,
+            )));
+    }
+
+    pub fn add_validatejson_step(&mut self, name: String, schema: String, instance: String) {
+        debug!("Added validate JSON step");
+
+        let schema_key = self.resources.templates.add_inline(
+            "validatejson_schema",
+            &name,
+            &format!("{schema}|tojson"),
+        );
+        let instance_key = self.resources.templates.add_inline(
+            "validatejson_instance",
+            &name,
+            &format!("{instance}|tojson"),
+        );
+        self.steps
+            .push(StepType::ValidateJson(ValidateJsonStep::new(
+                name,
+                schema_key,
+                instance_key,
+            )));
+    }
+
+    #[pyo3(signature = (name, schema, input, output, coercions_output=None))]
+    pub fn add_extract_structured_step(
+        &mut self,
+        name: String,
+        schema: String,
+        input: String,
+        output: String,
+        coercions_output: Option<String>,
+    ) {
+        debug!("Added extract structured step for input: {}", &input);
+
+        let schema_key = self.resources.templates.add_inline(
+            "extractstructured_schema",
+            &name,
+            &format!("{schema}|tojson"),
+        );
+        self.steps
+            .push(StepType::ExtractStructured(ExtractStructuredStep::new(
+                name,
+                schema_key,
+                input,
+                output,
+                coercions_output,
+            )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, schema, input, llm, output, max_attempts=2, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_repair_json_step(
+        &mut self,
+        name: String,
+        schema: String,
+        input: String,
+        llm: String,
+        output: String,
+        max_attempts: usize,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) {
+        debug!("Added repair JSON step with llm: {}", &llm);
+
+        let schema_key = self.resources.templates.add_inline(
+            "repairjson_schema",
+            &name,
+            &format!("{schema}|tojson"),
+        );
+        let template = tweaktune_core::templates::embed::repair_templates("json")
+            .expect("Repair template")
+            .to_string();
+        let template_key = khash("repair_json", &name, &template);
+        self.resources
+            .templates
+            .templates
+            .insert(template_key.clone(), template);
+        self.steps.push(StepType::RepairJson(RepairJsonStep::new(
+            name,
+            schema_key,
+            input,
+            template_key,
+            llm,
+            output,
+            max_attempts,
+            system_template,
+            max_tokens,
+            temperature,
+        )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, input, mode="fake".to_string(), openapi=None, output=None, llm=None, base_url=None, seed=None, timeout_secs=30, max_tokens=None, temperature=None))]
+    pub fn add_simulate_tool_response_step(
+        &mut self,
+        name: String,
+        input: String,
+        mode: String,
+        openapi: Option<String>,
+        output: Option<String>,
+        llm: Option<String>,
+        base_url: Option<String>,
+        seed: Option<u64>,
+        timeout_secs: u64,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!("Added simulate tool response step in mode: {}", &mode);
+        let mode = mode.parse::<ToolResponseMode>().map_pyerr()?;
+        let operations = match openapi {
+            Some(openapi) => openapi_operations(&read_config::<Value>(&openapi, None).map_pyerr()?),
+            None => HashMap::new(),
+        };
+        let generation_step = llm.map(|llm| {
+            let template = tweaktune_core::templates::embed::tool_templates("response")
+                .expect("Tool response template")
+                .to_string();
+            let key = khash("simulate_tool_response", &name, &template);
+            self.resources
+                .templates
+                .templates
+                .insert(key.clone(), template);
+            TextGenerationStep::new(
+                name.clone(),
+                key,
+                llm,
+                input.clone(),
+                None,
+                max_tokens,
+                temperature,
+            )
+        });
+        self.steps.push(StepType::SimulateToolResponse(
+            SimulateToolResponseStep::new(
+                name,
+                input,
+                output,
+                mode,
+                operations,
+                base_url,
+                generation_step,
+                seed,
+                timeout_secs,
+            )
+            .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, llm, output, max_turns=3, user_llm=None, instructions=None, tools=None, system_template=None, simulate_tools=None, openapi=None, base_url=None, max_tool_rounds=3, seed=None, user_template=None, assistant_template=None, max_tokens=None, temperature=None))]
+    pub fn add_dialogue_step(
+        &mut self,
+        name: String,
+        llm: String,
+        output: String,
+        max_turns: usize,
+        user_llm: Option<String>,
+        instructions: Option<String>,
+        tools: Option<String>,
+        system_template: Option<String>,
+        simulate_tools: Option<String>,
+        openapi: Option<String>,
+        base_url: Option<String>,
+        max_tool_rounds: usize,
+        seed: Option<u64>,
+        user_template: Option<String>,
+        assistant_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> PyResult<()> {
+        debug!("Added dialogue step with llm: {}", &llm);
+        let mut template = |template: Option<String>, role: &str| {
+            template.unwrap_or_else(|| {
+                let template = tweaktune_core::templates::embed::dialogue_templates(role)
+                    .expect("Dialogue template")
+                    .to_string();
+                let key = khash("dialogue", &format!("{name}_{role}"), &template);
+                self.resources
+                    .templates
+                    .templates
+                    .insert(key.clone(), template);
+                key
+            })
+        };
+        let user_template = template(user_template, "user");
+        let assistant_template = template(assistant_template, "assistant");
+
+        let tool_responses = match simulate_tools {
+            Some(mode) => {
+                let mode = mode.parse::<ToolResponseMode>().map_pyerr()?;
+                let operations = match openapi {
+                    Some(openapi) => {
+                        openapi_operations(&read_config::<Value>(&openapi, None).map_pyerr()?)
+                    }
+                    None => HashMap::new(),
+                };
+                let generation_step = (mode == ToolResponseMode::Llm).then(|| {
+                    let template = tweaktune_core::templates::embed::tool_templates("response")
+                        .expect("Tool response template")
+                        .to_string();
+                    let key = khash("simulate_tool_response", &name, &template);
+                    self.resources
+                        .templates
+                        .templates
+                        .insert(key.clone(), template);
+                    TextGenerationStep::new(
+                        name.clone(),
+                        key,
+                        llm.clone(),
+                        output.clone(),
+                        None,
+                        max_tokens,
+                        temperature,
+                    )
+                });
+                Some(
+                    SimulateToolResponseStep::new(
+                        name.clone(),
+                        output.clone(),
+                        None,
+                        mode,
+                        operations,
+                        base_url,
+                        generation_step,
+                        seed,
+                        30,
+                    )
+                    .map_pyerr()?,
+                )
+            }
+            None => None,
+        };
+
+        self.steps
+            .push(StepType::Dialogue(Box::new(DialogueStep::new(
+                name.clone(),
+                output.clone(),
+                TextGenerationStep::new(
+                    name.clone(),
+                    user_template,
+                    user_llm.unwrap_or_else(|| llm.clone()),
+                    output.clone(),
+                    None,
+                    max_tokens,
+                    temperature,
+                ),
+                TextGenerationStep::new(
+                    name,
+                    assistant_template,
+                    llm,
+                    output.clone(),
+                    None,
+                    max_tokens,
+                    temperature,
+                ),
+                max_turns,
+                instructions,
+                tools,
+                system_template,
+                tool_responses,
+                max_tool_rounds,
+            ))));
+        Ok(())
+    }
+
+    pub fn add_validatetools_step(&mut self, name: String, instances: String) {
+        debug!("Added validate tools step");
+
+        self.steps
+            .push(StepType::ValidateTools(ToolsValidateStep::new(
+                name, instances,
+            )));
+    }
+
+    pub fn add_normalizetools_step(&mut self, name: String, instances: String, output: String) {
+        debug!("Added normalize tools step");
+
+        self.steps
+            .push(StepType::NormalizeTools(ToolsNormalizeStep::new(
+                name, instances, output,
+            )));
+    }
+
+    pub fn add_filter_step(&mut self, name: String, condition: String) {
+        debug!("Added filter step");
+
+        let condition_key = self
+            .resources
+            .templates
+            .add_inline("filter", &name, &condition);
+        self.steps
+            .push(StepType::Filter(FilterStep::new(name, condition_key)));
+    }
+
+    pub fn add_mutate_step(
+        &mut self,
+        name: String,
+        mutation: String,
+        is_json: bool,
+        output: String,
+    ) {
+        debug!("Added mutate step");
+        let mutation = if is_json {
+            format!("{mutation}|tojson")
+        } else {
+            mutation
+        };
+        let mutation_key = self
+            .resources
+            .templates
+            .add_inline("mutate", &name, &mutation);
+        self.steps.push(StepType::Mutate(MutateStep::new(
+            name,
+            mutation_key,
+            output,
+            false,
+        )));
+    }
+
+    pub fn add_new_column_step(
+        &mut self,
+        name: String,
+        mutation: String,
+        is_json: bool,
+        output: String,
+    ) {
+        debug!("Added new column step");
+        let mutation = if is_json {
+            format!("{mutation}|tojson")
+        } else {
+            mutation
+        };
+
+        let new_column_key = self
+            .resources
+            .templates
+            .add_inline("new_column", &name, &mutation);
+        self.steps.push(StepType::Mutate(MutateStep::new(
+            name,
+            new_column_key,
+            output,
+            true,
+        )));
+    }
+
+    pub fn add_check_language_step(
+        &mut self,
+        name: String,
+        input: String,
+        language: String,
+        precision: f64,
+        detect_languages: Vec<String>,
+    ) {
+        debug!("Added check language step");
+        self.steps
+            .push(StepType::CheckLanguage(CheckLanguageStep::new(
+                name,
+                input,
+                language,
+                precision,
+                detect_languages,
+            )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, inputs, min_chars=None, max_chars=None, min_words=None, max_words=None, min_tokens=None, max_tokens=None, tokenizer=None))]
+    pub fn add_check_length_step(
+        &mut self,
+        name: String,
+        inputs: Vec<String>,
+        min_chars: Option<usize>,
+        max_chars: Option<usize>,
+        min_words: Option<usize>,
+        max_words: Option<usize>,
+        min_tokens: Option<usize>,
+        max_tokens: Option<usize>,
+        tokenizer: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added check length step for inputs: {:?}", &inputs);
+        let bounds = LengthBounds {
+            min_chars,
+            max_chars,
+            min_words,
+            max_words,
+            min_tokens,
+            max_tokens,
+        };
+        self.steps.push(StepType::CheckLength(
+            CheckLengthStep::new(name, inputs, bounds, tokenizer).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    #[pyo3(signature = (name, input, sources, min_ratio=1.0, min_overlap=0.8, output=None))]
+    pub fn add_check_grounding_step(
+        &mut self,
+        name: String,
+        input: String,
+        sources: Vec<String>,
+        min_ratio: f64,
+        min_overlap: f64,
+        output: Option<String>,
+    ) {
+        debug!("Added check grounding step for input: {}", &input);
+        self.steps
+            .push(StepType::CheckGrounding(CheckGroundingStep::new(
+                name,
+                input,
+                sources,
+                min_ratio,
+                min_overlap,
+                output,
+            )));
+    }
+
+    #[pyo3(signature = (name, input, expected=None, expression=None, tolerance=1e-6, output=None))]
+    pub fn add_verify_math_step(
+        &mut self,
+        name: String,
+        input: String,
+        expected: Option<String>,
+        expression: Option<String>,
+        tolerance: f64,
+        output: Option<String>,
+    ) -> PyResult<()> {
+        debug!("Added verify math step for input: {}", &input);
+        self.steps.push(StepType::VerifyMath(
+            VerifyMathStep::new(name, input, expected, expression, tolerance, output)
+                .map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_check_hash_step(&mut self, name: String, input: String) {
+        debug!("Added check hash step");
+        self.steps
+            .push(StepType::CheckHash(CheckHashStep::new(name, input)));
+    }
+
+    #[pyo3(signature = (name, inputs, output, unique=false))]
+    pub fn add_item_id_step(
+        &mut self,
+        name: String,
+        inputs: Vec<String>,
+        output: String,
+        unique: bool,
+    ) -> PyResult<()> {
+        debug!("Added item id step");
+        self.steps.push(StepType::ItemId(
+            ItemIdStep::new(name, inputs, output, unique).map_pyerr()?,
+        ));
+        Ok(())
+    }
+
+    pub fn add_check_simhash_step(&mut self, name: String, treshold: u32, input: String) {
+        debug!("Added check simhash step");
+        self.steps
+            .push(StepType::CheckSimHash(CheckSimHashStep::new(
+                name, input, treshold,
+            )));
+    }
+
+    pub fn add_check_embeddings_step(
+        &mut self,
+        name: String,
+        input: String,
+        embedding: String,
+        treshold: f32,
+        similarity_output: Option<String>,
+    ) {
+        debug!("Added check embeddings step");
+        self.steps
+            .push(StepType::CheckEmbedding(CheckEmbeddingStep::new(
+                name,
+                embedding,
+                input,
+                treshold,
+                similarity_output,
+            )));
+    }
+
+    #[pyo3(signature = (name, embedding, input, output, persist=false))]
+    pub fn add_embed_step(
+        &mut self,
+        name: String,
+        embedding: String,
+        input: String,
+        output: String,
+        persist: bool,
+    ) {
+        debug!("Added embed step");
+        self.steps.push(StepType::Embed(EmbedStep::new(
+            name, embedding, input, output, persist,
+        )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, embedding, input, references, min_similarity=None, max_similarity=None, similarity_output=None))]
+    pub fn add_similarity_filter_step(
+        &mut self,
+        name: String,
+        embedding: String,
+        input: String,
+        references: Vec<String>,
+        min_similarity: Option<f32>,
+        max_similarity: Option<f32>,
+        similarity_output: Option<String>,
+    ) {
+        debug!("Added similarity filter step");
+        self.steps
+            .push(StepType::SimilarityFilter(SimilarityFilterStep::new(
+                name,
+                embedding,
+                input,
+                references,
+                min_similarity,
+                max_similarity,
+                similarity_output,
+            )));
+    }
+
+    /// Post-run k-means over the vectors stored for `key`. Cluster ids are saved in the
+    /// state db and, when `output_path` is given, added to the matching JSONL rows.
+    #[pyo3(signature = (key, k, max_iter=None, seed=None, output_path=None, output_field="cluster".to_string()))]
+    pub fn cluster_embeddings(
+        &self,
+        key: String,
+        k: usize,
+        max_iter: Option<usize>,
+        seed: Option<u64>,
+        output_path: Option<String>,
+        output_field: String,
+    ) -> PyResult<usize> {
+        let state = self
+            .resources
+            .state
+            .as_ref()
+            .ok_or_err("state")
+            .map_pyerr()?;
+        let (clustered, clusters) = run_async(async {
+            let clustered = cluster_embeddings(state, &key, k, max_iter, seed).await?;
+            let clusters = state.embedding_clusters(&key).await?;
+            Ok::<_, anyhow::Error>((clustered, clusters))
+        })
+        .map_pyerr()?;
+        debug!("Clustered {} embeddings for key: {}", clustered, &key);
+
+        if let Some(path) = output_path {
+            let by_hash: HashMap<String, i64> = clusters
+                .into_iter()
+                .filter_map(|(_, text_hash, cluster)| text_hash.map(|h| (h, cluster)))
+                .collect();
+
+            let content = std::fs::read_to_string(&path).map_pyerr()?;
+            let mut lines = Vec::new();
+            for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                let mut row: serde_json::Value = serde_json::from_str(line).map_pyerr()?;
+                let cluster = row
+                    .get(&key)
+                    .and_then(|v| v.as_str())
+                    .and_then(|text| by_hash.get(&blake3_hash(text)));
+                if let (Some(cluster), Some(obj)) = (cluster, row.as_object_mut()) {
+                    obj.insert(output_field.clone(), json!(cluster));
+                }
+                lines.push(row.to_string());
+            }
+            std::fs::write(&path, lines.join("\n") + "\n").map_pyerr()?;
+        }
+
+        Ok(clustered)
+    }
+
+    #[pyo3(signature = (name, path, format, input, vector))]
+    pub fn add_write_embeddings_step(
+        &mut self,
+        name: String,
+        path: String,
+        format: String,
+        input: String,
+        vector: String,
+    ) -> PyResult<()> {
+        debug!("Added write embeddings step");
+        let format = format.parse::<EmbeddingsFormat>().map_pyerr()?;
+        self.steps
+            .push(StepType::EmbeddingsWriter(EmbeddingsWriterStep::new(
+                name, path, format, input, vector,
+            )));
+        Ok(())
+    }
+
+    pub fn add_group_by_step(
+        &mut self,
+        name: String,
+        key: String,
+        path: String,
+        aggregations: Vec<(String, String)>,
+    ) -> PyResult<()> {
+        debug!("Added group by step for key: {}", &key);
+        let aggregations = aggregations
+            .into_iter()
+            .map(|(output, aggregation)| Ok((output, aggregation.parse::<Aggregation>()?)))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_pyerr()?;
+        self.steps.push(StepType::GroupBy(GroupByStep::new(
+            name,
+            key,
+            path,
+            aggregations,
+        )));
+        Ok(())
+    }
+
+    pub fn add_retrieve_step(
+        &mut self,
+        name: String,
+        embedding: String,
+        query: String,
+        key: String,
+        k: usize,
+        output: String,
+    ) {
+        debug!("Added retrieve step");
+        self.steps.push(StepType::Retrieve(RetrieveStep::new(
+            name, embedding, query, key, k, output,
+        )));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, dataset, embedding, query, template, llm, output, k=3, text_column="text".to_string(), id_column=None, min_similarity=None, chunks="chunks".to_string(), sources="sources".to_string(), system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_grounded_generation_step(
+        &mut self,
+        name: String,
+        dataset: String,
+        embedding: String,
+        query: String,
+        template: String,
+        llm: String,
+        output: String,
+        k: usize,
+        text_column: String,
+        id_column: Option<String>,
+        min_similarity: Option<f32>,
+        chunks: String,
+        sources: String,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) {
+        debug!(
+            "Added grounded generation step with dataset: {}, template: {}",
+            &dataset, &template
+        );
+        let generation = TextGenerationStep::new(
+            format!("{}-generation", name),
+            template,
+            llm,
+            output,
+            system_template,
+            max_tokens,
+            temperature,
+        );
+        self.steps
+            .push(StepType::GroundedGeneration(GroundedGenerationStep::new(
+                name,
+                dataset,
+                embedding,
+                query,
+                k,
+                text_column,
+                id_column,
+                min_similarity,
+                chunks,
+                sources,
+                generation,
+            )));
+    }
+
+    /// Compiles the templates and checks that the LLMs, datasets, embeddings, tokenizers and
+    /// templates the steps use are registered and the outputs writable, failing with all the
+    /// problems found.
+    pub fn compile(&self) -> PyResult<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "🐔 The pipeline has {} problem(s):\n{}",
+            problems.len(),
+            problems.join("\n")
+        )))
+    }
+
+    #[pyo3(signature = (level=None, _target=None, file=None))]
+    pub fn log(&mut self, level: Option<&str>, _target: Option<&str>, file: Option<&str>) {
+        let level = match level {
+            Some("debug") => log::LevelFilter::Debug,
+            Some("info") => log::LevelFilter::Info,
+            Some("warn") => log::LevelFilter::Warn,
+            Some("error") => log::LevelFilter::Error,
+            _ => log::LevelFilter::Info,
+        };
+
+        let config = ConfigBuilder::new()
+            .set_level_color(Level::Error, Some(Color::Rgb(191, 0, 0)))
+            .set_level_color(Level::Warn, Some(Color::Rgb(255, 127, 0)))
+            .set_level_color(Level::Info, Some(Color::Rgb(192, 192, 0)))
+            .set_level_color(Level::Debug, Some(Color::Rgb(63, 127, 0)))
+            .set_level_color(Level::Trace, Some(Color::Rgb(127, 127, 255)))
+            .build();
+
+        if level == log::LevelFilter::Error && !self.metadata.enabled {
+            if let Err(e) = CombinedLogger::init(vec![
+                Box::new((*self.logs_collector).clone()) as Box<dyn simplelog::SharedLogger>
+            ]) {
+                debug!("Initialize logger issue: {}", e);
+            }
+        } else {
+            let now = Local::now();
+            let filename = if let Some(f) = file {
+                format!(
+                    "{}/logs/{}_{}.log",
+                    self.metadata.path,
+                    f,
+                    now.format("%Y-%m-%d_%H-%M-%S")
+                )
+            } else {
+                format!(
+                    "{}/logs/{}.log",
+                    self.metadata.path,
+                    now.format("%Y-%m-%d_%H-%M-%S")
+                )
+            };
+
+            self.log_path = Some(filename.clone());
+
+            if let Err(e) = CombinedLogger::init(vec![
+                WriteLogger::new(level, config.clone(), File::create(&filename).unwrap()),
+                Box::new((*self.logs_collector).clone()) as Box<dyn simplelog::SharedLogger>,
+            ]) {
+                debug!("Initialize logger issue: {}", e);
+            } else {
+                println!("📑 LOGGING INTO FILE {}", &filename);
+            }
+        }
+
+        // env_logger::builder().filter(target, level).init();
+    }
+
+    pub fn stop(&self) -> PyResult<()> {
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Holds back the items not started yet, the running ones finish, until `resume`.
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            info!("⏸️ Paused, no new items are started");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::SeqCst) {
+            info!("▶️ Resumed");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Runs the pipeline, loading the datasets not read yet first. Ctrl-C or `stop` let the
+    /// running items finish and flush the outputs, a second Ctrl-C exits right away. With
+    /// `profile` a breakdown of the time and allocations of each step is printed at the end.
+    #[pyo3(signature = (bus=None, profile=false))]
+    pub fn run(
+        slf: Py<Self>,
+        py: Python<'_>,
+        bus: Option<PyObject>,
+        profile: bool,
+    ) -> PyResult<RunResult> {
+        let datasets_bus = bus.as_ref().map(|bus| bus.clone_ref(py));
+        PipelineBuilder::load_datasets(slf.clone_ref(py), py, datasets_bus)?;
+        let builder = slf.borrow(py);
+        builder.run_interruptible(bus, profile)
+    }
+
+    /// Runs a range iteration until `n` items come out valid, i.e. no step failed or filtered
+    /// them, drawing as many iterations as it takes (at most `max_iterations`) instead of the
+    /// range's stop. Items aren't started while those in progress could be enough, so no more
+    /// than `n` are written.
+    #[pyo3(signature = (n, max_iterations=None, bus=None, profile=false))]
+    pub fn run_until(
+        slf: Py<Self>,
+        py: Python<'_>,
+        n: usize,
+        max_iterations: Option<usize>,
+        bus: Option<PyObject>,
+        profile: bool,
+    ) -> PyResult<RunResult> {
+        if !matches!(slf.borrow(py).iter_by, IterBy::Range { .. }) {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "🐔 run_until needs a range iteration, use iter_range",
+            ));
+        }
+        let datasets_bus = bus.as_ref().map(|bus| bus.clone_ref(py));
+        PipelineBuilder::load_datasets(slf.clone_ref(py), py, datasets_bus)?;
+        slf.borrow_mut(py).until = Some(RunUntil::new(n, max_iterations));
+        let result = slf.borrow(py).run_interruptible(bus, profile);
+        let until = slf.borrow_mut(py).until.take().expect("run_until target");
+        let valid = until.valid.load(Ordering::SeqCst);
+        if matches!(&result, Ok(result) if result.status == "completed") && valid < n {
+            warn!(
+                "🐔 Only {} of {} items came out valid in {} iterations",
+                valid,
+                n,
+                until.drawn.load(Ordering::SeqCst)
+            );
+        }
+        result
+    }
+
+    /// Reads the datasets registered since the last run, all at once, each on its own thread,
+    /// without holding the GIL. Each dataset read is logged and sent to the `bus` as a
+    /// `DatasetLoaded` event. Called by the runs before they start, so calling it beforehand
+    /// only moves the reading earlier.
+    #[pyo3(signature = (bus=None))]
+    pub fn load_datasets(slf: Py<Self>, py: Python<'_>, bus: Option<PyObject>) -> PyResult<()> {
+        let loaders = slf.borrow_mut(py).take_dataset_loaders();
+        if loaders.iter().all(DatasetLoader::is_empty) {
+            return Ok(());
+        }
+        let started = std::time::Instant::now();
+        let loaded = py
+            .allow_threads(|| {
+                DatasetLoader::load_all(loaders, |name, rows, duration| {
+                    info!(
+                        "📦 Loaded dataset {} ({} rows) in {:.2}s",
+                        name, rows, duration
+                    );
+                    if let Some(bus) = &bus {
+                        let event = PipelineEvent::DatasetLoaded {
+                            name: name.to_string(),
+                            rows,
+                            duration,
+                        };
+                        Python::with_gil(|py| deliver(py, bus, event));
+                    }
+                })
+            })
+            .map_pyerr()?;
+        debug!("Datasets loaded in {:.2}s", started.elapsed().as_secs_f64());
+        slf.borrow_mut(py)
+            .add_loaded_datasets(&mut loaded.into_iter())
+            .map_pyerr()
+    }
+
+    /// Starts the run on a background thread, without holding the GIL, and returns a handle
+    /// to follow, wait for or cancel it.
+    #[pyo3(signature = (bus=None, profile=false))]
+    pub fn spawn_run(
+        slf: Py<Self>,
+        py: Python<'_>,
+        bus: Option<PyObject>,
+        profile: bool,
+    ) -> RunHandle {
+        // marked before the thread starts, so a cancel right away isn't overwritten
+        slf.borrow(py).running.store(true, Ordering::SeqCst);
+        let builder = slf.clone_ref(py);
+        // the run is polled on this thread, it needs the stack of a main thread
+        let thread = thread::Builder::new()
+            .name("tweaktune-run".to_string())
+            .stack_size(RUN_THREAD_STACK)
+            .spawn(move || {
+                Python::with_gil(|py| {
+                    let datasets_bus = bus.as_ref().map(|bus| bus.clone_ref(py));
+                    PipelineBuilder::load_datasets(builder.clone_ref(py), py, datasets_bus)?;
+                    let builder = builder.borrow(py);
+                    let builder: &PipelineBuilder = &builder;
+                    py.allow_threads(|| builder.execute(bus, profile))
+                        .map_pyerr()
+                })
+                .map_err(|e| e.to_string())
+            })
+            .expect("Failed to spawn the run thread");
+        RunHandle {
+            builder: slf,
+            thread: std::sync::Mutex::new(Some(thread)),
+            result: std::sync::Mutex::new(None),
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// Runs the pipeline once for each combination of the `grid` values (JSON lists), set as
+    /// the run parameters of the same names; `temperature` and `model` set those of the API
+    /// LLMs too. File writers add the combination to their file names, so each run writes its
+    /// own files, and the runs are compared in `report`, by default `sweeps/<start time>.json`
+    /// of the metadata directory. An interrupted or failed run ends the sweep.
+    #[pyo3(signature = (grid, report=None))]
+    pub fn sweep(
+        slf: Py<Self>,
+        py: Python<'_>,
+        grid: Vec<(String, String)>,
+        report: Option<String>,
+    ) -> PyResult<Vec<RunResult>> {
+        PipelineBuilder::load_datasets(slf.clone_ref(py), py, None)?;
+        PipelineBuilder::sweep_grid(&slf, py, grid, report)
+    }
+}
+
+impl PipelineBuilder {
+    /// Runs the pipeline with the Ctrl-C handling of [`Self::run`], the datasets are loaded.
+    fn run_interruptible(&self, bus: Option<PyObject>, profile: bool) -> PyResult<RunResult> {
+        let _active = ActiveRun::register(self.running.clone());
+        self.execute(bus, profile).map_pyerr()
+    }
+
+    /// See [`Self::sweep`], the datasets are loaded.
+    fn sweep_grid(
+        slf: &Py<Self>,
+        py: Python<'_>,
+        grid: Vec<(String, String)>,
+        report: Option<String>,
+    ) -> PyResult<Vec<RunResult>> {
+        let grid = grid
+            .into_iter()
+            .map(|(key, values)| {
+                let values = serde_json::from_str::<Vec<Value>>(&values).map_pyerr()?;
+                if values.is_empty() {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                        "🐔 Sweep parameter {} has no values",
+                        key
+                    )));
+                }
+                Ok((key, values))
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let started_at = Local::now();
+        let (results, runs, error) = PipelineBuilder::sweep_runs(slf, py, &grid);
+
+        let builder = slf.borrow(py);
+        let report_path = report.unwrap_or_else(|| {
+            format!(
+                "{}/sweeps/{}.json",
+                builder.metadata.path,
+                started_at.format("%Y-%m-%d_%H-%M-%S")
+            )
+        });
+        let report = json!({
+            "name": builder.name,
+            "started_at": started_at.to_rfc3339(),
+            "grid": grid
+                .iter()
+                .map(|(key, values)| (key.clone(), json!(values)))
+                .collect::<serde_json::Map<_, _>>(),
+            "runs": runs,
+        });
+        if let Some(dir) = std::path::Path::new(&report_path).parent() {
+            create_dir_all(dir).map_pyerr()?;
+        }
+        std::fs::write(
+            &report_path,
+            serde_json::to_string_pretty(&report).map_pyerr()?,
+        )
+        .map_pyerr()?;
+        info!("📊 Sweep report written to {}", report_path);
+        println!("{}", sweep_table(&runs));
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(results),
+        }
+    }
+
+    /// Runs each combination of the `grid` values, see [`Self::sweep`], and puts the params,
+    /// file names, LLMs and deduplication keys back afterwards. Returns the results, the runs
+    /// for the report and the error that ended the sweep, if any. The pipeline is only borrowed
+    /// mutably to set up each run, not while it runs.
+    fn sweep_runs(
+        slf: &Py<Self>,
+        py: Python<'_>,
+        grid: &[(String, Vec<Value>)],
+    ) -> (Vec<RunResult>, Vec<Value>, Option<pyo3::PyErr>) {
+        let mut builder = slf.borrow_mut(py);
+        let params = builder.params.clone();
+        let paths = builder.redirect_outputs(&mut str::to_string);
+        let dedup_keys = builder
+            .dedup_keys()
+            .into_iter()
+            .map(|key| key.clone())
+            .collect::<Vec<_>>();
+        let llms = builder
+            .resources
+            .llms
+            .resources
+            .iter()
+            .filter_map(|(name, llm)| match llm {
+                LLMType::Api(llm) => Some((name.clone(), (llm.model.clone(), llm.temperature))),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        drop(builder);
+
+        let mut results = Vec::new();
+        let mut runs = Vec::new();
+        let mut error = None;
+        let mut choice = vec![0; grid.len()];
+        loop {
+            let combination = grid
+                .iter()
+                .zip(&choice)
+                .map(|((key, values), i)| (key.clone(), values[*i].clone()))
+                .collect::<serde_json::Map<_, _>>();
+            let suffix = sweep_suffix(&combination);
+            let mut builder = slf.borrow_mut(py);
+            builder.params.extend(combination.clone());
+            for llm in builder.resources.llms.resources.values_mut() {
+                if let LLMType::Api(llm) = llm {
+                    if let Some(temperature) =
+                        combination.get("temperature").and_then(Value::as_f64)
+                    {
+                        llm.temperature = temperature as f32;
+                    }
+                    if let Some(model) = combination.get("model").and_then(Value::as_str) {
+                        llm.model = Some(model.to_string());
+                    }
+                }
+            }
+            let mut originals = paths.iter();
+            builder.redirect_outputs(&mut |_| {
+                suffixed_path(originals.next().expect("Sweep output"), &suffix)
+            });
+            let outputs = paths
+                .iter()
+                .map(|path| suffixed_path(path, &suffix))
+                .collect::<Vec<_>>();
+            for (key, original) in builder.dedup_keys().into_iter().zip(&dedup_keys) {
+                *key = format!("{}{}", original, suffix);
+            }
+            drop(builder);
+
+            info!(
+                "🧪 Sweep run {}: {}",
+                runs.len() + 1,
+                Value::Object(combination.clone())
+            );
+            let mut run = json!({
+                "run": runs.len() + 1,
+                "params": combination,
+                "outputs": outputs,
+            });
+            let result = slf.borrow(py).run_interruptible(None, false);
+            match result {
+                Ok(result) => {
+                    for key in ["run_id", "status", "processed", "failed", "stopped_by"] {
+                        run[key] = result.report[key].clone();
+                    }
+                    for key in ["tokens", "cost", "elapsed"] {
+                        run[key] = result.report.get(key).cloned().unwrap_or(Value::Null);
+                    }
+                    let interrupted = result.status == "interrupted";
+                    results.push(result);
+                    runs.push(run);
+                    if interrupted {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    run["status"] = json!("failed");
+                    run["error"] = json!(e.to_string());
+                    runs.push(run);
+                    error = Some(e);
+                    break;
+                }
+            }
+
+            // the next combination, the last values change first
+            let mut position = grid.len();
+            while position > 0 {
+                position -= 1;
+                choice[position] += 1;
+                if choice[position] < grid[position].1.len() {
+                    break;
+                }
+                choice[position] = 0;
+            }
+            if choice.iter().all(|i| *i == 0) {
+                break;
+            }
+        }
+
+        let mut builder = slf.borrow_mut(py);
+        builder.params = params;
+        let mut originals = paths.into_iter();
+        builder.redirect_outputs(&mut |_| originals.next().expect("Sweep output"));
+        for (key, original) in builder.dedup_keys().into_iter().zip(dedup_keys) {
+            *key = original;
+        }
+        for (name, llm) in builder.resources.llms.resources.iter_mut() {
+            if let (LLMType::Api(llm), Some((model, temperature))) = (llm, llms.get(name)) {
+                llm.model = model.clone();
+                llm.temperature = *temperature;
+            }
+        }
+        (results, runs, error)
+    }
+
+    /// Points the file outputs of the pipeline and of its sub-pipelines, the writers and the
+    /// quarantine, at the paths `to` makes of their current ones and returns the current ones,
+    /// always in the same order.
+    fn redirect_outputs(&mut self, to: &mut dyn FnMut(&str) -> String) -> Vec<String> {
+        let mut paths = redirect(&mut self.steps, to);
+        if let Some(quarantine) = &mut self.quarantine {
+            let path = to(quarantine);
+            paths.push(std::mem::replace(quarantine, path));
+        }
+        for child in self.subpipelines.values_mut() {
+            paths.extend(child.redirect_outputs(to));
+        }
+        paths
+    }
+
+    /// Keys of the writer deduplication of the pipeline and of its sub-pipelines, always in
+    /// the same order.
+    fn dedup_keys(&mut self) -> Vec<&mut String> {
+        let mut keys = self
+            .writer_dedup
+            .values_mut()
+            .map(|dedup| &mut dedup.key)
+            .collect::<Vec<_>>();
+        for child in self.subpipelines.values_mut() {
+            keys.extend(child.dedup_keys());
+        }
+        keys
+    }
+
+    /// Runs the pipeline on the current thread, the items report to the `bus` when given.
+    fn execute(&self, bus: Option<PyObject>, profile: bool) -> Result<RunResult> {
+        if !self.datasets_loaded() {
+            bail!("🐔 Datasets are not loaded before the run");
+        }
+        self.running.store(true, Ordering::SeqCst);
+        self.paused.store(false, Ordering::SeqCst);
+        self.profiling.store(profile, Ordering::SeqCst);
+        self.timings.reset();
+        let started = std::time::Instant::now();
+        let started_at = Local::now();
+        if let Some(bus) = bus {
+            let (sender, receiver) = mpsc::channel::<PipelineEvent>();
+            let sender = Arc::new(sender);
+            if let Err(e) = WriteLogger::init(
+                log::LevelFilter::Info,
+                ConfigBuilder::new().build(),
+                ChannelWriter::new(sender.clone()),
+            ) {
+                debug!("Initialize bus logger issue: {}", e);
+            }
+
+            thread::spawn(move || {
+                for event in receiver {
+                    Python::with_gil(|py| deliver(py, &bus, event));
+                }
+            });
+
+            *self.events.write().unwrap() = Some(sender);
+        }
+        self.emit(PipelineEvent::RunStarted {
+            run_id: self.id.to_string(),
+            name: self.name.clone(),
+            total: self.total_items(),
+        });
+
+        let log_path = self.log_path.clone();
+
+        let result = run_async(async {
+            if self.metadata.enabled {
+                if let Some(state) = &self.resources.state {
+                    state
+                        .add_run(
+                            &self.id.to_string(),
+                            log_path.as_ref().expect("Log path not set"),
+                            None,
+                        )
+                        .await?;
+                }
+            }
+
+            if self.dag {
+                *self.dag_waves.write().unwrap() = dag::plan(&self.steps, &self.step_io)?;
+            }
+            if let Some(autoscaler) = &self.autoscaler {
+                autoscaler.restart(self.workers);
+            }
+            self.failures.reset();
+            self.stop_condition.reset();
+            self.stats.reset();
+            self.resources.reset_worker_rngs();
+            let successfull_iterations = self.processed.clone();
+            successfull_iterations.store(0, Ordering::SeqCst);
+            match &self.iter_by {
+                IterBy::Range { start, stop, step } => {
+                    debug!("Iterating by range: {}..{}..{}", start, stop, step);
+                    let (indices, bar): (Box<dyn Iterator<Item = usize>>, _) = match &self.until {
+                        Some(until) => (
+                            Box::new(
+                                (*start..)
+                                    .step_by(*step)
+                                    .take(until.max_iterations.unwrap_or(usize::MAX)),
+                            ),
+                            ProgressBar::new(until.target as u64),
+                        ),
+                        None => (
+                            Box::new((*start..*stop).step_by(*step)),
+                            ProgressBar::new((stop - start) as u64),
+                        ),
+                    };
+
+                    bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",)
+                    .unwrap().progress_chars("#>-"));
+
+                    drain_items(
+                        self,
+                        stream::iter(indices.take_while(|_| self.scheduling()).map(|i| {
+                            let bar = &bar;
+                            let value = successfull_iterations.clone();
+                            let rid = self.id.to_string();
+                            async move {
+                                if let Some(until) = &self.until {
+                                    // waits while the items in progress may be enough
+                                    while !until.try_start() {
+                                        if until.reached() || !self.running.load(Ordering::SeqCst) {
+                                            return Ok(());
+                                        }
+                                        tokio::time::sleep(RUN_UNTIL_POLL).await;
+                                    }
+                                }
+                                let mut context = StepContext::new();
+                                context.set("index", i);
+                                context.set_status(StepStatus::Running);
+                                let item_id = context.id.to_string();
+                                if self.metadata.enabled {
+                                    if let Some(state) = &self.resources.state {
+                                        state
+                                            .add_item(&item_id, &rid, i as i64, None)
+                                            .await
+                                            .unwrap();
+                                    }
+                                }
+                                if let Err(e) = process_item(self, context, None).await {
+                                    if let Some(state) = &self.resources.state {
+                                        state.delete_item(&item_id).await.ok();
+                                    }
+                                    return Err(format!("Error processing step: {} - {}", i, e));
+                                } else {
+                                    value.fetch_add(1, Ordering::SeqCst);
+                                }
+
+                                match &self.until {
+                                    Some(until) => {
+                                        bar.set_position(until.valid.load(Ordering::SeqCst) as u64)
+                                    }
+                                    None => bar.inc(1),
+                                }
+                                Ok(())
+                            }
+                        }))
+                        .buffered(self.concurrency()),
+                    )
+                    .await?;
+                }
+                IterBy::Dataset { .. }
+                | IterBy::Zip { .. }
+                | IterBy::Product { .. }
+                | IterBy::Sample { .. } => {
+                    debug!("Iterating by {:?}", self.iter_by);
+                    let names = match &self.iter_by {
+                        IterBy::Zip { names } | IterBy::Product { names } => names.as_slice(),
+                        _ => &[],
+                    };
+                    let datasets = names
+                        .iter()
+                        .map(|name| {
+                            let dataset = self.resources.datasets.get(name).ok_or_err(name)?;
+                            let Some(df) = dataset_df(dataset) else {
+                                bail!("🐔 Mixed dataset {} can't be combined", name);
+                            };
+                            Ok((name.clone(), df))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let mut first_index = 0;
+                    let rows: Box<dyn Iterator<Item = Result<Value>>> = match &self.iter_by {
+                        IterBy::Dataset { name, limit, skip } => {
+                            first_index = skip.unwrap_or(0);
+                            Box::new(
+                                self.dataset_rows(name)?
+                                    .skip(first_index)
+                                    .take(limit.unwrap_or(usize::MAX))
+                                    .map(|row| row.map(|row| json!({ name: row }))),
+                            )
+                        }
+                        IterBy::Zip { .. } => Box::new(zip_rows(&datasets)?),
+                        IterBy::Sample { name, n, seed } => {
+                            Box::new(self.sample_rows(name, *n, *seed)?.into_iter().map(Ok))
+                        }
+                        _ => Box::new(product_rows(&datasets)?.map(Ok)),
+                    };
+                    let rows: Box<dyn Iterator<Item = Result<Value>>> = if self.epochs > 1 {
+                        let seed = self.resources.step_seed("EPOCHS", None);
+                        let rows = rows.collect::<Result<Vec<_>>>()?;
+                        Box::new(epoch_rows(rows, self.epochs, self.shuffle_epochs, seed).map(Ok))
+                    } else {
+                        rows
+                    };
+
+                    let bar = ProgressBar::new(0);
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            "{spinner:.green} [{elapsed_precise}] ({pos})",
+                        )
+                        .unwrap(),
+                    );
+
+                    drain_items(
+                        self,
+                        stream::iter(rows.take_while(|_| self.scheduling()).enumerate().map(
+                            |(i, row)| {
+                                let bar = &bar;
+                                bar.inc_length(1);
+                                let value = successfull_iterations.clone();
+                                async move {
+                                    let row = row.map_err(|e| e.to_string())?;
+                                    let rows = row
+                                        .as_object()
+                                        .into_iter()
+                                        .flatten()
+                                        .map(|(name, json_row)| (name.as_str(), json_row))
+                                        .collect::<Vec<_>>();
+                                    let index = (first_index + i) as i32;
+                                    if let Err(e) = map_record_batches(self, &rows, &index).await {
+                                        return Err(format!(
+                                            "Error processing step: {} - {}",
+                                            i, e
+                                        ));
+                                    }
+                                    value.fetch_add(1, Ordering::SeqCst);
+                                    bar.inc(1);
+                                    Ok(())
+                                }
+                            },
+                        ))
+                        .buffered(self.concurrency()),
+                    )
+                    .await?;
+                }
+            }
+
+            finish_pipeline(self).await?;
+
+            if self.failures.exceeded() {
+                bail!(
+                    "🐔 Aborted after {} of {} items failed",
+                    self.failures.failures.load(Ordering::SeqCst),
+                    self.failures.items.load(Ordering::SeqCst)
+                );
+            }
+
+            let interrupted = !self.running.load(Ordering::SeqCst);
+            let stopped_by = self.stop_condition.fired();
+            let processed = successfull_iterations.load(Ordering::SeqCst);
+            let status = if interrupted {
+                info!("🛑 Interrupted, processed {} items", processed);
+                "interrupted"
+            } else if let Some(limit) = &stopped_by {
+                info!(
+                    "🛑 Stopped at the {} limit, processed {} items",
+                    limit, processed
+                );
+                "stopped"
+            } else {
+                info!("🚀 Finished all iterations, processed {} items", processed);
+                "completed"
+            };
+
+            Ok::<_, anyhow::Error>(RunResult {
+                status: status.to_string(),
+                processed,
+                failed: self.failures.failures.load(Ordering::SeqCst),
+                stopped_by,
+                report: Value::Null,
+            })
+        });
+
+        let report = self.report(&result, started_at, started.elapsed());
+        if self.metadata.enabled {
+            if let Err(e) = self.write_report(&report, started_at) {
+                error!("🐔 Failed to write the run report: {:#}", e);
+            }
+        }
+        let result = result.map(|result| RunResult { report, ..result });
+
+        self.emit(match &result {
+            Ok(result) => PipelineEvent::RunFinished {
+                status: result.status.clone(),
+                processed: result.processed,
+                failed: result.failed,
+                elapsed: started.elapsed().as_secs_f64(),
+                error: None,
+            },
+            Err(e) => PipelineEvent::RunFinished {
+                status: "failed".to_string(),
+                processed: self.processed.load(Ordering::SeqCst),
+                failed: self.failures.failures.load(Ordering::SeqCst),
+                elapsed: started.elapsed().as_secs_f64(),
+                error: Some(format!("{:#}", e)),
+            },
+        });
+        // the bus thread ends once the last sender is gone
+        self.events.write().unwrap().take();
+        if let Some(telemetry) = &self.telemetry {
+            if let Err(e) = telemetry.flush() {
+                error!("🐔 Failed to export traces: {:#}", e);
+            }
+        }
 
-        let conversation_key = format!("{}_dpo_conversation", output);
-        self.add_render_conversation_step(
-            name.clone(),
-            conversation,
-            conversation_key.to_string(),
-            tools.clone(),
-            separator,
-        );
+        println!("{}", self.logs_collector.summary_table());
+        if profile {
+            println!(
+                "{}",
+                self.timings
+                    .profile_table(started.elapsed(), self.concurrency())
+            );
+        } else if self.step_timings.is_some() {
+            println!("{}", self.timings.summary_table());
+        }
 
-        let messages_key = self.resources.templates.add_inline(
-            "render_dpo",
-            &name,
-            &format!("{}.messages|tojson", &conversation_key),
-        );
-        let chosen_key = self
-            .resources
-            .templates
-            .add_inline("render_dpo", &name, &chosen);
-        let rejected_key = self
-            .resources
-            .templates
-            .add_inline("render_dpo", &name, &rejected);
+        result
+    }
 
-        let tools_key = if tools.is_some() {
-            Some(self.resources.templates.add_inline(
-                "render_dpo",
-                &name,
-                &format!("{}.tools|tojson", &conversation_key),
-            ))
-        } else {
-            None
+    /// Machine-readable summary of a run: its outcome, the per-step counts, latencies and
+    /// errors, the tokens used and the fingerprints of the datasets.
+    fn report(
+        &self,
+        result: &Result<RunResult>,
+        started_at: chrono::DateTime<Local>,
+        elapsed: Duration,
+    ) -> Value {
+        let (status, error) = match result {
+            Ok(result) => (result.status.clone(), None),
+            Err(e) => ("failed".to_string(), Some(format!("{:#}", e))),
         };
+        let datasets = self
+            .resources
+            .datasets
+            .resources
+            .iter()
+            .map(|(name, dataset)| {
+                let df = dataset_df(dataset);
+                let dataset = json!({
+                    "rows": df.map(|df| df.height()),
+                    "fingerprint": df.and_then(|df| fingerprint_df(df).ok()),
+                });
+                (name.clone(), dataset)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let mut report = self.stats.report();
+        report["run_id"] = json!(self.id.to_string());
+        report["name"] = json!(self.name);
+        report["status"] = json!(status);
+        report["error"] = json!(error);
+        report["started_at"] = json!(started_at.to_rfc3339());
+        report["elapsed"] = json!(elapsed.as_secs_f64());
+        report["processed"] = json!(self.processed.load(Ordering::SeqCst));
+        report["failed"] = json!(self.failures.failures.load(Ordering::SeqCst));
+        report["stopped_by"] = json!(self.stop_condition.fired());
+        if let Some(until) = &self.until {
+            report["valid"] = json!(until.valid.load(Ordering::SeqCst));
+            report["iterations"] = json!(until.drawn.load(Ordering::SeqCst));
+        }
+        if let Some((_, price)) = self.stop_condition.max_cost {
+            report["cost"] = json!(self.stats.tokens() as f64 / 1000.0 * price);
+        }
+        report["datasets"] = Value::Object(datasets);
+        report
+    }
 
-        let tool_call_template =
-            r#"<tool_call>{"name":"{{name}}","arguments":{{arguments | tojson}}}</tool_call>"#;
-
-        let tool_call_template_key = format!("{}_tool_call_template", &name);
-        self.resources.templates.add(
-            tool_call_template_key.clone(),
-            tool_call_template.to_string(),
+    /// Writes `report` to `runs/<start time>/report.json` of the metadata directory.
+    fn write_report(&self, report: &Value, started_at: chrono::DateTime<Local>) -> Result<()> {
+        let dir = format!(
+            "{}/runs/{}",
+            self.metadata.path,
+            started_at.format("%Y-%m-%d_%H-%M-%S")
         );
+        create_dir_all(&dir)?;
+        let path = format!("{}/report.json", dir);
+        std::fs::write(&path, serde_json::to_string_pretty(report)?)?;
+        debug!("Run report written to {}", path);
+        Ok(())
+    }
 
-        self.steps.push(StepType::RenderDPO(RenderDPOStep::new(
-            name,
-            messages_key,
-            chosen_key,
-            rejected_key,
-            tool_call_template_key,
-            tools_key,
-            output,
-        )));
+    /// The datasets not read yet of this pipeline and of its sub-pipelines, in the order
+    /// [`Self::add_loaded_datasets`] takes them back.
+    fn take_dataset_loaders(&mut self) -> Vec<DatasetLoader> {
+        let mut loaders = vec![std::mem::take(&mut self.resources.pending_datasets)];
+        for child in self.subpipelines.values_mut() {
+            loaders.extend(child.take_dataset_loaders());
+        }
+        loaders
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn add_render_grpo_step(
+    fn datasets_loaded(&self) -> bool {
+        self.resources.pending_datasets.is_empty()
+            && self
+                .subpipelines
+                .values()
+                .all(PipelineBuilder::datasets_loaded)
+    }
+
+    fn add_loaded_datasets(
         &mut self,
-        name: String,
-        conversation: String,
-        output: String,
-        solution: String,
-        validator_id: String,
-        tools: Option<String>,
-        separator: Option<String>,
-    ) {
-        debug!("Added render GRPO step");
+        loaded: &mut impl Iterator<Item = LoadedDatasets>,
+    ) -> Result<()> {
+        loaded
+            .next()
+            .expect("Datasets of the pipeline")
+            .add_to(&mut self.resources.datasets)?;
+        for child in self.subpipelines.values_mut() {
+            child.add_loaded_datasets(loaded)?;
+        }
+        Ok(())
+    }
 
-        let conversation_key = format!("{}_grpo_conversation", output);
-        self.add_render_conversation_step(
-            name.clone(),
-            conversation,
-            conversation_key.to_string(),
-            tools.clone(),
-            separator,
-        );
+    /// Sends `event` to the bus of the run, if any.
+    fn emit(&self, event: PipelineEvent) {
+        if let Some(sender) = &*self.events.read().unwrap() {
+            sender.send(event).ok();
+        }
+    }
 
-        let messages_key = self.resources.templates.add_inline(
-            "render_grpo",
-            &name,
-            &format!("{}.messages|tojson", &conversation_key),
-        );
-        let solution_key = self
-            .resources
-            .templates
-            .add_inline("render_grpo", &name, &solution);
+    /// Number of items of the run when known before it starts.
+    fn total_items(&self) -> Option<usize> {
+        match &self.iter_by {
+            IterBy::Range { .. } if self.until.is_some() => None,
+            IterBy::Range { start, stop, step } => Some((*start..*stop).step_by(*step).len()),
+            IterBy::Dataset { name, limit, skip } => {
+                let dataset = self.resources.datasets.get(name)?;
+                let height = dataset_df(dataset)?
+                    .height()
+                    .saturating_sub(skip.unwrap_or(0));
+                Some(height.min(limit.unwrap_or(usize::MAX)) * self.epochs)
+            }
+            IterBy::Sample { n, .. } => Some(n * self.epochs),
+            _ => None,
+        }
+    }
 
-        let tools_key = if tools.is_some() {
-            Some(self.resources.templates.add_inline(
-                "render_grpo",
-                &name,
-                &format!("{}.tools|tojson", &conversation_key),
-            ))
-        } else {
-            None
-        };
+    /// Rows of the dataset `name`, mixed datasets give their combinations.
+    fn dataset_rows(&self, name: &str) -> Result<Box<dyn Iterator<Item = Result<Value>> + '_>> {
+        let dataset = self.resources.datasets.get(name).ok_or_err(name)?;
+        Ok(match dataset {
+            DatasetType::Mixed(mixed_dataset) => {
+                Box::new(mixed_dataset.stream_mix(&self.resources.datasets.resources)?)
+            }
+            dataset => Box::new(create_rows_stream(dataset_df(dataset).ok_or_err(name)?)?),
+        })
+    }
 
-        self.steps.push(StepType::RenderGRPO(RenderGRPOStep::new(
-            name,
-            messages_key,
-            solution_key,
-            validator_id,
-            tools_key,
-            output,
-        )));
+    /// `n` random rows of the dataset `name`, as objects keyed by the dataset name.
+    fn sample_rows(&self, name: &str, n: usize, seed: Option<u64>) -> Result<Vec<Value>> {
+        let mut rng = item_rng(self.resources.step_seed("ITER-SAMPLE", seed), 0);
+        let rows = sample_dataset(&self.resources, name, Some(n), &mut rng)?;
+        Ok(rows.into_iter().map(|row| json!({ name: row })).collect())
     }
 
-    pub fn add_render_tool_call_step(
+    fn add_api_llm(
         &mut self,
         name: String,
-        tool_name: String,
-        arguments: String,
-        output: String,
-        additional_template: Option<String>,
+        mut modes: Vec<ApiLLMMode>,
+        max_tokens: u32,
+        temperature: f32,
     ) {
-        debug!("Added render tool call step");
-        self.steps
-            .push(StepType::RenderToolCall(RenderToolCallStep::new(
-                name,
-                tool_name,
-                arguments,
-                output,
-                additional_template,
-            )));
+        let first = modes.remove(0);
+        let mut llm = ApiLLM::new(name, first, max_tokens, temperature).with_endpoints(modes);
+        llm.seed = self.resources.seed.map(|seed| seed as u32);
+        self.resources.llms.add(llm.name.clone(), LLMType::Api(llm));
     }
 
-    pub fn add_validatejson_step(&mut self, name: String, schema: String, instance: String) {
-        debug!("Added validate JSON step");
-
-        let schema_key = self.resources.templates.add_inline(
-            "validatejson_schema",
-            &name,
-            &format!("{schema}|tojson"),
-        );
-        let instance_key = self.resources.templates.add_inline(
-            "validatejson_instance",
-            &name,
-            &format!("{instance}|tojson"),
-        );
-        self.steps
-            .push(StepType::ValidateJson(ValidateJsonStep::new(
-                name,
-                schema_key,
-                instance_key,
-            )));
+    /// Problems found by [`Self::compile`], those of the sub-pipelines included.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // the sub-pipelines' templates were added to these, the environment is shared
+        if let Err(e) = self.resources.templates.compile() {
+            problems.push(format!("🐔 Failed to compile templates: {:#}", e));
+        }
+        problems.extend(self.reference_problems());
+        problems
     }
 
-    pub fn add_validatetools_step(&mut self, name: String, instances: String) {
-        debug!("Added validate tools step");
-
-        self.steps
-            .push(StepType::ValidateTools(ToolsValidateStep::new(
-                name, instances,
-            )));
+    /// References to unknown resources of the steps, those of the sub-pipelines included.
+    fn reference_problems(&self) -> Vec<String> {
+        let mut problems = references::check(&self.steps, &self.resources);
+        for (name, child) in &self.subpipelines {
+            problems.extend(
+                child
+                    .reference_problems()
+                    .into_iter()
+                    .map(|problem| format!("{} (sub-pipeline {})", problem, name)),
+            );
+        }
+        problems
     }
 
-    pub fn add_normalizetools_step(&mut self, name: String, instances: String, output: String) {
-        debug!("Added normalize tools step");
+    /// Items started at once, the upper bound when autoscaling.
+    fn concurrency(&self) -> usize {
+        match &self.autoscaler {
+            Some(autoscaler) => autoscaler.max,
+            None => self.workers,
+        }
+    }
 
-        self.steps
-            .push(StepType::NormalizeTools(ToolsNormalizeStep::new(
-                name, instances, output,
-            )));
+    /// Whether the next item is started, false once interrupted, over the failure limit, at
+    /// the `run_until` target or at a stop condition. Called once per item about to start.
+    fn scheduling(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+            && !self.failures.exceeded()
+            && !self.until.as_ref().is_some_and(RunUntil::reached)
+            && self.stop_condition.admit(self.stats.tokens())
     }
+}
 
-    pub fn add_filter_step(&mut self, name: String, condition: String) {
-        debug!("Added filter step");
+/// Outcome of a run that was not stopped by an error.
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    /// `completed`, `interrupted` by Ctrl-C or `stop`, or `stopped` by a stop condition.
+    #[pyo3(get)]
+    pub status: String,
+    #[pyo3(get)]
+    pub processed: usize,
+    #[pyo3(get)]
+    pub failed: usize,
+    /// The limit of the stop condition reached: `max_items`, `max_tokens`, `max_cost` or
+    /// `max_duration`.
+    #[pyo3(get)]
+    pub stopped_by: Option<String>,
+    pub report: Value,
+}
 
-        let condition_key = self
-            .resources
-            .templates
-            .add_inline("filter", &name, &condition);
-        self.steps
-            .push(StepType::Filter(FilterStep::new(name, condition_key)));
+#[pymethods]
+impl RunResult {
+    /// The run report as a dict, also written to `runs/<start time>/report.json` of the
+    /// metadata directory.
+    #[getter]
+    fn report(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let report = py
+            .import("json")?
+            .call_method1("loads", (self.report.to_string(),))?;
+        Ok(report.unbind())
     }
 
-    pub fn add_mutate_step(
-        &mut self,
-        name: String,
-        mutation: String,
-        is_json: bool,
-        output: String,
-    ) {
-        debug!("Added mutate step");
-        let mutation = if is_json {
-            format!("{mutation}|tojson")
-        } else {
-            mutation
-        };
-        let mutation_key = self
-            .resources
-            .templates
-            .add_inline("mutate", &name, &mutation);
-        self.steps.push(StepType::Mutate(MutateStep::new(
-            name,
-            mutation_key,
-            output,
-            false,
-        )));
+    fn __repr__(&self) -> String {
+        match &self.stopped_by {
+            Some(limit) => format!(
+                "RunResult(status={}, processed={}, failed={}, stopped_by={})",
+                self.status, self.processed, self.failed, limit
+            ),
+            None => format!(
+                "RunResult(status={}, processed={}, failed={})",
+                self.status, self.processed, self.failed
+            ),
+        }
     }
+}
 
-    pub fn add_new_column_step(
-        &mut self,
-        name: String,
-        mutation: String,
-        is_json: bool,
-        output: String,
-    ) {
-        debug!("Added new column step");
-        let mutation = if is_json {
-            format!("{mutation}|tojson")
-        } else {
-            mutation
-        };
+/// Handle of a run started with `spawn_run`.
+#[pyclass]
+pub struct RunHandle {
+    builder: Py<PipelineBuilder>,
+    thread: std::sync::Mutex<Option<thread::JoinHandle<Result<RunResult, String>>>>,
+    result: std::sync::Mutex<Option<Result<RunResult, String>>>,
+    started: std::time::Instant,
+}
 
-        let new_column_key = self
-            .resources
-            .templates
-            .add_inline("new_column", &name, &mutation);
-        self.steps.push(StepType::Mutate(MutateStep::new(
-            name,
-            new_column_key,
-            output,
-            true,
-        )));
+impl RunHandle {
+    /// Keeps the result once the run thread has finished.
+    fn poll(&self) {
+        let mut thread = self.thread.lock().unwrap();
+        if thread.as_ref().is_some_and(|thread| thread.is_finished()) {
+            let result = thread
+                .take()
+                .unwrap()
+                .join()
+                .unwrap_or_else(|_| Err("🐔 Run thread panicked".to_string()));
+            *self.result.lock().unwrap() = Some(result);
+        }
     }
+}
 
-    pub fn add_check_language_step(
-        &mut self,
-        name: String,
-        input: String,
-        language: String,
-        precision: f64,
-        detect_languages: Vec<String>,
-    ) {
-        debug!("Added check language step");
-        self.steps
-            .push(StepType::CheckLanguage(CheckLanguageStep::new(
-                name,
-                input,
-                language,
-                precision,
-                detect_languages,
-            )));
+#[pymethods]
+impl RunHandle {
+    /// `running`, `paused`, `cancelling` until the running items finish, then `completed`,
+    /// `interrupted`, `stopped` or `failed`.
+    pub fn status(&self, py: Python<'_>) -> String {
+        self.poll();
+        let builder = self.builder.borrow(py);
+        match &*self.result.lock().unwrap() {
+            Some(Ok(result)) => result.status.clone(),
+            Some(Err(_)) => "failed".to_string(),
+            None if !builder.running.load(Ordering::SeqCst) => "cancelling".to_string(),
+            None if builder.is_paused() => "paused".to_string(),
+            None => "running".to_string(),
+        }
     }
 
-    pub fn add_check_hash_step(&mut self, name: String, input: String) {
-        debug!("Added check hash step");
-        self.steps
-            .push(StepType::CheckHash(CheckHashStep::new(name, input)));
+    /// Counts of the items processed and failed so far, and the seconds since the start.
+    pub fn progress(&self, py: Python<'_>) -> HashMap<String, f64> {
+        let builder = self.builder.borrow(py);
+        HashMap::from([
+            (
+                "processed".to_string(),
+                builder.processed.load(Ordering::SeqCst) as f64,
+            ),
+            (
+                "failed".to_string(),
+                builder.failures.failures.load(Ordering::SeqCst) as f64,
+            ),
+            ("elapsed".to_string(), self.started.elapsed().as_secs_f64()),
+        ])
     }
 
-    pub fn add_check_simhash_step(&mut self, name: String, treshold: u32, input: String) {
-        debug!("Added check simhash step");
-        self.steps
-            .push(StepType::CheckSimHash(CheckSimHashStep::new(
-                name, input, treshold,
-            )));
+    /// Blocks until the run ends, or `timeout` seconds passed (then `None`), and returns its
+    /// result. Raises the error the run failed with.
+    #[pyo3(signature = (timeout=None))]
+    pub fn wait(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<RunResult>> {
+        let deadline =
+            timeout.map(|timeout| std::time::Instant::now() + Duration::from_secs_f64(timeout));
+        py.allow_threads(|| loop {
+            self.poll();
+            if self.result.lock().unwrap().is_some()
+                || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        });
+        match &*self.result.lock().unwrap() {
+            Some(Ok(result)) => Ok(Some(result.clone())),
+            Some(Err(e)) => Err(pyo3::exceptions::PyRuntimeError::new_err(e.clone())),
+            None => Ok(None),
+        }
     }
 
-    pub fn add_check_embeddings_step(
-        &mut self,
-        name: String,
-        input: String,
-        embedding: String,
-        treshold: f32,
-        similarity_output: Option<String>,
-    ) {
-        debug!("Added check embeddings step");
-        self.steps
-            .push(StepType::CheckEmbedding(CheckEmbeddingStep::new(
-                name,
-                embedding,
-                input,
-                treshold,
-                similarity_output,
-            )));
+    /// Stops scheduling new items, the running ones finish and the outputs are flushed as on
+    /// Ctrl-C.
+    pub fn cancel(&self, py: Python<'_>) {
+        self.builder
+            .borrow(py)
+            .running
+            .store(false, Ordering::SeqCst);
     }
 
-    pub fn compile(&self) {
-        self.resources.templates.compile().unwrap();
+    /// Starts no new items until `resume`, the running ones finish.
+    pub fn pause(&self, py: Python<'_>) {
+        self.builder.borrow(py).pause();
     }
 
-    #[pyo3(signature = (level=None, _target=None, file=None))]
-    pub fn log(&mut self, level: Option<&str>, _target: Option<&str>, file: Option<&str>) {
-        let level = match level {
-            Some("debug") => log::LevelFilter::Debug,
-            Some("info") => log::LevelFilter::Info,
-            Some("warn") => log::LevelFilter::Warn,
-            Some("error") => log::LevelFilter::Error,
-            _ => log::LevelFilter::Info,
-        };
+    pub fn resume(&self, py: Python<'_>) {
+        self.builder.borrow(py).resume();
+    }
 
-        let config = ConfigBuilder::new()
-            .set_level_color(Level::Error, Some(Color::Rgb(191, 0, 0)))
-            .set_level_color(Level::Warn, Some(Color::Rgb(255, 127, 0)))
-            .set_level_color(Level::Info, Some(Color::Rgb(192, 192, 0)))
-            .set_level_color(Level::Debug, Some(Color::Rgb(63, 127, 0)))
-            .set_level_color(Level::Trace, Some(Color::Rgb(127, 127, 255)))
-            .build();
+    pub fn done(&self) -> bool {
+        self.poll();
+        self.result.lock().unwrap().is_some()
+    }
+}
 
-        if level == log::LevelFilter::Error && !self.metadata.enabled {
-            if let Err(e) = CombinedLogger::init(vec![
-                Box::new((*self.logs_collector).clone()) as Box<dyn simplelog::SharedLogger>
-            ]) {
-                debug!("Initialize logger issue: {}", e);
-            }
-        } else {
-            let now = Local::now();
-            let filename = if let Some(f) = file {
-                format!(
-                    "{}/logs/{}_{}.log",
-                    self.metadata.path,
-                    f,
-                    now.format("%Y-%m-%d_%H-%M-%S")
-                )
-            } else {
-                format!(
-                    "{}/logs/{}.log",
-                    self.metadata.path,
-                    now.format("%Y-%m-%d_%H-%M-%S")
-                )
-            };
+/// The `running` flags of the runs in progress, the Ctrl-C handler can be set only once per
+/// process so it signals whichever runs are registered here.
+static ACTIVE_RUNS: Mutex<Vec<Arc<AtomicBool>>> = Mutex::new(Vec::new());
+static CTRLC_HANDLER: Once = Once::new();
 
-            self.log_path = Some(filename.clone());
+/// Keeps a run registered for Ctrl-C until dropped. The first Ctrl-C stops the active runs, a
+/// second one exits right away. Without an active run Ctrl-C does nothing.
+struct ActiveRun(Arc<AtomicBool>);
 
-            if let Err(e) = CombinedLogger::init(vec![
-                WriteLogger::new(level, config.clone(), File::create(&filename).unwrap()),
-                Box::new((*self.logs_collector).clone()) as Box<dyn simplelog::SharedLogger>,
-            ]) {
-                debug!("Initialize logger issue: {}", e);
-            } else {
-                println!("📑 LOGGING INTO FILE {}", &filename);
+impl ActiveRun {
+    fn register(running: Arc<AtomicBool>) -> Self {
+        CTRLC_HANDLER.call_once(|| match ctrlc::set_handler(interrupt_active_runs) {
+            Ok(_) => debug!("Ctrl-C handler set"),
+            Err(e) => debug!("Error setting Ctrl-C handler: {}", e),
+        });
+        ACTIVE_RUNS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(running.clone());
+        Self(running)
+    }
+}
+
+impl Drop for ActiveRun {
+    fn drop(&mut self) {
+        ACTIVE_RUNS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|r| !Arc::ptr_eq(r, &self.0));
+    }
+}
+
+fn interrupt_active_runs() {
+    let runs = ACTIVE_RUNS.lock().unwrap_or_else(|e| e.into_inner());
+    if runs.is_empty() {
+        return;
+    }
+    let mut stopped = false;
+    for running in runs.iter() {
+        stopped |= running.swap(false, Ordering::SeqCst);
+    }
+    if !stopped {
+        std::process::exit(130);
+    }
+}
+
+/// Drives the scheduled items, checking each result as it completes so a failing run stops
+/// right away and nothing is kept per item. Once the run is interrupted the items still
+/// running get the shutdown timeout to finish, then they are abandoned.
+async fn drain_items(
+    pipeline: &PipelineBuilder,
+    items: impl stream::Stream<Item = Result<(), String>>,
+) -> Result<()> {
+    let mut items = std::pin::pin!(items);
+    let mut deadline = None;
+    loop {
+        if deadline.is_none() && !pipeline.running.load(Ordering::SeqCst) {
+            info!("🛑 Interrupted, waiting for the running items to finish");
+            deadline = Some(tokio::time::Instant::now() + pipeline.shutdown_timeout);
+        }
+        // without a deadline the wait is cut short to notice an interruption
+        let wait = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, items.next()).await,
+            None => tokio::time::timeout(Duration::from_millis(100), items.next()).await,
+        };
+        match wait {
+            Ok(Some(result)) => pipeline.failures.check(result)?,
+            Ok(None) => break,
+            Err(_) if deadline.is_some() => {
+                warn!("🐔 Abandoned the items still running after the shutdown timeout");
+                break;
             }
+            Err(_) => {}
         }
+    }
+    Ok(())
+}
 
-        // env_logger::builder().filter(target, level).init();
+async fn map_record_batches(
+    pipeline: &PipelineBuilder,
+    rows: &[(&str, &serde_json::Value)],
+    inc: &i32,
+) -> Result<()> {
+    let mut context = StepContext::new();
+
+    for (dataset_name, json_row) in rows {
+        context.set(dataset_name, json_row);
+    }
+    context.set("index", inc);
+    context.set_status(StepStatus::Running);
+    let item_id = context.id.to_string();
+    if pipeline.metadata.enabled {
+        if let Some(state) = &pipeline.resources.state {
+            state
+                .add_item(&item_id, &pipeline.id.to_string(), *inc as i64, None)
+                .await
+                .unwrap();
+        }
     }
 
-    pub fn stop(&self) -> PyResult<()> {
-        self.running.store(false, Ordering::SeqCst);
-        Ok(())
+    if let Err(e) = process_item(pipeline, context, None).await {
+        if let Some(state) = &pipeline.resources.state {
+            state.delete_item(&item_id).await.ok();
+        }
+        return Err(e);
     }
+    Ok(())
+}
 
-    #[pyo3(signature = (bus=None))]
-    pub fn run(&self, bus: Option<PyObject>) -> PyResult<()> {
-        self.running.store(true, Ordering::SeqCst);
-        let r = self.running.clone();
-        match ctrlc::set_handler(move || {
-            r.store(false, std::sync::atomic::Ordering::SeqCst);
-        }) {
-            Ok(_) => {
-                debug!("Ctrl-C handler set");
+/// Flushes the accumulators and finishes the steps of the pipeline and its sub-pipelines.
+async fn finish_pipeline(pipeline: &PipelineBuilder) -> Result<()> {
+    flush_accumulators(pipeline).await?;
+    finish_steps(&pipeline.steps).await?;
+    for child in pipeline.subpipelines.values() {
+        Box::pin(finish_pipeline(child)).await?;
+    }
+    Ok(())
+}
+
+/// Runs the steps following each accumulator for its last, partial batch.
+async fn flush_accumulators(pipeline: &PipelineBuilder) -> Result<()> {
+    for (i, step) in pipeline.steps.iter().enumerate() {
+        if let StepType::Accumulate(accumulate_step) = step {
+            if let Some(context) = accumulate_step.flush() {
+                process_item(pipeline, context, Some(&pipeline.steps[i + 1..])).await?;
             }
-            Err(e) => {
-                debug!("Error setting Ctrl-C handler: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MaxFailures {
+    Count(usize),
+    /// Share of failed items, checked once the given number of items was processed.
+    Ratio(f64, usize),
+}
+
+/// Counts items failing with an error against the limit of `with_max_failures`.
+#[derive(Default)]
+struct FailureBudget {
+    limit: Option<MaxFailures>,
+    items: std::sync::atomic::AtomicUsize,
+    failures: std::sync::atomic::AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+impl FailureBudget {
+    fn reset(&self) {
+        self.items.store(0, Ordering::SeqCst);
+        self.failures.store(0, Ordering::SeqCst);
+        self.exceeded.store(false, Ordering::SeqCst);
+    }
+
+    fn record(&self, failed: bool) {
+        let items = self.items.fetch_add(1, Ordering::SeqCst) + 1;
+        let failures = if failed {
+            self.failures.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.failures.load(Ordering::SeqCst)
+        };
+        let exceeded = match self.limit {
+            Some(MaxFailures::Count(count)) => failures > count,
+            Some(MaxFailures::Ratio(ratio, min_items)) => {
+                items >= min_items && failures as f64 / items as f64 > ratio
             }
+            None => false,
+        };
+        if exceeded && !self.exceeded.swap(true, Ordering::SeqCst) {
+            error!(
+                "🐔 {} of {} items failed, aborting the run",
+                failures, items
+            );
         }
+    }
 
-        let sender = if let Some(bus) = bus {
-            let bus_logger = Python::with_gil(|py| {
-                let py_obj: PyObject = bus.clone_ref(py);
-                py_obj
-            });
+    fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
 
-            let (log_sender, log_receiver) = mpsc::channel::<String>();
-            let sender = Arc::new(log_sender);
-            let channel_writer = ChannelWriter::new(sender.clone());
+    /// Fails on an item error unless a limit is set, then errors are only logged.
+    fn check(&self, result: Result<(), String>) -> Result<()> {
+        if let Err(e) = result {
+            if self.limit.is_none() {
+                bail!(e);
+            }
+            error!("🐔 {}", e);
+        }
+        Ok(())
+    }
+}
 
-            WriteLogger::init(
-                log::LevelFilter::Info,
-                ConfigBuilder::new().build(),
-                channel_writer,
-            )
-            .unwrap();
+/// Limits of `with_stop_condition`, the first one reached is kept.
+#[derive(Default)]
+struct StopCondition {
+    max_items: Option<usize>,
+    max_tokens: Option<u64>,
+    /// The most to spend and the cost of 1000 tokens.
+    max_cost: Option<(f64, f64)>,
+    max_duration: Option<Duration>,
+    started: std::sync::Mutex<Option<std::time::Instant>>,
+    items: std::sync::atomic::AtomicUsize,
+    fired: std::sync::Mutex<Option<&'static str>>,
+}
 
-            thread::spawn(move || {
-                for message in log_receiver {
-                    Python::with_gil(|py| {
-                        bus_logger.call_method1(py, "put", (message,)).unwrap();
-                    });
-                }
-            });
+impl StopCondition {
+    fn reset(&self) {
+        *self.started.lock().unwrap() = Some(std::time::Instant::now());
+        self.items.store(0, Ordering::SeqCst);
+        *self.fired.lock().unwrap() = None;
+    }
 
-            Some(sender.clone())
+    /// Counts the item about to start unless a limit is reached with `tokens` used so far.
+    /// Items in progress still add their tokens, so the token and cost limits can be passed.
+    fn admit(&self, tokens: u64) -> bool {
+        let mut fired = self.fired.lock().unwrap();
+        if fired.is_some() {
+            return false;
+        }
+        let elapsed = self
+            .started
+            .lock()
+            .unwrap()
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        let limit = if self
+            .max_items
+            .is_some_and(|max| self.items.load(Ordering::SeqCst) >= max)
+        {
+            Some("max_items")
+        } else if self.max_tokens.is_some_and(|max| tokens >= max) {
+            Some("max_tokens")
+        } else if self
+            .max_cost
+            .is_some_and(|(max, price)| tokens as f64 / 1000.0 * price >= max)
+        {
+            Some("max_cost")
+        } else if self.max_duration.is_some_and(|max| elapsed >= max) {
+            Some("max_duration")
         } else {
             None
         };
+        if let Some(limit) = limit {
+            warn!("🐔 Reached the {} limit, no new items are started", limit);
+            *fired = Some(limit);
+            return false;
+        }
+        self.items.fetch_add(1, Ordering::SeqCst);
+        true
+    }
 
-        let log_path = self.log_path.clone();
+    fn fired(&self) -> Option<String> {
+        self.fired.lock().unwrap().map(str::to_string)
+    }
+}
 
-        let result = run_async(async {
-            if self.metadata.enabled {
-                if let Some(state) = &self.resources.state {
-                    state
-                        .add_run(
-                            &self.id.to_string(),
-                            log_path.as_ref().expect("Log path not set"),
-                            None,
-                        )
-                        .await?;
-                }
-            }
+/// Target of `run_until`: range items are started while the valid ones and those in progress
+/// could still fall short of `target`, so no more than `target` come out valid.
+struct RunUntil {
+    target: usize,
+    max_iterations: Option<usize>,
+    valid: std::sync::atomic::AtomicUsize,
+    in_progress: std::sync::atomic::AtomicUsize,
+    /// Iterations started so far.
+    drawn: std::sync::atomic::AtomicUsize,
+}
 
-            let successfull_iterations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-            match &self.iter_by {
-                IterBy::Range { start, stop, step } => {
-                    debug!("Iterating by range: {}..{}..{}", start, stop, step);
-                    let bar = ProgressBar::new((stop - start) as u64);
+impl RunUntil {
+    fn new(target: usize, max_iterations: Option<usize>) -> Self {
+        Self {
+            target,
+            max_iterations,
+            valid: Default::default(),
+            in_progress: Default::default(),
+            drawn: Default::default(),
+        }
+    }
 
-                    bar.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] ({pos}/{len}, ETA {eta})",)
-                    .unwrap().progress_chars("#>-"));
+    fn reached(&self) -> bool {
+        self.valid.load(Ordering::SeqCst) >= self.target
+    }
 
-                    let iter_results = stream::iter((*start..*stop).step_by(*step).map(|i| {
-                        let bar = &bar;
-                        if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
-                            bar.finish_with_message("Interrupted");
-                            std::process::exit(1);
-                        }
+    /// Takes a place for an item, false while the items in progress could reach the target.
+    fn try_start(&self) -> bool {
+        let started = self
+            .in_progress
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_progress| {
+                (self.valid.load(Ordering::SeqCst) + in_progress < self.target)
+                    .then_some(in_progress + 1)
+            })
+            .is_ok();
+        if started {
+            self.drawn.fetch_add(1, Ordering::SeqCst);
+        }
+        started
+    }
 
-                        let sender = sender.clone();
-                        let value = successfull_iterations.clone();
-                        let rid = self.id.to_string();
-                        async move {
-                            let mut context = StepContext::new();
-                            context.set("index", i);
-                            context.set_status(StepStatus::Running);
-                            let item_id = context.id.to_string();
-                            if self.metadata.enabled {
-                                if let Some(state) = &self.resources.state {
-                                    state
-                                        .add_item(&item_id, &rid, i as i64, None)
-                                        .await
-                                        .unwrap();
-                                }
-                            }
-                            if let Err(e) = process_steps(self, context, None).await {
-                                if let Some(state) = &self.resources.state {
-                                    state.delete_item(&item_id).await.ok();
-                                }
-                                return Err(format!("Error processing step: {} - {}", i, e));
-                            } else {
-                                value.fetch_add(1, Ordering::SeqCst);
-                            }
+    fn finish(&self, valid: bool) {
+        if valid {
+            self.valid.fetch_add(1, Ordering::SeqCst);
+        }
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-                            bar.inc(1);
+/// How often range items waiting for `run_until` check whether they are still needed.
+const RUN_UNTIL_POLL: Duration = Duration::from_millis(20);
+
+/// Stack size of the thread of `spawn_run`, the default of a main thread.
+const RUN_THREAD_STACK: usize = 8 * 1024 * 1024;
+
+/// How often paused items check whether the run was resumed.
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+
+/// How often the concurrency is adjusted to the LLM calls of the last window.
+const AUTOSCALE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Limits the items in progress for `with_autoscaling`, the limit follows the API LLM calls
+/// of each window: halved when calls are rate limited, more than a tenth fail or the mean
+/// latency is above the target, raised by one otherwise.
+struct Autoscaler {
+    min: usize,
+    max: usize,
+    target_latency: Option<Duration>,
+    /// The LLM calls the limit follows.
+    pressure: &'static LlmPressure,
+    limit: std::sync::atomic::AtomicUsize,
+    /// Permits dropped on release after the limit went down.
+    debt: std::sync::atomic::AtomicUsize,
+    semaphore: tokio::sync::Semaphore,
+    window_start: std::sync::Mutex<std::time::Instant>,
+}
 
-                            if let Some(sender) = &sender {
-                                sender
-                                    .send(BusEvent::build(
-                                        "progress",
-                                        json!({"index": i, "total": (stop - start) / step}),
-                                    ))
-                                    .unwrap();
-                            }
-                            Ok(())
-                        }
-                    }))
-                    .buffered(self.workers)
-                    .collect::<Vec<_>>()
-                    .await;
+impl Autoscaler {
+    fn new(
+        min: usize,
+        max: usize,
+        target_latency: Option<Duration>,
+        pressure: &'static LlmPressure,
+    ) -> Self {
+        Self {
+            min,
+            max,
+            target_latency,
+            pressure,
+            limit: std::sync::atomic::AtomicUsize::new(0),
+            debt: std::sync::atomic::AtomicUsize::new(0),
+            semaphore: tokio::sync::Semaphore::new(0),
+            window_start: std::sync::Mutex::new(std::time::Instant::now()),
+        }
+    }
 
-                    for result in iter_results {
-                        if let Err(e) = result {
-                            bail!(e)
-                        }
-                    }
-                }
-                IterBy::Dataset { name } => {
-                    debug!("Iterating by dataset: {}", name);
-                    let bar = ProgressBar::new(0);
+    /// Starts a run from `workers` items at once, nothing may be in progress.
+    fn restart(&self, workers: usize) {
+        let limit = workers.clamp(self.min, self.max);
+        if let Ok(permits) = self
+            .semaphore
+            .try_acquire_many(self.semaphore.available_permits() as u32)
+        {
+            permits.forget();
+        }
+        self.semaphore.add_permits(limit);
+        self.limit.store(limit, Ordering::SeqCst);
+        self.debt.store(0, Ordering::SeqCst);
+        *self.window_start.lock().unwrap() = std::time::Instant::now();
+        self.pressure.take();
+    }
 
-                    bar.set_style(
-                        ProgressStyle::with_template(
-                            "{spinner:.green} [{elapsed_precise}] ({pos})",
-                        )
-                        .unwrap(),
-                    );
+    async fn acquire(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.semaphore
+            .acquire()
+            .await
+            .expect("Autoscaler semaphore is never closed")
+    }
 
-                    let dataset = self.resources.datasets.get(name).ok_or_err(name)?;
-                    let mut inc = 0;
-                    // macros to reduce duplicated iteration logic for datasets
-                    macro_rules! process_dataset {
-                        ($dataset:expr) => {{
-                            let iter_results = stream::iter($dataset.stream()?.map(|json_row| {
-                                let bar = &bar;
-                                let sender = sender.clone();
-                                process_progress_bar(bar, &self.running);
-                                let value = successfull_iterations.clone();
-                                async move {
-                                    if let Err(e) =
-                                        map_record_batches(self, name, &json_row.unwrap(), &inc)
-                                            .await
-                                    {
-                                        return Err(format!(
-                                            "Error processing step: {} - {}",
-                                            name, e
-                                        ));
-                                    } else {
-                                        value.fetch_add(1, Ordering::SeqCst);
-                                    }
-                                    bar.inc(1);
-                                    inc += 1;
-                                    send_progress_event(&sender, inc);
-                                    Ok(())
-                                }
-                            }))
-                            .buffered(self.workers)
-                            .collect::<Vec<_>>()
-                            .await;
-                            for result in iter_results {
-                                if let Err(e) = result {
-                                    bail!(e)
-                                }
-                            }
-                        }};
-                    }
+    fn release(&self, permit: tokio::sync::SemaphorePermit<'_>) {
+        if self
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok()
+        {
+            permit.forget();
+        } else {
+            drop(permit);
+        }
+        self.adjust();
+    }
 
-                    macro_rules! process_dataset_mix {
-                        ($dataset:expr) => {{
-                            let iter_results = stream::iter(
-                                $dataset
-                                    .stream_mix(&self.resources.datasets.resources)?
-                                    .map(|json_row| {
-                                        let bar = &bar;
-                                        let sender = sender.clone();
-                                        process_progress_bar(bar, &self.running);
-                                        let value = successfull_iterations.clone();
-                                        async move {
-                                            if let Err(e) = map_record_batches(
-                                                self,
-                                                name,
-                                                &json_row.unwrap(),
-                                                &inc,
-                                            )
-                                            .await
-                                            {
-                                                return Err(format!(
-                                                    "Error processing step: {} - {}",
-                                                    name, e
-                                                ));
-                                            } else {
-                                                value.fetch_add(1, Ordering::SeqCst);
-                                            }
-                                            bar.inc(1);
-                                            inc += 1;
-                                            send_progress_event(&sender, inc);
-                                            Ok(())
-                                        }
-                                    }),
-                            )
-                            .buffered(self.workers)
-                            .collect::<Vec<_>>()
-                            .await;
-                            for result in iter_results {
-                                if let Err(e) = result {
-                                    bail!(e)
-                                }
-                            }
-                        }};
-                    }
-                    match dataset {
-                        DatasetType::Jsonl(dataset) => process_dataset!(dataset),
-                        DatasetType::Json(dataset) => process_dataset!(dataset),
-                        DatasetType::JsonList(dataset) => process_dataset!(dataset),
-                        DatasetType::OpenApi(dataset) => process_dataset!(dataset),
-                        DatasetType::Polars(dataset) => process_dataset!(dataset),
-                        DatasetType::Ipc(dataset) => process_dataset!(dataset),
-                        DatasetType::Csv(dataset) => process_dataset!(dataset),
-                        DatasetType::Parquet(dataset) => process_dataset!(dataset),
-                        DatasetType::Mixed(dataset) => process_dataset_mix!(dataset),
-                        DatasetType::PhfSet(phf_set_dataset) => process_dataset!(phf_set_dataset),
-                    }
-                }
+    fn adjust(&self) {
+        let mut window_start = self.window_start.lock().unwrap();
+        if window_start.elapsed() < AUTOSCALE_WINDOW {
+            return;
+        }
+        *window_start = std::time::Instant::now();
+        let window = self.pressure.take();
+        if window.calls == 0 {
+            return;
+        }
+
+        let limit = self.limit.load(Ordering::SeqCst);
+        let pressure = window.throttled > 0
+            || window.errors * 10 > window.calls
+            || self
+                .target_latency
+                .is_some_and(|target| window.latency > target);
+        let next = if pressure {
+            (limit / 2).max(self.min)
+        } else {
+            (limit + 1).min(self.max)
+        };
+        if next > limit {
+            let mut added = next - limit;
+            while added > 0
+                && self
+                    .debt
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                        debt.checked_sub(1)
+                    })
+                    .is_ok()
+            {
+                added -= 1;
             }
-
+            self.semaphore.add_permits(added);
+        } else if next < limit {
+            self.debt.fetch_add(limit - next, Ordering::SeqCst);
+        }
+        if next != limit {
             info!(
-                "🚀 Finished all iterations, processed {} items",
-                successfull_iterations.load(Ordering::SeqCst)
+                "🚦 Workers {} -> {} ({} LLM calls, {} rate limited, {} failed, {:?} mean latency)",
+                limit, next, window.calls, window.throttled, window.errors, window.latency
             );
+            self.limit.store(next, Ordering::SeqCst);
+        }
+    }
+}
 
-            if let Some(sender) = &sender {
-                sender
-                    .send(BusEvent::build("finished", json!({"message": "Finished"})))
-                    .unwrap();
-            }
+/// Error of a step, keeps the item as it was when the step failed for the quarantine.
+struct StepError {
+    step: String,
+    id: uuid::Uuid,
+    data: Value,
+    error: anyhow::Error,
+}
 
-            Ok::<_, anyhow::Error>(())
-        });
+impl StepError {
+    /// Wraps `error` unless a nested step already did.
+    fn wrap(error: anyhow::Error, step: &str, context: &StepContext) -> anyhow::Error {
+        if error.downcast_ref::<StepError>().is_some() {
+            return error;
+        }
+        anyhow::Error::new(StepError {
+            step: step.to_string(),
+            id: context.id,
+            data: context.data.clone(),
+            error,
+        })
+    }
+}
 
-        println!("{}", self.logs_collector.summary_table());
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
 
-        result.map_pyerr()
+impl fmt::Debug for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
     }
 }
 
-fn send_progress_event(sender: &Option<Arc<mpsc::Sender<String>>>, inc: i32) {
-    if let Some(sender) = sender {
-        let event = BusEvent::build("progress", json!({"inc": inc,}));
-        if let Err(e) = sender.send(event) {
-            error!("Failed to send progress event: {}", e);
-        }
+impl std::error::Error for StepError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// File name suffix of a sweep run, e.g. `_temperature-0.2_variant-short`.
+fn sweep_suffix(combination: &serde_json::Map<String, Value>) -> String {
+    combination
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            let value = value
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                        c
+                    } else {
+                        '-'
+                    }
+                })
+                .collect::<String>();
+            format!("_{}-{}", key, value)
+        })
+        .collect()
+}
+
+/// Delivers `event` to the `bus` of a run, a queue (`put`) or a callback.
+fn deliver(py: Python<'_>, bus: &PyObject, event: PipelineEvent) {
+    let bus = bus.bind(py);
+    let delivered = if bus.hasattr("put").unwrap_or(false) {
+        bus.call_method1("put", (event,))
+    } else {
+        bus.call1((event,))
+    };
+    // not logged, the log lines go to the bus
+    if let Err(e) = delivered {
+        e.print(py);
     }
 }
 
-fn process_progress_bar(bar: &ProgressBar, running: &Arc<AtomicBool>) {
-    if !running.load(std::sync::atomic::Ordering::SeqCst) {
-        bar.finish_with_message("Interrupted");
-        std::process::exit(1);
+/// Pairs the API keys of an LLM with its urls, a single key or url goes with all of the
+/// others.
+fn pair_endpoints(
+    name: &str,
+    keys: Vec<String>,
+    urls: Vec<String>,
+) -> PyResult<Vec<(String, String)>> {
+    match (keys.len(), urls.len()) {
+        (0, _) | (_, 0) => Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "🐔 LLM {} needs an API key and url",
+            name
+        ))),
+        (1, _) => Ok(urls.into_iter().map(|url| (keys[0].clone(), url)).collect()),
+        (_, 1) => Ok(keys.into_iter().map(|key| (key, urls[0].clone())).collect()),
+        (k, u) if k == u => Ok(keys.into_iter().zip(urls).collect()),
+        (k, u) => Err(pyo3::exceptions::PyValueError::new_err(format!(
+            "🐔 LLM {} has {} API keys for {} urls, give one of them or as many of each",
+            name, k, u
+        ))),
     }
-    bar.inc_length(1);
 }
 
-async fn map_record_batches(
+/// Appends a failed item to the quarantine file, if one is configured. The items share one
+/// handle and each line goes out in a single write, so concurrent items don't interleave.
+fn quarantine(
     pipeline: &PipelineBuilder,
-    dataset_name: &str,
-    json_row: &serde_json::Value,
-    inc: &i32,
+    id: &uuid::Uuid,
+    step: Option<&str>,
+    error: &str,
+    data: &Value,
 ) -> Result<()> {
-    let mut context = StepContext::new();
-
-    context.set(dataset_name, json_row);
-    context.set("index", inc);
-    context.set_status(StepStatus::Running);
-    let item_id = context.id.to_string();
-    if pipeline.metadata.enabled {
-        if let Some(state) = &pipeline.resources.state {
-            state
-                .add_item(&item_id, &pipeline.id.to_string(), *inc as i64, None)
-                .await
-                .unwrap();
+    let Some(path) = &pipeline.quarantine else {
+        return Ok(());
+    };
+    let row = json!({
+        "id": id.to_string(),
+        "step": step,
+        "error": error,
+        "data": data,
+    });
+    let line = format!("{}\n", row);
+    let mut open = pipeline
+        .quarantine_file
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    match open.as_mut() {
+        Some((open_path, file)) if open_path == path => file.write_all(line.as_bytes())?,
+        _ => {
+            let mut file = File::options().append(true).create(true).open(path)?;
+            file.write_all(line.as_bytes())?;
+            *open = Some((path.clone(), file));
         }
     }
+    Ok(())
+}
 
-    if let Err(e) = process_steps(pipeline, context, None).await {
-        if let Some(state) = &pipeline.resources.state {
-            state.delete_item(&item_id).await.ok();
+fn quarantine_failed(pipeline: &PipelineBuilder, context: &StepContext) -> Result<()> {
+    if !matches!(context.get_status(), StepStatus::Failed) {
+        return Ok(());
+    }
+    quarantine(
+        pipeline,
+        &context.id,
+        context.failed_step(),
+        context.error().unwrap_or("Item marked as failed"),
+        &context.data,
+    )
+}
+
+/// Runs the steps for a whole item, failed items go to the quarantine.
+async fn process_item(
+    pipeline: &PipelineBuilder,
+    mut context: StepContext,
+    steps: Option<&[StepType]>,
+) -> Result<()> {
+    if steps.is_none() {
+        if !pipeline.params.is_empty() {
+            context.set("params", &pipeline.params);
+        }
+        // paused items start once resumed, or go through the shutdown when interrupted
+        while pipeline.paused.load(Ordering::SeqCst) && pipeline.running.load(Ordering::SeqCst) {
+            tokio::time::sleep(PAUSE_POLL).await;
+        }
+    }
+    let permit = match (&pipeline.autoscaler, steps) {
+        (Some(autoscaler), None) => Some(autoscaler.acquire().await),
+        _ => None,
+    };
+    let index = context.get("index").and_then(|index| index.as_i64());
+    let started = std::time::Instant::now();
+    let span = match (&pipeline.telemetry, steps) {
+        (Some(telemetry), None) => Some(telemetry.item(&pipeline.id.to_string(), index)),
+        _ => None,
+    };
+    // boxed, the steps' futures are too large to be moved around on the stack
+    let processing = Box::pin(in_span(
+        span.as_ref(),
+        track_llm_usage(async {
+            match steps {
+                None if pipeline.dag => process_dag(pipeline, context).await,
+                _ => process_steps(pipeline, context, steps).await,
+            }
+        }),
+    ));
+    let (processed, usage) = match steps {
+        None => {
+            let worker = pipeline.worker_ids.acquire();
+            let processed = in_worker(worker, processing).await;
+            pipeline.worker_ids.release(worker);
+            processed
+        }
+        Some(_) => processing.await,
+    };
+    let tokens = usage.tokens;
+    if let (Some(until), None) = (&pipeline.until, steps) {
+        until.finish(matches!(
+            &processed,
+            Ok(context) if context.error().is_none()
+                && !matches!(context.get_status(), StepStatus::Failed)
+        ));
+    }
+    if let (Some(autoscaler), Some(permit)) = (&pipeline.autoscaler, permit) {
+        autoscaler.release(permit);
+    }
+    if steps.is_none() {
+        pipeline.stats.record_tokens(tokens);
+        match &processed {
+            Ok(context) => end_span(
+                span,
+                &usage,
+                if context.error().is_none() {
+                    "ok"
+                } else {
+                    "failed"
+                },
+                context.error(),
+            ),
+            Err(e) => end_span(span, &usage, "failed", Some(&format!("{:#}", e))),
+        }
+        pipeline.emit(match &processed {
+            Ok(context) if context.error().is_none() => PipelineEvent::ItemCompleted {
+                index,
+                duration: started.elapsed().as_secs_f64(),
+                tokens,
+            },
+            Ok(context) => PipelineEvent::ItemFailed {
+                index,
+                step: context.failed_step().map(str::to_string),
+                error: context.error().unwrap_or_default().to_string(),
+            },
+            Err(e) => PipelineEvent::ItemFailed {
+                index,
+                step: e
+                    .downcast_ref::<StepError>()
+                    .map(|failure| failure.step.clone()),
+                error: format!("{:#}", e),
+            },
+        });
+    }
+    match processed {
+        Ok(context) => {
+            pipeline.failures.record(context.error().is_some());
+            quarantine_failed(pipeline, &context)
+        }
+        Err(e) => {
+            pipeline.failures.record(true);
+            if let Some(failure) = e.downcast_ref::<StepError>() {
+                quarantine(
+                    pipeline,
+                    &failure.id,
+                    Some(&failure.step),
+                    &format!("{:#}", failure.error),
+                    &failure.data,
+                )?;
+            }
+            Err(e)
         }
-        return Err(e);
     }
-    Ok(())
 }
 
 async fn process_steps(
     pipeline: &PipelineBuilder,
     mut context: StepContext,
-    steps: Option<&Vec<StepType>>,
+    steps: Option<&[StepType]>,
 ) -> Result<StepContext> {
     let steps = if let Some(steps) = steps {
         steps
@@ -1525,80 +5249,477 @@ async fn process_steps(
         &pipeline.steps
     };
 
-    for step in steps {
-        if matches!(context.get_status(), StepStatus::Failed) {
+    for (position, step) in steps.iter().enumerate() {
+        match context.get_status() {
+            // completed items were consumed by an accumulator or exploded
+            StepStatus::Completed => break,
+            // failed items only pass through dump steps
+            StepStatus::Failed if !matches!(step, StepType::Dump(_)) => continue,
+            _ => {}
+        }
+
+        let dedup = match (
+            pipeline.writer_dedup.get(step.name()),
+            &pipeline.resources.state,
+        ) {
+            (Some(dedup), Some(state)) => {
+                if !dedup.claim(state, &context).await? {
+                    debug!(target: "pipeline", "🐔 Skipping {} of a record already written", step.name());
+                    continue;
+                }
+                Some((dedup, state))
+            }
+            _ => None,
+        };
+
+        let started = std::time::Instant::now();
+        let policy = pipeline
+            .error_policies
+            .get(step.name())
+            .copied()
+            .unwrap_or(ErrorPolicy::Fail);
+        let before = (policy != ErrorPolicy::Fail).then(|| context.clone());
+        let was_failed = matches!(context.get_status(), StepStatus::Failed);
+        let span = pipeline
+            .telemetry
+            .as_ref()
+            .map(|telemetry| telemetry.step(step.name()));
+        let mut usage = LlmUsage::default();
+        let mut allocations = Allocations::default();
+        let mut error = None;
+        let mut outcome = "ok";
+        let mut attempt = 1;
+        loop {
+            let ((processed, attempt_usage), attempt_allocations) = track_allocations(in_span(
+                span.as_ref(),
+                track_llm_usage(process_step(pipeline, steps, position, step, &mut context)),
+            ))
+            .await;
+            usage.add(&attempt_usage);
+            allocations.bytes += attempt_allocations.bytes;
+            allocations.count += attempt_allocations.count;
+            let Err(e) = processed else {
+                break;
+            };
+            match (policy, before.as_ref()) {
+                (ErrorPolicy::Skip, Some(before)) => {
+                    warn!(target: "pipeline", "🐔 Skipping step {} after error: {:#}", step.name(), e);
+                    context = before.clone();
+                    error = Some(format!("{:#}", e));
+                    outcome = "skipped";
+                }
+                (ErrorPolicy::Retry { max_attempts, .. }, Some(before))
+                    if attempt < max_attempts =>
+                {
+                    debug!(target: "pipeline", "🐔 Attempt {} of {} of step {} failed: {:#}", attempt, max_attempts, step.name(), e);
+                    tokio::time::sleep(policy.delay(attempt)).await;
+                    context = before.clone();
+                    attempt += 1;
+                    continue;
+                }
+                (ErrorPolicy::Quarantine, Some(before)) => {
+                    context = before.clone();
+                    context.set_error(&format!("{:#}", e));
+                    context.set_status(StepStatus::Failed);
+                }
+                _ => {
+                    let error = format!("{:#}", e);
+                    pipeline
+                        .stats
+                        .record_step(step.name(), started.elapsed(), Some(&error));
+                    end_span(span, &usage, "failed", Some(&error));
+                    if let Some((dedup, state)) = dedup {
+                        dedup.release(state, &context).await?;
+                    }
+                    return Err(StepError::wrap(e, step.name(), &context));
+                }
+            }
             break;
         }
+        if error.is_none() && !was_failed && matches!(context.get_status(), StepStatus::Failed) {
+            error = Some(context.error().unwrap_or("failed").to_string());
+        }
+        if error.is_some() && outcome == "ok" {
+            outcome = "failed";
+        }
+        pipeline
+            .stats
+            .record_step(step.name(), started.elapsed(), error.as_deref());
+        end_span(span, &usage, outcome, error.as_deref());
+        if let Some((dedup, state)) = dedup {
+            if error.is_some() {
+                dedup.release(state, &context).await?;
+            } else {
+                dedup.written(state, &context).await?;
+            }
+        }
+        let elapsed = started.elapsed();
+        if pipeline.step_timings.is_some() || pipeline.profiling.load(Ordering::Relaxed) {
+            pipeline.timings.record(step.name(), elapsed, allocations);
+        }
+        if let Some(output) = &pipeline.step_timings {
+            context.data[output][step.name()] = json!(elapsed.as_secs_f64());
+        }
+        if matches!(context.get_status(), StepStatus::Failed) {
+            context.set_failed_step(step.name());
+        }
+    }
 
-        // macro to collapse the repeated `step.process(...).await?` pattern
-        macro_rules! process_common {
-            ($step_ident:ident) => {{
-                context = $step_ident.process(&pipeline.resources, &context).await?;
-            }};
+    Ok(context)
+}
+
+/// Runs the steps wave by wave as planned by [`dag::plan`], the steps of a wave each on their
+/// own copy of the item, merging back their declared outputs.
+async fn process_dag(pipeline: &PipelineBuilder, mut context: StepContext) -> Result<StepContext> {
+    let waves = pipeline.dag_waves.read().unwrap().clone();
+    for wave in waves {
+        if matches!(context.get_status(), StepStatus::Completed) {
+            break;
+        }
+        let steps = wave.iter().map(|&i| &pipeline.steps[i]).collect::<Vec<_>>();
+        let branches = steps.iter().map(|step| {
+            let context = context.clone();
+            async move {
+                let step_io = pipeline.step_io.get(step.name());
+                if let Some(step_io) = step_io {
+                    if !matches!(context.get_status(), StepStatus::Failed) {
+                        dag::check_inputs(step.name(), step_io, &context)
+                            .map_err(|e| StepError::wrap(e, step.name(), &context))?;
+                    }
+                }
+                process_steps(pipeline, context, Some(std::slice::from_ref(*step))).await
+            }
+        });
+        let branches = futures::future::try_join_all(branches).await?;
+        for (step, branch) in steps.iter().zip(branches) {
+            match pipeline.step_io.get(step.name()) {
+                Some(step_io) => {
+                    dag::merge(&mut context, &branch, &step_io.outputs);
+                    if let Some(output) = &pipeline.step_timings {
+                        context.data[output][step.name()] =
+                            branch.data[output][step.name()].clone();
+                    }
+                }
+                // steps without declared io run alone and hand over the whole item
+                None => context = branch,
+            }
         }
+    }
+    Ok(context)
+}
 
-        match step {
-            StepType::IfElse(if_step) => {
-                let check_result = if_step
-                    .check(
-                        &pipeline.resources.datasets.resources,
-                        &pipeline.resources.templates,
-                        &pipeline.resources.llms.resources,
-                        &pipeline.resources.embeddings.resources,
-                        &context,
-                    )
-                    .await?;
+/// Runs a single step of `steps`, updating `context` in place.
+async fn process_step(
+    pipeline: &PipelineBuilder,
+    steps: &[StepType],
+    position: usize,
+    step: &StepType,
+    context: &mut StepContext,
+) -> Result<()> {
+    // macro to collapse the repeated `step.process(...).await?` pattern
+    macro_rules! process_common {
+        ($step_ident:ident) => {{
+            *context = $step_ident.process(&pipeline.resources, context).await?;
+        }};
+    }
 
-                if check_result {
-                    context = Box::pin(process_steps(
+    match step {
+        StepType::IfElse(if_step) => {
+            let check_result = if_step
+                .check(
+                    &pipeline.resources.datasets.resources,
+                    &pipeline.resources.templates,
+                    &pipeline.resources.llms.resources,
+                    &pipeline.resources.embeddings.resources,
+                    context,
+                )
+                .await?;
+
+            if check_result {
+                *context = Box::pin(process_steps(
+                    pipeline,
+                    context.clone(),
+                    Some(&if_step.then_steps),
+                ))
+                .await?;
+            } else if let Some(else_steps) = &if_step.else_steps {
+                *context =
+                    Box::pin(process_steps(pipeline, context.clone(), Some(else_steps))).await?;
+            }
+        }
+        StepType::Switch(switch_step) => {
+            if let Some(steps) = switch_step.branch(&pipeline.resources.templates, context)? {
+                *context = Box::pin(process_steps(pipeline, context.clone(), Some(steps))).await?;
+            }
+        }
+        StepType::Parallel(parallel_step) => {
+            let results =
+                futures::future::join_all(parallel_step.branches.iter().map(|branch| {
+                    Box::pin(process_steps(pipeline, context.clone(), Some(branch)))
+                }))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+            *context = parallel_step.merge(context, results);
+        }
+        StepType::Tee(tee_step) => {
+            let results = futures::future::join_all(tee_step.sinks.iter().map(|sink| {
+                Box::pin(process_steps(
+                    pipeline,
+                    context.clone(),
+                    Some(std::slice::from_ref(sink)),
+                ))
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+            *context = tee_step.merge(context, results);
+        }
+        StepType::Cache(cache_step) => {
+            let state = pipeline.resources.state.as_ref();
+            let key = cache_step.key(&pipeline.resources.templates, context);
+            let cached = match state {
+                Some(state) => state.cached_step_result(&key).await?,
+                None => None,
+            };
+            match cached {
+                Some(changes) => {
+                    debug!(target: "cache_step", "🤗 Reusing cached result of {}", cache_step.name);
+                    CacheStep::restore(context, &changes);
+                }
+                None => {
+                    let result = Box::pin(process_steps(
                         pipeline,
                         context.clone(),
-                        Some(&if_step.then_steps),
+                        Some(&cache_step.steps),
                     ))
                     .await?;
-                } else if let Some(else_steps) = &if_step.else_steps {
-                    context = Box::pin(process_steps(pipeline, context.clone(), Some(else_steps)))
-                        .await?;
+                    if let (Some(state), false) =
+                        (state, matches!(result.get_status(), StepStatus::Failed))
+                    {
+                        let changes = CacheStep::changes(context, &result);
+                        state
+                            .cache_step_result(&cache_step.name, &key, &changes)
+                            .await?;
+                    }
+                    *context = result;
+                }
+            }
+        }
+        StepType::Retry(retry_step) => {
+            for attempt in 1..=retry_step.max_attempts {
+                let result = Box::pin(process_steps(
+                    pipeline,
+                    context.clone(),
+                    Some(&retry_step.steps),
+                ))
+                .await;
+                match result {
+                    Ok(result) if !matches!(result.get_status(), StepStatus::Failed) => {
+                        *context = result;
+                        break;
+                    }
+                    Ok(_) => {
+                        debug!(target: "retry_step", "🐔 Attempt {} of {} failed", attempt, retry_step.max_attempts)
+                    }
+                    Err(e) => {
+                        debug!(target: "retry_step", "🐔 Attempt {} of {} failed: {}", attempt, retry_step.max_attempts, e)
+                    }
+                }
+
+                if attempt == retry_step.max_attempts {
+                    error!(target: "retry_step", "🐔 All {} attempts failed", retry_step.max_attempts);
+                    context.set_status(StepStatus::Failed);
+                } else {
+                    tokio::time::sleep(retry_step.delay(attempt)).await;
                 }
             }
-            StepType::Py(py_step) => process_common!(py_step),
-            StepType::TextGeneration(text_generation_step) => process_common!(text_generation_step),
-            StepType::JsonGeneration(json_generation_step) => process_common!(json_generation_step),
-            StepType::PyValidator(py_validator) => process_common!(py_validator),
-            StepType::JsonWriter(jsonl_writer_step) => process_common!(jsonl_writer_step),
-            StepType::CsvWriter(csv_writer_step) => process_common!(csv_writer_step),
-            StepType::Print(print_step) => process_common!(print_step),
-            StepType::DataSampler(data_sampler_step) => process_common!(data_sampler_step),
-            StepType::Chunk(chunk_step) => process_common!(chunk_step),
-            StepType::Render(render_step) => process_common!(render_step),
-            StepType::ValidateJson(validate_json_step) => process_common!(validate_json_step),
-            StepType::ValidateTools(tools_validate_step) => process_common!(tools_validate_step),
-            StepType::NormalizeTools(tools_normalize_step) => process_common!(tools_normalize_step),
-            StepType::ConversationValidate(conversation_validate_step) => {
-                process_common!(conversation_validate_step)
+        }
+        StepType::Loop(loop_step) => {
+            let mut done = false;
+            for _ in 0..loop_step.max_iters {
+                let result = Box::pin(process_steps(
+                    pipeline,
+                    context.clone(),
+                    Some(&loop_step.steps),
+                ))
+                .await?;
+                if matches!(result.get_status(), StepStatus::Failed) {
+                    continue;
+                }
+                *context = result;
+                if loop_step.check(&pipeline.resources.templates, context)? {
+                    done = true;
+                    break;
+                }
             }
-            StepType::IntoList(into_list_step) => process_common!(into_list_step),
-            StepType::RenderConversation(render_conversation_step) => {
-                process_common!(render_conversation_step)
+            if !done {
+                error!(target: "loop_step", "🐔 Condition not met after {} iterations", loop_step.max_iters);
+                context.set_status(StepStatus::Failed);
             }
-            StepType::Filter(filter_step) => process_common!(filter_step),
-            StepType::Mutate(mutate_step) => process_common!(mutate_step),
-            StepType::CheckLanguage(check_language_step) => process_common!(check_language_step),
-            StepType::RenderToolCall(render_tool_call_step) => {
-                process_common!(render_tool_call_step)
+        }
+        StepType::SubPipeline(subpipeline_step) => {
+            let child = &pipeline.subpipelines[&subpipeline_step.name];
+            let result = if child.dag {
+                Box::pin(process_dag(child, context.clone())).await?
+            } else {
+                Box::pin(process_steps(child, context.clone(), None)).await?
+            };
+            dag::merge(context, &result, &subpipeline_step.outputs);
+        }
+        StepType::ForEach(foreach_step) => {
+            let items = match foreach_step.items(context) {
+                Some(items) => items,
+                None => {
+                    context.set_status(StepStatus::Failed);
+                    return Ok(());
+                }
+            };
+
+            let mut outputs = Vec::with_capacity(items.len());
+            for (index, item) in items.into_iter().enumerate() {
+                let item_context = foreach_step.item_context(context, index, item);
+                let item_context = Box::pin(process_steps(
+                    pipeline,
+                    item_context,
+                    Some(&foreach_step.steps),
+                ))
+                .await?;
+                if let Some(output) = foreach_step.collect(&item_context) {
+                    outputs.push(output);
+                }
             }
-            StepType::CheckHash(check_hash_step) => process_common!(check_hash_step),
-            StepType::CheckSimHash(check_sim_hash_step) => process_common!(check_sim_hash_step),
-            StepType::CheckEmbedding(embedding_step) => process_common!(embedding_step),
-            StepType::JudgeConversation(judge_conversation_step) => {
-                process_common!(judge_conversation_step)
+            context.set(&foreach_step.output, outputs);
+        }
+        StepType::Explode(explode_step) => {
+            let Some(items) = explode_step.explode(context) else {
+                context.set_status(StepStatus::Failed);
+                return Ok(());
+            };
+            for item_context in items {
+                let item_context = Box::pin(process_steps(
+                    pipeline,
+                    item_context,
+                    Some(&steps[position + 1..]),
+                ))
+                .await?;
+                quarantine_failed(pipeline, &item_context)?;
             }
-            StepType::RenderDPO(render_dpostep) => process_common!(render_dpostep),
-            StepType::RenderGRPO(render_grpostep) => process_common!(render_grpostep),
+            context.set_status(StepStatus::Completed);
+        }
+        StepType::Accumulate(accumulate_step) => match accumulate_step.push(context) {
+            Some(batch_context) => *context = batch_context,
+            None => context.set_status(StepStatus::Completed),
+        },
+        StepType::GroupBy(group_by_step) => process_common!(group_by_step),
+        StepType::Py(py_step) => process_common!(py_step),
+        StepType::TextGeneration(text_generation_step) => process_common!(text_generation_step),
+        StepType::JsonGeneration(json_generation_step) => process_common!(json_generation_step),
+        StepType::PyValidator(py_validator) => process_common!(py_validator),
+        StepType::PyWriter(py_writer) => process_common!(py_writer),
+        StepType::JsonWriter(jsonl_writer_step) => process_common!(jsonl_writer_step),
+        StepType::CsvWriter(csv_writer_step) => process_common!(csv_writer_step),
+        StepType::IpcWriter(ipc_writer_step) => process_common!(ipc_writer_step),
+        StepType::SqliteWriter(sqlite_writer_step) => process_common!(sqlite_writer_step),
+        StepType::PostgresWriter(postgres_writer_step) => {
+            process_common!(postgres_writer_step)
+        }
+        StepType::HttpWriter(http_writer_step) => process_common!(http_writer_step),
+        #[cfg(feature = "kafka")]
+        StepType::KafkaWriter(kafka_writer_step) => process_common!(kafka_writer_step),
+        StepType::Print(print_step) => process_common!(print_step),
+        StepType::Dump(dump_step) => process_common!(dump_step),
+        StepType::DataSampler(data_sampler_step) => process_common!(data_sampler_step),
+        StepType::Chunk(chunk_step) => process_common!(chunk_step),
+        StepType::Render(render_step) => process_common!(render_step),
+        StepType::ValidateJson(validate_json_step) => process_common!(validate_json_step),
+        StepType::ValidateTools(tools_validate_step) => process_common!(tools_validate_step),
+        StepType::NormalizeTools(tools_normalize_step) => process_common!(tools_normalize_step),
+        StepType::ConversationValidate(conversation_validate_step) => {
+            process_common!(conversation_validate_step)
+        }
+        StepType::IntoList(into_list_step) => process_common!(into_list_step),
+        StepType::Zip(zip_step) => process_common!(zip_step),
+        StepType::RenderConversation(render_conversation_step) => {
+            process_common!(render_conversation_step)
+        }
+        StepType::TruncateConversation(truncate_conversation_step) => {
+            process_common!(truncate_conversation_step)
+        }
+        StepType::ConvertFormat(convert_format_step) => process_common!(convert_format_step),
+        StepType::AugmentConversation(augment_conversation_step) => {
+            process_common!(augment_conversation_step)
+        }
+        StepType::SimulateToolResponse(simulate_tool_response_step) => {
+            process_common!(simulate_tool_response_step)
         }
+        StepType::Dialogue(dialogue_step) => process_common!(dialogue_step),
+        StepType::Persona(persona_step) => process_common!(persona_step),
+        StepType::WeightedChoice(weighted_choice_step) => process_common!(weighted_choice_step),
+        StepType::NegativeToolSampler(negative_tool_sampler_step) => {
+            process_common!(negative_tool_sampler_step)
+        }
+        StepType::PreferencePair(preference_pair_step) => {
+            process_common!(preference_pair_step)
+        }
+        StepType::Rewards(rewards_step) => process_common!(rewards_step),
+        StepType::GroundedGeneration(grounded_generation_step) => {
+            process_common!(grounded_generation_step)
+        }
+        StepType::Filter(filter_step) => process_common!(filter_step),
+        StepType::Mutate(mutate_step) => process_common!(mutate_step),
+        StepType::MapKeys(map_keys_step) => process_common!(map_keys_step),
+        StepType::SelectKeys(select_keys_step) => process_common!(select_keys_step),
+        StepType::DropKeys(drop_keys_step) => process_common!(drop_keys_step),
+        StepType::RegexExtract(regex_extract_step) => process_common!(regex_extract_step),
+        StepType::RegexReplace(regex_replace_step) => process_common!(regex_replace_step),
+        StepType::JsonPath(jsonpath_step) => process_common!(jsonpath_step),
+        StepType::Jq(jq_step) => process_common!(jq_step),
+        StepType::Sql(sql_step) => process_common!(sql_step),
+        StepType::Shell(shell_step) => process_common!(shell_step),
+        StepType::ExecuteCode(execute_code_step) => process_common!(execute_code_step),
+        StepType::CodeTests(code_tests_step) => process_common!(code_tests_step),
+        StepType::TokenCount(token_count_step) => process_common!(token_count_step),
+        StepType::TruncateTokens(truncate_step) => process_common!(truncate_step),
+        StepType::Cleanup(cleanup_step) => process_common!(cleanup_step),
+        StepType::PiiRedaction(pii_step) => process_common!(pii_step),
+        StepType::Translate(translate_step) => process_common!(translate_step),
+        StepType::CheckLength(check_length_step) => process_common!(check_length_step),
+        StepType::CheckGrounding(check_grounding_step) => {
+            process_common!(check_grounding_step)
+        }
+        StepType::VerifyMath(verify_math_step) => process_common!(verify_math_step),
+        StepType::RepairJson(repair_json_step) => process_common!(repair_json_step),
+        StepType::ExtractStructured(extract_step) => process_common!(extract_step),
+        StepType::CheckLanguage(check_language_step) => process_common!(check_language_step),
+        StepType::RenderToolCall(render_tool_call_step) => {
+            process_common!(render_tool_call_step)
+        }
+        StepType::CheckHash(check_hash_step) => process_common!(check_hash_step),
+        StepType::ItemId(item_id_step) => process_common!(item_id_step),
+        StepType::CheckSimHash(check_sim_hash_step) => process_common!(check_sim_hash_step),
+        StepType::CheckEmbedding(embedding_step) => process_common!(embedding_step),
+        StepType::Embed(embed_step) => process_common!(embed_step),
+        StepType::SimilarityFilter(similarity_filter_step) => {
+            process_common!(similarity_filter_step)
+        }
+        StepType::EmbeddingsWriter(embeddings_writer_step) => {
+            process_common!(embeddings_writer_step)
+        }
+        StepType::Retrieve(retrieve_step) => process_common!(retrieve_step),
+        StepType::JudgeConversation(judge_conversation_step) => {
+            process_common!(judge_conversation_step)
+        }
+        StepType::Judge(judge_step) => process_common!(judge_step),
+        StepType::PairwiseJudge(pairwise_judge_step) => process_common!(pairwise_judge_step),
+        StepType::SelfConsistency(self_consistency_step) => {
+            process_common!(self_consistency_step)
+        }
+        StepType::RenderDPO(render_dpostep) => process_common!(render_dpostep),
+        StepType::RenderGRPO(render_grpostep) => process_common!(render_grpostep),
     }
 
-    Ok(context)
+    Ok(())
 }
 
 #[pyclass]
@@ -1609,8 +5730,23 @@ pub enum IterBy {
         stop: usize,
         step: usize,
     },
+    /// Rows of the dataset, without the first `skip` ones and at most `limit` of them.
+    #[pyo3(constructor = (name, limit=None, skip=None))]
     Dataset {
         name: String,
+        limit: Option<usize>,
+        skip: Option<usize>,
+    },
+    /// Rows of the datasets side by side, until the shortest one ends.
+    Zip { names: Vec<String> },
+    /// Every combination of rows of the datasets.
+    Product { names: Vec<String> },
+    /// `n` random rows of the dataset.
+    #[pyo3(constructor = (name, n, seed=None))]
+    Sample {
+        name: String,
+        n: usize,
+        seed: Option<u64>,
     },
 }
 
@@ -1622,6 +5758,7 @@ pub enum Template {
 
 #[pyclass]
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum LLM {
     OpenAI {
         name: String,
@@ -1722,6 +5859,37 @@ impl StepsChain {
         });
     }
 
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (name, template, llm, output, rationale_output=None, threshold=None, system_template=None, max_tokens=None, temperature=None))]
+    pub fn add_judge_step(
+        &mut self,
+        name: String,
+        template: String,
+        llm: String,
+        output: String,
+        rationale_output: Option<String>,
+        threshold: Option<f64>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) {
+        debug!(
+            "Added judge step with llm: {}, template: {}",
+            &llm, &template
+        );
+        self.steps.push(Step::Judge {
+            name,
+            template,
+            llm,
+            output,
+            rationale_output,
+            threshold,
+            system_template,
+            max_tokens,
+            temperature,
+        });
+    }
+
     pub fn add_print_step(
         &mut self,
         name: String,
@@ -1831,6 +5999,12 @@ pub enum Step {
         name: String,
         template: String,
         llm: String,
+        output: String,
+        rationale_output: Option<String>,
+        threshold: Option<f64>,
+        system_template: Option<String>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
     },
     PyValidator {
         name: String,
@@ -1938,6 +6112,216 @@ fn map_step(step: &Step, templates: &mut Templates) -> StepType {
             Some(*size),
             output.clone(),
         )),
+        Step::Judge {
+            name,
+            template,
+            llm,
+            output,
+            rationale_output,
+            threshold,
+            system_template,
+            max_tokens,
+            temperature,
+        } => StepType::Judge(JudgeStep::new(
+            name.clone(),
+            template.clone(),
+            llm.clone(),
+            output.clone(),
+            rationale_output.clone(),
+            *threshold,
+            system_template.clone(),
+            *max_tokens,
+            *temperature,
+        )),
+        Step::PyValidator { name, py_func } => Python::with_gil(|py| {
+            let py_obj: PyObject = py_func.clone_ref(py);
+            StepType::PyValidator(PyValidator::new(name.clone(), py_obj))
+        }),
         _ => unimplemented!(), // Handle other step types as needed
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tweaktune_core::llms::LlmOutcome;
+
+    /// A pipeline running with `workers`, without metadata.
+    fn pipeline(workers: usize) -> PipelineBuilder {
+        let metadata = Metadata::new(String::new(), false);
+        let mut pipeline = PipelineBuilder::new("test".to_string(), Some(metadata));
+        pipeline.workers = workers;
+        pipeline.running.store(true, Ordering::SeqCst);
+        pipeline
+    }
+
+    /// Items taking a few milliseconds each, recording the order they start and end in and
+    /// the most running at once. Item `fail` fails.
+    struct Items {
+        started: std::sync::Mutex<Vec<usize>>,
+        finished: std::sync::Mutex<Vec<usize>>,
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+    }
+
+    impl Items {
+        fn new() -> Self {
+            Self {
+                started: Default::default(),
+                finished: Default::default(),
+                running: Default::default(),
+                most_running: Default::default(),
+            }
+        }
+
+        async fn run(&self, i: usize, fail: Option<usize>) -> Result<(), String> {
+            self.started.lock().unwrap().push(i);
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis((7 - i as u64 % 7) * 2)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.finished.lock().unwrap().push(i);
+            if fail == Some(i) {
+                return Err(format!("item {} failed", i));
+            }
+            Ok(())
+        }
+    }
+
+    fn autoscaler(min: usize, max: usize, workers: usize) -> Autoscaler {
+        let pressure = Box::leak(Box::new(LlmPressure::new()));
+        let autoscaler = Autoscaler::new(min, max, Some(Duration::from_millis(500)), pressure);
+        autoscaler.restart(workers);
+        autoscaler
+    }
+
+    /// Ends the window of `autoscaler` with `calls` and adjusts the limit.
+    fn window(autoscaler: &Autoscaler, calls: Vec<(u64, LlmOutcome)>) -> usize {
+        for (latency_ms, outcome) in calls {
+            autoscaler
+                .pressure
+                .record(Duration::from_millis(latency_ms), outcome);
+        }
+        *autoscaler.window_start.lock().unwrap() -= AUTOSCALE_WINDOW;
+        autoscaler.adjust();
+        autoscaler.limit.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_autoscaler_scales_down_under_pressure() {
+        let autoscaler = autoscaler(1, 16, 16);
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Throttled)]), 8);
+        let errors = vec![(10, LlmOutcome::Ok), (10, LlmOutcome::Error)];
+        assert_eq!(window(&autoscaler, errors), 4);
+        assert_eq!(window(&autoscaler, vec![(900, LlmOutcome::Ok)]), 2);
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Throttled)]), 1);
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Throttled)]), 1);
+        assert_eq!(autoscaler.debt.load(Ordering::SeqCst), 15);
+    }
+
+    #[test]
+    fn test_autoscaler_recovers() {
+        let autoscaler = autoscaler(1, 6, 4);
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Throttled)]), 2);
+        // a window without calls changes nothing
+        assert_eq!(window(&autoscaler, vec![]), 2);
+        // the permits held back are given back first
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Ok)]), 3);
+        assert_eq!(autoscaler.debt.load(Ordering::SeqCst), 1);
+        assert_eq!(autoscaler.semaphore.available_permits(), 4);
+        for limit in [4, 5, 6, 6] {
+            assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Ok)]), limit);
+        }
+        assert_eq!(autoscaler.debt.load(Ordering::SeqCst), 0);
+        assert_eq!(autoscaler.semaphore.available_permits(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_autoscaler_permits_follow_limit() {
+        let autoscaler = autoscaler(1, 8, 4);
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(autoscaler.acquire().await);
+        }
+        assert_eq!(window(&autoscaler, vec![(10, LlmOutcome::Throttled)]), 2);
+        for permit in permits {
+            autoscaler.release(permit);
+        }
+        assert_eq!(autoscaler.semaphore.available_permits(), 2);
+
+        // a new run starts from the workers and forgets the calls of the last one
+        autoscaler
+            .pressure
+            .record(Duration::from_millis(10), LlmOutcome::Throttled);
+        autoscaler.restart(3);
+        assert_eq!(autoscaler.semaphore.available_permits(), 3);
+        assert_eq!(autoscaler.pressure.take().calls, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_items_order() -> Result<()> {
+        let pipeline = pipeline(4);
+        let items = Items::new();
+        let stream = stream::iter((0..30).map(|i| items.run(i, None)));
+        drain_items(&pipeline, stream.buffered(pipeline.concurrency())).await?;
+
+        // started in order, at most the workers at once, all finished
+        assert_eq!(*items.started.lock().unwrap(), (0..30).collect::<Vec<_>>());
+        assert_eq!(items.most_running.load(Ordering::SeqCst), 4);
+        let mut finished = items.finished.lock().unwrap().clone();
+        assert_ne!(finished, (0..30).collect::<Vec<_>>());
+        finished.sort();
+        assert_eq!(finished, (0..30).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_items_error() {
+        let pipeline = pipeline(4);
+        let items = Items::new();
+        let stream = stream::iter((0..100).map(|i| items.run(i, Some(5))));
+        let result = drain_items(&pipeline, stream.buffered(pipeline.concurrency())).await;
+
+        assert_eq!(result.unwrap_err().to_string(), "item 5 failed");
+        // the items after the failing one beyond those buffered with it aren't started
+        let started = items.started.lock().unwrap().len();
+        assert!((6..=9).contains(&started), "{} items started", started);
+    }
+
+    #[tokio::test]
+    async fn test_drain_items_error_under_failure_limit() -> Result<()> {
+        let mut pipeline = pipeline(4);
+        pipeline.failures.limit = Some(MaxFailures::Count(10));
+        let items = Items::new();
+        let stream = stream::iter((0..20).map(|i| items.run(i, Some(5))));
+        drain_items(&pipeline, stream.buffered(pipeline.concurrency())).await?;
+
+        assert_eq!(items.finished.lock().unwrap().len(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_items_interrupted() -> Result<()> {
+        let mut pipeline = pipeline(2);
+        pipeline.shutdown_timeout = Duration::from_millis(50);
+        let started = AtomicUsize::new(0);
+        let stream = stream::iter((0..10).map(|_| async {
+            if started.fetch_add(1, Ordering::SeqCst) == 1 {
+                pipeline.running.store(false, Ordering::SeqCst);
+            }
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }));
+        let drained = tokio::time::timeout(
+            Duration::from_secs(5),
+            drain_items(&pipeline, stream.buffered(pipeline.concurrency())),
+        )
+        .await;
+
+        // the running items are abandoned after the shutdown timeout
+        drained.expect("drain_items waits at most the shutdown timeout")?;
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::future::Future;
+
+/// System allocator counting the allocations of each thread, for the profile of a run.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<Allocations> = const { Cell::new(Allocations { bytes: 0, count: 0 }) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count(bytes: usize) {
+    // unavailable while the thread is torn down
+    let _ = ALLOCATED.try_with(|allocated| {
+        let mut current = allocated.get();
+        current.bytes += bytes as u64;
+        current.count += 1;
+        allocated.set(current);
+    });
+}
+
+/// Bytes and number of allocations.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Allocations {
+    pub bytes: u64,
+    pub count: u64,
+}
+
+/// Runs `future` counting the allocations made while it is polled. Work it hands to other
+/// threads (Python callbacks on their own thread, blocking tasks) isn't counted.
+pub async fn track_allocations<F: Future>(future: F) -> (F::Output, Allocations) {
+    let mut future = std::pin::pin!(future);
+    let mut total = Allocations::default();
+    let output = std::future::poll_fn(|cx| {
+        let before = ALLOCATED.with(Cell::get);
+        let poll = future.as_mut().poll(cx);
+        let after = ALLOCATED.with(Cell::get);
+        total.bytes += after.bytes - before.bytes;
+        total.count += after.count - before.count;
+        poll
+    })
+    .await;
+    (output, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_track_allocations() {
+        let (len, allocations) = track_allocations(async {
+            let first = vec![0u8; 1 << 20];
+            tokio::task::yield_now().await;
+            let second = vec![1u8; 1 << 10];
+            first.len() + second.len()
+        })
+        .await;
+        assert_eq!(len, (1 << 20) + (1 << 10));
+        assert!(allocations.bytes >= (1 << 20) + (1 << 10));
+        assert!(allocations.count >= 2);
+
+        let (_, allocations) = track_allocations(async { 1 + 1 }).await;
+        assert_eq!(allocations, Allocations::default());
+    }
+}
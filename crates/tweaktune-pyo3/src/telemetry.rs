@@ -0,0 +1,92 @@
+use anyhow::Result;
+use opentelemetry::context::FutureExt;
+use opentelemetry::trace::{Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::future::Future;
+use tweaktune_core::llms::LlmUsage;
+
+/// Exports a span per item and per step of a run over OTLP/HTTP.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+impl Telemetry {
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_string())
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer("tweaktune");
+        Ok(Self { provider, tracer })
+    }
+
+    /// Starts the span of an item, a root span.
+    pub fn item(&self, run_id: &str, index: Option<i64>) -> Context {
+        let mut attributes = vec![KeyValue::new("tweaktune.run_id", run_id.to_string())];
+        if let Some(index) = index {
+            attributes.push(KeyValue::new("tweaktune.index", index));
+        }
+        let span = self
+            .tracer
+            .span_builder("item")
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &Context::new());
+        Context::current_with_span(span)
+    }
+
+    /// Starts the span of a step, a child of the current item or step.
+    pub fn step(&self, name: &str) -> Context {
+        let span = self
+            .tracer
+            .span_builder(name.to_string())
+            .with_attributes([KeyValue::new("tweaktune.step", name.to_string())])
+            .start(&self.tracer);
+        Context::current_with_span(span)
+    }
+
+    /// Sends the spans ended so far.
+    pub fn flush(&self) -> Result<()> {
+        self.provider.force_flush()?;
+        Ok(())
+    }
+}
+
+/// Runs `future` with the span of `context` as the current one, so the spans started
+/// within are its children.
+pub async fn in_span<F: Future>(context: Option<&Context>, future: F) -> F::Output {
+    match context {
+        Some(context) => future.with_context(context.clone()).await,
+        None => future.await,
+    }
+}
+
+/// Ends the span of `context` with the LLMs used, their tokens and the outcome: `ok`,
+/// `failed` with an `error`, or `skipped`.
+pub fn end_span(context: Option<Context>, usage: &LlmUsage, outcome: &str, error: Option<&str>) {
+    let Some(context) = context else {
+        return;
+    };
+    let span = context.span();
+    if !usage.llms.is_empty() {
+        let llms = usage.llms.iter().cloned().collect::<Vec<_>>().join(",");
+        span.set_attribute(KeyValue::new("tweaktune.llm", llms));
+    }
+    span.set_attribute(KeyValue::new("tweaktune.tokens", usage.tokens as i64));
+    span.set_attribute(KeyValue::new("tweaktune.outcome", outcome.to_string()));
+    if let Some(error) = error {
+        span.set_status(Status::error(error.to_string()));
+    }
+    span.end();
+}
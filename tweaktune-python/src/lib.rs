@@ -1,9 +1,10 @@
 use pyo3::prelude::*;
 use tweaktune_pyo3::{
     chat_template::{ChatTemplateBuilder, EmbedChatTemplates},
+    logging::PipelineEvent,
     pipeline::{
         Dataset, Embeddings, InternalDatasetType, IterBy, JudgeType, Metadata, PipelineBuilder,
-        Step, StepsChain, Template, LLM,
+        RunHandle, RunResult, Step, StepsChain, Template, LLM,
     },
     steps::{Lang, StepConfigTest, StepTest},
 };
@@ -20,6 +21,9 @@ fn tweaktune(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<StepConfigTest>()?;
     m.add_class::<Lang>()?;
     m.add_class::<PipelineBuilder>()?;
+    m.add_class::<RunResult>()?;
+    m.add_class::<RunHandle>()?;
+    m.add_class::<PipelineEvent>()?;
     m.add_class::<IterBy>()?;
     m.add_class::<Dataset>()?;
     m.add_class::<LLM>()?;
@@ -0,0 +1,517 @@
+import http.server
+import json
+import os
+import threading
+import time
+
+import pytest
+
+from tweaktune import Pipeline
+
+
+def test_run_until_iterations(request, output_dir, metadata):
+    """Test that run_until reports the iterations it drew, those of the invalid items included,
+    and that the pipeline can be used from a step while it runs."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    drawn = []
+
+    def draw(data):
+        drawn.append(p.builder.is_paused())
+        return data["index"] % 3 == 0
+
+    p = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(30)
+        .add_column("keep", draw)
+        .validate(lambda context: context["data"]["keep"])
+        .write_jsonl(path=output_file, template="output")
+    )
+    result = p.run_until(4)
+
+    assert result.report["valid"] == 4
+    assert result.report["iterations"] == len(drawn) == 10
+    indexes = [json.loads(line)["index"] for line in open(output_file)]
+    assert sorted(indexes) == [0, 3, 6, 9]
+
+
+def test_run_until_max_iterations(request, output_dir, metadata):
+    """Test that run_until stops drawing at max_iterations when too few items are valid."""
+    drawn = []
+
+    def draw(data):
+        drawn.append(data["index"])
+        return data["index"]
+
+    result = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .iter_range(30)
+        .add_column("drawn", draw)
+        .validate(lambda context: False)
+        .run_until(3, max_iterations=5)
+    )
+
+    assert result.status == "completed"
+    assert result.report["valid"] == 0
+    assert result.report["iterations"] == len(drawn) == 5
+
+
+def test_sweep(request, output_dir, metadata):
+    """Test that each sweep combination writes its own file with its params, and that the
+    pipeline can be used from a step while the runs go on."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    report_file = f"{output_dir}/{request.node.name}.report.json"
+    paused = []
+
+    def level(data):
+        paused.append(p.builder.is_paused())
+        return data["params"]["level"]
+
+    p = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"level": {{level}} }""")
+        .iter_range(3)
+        .add_column("level", level)
+        .write_jsonl(path=output_file, template="output")
+    )
+    results = p.sweep({"level": [1, 2]}, report=report_file)
+
+    assert [result.processed for result in results] == [3, 3]
+    assert paused == [False] * 6
+    runs = json.load(open(report_file))["runs"]
+    assert [run["params"] for run in runs] == [{"level": 1}, {"level": 2}]
+    for run in runs:
+        (output,) = run["outputs"]
+        levels = [json.loads(line)["level"] for line in open(output)]
+        assert levels == [run["params"]["level"]] * 3
+
+
+
+def test_max_failures_count(request, output_dir, metadata):
+    """Test that the run aborts once more items failed with an error than the count, the
+    items processed before still written."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    with pytest.raises(Exception, match="Aborted after 3 of 6 items failed"):
+        (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .with_max_failures(2)
+            .with_template("output", """{"index": {{index}} }""")
+            .iter_range(100)
+            # odd items divide by zero
+            .add_column("value", "(1 // ((index|int) % 2 - 1))")
+            .write_jsonl(path=output_file, template="output")
+            .run()
+        )
+
+    indexes = [json.loads(line)["index"] for line in open(output_file)]
+    assert indexes == [0, 2, 4]
+
+
+def test_max_failures_ratio(request, output_dir, metadata):
+    """Test that the ratio is only checked from `min_items` on and aborts once exceeded."""
+    with pytest.raises(Exception, match="Aborted after 5 of 5 items failed"):
+        (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .with_max_failures(0.5, min_items=5)
+            .iter_range(100)
+            .add_column("value", "(1 // 0)")
+            .run()
+        )
+
+
+def test_max_failures_under_budget(request, output_dir, metadata):
+    """Test that failures below the count or the ratio don't stop the run."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    for limit in [5, 0.4]:
+        if os.path.exists(output_file):
+            os.remove(output_file)
+        result = (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .with_max_failures(limit, min_items=5)
+            .with_template("output", """{"index": {{index}} }""")
+            .iter_range(20)
+            # every fourth item divides by zero
+            .add_column("value", "(1 // (((index|int) % 4 > 0)|int))")
+            .write_jsonl(path=output_file, template="output")
+            .run()
+        )
+
+        assert result.status == "completed"
+        assert result.failed == 5
+        assert len(open(output_file).readlines()) == 15
+
+
+def test_stop_condition_max_items(request, output_dir, metadata):
+    """Test that the run stops once `max_items` were started and still writes them."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    result = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_stop_condition(max_items=5)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(100)
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    assert result.status == "stopped"
+    assert result.stopped_by == "max_items"
+    assert result.processed == 5
+    indexes = [json.loads(line)["index"] for line in open(output_file)]
+    assert indexes == [0, 1, 2, 3, 4]
+
+
+def test_stop_condition_max_duration(request, output_dir, metadata):
+    """Test that the run stops after `max_duration` and keeps the items it accepted before."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    def slow(data):
+        time.sleep(0.05)
+        return data["index"]
+
+    result = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_stop_condition(max_duration=0.5)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(1000)
+        .add_column("value", slow)
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    assert result.status == "stopped"
+    assert result.stopped_by == "max_duration"
+    indexes = [json.loads(line)["index"] for line in open(output_file)]
+    assert 0 < len(indexes) < 1000
+    assert indexes == list(range(len(indexes)))
+    assert result.processed == len(indexes)
+
+
+def test_run_async(request, output_dir, metadata):
+    """Test that run_async returns right away and the handle follows the run until it's joined."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    release = threading.Event()
+
+    def blocked(data):
+        release.wait(10)
+        return data["index"]
+
+    handle = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(5)
+        .add_column("value", blocked)
+        .write_jsonl(path=output_file, template="output")
+        .run_async()
+    )
+
+    assert handle.wait(timeout=0.2) is None
+    assert handle.status() == "running"
+    assert not handle.done()
+    assert handle.progress()["processed"] == 0
+
+    release.set()
+    result = handle.wait()
+    assert result.status == "completed"
+    assert result.processed == 5
+    assert handle.status() == "completed"
+    assert handle.done()
+    assert handle.progress()["processed"] == 5
+    assert len(open(output_file).readlines()) == 5
+
+
+def test_run_async_error(request, output_dir, metadata):
+    """Test that the error of a spawned run is raised by wait and reported by status."""
+    handle = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .iter_range(5)
+        .add_column("value", "(1 // 0)")
+        .run_async()
+    )
+
+    with pytest.raises(RuntimeError, match="Failed to render template"):
+        handle.wait(timeout=10)
+    assert handle.status() == "failed"
+    assert handle.done()
+
+
+def test_run_async_cancel(request, output_dir, metadata):
+    """Test that a cancelled run finishes the running item and ends as interrupted."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    started = threading.Event()
+    release = threading.Event()
+
+    def blocked(data):
+        started.set()
+        release.wait(10)
+        return data["index"]
+
+    handle = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(100)
+        .add_column("value", blocked)
+        .write_jsonl(path=output_file, template="output")
+        .run_async()
+    )
+
+    assert started.wait(10)
+    handle.cancel()
+    assert handle.status() == "cancelling"
+    release.set()
+    result = handle.wait(timeout=10)
+    assert result.status == "interrupted"
+    assert 0 < result.processed < 100
+    assert len(open(output_file).readlines()) == result.processed
+
+
+def test_pause_from_step(request, output_dir, metadata):
+    """Test that pausing from a step lets the item finish but starts no new ones until resumed."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    started = []
+
+    def pause_at_two(data):
+        started.append(data["index"])
+        if data["index"] == 2:
+            p.pause()
+        return data["index"]
+
+    p = (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"index": {{index}} }""")
+        .iter_range(10)
+        .add_column("value", pause_at_two)
+        .write_jsonl(path=output_file, template="output")
+    )
+    handle = p.run_async()
+
+    assert handle.wait(timeout=0.5) is None
+    assert handle.status() == "paused"
+    assert started == [0, 1, 2]
+
+    p.resume()
+    result = handle.wait(timeout=10)
+    assert result.status == "completed"
+    assert started == list(range(10))
+    indexes = [json.loads(line)["index"] for line in open(output_file)]
+    assert indexes == list(range(10))
+
+
+def _protobuf_fields(message):
+    """Fields of a protobuf message as (number, value) pairs, nested messages left as bytes."""
+    fields, position = [], 0
+
+    def varint():
+        nonlocal position
+        value = shift = 0
+        while True:
+            byte = message[position]
+            position += 1
+            value |= (byte & 0x7F) << shift
+            shift += 7
+            if byte < 0x80:
+                return value
+
+    while position < len(message):
+        key = varint()
+        number, wire_type = key >> 3, key & 7
+        if wire_type == 0:
+            value = varint()
+        elif wire_type == 2:
+            length = varint()
+            value = message[position : position + length]
+            position += length
+        else:
+            size = 8 if wire_type == 1 else 4
+            value = message[position : position + size]
+            position += size
+        fields.append((number, value))
+    return fields
+
+
+def _otlp_spans(bodies):
+    """Name, ids and string or int attributes of the spans of OTLP trace export requests."""
+    spans = []
+    for body in bodies:
+        for _, resource_spans in _protobuf_fields(body):
+            for number, scope_spans in _protobuf_fields(resource_spans):
+                if number != 2:
+                    continue
+                for number, span in _protobuf_fields(scope_spans):
+                    if number != 2:
+                        continue
+                    fields = _protobuf_fields(span)
+                    attributes = {}
+                    for number, attribute in fields:
+                        if number == 9:
+                            attribute = dict(_protobuf_fields(attribute))
+                            value = dict(_protobuf_fields(attribute[2]))
+                            attributes[attribute[1].decode()] = (
+                                value[1].decode() if 1 in value else value.get(3)
+                            )
+                    fields = dict(fields)
+                    spans.append(
+                        {
+                            "name": fields[5].decode(),
+                            "id": fields[2],
+                            "parent": fields.get(4, b""),
+                            "attributes": attributes,
+                        }
+                    )
+    return spans
+
+
+def test_otlp_tracing(request, output_dir, metadata):
+    """Test that a span per item and per step of the item is exported, with the outcomes."""
+    bodies = []
+
+    class Collector(http.server.BaseHTTPRequestHandler):
+        def do_POST(self):
+            bodies.append(self.rfile.read(int(self.headers["Content-Length"])))
+            self.send_response(200)
+            self.send_header("Content-Type", "application/x-protobuf")
+            self.send_header("Content-Length", "0")
+            self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), Collector)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    try:
+        (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .with_otlp_tracing(
+                endpoint=f"http://127.0.0.1:{server.server_port}/v1/traces",
+                service_name=request.node.name,
+            )
+            .iter_range(3)
+            .add_column("value", lambda data: data["index"])
+            .validate(lambda context: context["data"]["index"] != 1)
+            # the collector answers while the run doesn't hold the GIL
+            .run_async()
+            .wait(timeout=30)
+        )
+    finally:
+        server.shutdown()
+
+    spans = _otlp_spans(bodies)
+    items = {span["id"]: span for span in spans if span["name"] == "item"}
+    assert sorted(item["attributes"]["tweaktune.index"] for item in items.values()) == [0, 1, 2]
+    assert len({item["attributes"]["tweaktune.run_id"] for item in items.values()}) == 1
+
+    steps = [span for span in spans if span["name"] != "item"]
+    assert len(steps) == 6
+    outcomes = {}
+    for step in steps:
+        index = items[step["parent"]]["attributes"]["tweaktune.index"]
+        assert step["attributes"]["tweaktune.step"] == step["name"]
+        assert step["attributes"]["tweaktune.tokens"] == 0
+        outcomes[(index, step["name"].split("--")[0])] = step["attributes"]["tweaktune.outcome"]
+    assert outcomes == {
+        (0, "ADD-COLUMN"): "ok",
+        (0, "VALIDATE"): "ok",
+        (1, "ADD-COLUMN"): "ok",
+        (1, "VALIDATE"): "failed",
+        (2, "ADD-COLUMN"): "ok",
+        (2, "VALIDATE"): "ok",
+    }
+
+
+def _table_rows(output):
+    """Rows of the tables printed to `output`, by the step name prefix in their first cell."""
+    rows = {}
+    for line in output.splitlines():
+        cells = [cell.strip() for cell in line.strip("│ ").split("┆")]
+        if len(cells) > 1 and "--" in cells[0]:
+            rows[cells[0].split("--")[0]] = cells[1:]
+    return rows
+
+
+def test_profile(request, output_dir, metadata, capfd):
+    """Test that the profile counts the calls of each step and puts the slowest first."""
+
+    def slow(data):
+        time.sleep(0.05)
+        return data["index"]
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .iter_range(4)
+        .add_column("fast", lambda data: data["index"], name="FAST")
+        .add_column("slow", slow, name="SLOW")
+        .run(profile=True)
+    )
+    output, _ = capfd.readouterr()
+
+    assert "Share [%]" in output and "Alloc/call [KB]" in output
+    rows = _table_rows(output)
+    count, total, share, mean, maximum, allocated, allocations = rows["SLOW"]
+    assert count == "4"
+    assert float(total) >= 0.2
+    assert float(mean) >= 50 and float(maximum) >= 50
+    assert 50 <= float(share) <= 100
+    assert float(allocated) >= 0 and float(allocations) >= 0
+    assert rows["FAST"][0] == "4"
+    assert float(rows["FAST"][1]) < float(total)
+    assert output.index("SLOW--") < output.index("FAST--")
+    assert "kept the 1 workers busy" in output
+
+
+def test_step_timings(request, output_dir, metadata, capfd):
+    """Test that each item gets the duration of every step and the summary adds them up."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    def slow(data):
+        time.sleep(0.05)
+        return data["index"]
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_step_timings("timings")
+        .with_template("output", """{"timings": {{timings|tojson}} }""")
+        .iter_range(3)
+        .add_column("fast", lambda data: data["index"], name="FAST")
+        .add_column("slow", slow, name="SLOW")
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+    output, _ = capfd.readouterr()
+
+    items = [json.loads(line)["timings"] for line in open(output_file)]
+    assert len(items) == 3
+    for timings in items:
+        steps = {name.split("--")[0]: seconds for name, seconds in timings.items()}
+        assert set(steps) == {"FAST", "SLOW"}
+        assert steps["SLOW"] >= 0.05
+        assert steps["FAST"] < steps["SLOW"]
+
+    rows = _table_rows(output)
+    count, total, mean, maximum = rows["SLOW"]
+    assert count == "3"
+    slow_total = sum(
+        seconds for timings in items for name, seconds in timings.items() if name.startswith("SLOW")
+    )
+    assert float(total) == pytest.approx(slow_total, abs=0.001)
+    assert float(mean) >= 50 and float(maximum) >= float(mean)
+    assert rows["FAST"][0] == "3"
+    # the writer ran after the timings were rendered, it's only in the summary
+    assert rows["WRITE-JSONL"][0] == "3"
+    assert output.index("SLOW--") < output.index("FAST--")
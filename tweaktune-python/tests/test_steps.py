@@ -1,5 +1,12 @@
+import http.server
 import json
+import os
+import queue
 import random
+import shutil
+import threading
+
+import pytest
 
 from tweaktune import Pipeline
 from tweaktune.chain import Chain
@@ -778,3 +785,475 @@ def test_step_into_list(request, output_dir, data_dir, arrow_dataset, metadata):
     assert len(lines) == 10
     assert "my_list" in item
     assert item["my_list"] == [1, 2]
+
+
+def test_step_foreach(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test running a chain for every element of a list."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"doubled": {{doubled|tojson}} }""")
+        .iter_range(10)
+        .add_column("numbers", lambda data: [1, 2, 3, 4])
+        .foreach(
+            input="numbers",
+            item_key="number",
+            chain=Chain()
+            .filter(lambda data: data["number"] % 2 == 0)
+            .add_column("double", lambda data: data["number"] * 2),
+            output="doubled",
+            item_output="double",
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = open(output_file).readlines()
+    assert len(lines) == 10
+    item = json.loads(lines[0])
+    assert item["doubled"] == [4, 8]
+
+
+def test_step_loop_until(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test repeating a chain until the condition holds."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"attempts": {{attempts}} }""")
+        .iter_range(10)
+        .add_column("attempts", lambda data: 0)
+        .loop_until(
+            condition="attempts >= 3",
+            chain=Chain().add_column("attempts", lambda data: data["attempts"] + 1),
+            max_iters=5,
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = open(output_file).readlines()
+    assert len(lines) == 10
+    assert json.loads(lines[0])["attempts"] == 3
+
+
+def test_step_loop_until_max_iters(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that items never meeting the condition are dropped."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"attempts": {{attempts}} }""")
+        .iter_range(10)
+        .add_column("attempts", lambda data: 0)
+        .loop_until(
+            condition=lambda data: data["attempts"] >= 10,
+            chain=Chain().add_column("attempts", lambda data: data["attempts"] + 1),
+            max_iters=2,
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    assert not os.path.exists(output_file)
+
+
+def test_step_loop_until_failing_body(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that a failing chain is retried from the context before the run and the item
+    fails once `max_iters` runs were made."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    attempts = {}
+
+    def attempt(data):
+        attempts[data["index"]] = attempts.get(data["index"], 0) + 1
+        return data["attempts"] + 1
+
+    quarantine_file = f"{output_dir}/{request.node.name}.quarantine.jsonl"
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_quarantine(quarantine_file)
+        .with_template("output", """{"attempts": {{attempts}} }""")
+        .iter_range(4)
+        .add_column("attempts", lambda data: 0)
+        .loop_until(
+            condition="attempts >= 1",
+            chain=Chain().add_column("attempts", attempt).validate(lambda context: False),
+            max_iters=3,
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    assert attempts == {0: 3, 1: 3, 2: 3, 3: 3}
+    assert not os.path.exists(output_file)
+    failed = [json.loads(line) for line in open(quarantine_file)]
+    assert len(failed) == 4
+    assert all(item["data"]["attempts"] == 0 for item in failed)
+
+
+def test_quarantine_concurrent_items(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that items failing on several workers at once are quarantined as whole lines."""
+    quarantine_file = f"{output_dir}/{request.node.name}.quarantine.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(8)
+        .with_quarantine(quarantine_file)
+        .iter_range(200)
+        .add_column("payload", lambda data: "x" * 20000)
+        .validate(lambda context: False)
+        .run()
+    )
+
+    failed = [json.loads(line) for line in open(quarantine_file)]
+    assert len(failed) == 200
+    assert sorted(item["data"]["index"] for item in failed) == list(range(200))
+    assert all(len(item["data"]["payload"]) == 20000 for item in failed)
+
+
+def test_step_subpipeline(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that the sub-pipeline sees the item and only its outputs are copied back."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    child = (
+        Pipeline(name=f"{request.node.name}_child", metadata=metadata)
+        .with_template("child_template", """{{x}}-{{index}}""")
+        .iter_range(1)
+        .add_column("y", lambda data: data["x"] + 1)
+        .add_column("scratch", lambda data: "child only")
+        .render(template="child_template", output="label")
+    )
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template(
+            "output",
+            """{"x": {{x}}, "y": {{y}}, "label": "{{label}}", "scratch": {{has_scratch|tojson}} }""",
+        )
+        .iter_range(4)
+        .add_column("x", lambda data: data["index"] * 10)
+        .subpipeline(child, outputs=["y", "label"])
+        .add_column("has_scratch", lambda data: "scratch" in data)
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = [json.loads(line) for line in open(output_file)]
+    assert lines == [
+        {"x": i * 10, "y": i * 10 + 1, "label": f"{i * 10}-{i}", "scratch": False} for i in range(4)
+    ]
+
+
+def test_step_subpipeline_failure(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that an item failing in the sub-pipeline fails in the parent, with the failing step."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    quarantine_file = f"{output_dir}/{request.node.name}.quarantine.jsonl"
+    after = []
+
+    child = (
+        Pipeline(name=f"{request.node.name}_child", metadata=metadata)
+        .iter_range(1)
+        .add_column("y", lambda data: data["index"] + 1)
+        .validate(lambda context: context["data"]["index"] % 2 == 0)
+    )
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_quarantine(quarantine_file)
+        .with_template("output", """{"index": {{index}}, "y": {{y}} }""")
+        .iter_range(6)
+        .subpipeline(child, outputs=["y"])
+        .add_column("after", lambda data: after.append(data["index"]))
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = [json.loads(line) for line in open(output_file)]
+    assert lines == [{"index": i, "y": i + 1} for i in [0, 2, 4]]
+    assert after == [0, 2, 4]
+    failed = [json.loads(line) for line in open(quarantine_file)]
+    assert sorted(item["data"]["index"] for item in failed) == [1, 3, 5]
+    # the parent has no validate step, the child's is reported
+    assert all(item["step"].startswith("VALIDATE--") for item in failed)
+
+
+def test_step_subpipeline_error(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that an error in the sub-pipeline fails the run with the child's step."""
+    child = (
+        Pipeline(name=f"{request.node.name}_child", metadata=metadata)
+        .iter_range(1)
+        .add_column("y", "(1 // 0)")
+    )
+
+    with pytest.raises(Exception, match="Failed to render template"):
+        (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .iter_range(3)
+            .subpipeline(child, outputs=["y"])
+            .run()
+        )
+
+
+def test_step_dump(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that the items matching the condition and the failed items are dumped as JSON."""
+    dump_dir = f"{output_dir}/{request.node.name}"
+    shutil.rmtree(dump_dir, ignore_errors=True)
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .iter_range(6)
+        .add_column("square", lambda data: data["index"] ** 2)
+        .dump(f"{dump_dir}/even", condition="index|int % 2 == 0")
+        .validate(lambda context: context["data"]["index"] < 4)
+        .dump(f"{dump_dir}/failed", failed=True)
+        .run()
+    )
+
+    def dumped(dir):
+        items = [json.load(open(f"{dir}/{file}")) for file in os.listdir(dir)]
+        return sorted(items, key=lambda item: item["data"]["index"])
+
+    even = dumped(f"{dump_dir}/even")
+    assert [item["data"] for item in even] == [{"index": i, "square": i**2} for i in [0, 2, 4]]
+    assert all(item["status"] == "Running" for item in even)
+    assert all(
+        os.path.basename(file).startswith("DUMP--2-") for file in os.listdir(f"{dump_dir}/even")
+    )
+
+    failed = dumped(f"{dump_dir}/failed")
+    assert [item["data"]["index"] for item in failed] == [4, 5]
+    assert all(item["status"] == "Failed" for item in failed)
+    assert {f"DUMP--4-{item['id']}.json" for item in failed} == set(
+        os.listdir(f"{dump_dir}/failed")
+    )
+
+
+def test_step_write_py(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that the sink receives the selected columns of every item and on_finish runs last."""
+    records, calls = [], []
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .iter_range(4)
+        .add_column("square", lambda data: data["index"] ** 2)
+        .add_column("name", lambda data: f"item-{data['index']}")
+        .write_py(
+            lambda record: (records.append(record), calls.append("record")),
+            columns=["name", "square"],
+            on_finish=lambda: calls.append("finish"),
+        )
+        .run()
+    )
+
+    assert records == [{"name": f"item-{i}", "square": i**2} for i in range(4)]
+    assert calls == ["record"] * 4 + ["finish"]
+
+
+def test_step_write_py_queue(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that a queue sink gets the whole items and items missing a column fail."""
+    quarantine_file = f"{output_dir}/{request.node.name}.quarantine.jsonl"
+    sink = queue.Queue()
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_quarantine(quarantine_file)
+        .iter_range(4)
+        .add_column("square", lambda data: data["index"] ** 2)
+        .write_py(sink)
+        .filter(condition="index|int < 2")
+        .write_py(sink, columns=["missing"])
+        .run()
+    )
+
+    records = [sink.get_nowait() for _ in range(sink.qsize())]
+    assert records == [{"index": i, "square": i**2} for i in range(4)]
+    failed = [json.loads(line) for line in open(quarantine_file)]
+    # the filtered items are quarantined by the filter step
+    written = [item for item in failed if item["step"] == "WRITE-PY--4"]
+    assert sorted(item["data"]["index"] for item in written) == [0, 1]
+
+
+def test_step_embed(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test that each embed step writes the vector of its own embeddings to its output."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    requests = []
+
+    class EmbeddingsApi(http.server.BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+            requests.append((self.path, body["model"], body["input"]))
+            scale = {"small": 1.0, "large": 10.0}[body["model"]]
+            data = [{"embedding": [len(text) * scale, scale]} for text in body["input"]]
+            response = json.dumps({"data": data}).encode()
+            self.send_response(200)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(response)))
+            self.end_headers()
+            self.wfile.write(response)
+
+        def log_message(self, *args):
+            pass
+
+    server = http.server.ThreadingHTTPServer(("127.0.0.1", 0), EmbeddingsApi)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    base_url = f"http://127.0.0.1:{server.server_port}"
+    try:
+        (
+            Pipeline(name=request.node.name, metadata=metadata)
+            .with_workers(1)
+            .with_embedings_api("small", base_url, "key", "small")
+            .with_embedings_api("large", base_url, "key", "large")
+            .with_template(
+                "output",
+                '{"text": {{text|tojson}}, "small": {{small|tojson}}, '
+                '"large": {{large|tojson}} }',
+            )
+            .iter_range(3)
+            .add_column("text", lambda data: "x" * (data["index"] + 1))
+            .embed(input="text", embedding="small", output="small")
+            .embed(input="text", embedding="large", output="large")
+            .write_jsonl(path=output_file, template="output")
+            # the api answers while the run doesn't hold the GIL
+            .run_async()
+            .wait(timeout=30)
+        )
+    finally:
+        server.shutdown()
+
+    lines = sorted((json.loads(line) for line in open(output_file)), key=lambda x: x["text"])
+    assert lines == [
+        {"text": "x" * n, "small": [n * 1.0, 1.0], "large": [n * 10.0, 10.0]} for n in [1, 2, 3]
+    ]
+    assert {path for path, _, _ in requests} == {"/v1/embeddings"}
+    assert sorted((model, input) for _, model, input in requests) == [
+        (model, ["x" * n]) for model in ["large", "small"] for n in [1, 2, 3]
+    ]
+
+
+def test_step_retry(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test re-running a chain until it passes validation."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+    attempts = {}
+
+    def flaky(data):
+        attempts[data["index"]] = attempts.get(data["index"], 0) + 1
+        return attempts[data["index"]]
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"attempt": {{attempt}} }""")
+        .iter_range(10)
+        .retry(
+            chain=Chain()
+            .add_column("attempt", flaky)
+            .validate(lambda context: context["data"]["attempt"] >= 2),
+            max_attempts=3,
+            backoff_ms=1,
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = open(output_file).readlines()
+    assert len(lines) == 10
+    assert json.loads(lines[0])["attempt"] == 2
+
+
+def test_step_parallel(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test running chains concurrently and merging their outputs."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"question": {{question|jstr}}, "answer": {{answer}} }""")
+        .iter_range(10)
+        .parallel(
+            [
+                (
+                    Chain()
+                    .add_column("question", lambda data: "2 + 2")
+                    .add_column("scratch", lambda data: 1),
+                    ["question"],
+                ),
+                (Chain().add_column("answer", lambda data: 4), ["answer"]),
+            ]
+        )
+        .add_column("has_scratch", lambda data: "scratch" in data)
+        .filter(lambda data: not data["has_scratch"])
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = open(output_file).readlines()
+    assert len(lines) == 10
+    item = json.loads(lines[0])
+    assert item["question"] == "2 + 2"
+    assert item["answer"] == 4
+
+
+def test_step_switch(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test routing items to chains by a label."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_template("output", """{"label": {{label|jstr}}, "route": {{route|jstr}} }""")
+        .iter_range(9)
+        .add_column("label", lambda data: ["math", "code", "other"][data["index"] % 3])
+        .switch(
+            key="label",
+            cases={
+                "math": Chain().add_column("route", lambda data: "math-chain"),
+                "code": Chain().add_column("route", lambda data: "code-chain"),
+            },
+            default=Chain().add_column("route", lambda data: "default-chain"),
+        )
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    lines = open(output_file).readlines()
+    assert len(lines) == 9
+    for line in lines:
+        item = json.loads(line)
+        expected = {"math": "math-chain", "code": "code-chain"}.get(item["label"], "default-chain")
+        assert item["route"] == expected
+
+
+def test_step_shell(request, output_dir, data_dir, arrow_dataset, metadata):
+    """Test running a shell command with stdin and capturing the exit code."""
+    output_file = f"{output_dir}/{request.node.name}.jsonl"
+
+    with pytest.raises(ValueError):
+        Pipeline(name=request.node.name, metadata=metadata).iter_range(1).shell(command="true", output="out")
+
+    (
+        Pipeline(name=request.node.name, metadata=metadata)
+        .with_workers(1)
+        .with_shell_commands()
+        .with_template("output", """{"upper": {{upper|jstr}}, "code": {{code}} }""")
+        .iter_range(3)
+        .add_column("text", lambda data: f"item {data['index']}")
+        .shell(command="tr a-z A-Z; exit {{index}}", stdin="text", output="upper", exit_code_output="code", fail_on_error=False)
+        .shell(command="test {{code}} -lt 2", output="ignored")
+        .write_jsonl(path=output_file, template="output")
+        .run()
+    )
+
+    items = [json.loads(line) for line in open(output_file).readlines()]
+    assert sorted(items, key=lambda item: item["code"]) == [
+        {"upper": "ITEM 0", "code": 0},
+        {"upper": "ITEM 1", "code": 1},
+    ]
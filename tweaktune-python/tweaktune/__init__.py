@@ -21,6 +21,7 @@ from tweaktune.tweaktune import (
     JudgeType,
     Metadata,
     PipelineBuilder,
+    PipelineEvent,
 )
 from tweaktune.tweaktune import ChatTemplateBuilder as _ChatTemplateBuilder
 from tweaktune.wrappers import (
@@ -28,13 +29,21 @@ from tweaktune.wrappers import (
     PyConditionWrapper,
     PyStepValidatorWrapper,
     PyStepWrapper,
+    PyWriterWrapper,
     UnslothWrapper,
 )
 
 
+# locals left out of the recorded arguments, `op_config_str` is `op_config` serialized and
+# would hide its secrets from the redaction of the exported config
+SKIPPED_ARGS = {"self", "ipc_data", "op_config_str"}
+
+
 def step_item(name: str):
     frame = inspect.currentframe().f_back
-    args_info = {str(k): v for k, v in inspect.getargvalues(frame).locals.items() if k != "self"}
+    args_info = {
+        str(k): v for k, v in inspect.getargvalues(frame).locals.items() if k not in SKIPPED_ARGS
+    }
     return StepItem(name=name, func=frame.f_code.co_name, args=args_info)
 
 
@@ -51,9 +60,9 @@ class StepItem(BaseModel):
 def config_item(name: str):
     frame = inspect.currentframe().f_back
     args_info = {
-        str(k): str(v).replace('"', "")
+        str(k): v if isinstance(v, dict) else str(v).replace('"', "")
         for k, v in inspect.getargvalues(frame).locals.items()
-        if k != "ipc_data" and k != "self"
+        if k not in SKIPPED_ARGS
     }
     return ConfigItem(name=name, func=frame.f_code.co_name, args=args_info)
 
@@ -61,7 +70,7 @@ def config_item(name: str):
 class ConfigItem(BaseModel):
     name: str
     func: str
-    args: Dict[str, str] = {}
+    args: Dict[str, Any] = {}
 
 
 def start_item(name: str):
@@ -69,7 +78,7 @@ def start_item(name: str):
     args_info = {
         str(k): str(v).replace('"', "")
         for k, v in inspect.getargvalues(frame).locals.items()
-        if k != "ipc_data" and k != "self"
+        if k not in SKIPPED_ARGS
     }
     return ConfigItem(name=name, func=frame.f_code.co_name, args=args_info)
 
@@ -93,6 +102,20 @@ class Graph(BaseModel):
     start: Optional[StartItem] = None
 
 
+def as_list(value: Union[str, List[str]]) -> List[str]:
+    return [value] if isinstance(value, str) else list(value)
+
+
+def config_value(value: Any):
+    """JSON fallback for step arguments: nested pipelines by their graph, functions and classes by name."""
+    if isinstance(value, (Pipeline, PipelineRunner)):
+        return value.graph.model_dump()
+    if isinstance(value, BaseModel):
+        return value.model_dump()
+    name = getattr(value, "__qualname__", None) or getattr(type(value), "__qualname__", None)
+    return f"<{name}>" if name else repr(value)
+
+
 class Pipeline:
     def __init__(self, name: Optional[str] = None, metadata: Optional[Metadata] = None):
         if name is None:
@@ -272,7 +295,7 @@ class Pipeline:
         """Adds a LLM to the pipeline."""
         if llm.__class__ == LLM.OpenAI:
             self.builder.with_llm_api(
-                llm.name, llm.base_url, llm.api_key, llm.model, llm.max_tokens
+                llm.name, [llm.base_url], [llm.api_key], llm.model, llm.max_tokens
             )
             self.graph.config.llms.append(config_item(llm.name))
         else:
@@ -283,38 +306,51 @@ class Pipeline:
     def with_llm_api(
         self,
         name: str,
-        base_url: str,
-        api_key: str,
+        base_url: Union[str, List[str]],
+        api_key: Union[str, List[str]],
         model: str,
         max_tokens: int = 2048,
         temperature: float = 0.7,
     ):
-        """Adds an OpenAI LLM to the pipeline."""
-        self.builder.with_llm_api(name, base_url, api_key, model, max_tokens, temperature)
+        """Adds an OpenAI LLM to the pipeline. Several API keys or base urls are rotated through by the workers."""
+        self.builder.with_llm_api(
+            name, as_list(base_url), as_list(api_key), model, max_tokens, temperature
+        )
         self.graph.config.llms.append(config_item(name))
         return self
 
     def with_llm_openai(
-        self, name: str, api_key: str, model: str, max_tokens: int = 2048, temperature: float = 0.7
+        self,
+        name: str,
+        api_key: Union[str, List[str]],
+        model: str,
+        max_tokens: int = 2048,
+        temperature: float = 0.7,
     ):
-        """Adds an OpenAI LLM to the pipeline."""
-        self.builder.with_llm_openai(name, api_key, model, max_tokens, temperature)
+        """Adds an OpenAI LLM to the pipeline. Several API keys are rotated through by the workers."""
+        self.builder.with_llm_openai(name, as_list(api_key), model, max_tokens, temperature)
         self.graph.config.llms.append(config_item(name))
         return self
 
     def with_llm_azure_openai(
         self,
         name: str,
-        api_key: str,
-        endpoint: str,
+        api_key: Union[str, List[str]],
+        endpoint: Union[str, List[str]],
         deployment_name: str,
         api_version: str,
         max_tokens: int = 2048,
         temperature: float = 0.7,
     ):
-        """Adds an OpenAI LLM to the pipeline."""
+        """Adds an OpenAI LLM to the pipeline. Several API keys or endpoints are rotated through by the workers."""
         self.builder.with_llm_azure_openai(
-            name, api_key, endpoint, deployment_name, api_version, max_tokens, temperature
+            name,
+            as_list(api_key),
+            as_list(endpoint),
+            deployment_name,
+            api_version,
+            max_tokens,
+            temperature,
         )
         self.graph.config.llms.append(config_item(name))
         return self
@@ -394,11 +430,167 @@ class Pipeline:
         self.graph.config.llms.append(config_item("EMBEDDINGS"))
         return self
 
+    def with_embeddings_cohere(
+        self,
+        name: str,
+        api_key: str,
+        model: str,
+        input_type: Optional[str] = None,
+        base_url: Optional[str] = None,
+    ):
+        self.builder.with_embeddings_cohere(name, api_key, model, input_type, base_url)
+        self.graph.config.llms.append(config_item("EMBEDDINGS"))
+        return self
+
+    def with_embeddings_jina(
+        self,
+        name: str,
+        api_key: str,
+        model: str,
+        task: Optional[str] = None,
+        base_url: Optional[str] = None,
+    ):
+        self.builder.with_embeddings_jina(name, api_key, model, task, base_url)
+        self.graph.config.llms.append(config_item("EMBEDDINGS"))
+        return self
+
+    def with_embeddings_bert(
+        self,
+        name: str,
+        model_repo: str = "sentence-transformers/all-MiniLM-L6-v2",
+        pooling: Optional[str] = None,
+        normalize: Optional[bool] = None,
+        device: Optional[str] = None,
+    ):
+        """Local sentence-transformers model (e.g. MiniLM, BGE-small) running on candle.
+        `pooling` is `mean` (default) or `cls` (BGE models)."""
+        self.builder.with_embeddings_bert(name, model_repo, pooling, normalize, device)
+        self.graph.config.llms.append(config_item("EMBEDDINGS"))
+        return self
+
+    def with_embeddings_dimensions(self, key: str, dimensions: int):
+        """Truncates (and renormalizes) vectors stored under `key` to `dimensions`.
+        Intended for Matryoshka models, requires metadata to be enabled."""
+        self.builder.with_embeddings_dimensions(key, dimensions)
+        return self
+
+    def with_embeddings_precision(self, key: str, precision: str):
+        """Stores vectors for `key` as `f32` (default), `f16` or `int8`. Requires metadata."""
+        self.builder.with_embeddings_precision(key, precision)
+        return self
+
+    def warm_embeddings_cache(self, embeddings: str, texts: List[str]):
+        """Embeds texts ahead of the run so steps can reuse the cached vectors."""
+        self.builder.warm_embeddings_cache(embeddings, texts)
+        return self
+
+    def flush_embeddings_cache(self, embeddings: Optional[str] = None) -> int:
+        """Removes cached vectors for the given embeddings (or all of them)."""
+        return self.builder.flush_embeddings_cache(embeddings)
+
+    def flush_step_cache(self, step: Optional[str] = None) -> int:
+        """Removes cached results of the given `cache` step (or all of them)."""
+        return self.builder.flush_step_cache(step)
+
+    def with_tokenizer(self, name: str, path_or_repo: str, hf_token: str = None):
+        """Registers a tokenizer from a local `tokenizer.json` or a Hugging Face model repo."""
+        self.builder.with_tokenizer(name, path_or_repo, hf_token)
+        return self
+
     def with_workers(self, workers: int):
         self.builder.with_workers(workers)
         self.graph.config.workers = workers
         return self
 
+    def with_autoscaling(self, min_workers: int = 1, max_workers: int = 32, target_latency: float = None):
+        """Adjusts the workers between `min_workers` and `max_workers` to the LLM endpoint: halved
+        when calls are rate limited, fail or take longer than `target_latency` seconds, raised
+        by one while it is healthy. Starts from `with_workers`."""
+        target_latency_ms = int(target_latency * 1000) if target_latency is not None else None
+        self.builder.with_autoscaling(min_workers, max_workers, target_latency_ms)
+        return self
+
+    def with_epochs(self, epochs: int, shuffle: bool = True):
+        """Iterates over the dataset `epochs` times, each item gets its `epoch`. With `shuffle`
+        the rows come in a new order every epoch. The rows are loaded in memory."""
+        self.builder.with_epochs(epochs, shuffle)
+        return self
+
+    def with_dag(self, enabled: bool = True):
+        """Orders the steps by their declared `io` instead of the order they were added,
+        steps that don't depend on each other run concurrently for each item."""
+        self.builder.with_dag(enabled)
+        return self
+
+    def with_seed(self, seed: int):
+        """Seeds sampling, random choices, template filters and LLM requests so the run can be reproduced.
+        Steps with their own `seed` keep it."""
+        self.builder.with_seed(seed)
+        return self
+
+    def with_param(self, key: str, value: Any):
+        """Sets a run parameter, every item gets the parameters as `params`. `${VAR}` in string values
+        is replaced with the environment variable."""
+        self.builder.with_param(key, json.dumps(value, ensure_ascii=False))
+        return self
+
+    def with_worker_rng(self):
+        """Seeded steps draw from a random stream of each worker instead of one of each item."""
+        self.builder.with_worker_rng()
+        return self
+
+    def with_shell_commands(self, enabled: bool = True):
+        """Allows `shell` steps. Commands run with the permissions of the current process."""
+        self.builder.with_shell_commands(enabled)
+        return self
+
+    def with_quarantine(self, path: str):
+        """Writes failed items to a JSONL file with the failing step and error."""
+        self.builder.with_quarantine(path)
+        return self
+
+    def with_max_failures(self, count_or_ratio: Union[int, float], min_items: int = 10):
+        """Aborts the run once more items failed with an error than `count_or_ratio`: a number of items or,
+        as a float below 1, a share of the processed items (checked from `min_items` on).
+        Outputs are still flushed, and below the limit errors don't fail the run."""
+        if isinstance(count_or_ratio, float):
+            self.builder.with_max_failures(None, count_or_ratio, min_items)
+        else:
+            self.builder.with_max_failures(count_or_ratio, None, min_items)
+        return self
+
+    def with_stop_condition(
+        self,
+        max_items: Optional[int] = None,
+        max_tokens: Optional[int] = None,
+        max_cost: Optional[float] = None,
+        max_duration: Optional[float] = None,
+        cost_per_1k_tokens: Optional[float] = None,
+    ):
+        """Stops the run gracefully once `max_items` were started, `max_tokens` used by the API
+        LLMs, `max_cost` spent (at `cost_per_1k_tokens`) or `max_duration` seconds passed.
+        The run ends as `stopped` with the limit reached in `RunResult.stopped_by`."""
+        self.builder.with_stop_condition(
+            max_items, max_tokens, max_cost, max_duration, cost_per_1k_tokens
+        )
+        return self
+
+    def with_shutdown_timeout(self, seconds: float = 30):
+        """On Ctrl-C, waits up to `seconds` for the running items before flushing the outputs."""
+        self.builder.with_shutdown_timeout(seconds)
+        return self
+
+    def with_otlp_tracing(self, endpoint: str = "http://localhost:4318/v1/traces", service_name: str = "tweaktune"):
+        """Exports a span per item and per step (step name, LLMs used, tokens, outcome) to an
+        OTLP/HTTP collector such as Jaeger or Tempo."""
+        self.builder.with_otlp_tracing(endpoint, service_name)
+        return self
+
+    def with_step_timings(self, output: str = "step_timings"):
+        """Records the duration of each step in seconds under `output` and prints a timing summary after the run."""
+        self.builder.with_step_timings(output)
+        return self
+
     def from_yaml(self, path_or_url: str):
         # TODO: Implement fetch configuration from yaml
         return self
@@ -408,18 +600,50 @@ class Pipeline:
             self.builder.iter_by_range(iter_by.start, iter_by.stop, iter_by.step)
             self.graph.start = start_item("ITER-RANGE")
         elif iter_by.__class__ == IterBy.Dataset:
-            self.builder.iter_by_dataset(iter_by.name)
+            self.builder.iter_by_dataset(iter_by.name, iter_by.limit, iter_by.skip)
             self.graph.start = start_item("ITER-DATASET")
+        elif iter_by.__class__ == IterBy.Sample:
+            self.builder.iter_by_dataset_sample(iter_by.name, iter_by.n, iter_by.seed)
+            self.graph.start = start_item("ITER-SAMPLE")
+        elif iter_by.__class__ == IterBy.Zip:
+            self.builder.iter_by_zip(iter_by.names)
+            self.graph.start = start_item("ITER-ZIP")
+        elif iter_by.__class__ == IterBy.Product:
+            self.builder.iter_by_product(iter_by.names)
+            self.graph.start = start_item("ITER-PRODUCT")
         else:
             raise ValueError("Invalid IterBy type")
 
         return PipelineRunner(self.builder, self.graph)
 
-    def iter_dataset(self, name: str):
-        self.builder.iter_by_dataset(name)
+    def iter_dataset(self, name: str, limit: Optional[int] = None, skip: Optional[int] = None):
+        """Iterates over the rows of the dataset, skipping the first `skip` rows and stopping
+        after `limit` rows, e.g. to smoke-test a pipeline or resume at an offset."""
+        self.builder.iter_by_dataset(name, limit, skip)
         self.graph.start = start_item("ITER-DATASET")
         return PipelineRunner(self.builder, self.graph)
 
+    def iter_dataset_sample(self, name: str, n: int, seed: Optional[int] = None):
+        """Iterates over `n` random rows of the dataset, e.g. for calibration runs or quality probes.
+        The sample is reproducible with a `seed` (or the pipeline seed, see `with_seed`)."""
+        self.builder.iter_by_dataset_sample(name, n, seed)
+        self.graph.start = start_item("ITER-SAMPLE")
+        return PipelineRunner(self.builder, self.graph)
+
+    def iter_zip(self, names: List[str]):
+        """Iterates over the rows of the datasets side by side until the shortest one ends,
+        each item gets the row of every dataset under the dataset name."""
+        self.builder.iter_by_zip(names)
+        self.graph.start = start_item("ITER-ZIP")
+        return PipelineRunner(self.builder, self.graph)
+
+    def iter_product(self, names: List[str]):
+        """Iterates over every combination of rows of the datasets, e.g. persona x topic,
+        each item gets the row of every dataset under the dataset name."""
+        self.builder.iter_by_product(names)
+        self.graph.start = start_item("ITER-PRODUCT")
+        return PipelineRunner(self.builder, self.graph)
+
     def iter_range(self, *args, **kwargs):
         start = kwargs.get("start", 0)
         stop = kwargs.get("stop", 0)
@@ -457,6 +681,30 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def on_error(self, policy: str, max_attempts: int = 3, backoff_ms: int = 500):
+        """Sets what happens when the previous step raises an error: `fail` (default) fails the run,
+        `skip` continues with the item as it was before the step, `retry` re-runs the step up to
+        `max_attempts` times and `quarantine` marks the item as failed (see `with_quarantine`)."""
+        self.builder.set_error_policy(policy, max_attempts, backoff_ms)
+        return self
+
+    def dedup(
+        self,
+        inputs: List[str],
+        key: Optional[str] = None,
+        simhash_threshold: Optional[int] = None,
+    ):
+        """Skips the previous writer step for records whose `inputs` fields were already written,
+        by this or earlier runs (requires metadata). With `simhash_threshold` near duplicates
+        count as written too. Writers sharing a `key` share the written records."""
+        self.builder.set_writer_dedup(inputs, key, simhash_threshold)
+        return self
+
+    def io(self, inputs: Optional[List[str]] = None, outputs: Optional[List[str]] = None):
+        """Declares the context keys the previous step reads and writes, used by `with_dag`."""
+        self.builder.set_step_io(inputs or [], outputs or [])
+        return self
+
     def ifelse(
         self,
         condition: Union[Callable, str],
@@ -484,6 +732,130 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def switch(
+        self,
+        key: str,
+        cases: Dict[str, Chain],
+        default: Optional[Chain] = None,
+        name: str = "SWITCH",
+    ):
+        """Runs the chain of the case equal to the rendered `key` (an expression such as
+        `"category"` or a template), or `default`. Unmatched items pass through."""
+        self.builder.add_switch_step(
+            self.__name(name),
+            key,
+            {value: chain.steps_chain for value, chain in cases.items()},
+            default.steps_chain if default else None,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def parallel(self, branches: List[Tuple[Chain, List[str]]], name: str = "PARALLEL"):
+        """Runs the chains concurrently and copies the listed outputs of each back."""
+        self.builder.add_parallel_step(
+            self.__name(name),
+            [chain.steps_chain for chain, _ in branches],
+            [outputs for _, outputs in branches],
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def retry(
+        self,
+        chain: Chain,
+        max_attempts: int = 3,
+        backoff_ms: int = 500,
+        name: str = "RETRY",
+    ):
+        """Re-runs `chain` (up to `max_attempts` times) when it errors or fails the item."""
+        self.builder.add_retry_step(self.__name(name), chain.steps_chain, max_attempts, backoff_ms)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def cache(
+        self,
+        chain: Chain,
+        inputs: List[str] = None,
+        name: str = "CACHE",
+    ):
+        """Stores what `chain` adds to the item in the state and reuses it on later runs.
+
+        The key hashes the chain config, the templates it uses and `inputs` (the whole item when empty).
+        Requires metadata to be enabled.
+        """
+        self.builder.add_cache_step(self.__name(name), chain.steps_chain, inputs or [])
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def loop_until(
+        self,
+        condition: Union[Callable, str],
+        chain: Chain,
+        max_iters: int = 3,
+        name: str = "LOOP-UNTIL",
+    ):
+        """Re-runs `chain` until `condition` holds (the item fails after `max_iters` runs)."""
+        name = self.__name(name)
+        if callable(condition):
+            condition_func: Callable = condition
+            step = type(
+                name.replace("-", "_"),
+                (object,),
+                {"check": lambda self, context: condition_func(context)},
+            )()
+            self.builder.add_loop_step(
+                name, PyConditionWrapper(step), None, chain.steps_chain, max_iters
+            )
+        elif isinstance(condition, str):
+            self.builder.add_loop_step(name, None, condition, chain.steps_chain, max_iters)
+        else:
+            raise ValueError("Condition must be a callable or a string expression.")
+
+        self.graph.steps.append(step_item(name=name))
+        self.step_index += 1
+        return self
+
+    def foreach(
+        self,
+        input: str,
+        item_key: str,
+        chain: Chain,
+        output: str,
+        item_output: Optional[str] = None,
+        name: str = "FOREACH",
+    ):
+        """Runs `chain` for every element of the `input` list (available as `item_key`)
+        and collects `item_output` (defaults to `item_key`) of each run into `output`."""
+        self.builder.add_foreach_step(
+            self.__name(name), input, item_key, chain.steps_chain, output, item_output
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def explode(self, input: str, item_key: Optional[str] = None, name: str = "EXPLODE"):
+        """Runs the following steps (writers included) once per element of the `input` list,
+        available as `item_key` (defaults to `input`) with its position in `{item_key}_index`."""
+        self.builder.add_explode_step(self.__name(name), input, item_key or input)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def accumulate(
+        self, size: int, output: str, inputs: List[str] = None, name: str = "ACCUMULATE"
+    ):
+        """Collects items into batches of `size`, the following steps run once per batch with
+        the list of items (or only their `inputs` keys) in `output`. The last partial batch is
+        flushed at the end of the run."""
+        self.builder.add_accumulate_step(self.__name(name), size, output, inputs)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def map(self, func: Callable, name: str = "PY-MAP"):
         name = self.__name(name)
         step = type(
@@ -586,6 +958,26 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def translate(
+        self,
+        input: str,
+        output: str,
+        target_lang: str,
+        backend: str,
+        source_lang: str = "English",
+        max_tokens: int = None,
+        temperature: float = None,
+        name: str = "TRANSLATE",
+    ):
+        """Translates `input` to `target_lang` with a registered LLM or, when `backend` is a
+        seq2seq model name such as `mt5-small`, with the local (m)T5 model."""
+        self.builder.add_translate_step(
+            self.__name(name), input, output, target_lang, backend, source_lang, max_tokens, temperature
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def generate_json(
         self,
         template: str,
@@ -660,113 +1052,332 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
-    def render(self, template: str, output: str, name: str = "RENDER"):
-        self.builder.add_render_step(self.__name(name), template, output)
-        self.graph.steps.append(step_item(name=self.__name(name)))
-        self.step_index += 1
-        return self
-
-    def render_conversation(
+    def persona(
         self,
-        conversation: str,
         output: str,
-        tools: Optional[str] = None,
-        separator: Optional[str] = "|",
-        name: str = "RENDER-CONVERSATION",
+        attributes: List[str] = None,
+        custom: Dict[str, List[str]] = None,
+        seed: int = None,
+        name: str = "PERSONA",
     ):
-        self.builder.add_render_conversation_step(
-            self.__name(name), conversation, output, tools, separator
+        """Samples a persona into `output`: one value per built-in attribute in `attributes`
+        (all when neither `attributes` nor `custom` are given) plus the `custom` value lists.
+        `seed` makes the persona reproducible per item index."""
+        self.builder.add_persona_step(
+            self.__name(name), output, attributes or [], list((custom or {}).items()), seed
         )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def render_sft(
+    def choice(
         self,
-        conversation: str,
         output: str,
-        tools: Optional[str] = None,
-        separator: Optional[str] = "|",
-        name: str = "RENDER-SFT",
+        options: Union[List[str], Dict[str, float]],
+        weights: List[float] = None,
+        seed: int = None,
+        name: str = "CHOICE",
     ):
-        self.builder.add_render_sft_step(self.__name(name), conversation, output, tools, separator)
+        """Picks one of `options` (a list, or a dict of option to weight) into `output`,
+        with probability proportional to its weight (equal weights by default).
+        `seed` makes the choice reproducible per item index."""
+        if isinstance(options, dict):
+            options = list(options.items())
+        else:
+            weights = weights or [1.0] * len(options)
+            if len(weights) != len(options):
+                raise ValueError("weights must have the same length as options")
+            options = list(zip(options, weights))
+        self.builder.add_weighted_choice_step(self.__name(name), output, options, seed)
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def render_dpo(
+    def sample_negative_tools(
         self,
-        conversation: str,
+        dataset: str,
+        size: int,
+        gold: str,
         output: str,
-        chosen: str,
-        rejected: str,
-        tools: Optional[str] = None,
-        separator: Optional[str] = "|",
-        name: str = "RENDER-DPO",
+        seed: int = None,
+        name: str = "SAMPLE-NEGATIVE-TOOLS",
     ):
-        self.builder.add_render_dpo_step(
-            self.__name(name), conversation, output, chosen, rejected, tools, separator
+        """Samples `size` tools from `dataset` that match none of the gold tool calls or tools in
+        `gold` by name or signature, producing hard negatives for function calling."""
+        self.builder.add_negative_tool_sampler_step(
+            self.__name(name), dataset, size, gold, output, seed
         )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def render_grpo(
+    def render(self, template: str, output: str, name: str = "RENDER"):
+        self.builder.add_render_step(self.__name(name), template, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def render_conversation(
         self,
         conversation: str,
         output: str,
-        solution: str,
-        validator_id: str,
         tools: Optional[str] = None,
         separator: Optional[str] = "|",
-        name: str = "RENDER-GRPO",
+        name: str = "RENDER-CONVERSATION",
     ):
-        self.builder.add_render_grpo_step(
-            self.__name(name), conversation, output, solution, validator_id, tools, separator
+        self.builder.add_render_conversation_step(
+            self.__name(name), conversation, output, tools, separator
         )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def render_tool_call(
+    def truncate_conversation(
         self,
-        arguments: str,
-        output: str,
-        tool: str = None,
-        tool_name: str = None,
-        additional_template: Optional[str] = None,
-        name: str = "RENDER-TOOL-CALL",
+        input: str,
+        max_turns: int = None,
+        max_tokens: int = None,
+        tokenizer: str = None,
+        output: str = None,
+        name: str = "TRUNCATE-CONVERSATION",
     ):
-        if tool:
-            tool_name = f"{self.__name(name)}-TOOL"
-            self.builder.add_new_column_step(self.__name(name), tool, True, output=tool_name)
-
-        self.builder.add_render_tool_call_step(
-            self.__name(name), tool_name, arguments, output, additional_template
+        """Keeps the system message and the most recent turns of the conversation in `input`
+        so that at most `max_turns` turns and `max_tokens` tokens (needs `tokenizer`) remain."""
+        self.builder.add_truncate_conversation_step(
+            self.__name(name), input, output, max_turns, max_tokens, tokenizer
         )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def check_hash(self, input: str, name: str = "CHECK-HASH"):
-        self.builder.add_check_hash_step(self.__name(name), input)
+    def convert_format(
+        self,
+        input: str,
+        from_format: str,
+        to_format: str,
+        output: str,
+        name: str = "CONVERT-FORMAT",
+    ):
+        """Converts the conversation in `input` between the `openai`, `sharegpt`, `alpaca` and
+        `internal` (speaker/action) formats. Items the target format cannot hold fail."""
+        self.builder.add_convert_format_step(self.__name(name), input, from_format, to_format, output)
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def check_simhash(self, input: str, treshold: int = 3, name: str = "CHECK-SIMHASH"):
-        self.builder.add_check_simhash_step(self.__name(name), treshold, input)
+    def augment_conversation(
+        self,
+        input: str,
+        output: str,
+        system_prompts: List[str] = None,
+        shuffle_tools: bool = False,
+        llm: str = None,
+        paraphrase_template: str = None,
+        paraphrase_prob: float = 1.0,
+        seed: int = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.7,
+        name: str = "AUGMENT-CONVERSATION",
+    ):
+        """Produces a variant of the conversation in `input`: sets a system prompt picked from the
+        `system_prompts` templates, shuffles the tools and, with `llm`, paraphrases user turns
+        (the template gets the turn as `message`). `seed` makes the choices reproducible."""
+        self.builder.add_augment_conversation_step(
+            self.__name(name),
+            input,
+            output,
+            system_prompts or [],
+            shuffle_tools,
+            llm,
+            paraphrase_template,
+            paraphrase_prob,
+            seed,
+            max_tokens,
+            temperature,
+        )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def check_embedding(
+    def generate_dialogue(
         self,
-        input: str,
-        embedding: str,
-        treshold: int = 3,
-        similarity_output: str = None,
-        name: str = "CHECK-EMBEDDING",
+        llm: str,
+        output: str,
+        max_turns: int = 3,
+        user_llm: str = None,
+        instructions: str = None,
+        tools: str = None,
+        system_template: str = None,
+        simulate_tools: str = None,
+        openapi: str = None,
+        base_url: str = None,
+        max_tool_rounds: int = 3,
+        seed: int = None,
+        user_template: str = None,
+        assistant_template: str = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.7,
+        name: str = "GENERATE-DIALOGUE",
+    ):
+        """Generates a conversation of up to `max_turns` turns with `user_llm` playing the user
+        (guided by the `instructions` context key) and `llm` the assistant. With `tools` the
+        assistant may call them and `simulate_tools` (`fake`, `llm` or `http`) answers the calls."""
+        self.builder.add_dialogue_step(
+            self.__name(name),
+            llm,
+            output,
+            max_turns,
+            user_llm,
+            instructions,
+            tools,
+            system_template,
+            simulate_tools,
+            openapi,
+            base_url,
+            max_tool_rounds,
+            seed,
+            user_template,
+            assistant_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def simulate_tool_response(
+        self,
+        input: str,
+        openapi: str = None,
+        mode: str = "fake",
+        output: str = None,
+        llm: str = None,
+        base_url: str = None,
+        seed: int = None,
+        timeout_secs: int = 30,
+        max_tokens: int = 1024,
+        temperature: float = 0.7,
+        name: str = "SIMULATE-TOOL-RESPONSE",
+    ):
+        """Answers the pending tool calls of the conversation in `input` with `tool` messages,
+        using fake data from the `openapi` response schemas (`fake`), the `llm` (`llm`) or the
+        real API under `base_url` (`http`)."""
+        self.builder.add_simulate_tool_response_step(
+            self.__name(name),
+            input,
+            mode,
+            openapi,
+            output,
+            llm,
+            base_url,
+            seed,
+            timeout_secs,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def render_sft(
+        self,
+        conversation: str,
+        output: str,
+        tools: Optional[str] = None,
+        separator: Optional[str] = "|",
+        name: str = "RENDER-SFT",
+    ):
+        self.builder.add_render_sft_step(self.__name(name), conversation, output, tools, separator)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def render_dpo(
+        self,
+        conversation: str,
+        output: str,
+        chosen: str,
+        rejected: str,
+        tools: Optional[str] = None,
+        separator: Optional[str] = "|",
+        name: str = "RENDER-DPO",
+    ):
+        self.builder.add_render_dpo_step(
+            self.__name(name), conversation, output, chosen, rejected, tools, separator
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def render_grpo(
+        self,
+        conversation: str,
+        output: str,
+        solution: str,
+        validator_id: str,
+        tools: Optional[str] = None,
+        separator: Optional[str] = "|",
+        name: str = "RENDER-GRPO",
+    ):
+        self.builder.add_render_grpo_step(
+            self.__name(name), conversation, output, solution, validator_id, tools, separator
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def render_tool_call(
+        self,
+        arguments: str,
+        output: str,
+        tool: str = None,
+        tool_name: str = None,
+        additional_template: Optional[str] = None,
+        name: str = "RENDER-TOOL-CALL",
+    ):
+        if tool:
+            tool_name = f"{self.__name(name)}-TOOL"
+            self.builder.add_new_column_step(self.__name(name), tool, True, output=tool_name)
+
+        self.builder.add_render_tool_call_step(
+            self.__name(name), tool_name, arguments, output, additional_template
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def check_hash(self, input: str, name: str = "CHECK-HASH"):
+        self.builder.add_check_hash_step(self.__name(name), input)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def item_id(
+        self,
+        inputs: List[str],
+        output: str = "item_id",
+        unique: bool = False,
+        name: str = "ITEM-ID",
+    ):
+        """Sets a deterministic id hashed from the input fields, with `unique` drops items seen in earlier runs."""
+        self.builder.add_item_id_step(self.__name(name), inputs, output, unique)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def check_simhash(self, input: str, treshold: int = 3, name: str = "CHECK-SIMHASH"):
+        self.builder.add_check_simhash_step(self.__name(name), treshold, input)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def check_embedding(
+        self,
+        input: str,
+        embedding: str,
+        treshold: int = 3,
+        similarity_output: str = None,
+        name: str = "CHECK-EMBEDDING",
     ):
         self.builder.add_check_embeddings_step(
             self.__name(name), input, embedding, treshold, similarity_output
@@ -775,12 +1386,158 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def embed(
+        self,
+        input: str,
+        embedding: str,
+        output: str,
+        persist: bool = False,
+        name: str = "EMBED",
+    ):
+        self.builder.add_embed_step(self.__name(name), embedding, input, output, persist)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def filter_similarity(
+        self,
+        input: str,
+        references: Union[str, List[str]],
+        embedding: str,
+        min_similarity: Optional[float] = None,
+        max_similarity: Optional[float] = None,
+        similarity_output: Optional[str] = None,
+        name: str = "FILTER-SIMILARITY",
+    ):
+        if isinstance(references, str):
+            references = [references]
+        self.builder.add_similarity_filter_step(
+            self.__name(name),
+            embedding,
+            input,
+            references,
+            min_similarity,
+            max_similarity,
+            similarity_output,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def retrieve(
+        self,
+        query: str,
+        embedding: str,
+        key: str,
+        output: str,
+        k: int = 5,
+        name: str = "RETRIEVE",
+    ):
+        """Finds the `k` items nearest to `query` among vectors stored under `key`
+        (e.g. by `embed(..., persist=True)`) and writes them to `output`."""
+        self.builder.add_retrieve_step(self.__name(name), embedding, query, key, k, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def generate_grounded(
+        self,
+        dataset: str,
+        embedding: str,
+        query: str,
+        template: str,
+        llm: str,
+        output: str,
+        k: int = 3,
+        text_column: str = "text",
+        id_column: str = None,
+        min_similarity: float = None,
+        chunks: str = "chunks",
+        sources: str = "sources",
+        system_template: str = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.1,
+        name: str = "GENERATE-GROUNDED",
+    ):
+        """Generates `output` from the `k` chunks of `dataset` closest to `query`.
+
+        The chunks are available to the template as `chunks` and their ids are written to `sources`.
+        """
+        self.builder.add_grounded_generation_step(
+            self.__name(name),
+            dataset,
+            embedding,
+            query,
+            template,
+            llm,
+            output,
+            k,
+            text_column,
+            id_column,
+            min_similarity,
+            chunks,
+            sources,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def validate_json(self, schema: str, instance: str, name: str = "VALIDATE-JSON"):
         self.builder.add_validatejson_step(self.__name(name), schema, instance)
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
+    def extract_structured(
+        self,
+        schema: str,
+        input: str,
+        output: str,
+        coercions_output: str = None,
+        name: str = "EXTRACT-STRUCTURED",
+    ):
+        """Parses the JSON in `input` and validates it against `schema`, first fixing single
+        quotes, trailing commas and near-miss types such as `"5"` for an integer. The applied
+        coercions are written to `coercions_output`."""
+        self.builder.add_extract_structured_step(
+            self.__name(name), schema, input, output, coercions_output
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def repair_json(
+        self,
+        schema: str,
+        input: str,
+        llm: str,
+        output: str,
+        max_attempts: int = 2,
+        system_template: str = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.1,
+        name: str = "REPAIR-JSON",
+    ):
+        """Validates the JSON in `input` against `schema` and asks `llm` to fix it using the
+        validation errors, at most `max_attempts` times. The valid object goes to `output`."""
+        self.builder.add_repair_json_step(
+            self.__name(name),
+            schema,
+            input,
+            llm,
+            output,
+            max_attempts,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def validate_tools(self, instances: str, name: str = "VALIDATE-TOOLS"):
         self.builder.add_validatetools_step(self.__name(name), instances)
         self.graph.steps.append(step_item(name=self.__name(name)))
@@ -793,20 +1550,270 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
-    def normalize_tools(self, instances: str, output: str, name: str = "NORMALIZE-TOOLS"):
-        self.builder.add_normalizetools_step(self.__name(name), instances, output)
+    def normalize_tools(self, instances: str, output: str, name: str = "NORMALIZE-TOOLS"):
+        self.builder.add_normalizetools_step(self.__name(name), instances, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def into_list(self, inputs: List[str], output: str, name: str = "INTO-LIST"):
+        self.builder.add_into_list_step(self.__name(name), inputs, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def zip(self, inputs: Union[List[str], Dict[str, str]], output: str, name: str = "ZIP"):
+        """Zips equal-length lists into a list of objects. `inputs` maps object keys to list
+        fields (`{"question": "questions"}`), a plain list uses the field names as keys."""
+        if isinstance(inputs, dict):
+            pairs = list(inputs.items())
+        else:
+            pairs = [(input, input) for input in inputs]
+        self.builder.add_zip_step(self.__name(name), pairs, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def map_keys(self, mapping: Dict[str, str], copy: bool = False, name: str = "MAP-KEYS"):
+        """Renames context keys (`{from: to}`), with `copy=True` the source keys are kept."""
+        self.builder.add_map_keys_step(self.__name(name), list(mapping.items()), copy)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def select_keys(self, keys: List[str], name: str = "SELECT-KEYS"):
+        """Keeps only the listed columns in the context."""
+        self.builder.add_select_keys_step(self.__name(name), keys)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def drop_keys(self, keys: List[str], name: str = "DROP-KEYS"):
+        """Removes the listed columns from the context."""
+        self.builder.add_drop_keys_step(self.__name(name), keys)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def regex_extract(self, input: str, pattern: str, output: str, name: str = "REGEX-EXTRACT"):
+        """Extracts the first match of `pattern` from `input`, named groups become an object.
+        Items without a match are dropped."""
+        self.builder.add_regex_extract_step(self.__name(name), input, pattern, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def regex_replace(
+        self,
+        input: str,
+        replacements: Optional[Dict[str, str]] = None,
+        presets: Optional[List[str]] = None,
+        output: Optional[str] = None,
+        name: str = "REGEX-REPLACE",
+    ):
+        """Applies regex substitutions (`{pattern: replacement}`) to `input`, after the
+        `presets` ("code_fences", "chat_tokens", "whitespace"). Writes in place by default."""
+        self.builder.add_regex_replace_step(
+            self.__name(name),
+            input,
+            list((replacements or {}).items()),
+            presets or [],
+            output,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def cleanup(
+        self,
+        input: str,
+        output: str = None,
+        html: bool = True,
+        markdown: bool = True,
+        keep_headings: bool = False,
+        keep_lists: bool = True,
+        name: str = "CLEANUP",
+    ):
+        """Strips HTML tags and entities, flattens Markdown to plain text and normalizes
+        whitespace in `input`. Writes to `output` or back to `input`."""
+        self.builder.add_cleanup_step(
+            self.__name(name), input, output, html, markdown, keep_headings, keep_lists
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def redact_pii(
+        self,
+        input: str,
+        output: str,
+        entities: List[str] = None,
+        replacements: Dict[str, str] = None,
+        name: str = "PII-REDACTION",
+    ):
+        """Replaces emails, phones, IBANs, card numbers, national IDs and IPs in `input` with
+        tokens like `[EMAIL]`. `entities` limits the detectors, `replacements` overrides tokens."""
+        self.builder.add_pii_redaction_step(
+            self.__name(name), input, output, entities or [], replacements or {}
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def jsonpath(self, input: str, path: str, output: str, all: bool = False, name: str = "JSONPATH"):
+        """Selects the first value (or all values with `all=True`) matching the JSONPath
+        expression from the JSON in `input`. Items without a match are dropped."""
+        self.builder.add_jsonpath_step(self.__name(name), input, path, output, all)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def jq(self, filter: str, output: str, input: str = None, all: bool = False, name: str = "JQ"):
+        """Runs a jq filter over the JSON in `input` (the whole context when omitted) and
+        writes the first result (or all results with `all=True`) to `output`."""
+        self.builder.add_jq_step(self.__name(name), filter, output, input, all)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def sql(self, input: str, sql: str, output: str, name: str = "SQL"):
+        """Runs a Polars SQL query over the list of objects in `input` (registered as a
+        table named after `input`) and writes the resulting rows to `output`."""
+        self.builder.add_sql_step(self.__name(name), input, sql, output)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def shell(
+        self,
+        command: str,
+        output: str,
+        stdin: str = None,
+        exit_code_output: str = None,
+        timeout_secs: int = 60,
+        fail_on_error: bool = True,
+        name: str = "SHELL",
+    ):
+        """Runs the `command` template with `sh -c` and writes its stdout to `output`.
+        Requires `with_shell_commands()` on the pipeline."""
+        self.builder.add_shell_step(
+            self.__name(name),
+            command,
+            output,
+            stdin,
+            exit_code_output,
+            timeout_secs,
+            fail_on_error,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def execute_code(
+        self,
+        input: str,
+        output: str,
+        language: str = "python",
+        command: str = None,
+        stdin: str = None,
+        timeout_secs: int = 10,
+        memory_mb: int = None,
+        network: bool = False,
+        fail_on_error: bool = True,
+        name: str = "EXECUTE-CODE",
+    ):
+        """Runs the code in `input` (the first fenced block when present) in a temporary directory,
+        without network access unless `network` is set, and writes its stdout, stderr, exit code
+        and timeout flag to `output`. Requires `with_shell_commands()` on the pipeline. Cutting off
+        the network needs user namespaces (`unshare -rn`), otherwise adding the step raises."""
+        self.builder.add_execute_code_step(
+            self.__name(name),
+            input,
+            output,
+            language,
+            command,
+            stdin,
+            timeout_secs,
+            memory_mb,
+            network,
+            fail_on_error,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def run_code_tests(
+        self,
+        code: str,
+        tests: str,
+        output: str,
+        language: str = "python",
+        command: str = None,
+        timeout_secs: int = 30,
+        memory_mb: int = None,
+        network: bool = False,
+        name: str = "RUN-CODE-TESTS",
+    ):
+        """Runs the `tests` field against the `code` field in the `execute_code` sandbox and keeps
+        only passing pairs. `output` gets `{passed, results, stdout, stderr, exit_code, timed_out}`.
+        Requires `with_shell_commands()` on the pipeline."""
+        self.builder.add_code_tests_step(
+            self.__name(name),
+            code,
+            tests,
+            output,
+            language,
+            command,
+            timeout_secs,
+            memory_mb,
+            network,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def token_count(self, tokenizer: str, input: str, output: str, name: str = "TOKEN-COUNT"):
+        """Writes the number of tokens in `input` (using a registered tokenizer) to `output`."""
+        self.builder.add_token_count_step(self.__name(name), tokenizer, input, output)
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def into_list(self, inputs: List[str], output: str, name: str = "INTO-LIST"):
-        self.builder.add_into_list_step(self.__name(name), inputs, output)
+    def truncate_tokens(
+        self,
+        tokenizer: str,
+        input: str,
+        max_tokens: int,
+        strategy: str = "head",
+        output: str = None,
+        marker: str = " ... ",
+        name: str = "TRUNCATE-TOKENS",
+    ):
+        """Truncates `input` to `max_tokens` tokens keeping the `head`, the `tail` or both
+        ends (`middle`, joined with `marker`). Writes to `output` or back to `input`."""
+        self.builder.add_truncate_tokens_step(
+            self.__name(name), tokenizer, input, max_tokens, strategy, output, marker
+        )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
 
-    def chunk(self, capacity: Tuple[int, int], input: str, output: str, name: str = "CHUNK"):
-        self.builder.add_chunk_step(self.__name(name), capacity, input, output)
+    def chunk(
+        self,
+        capacity: Tuple[int, int],
+        input: str,
+        output: str,
+        tokenizer: str = None,
+        embedding: str = None,
+        threshold: float = 0.75,
+        name: str = "CHUNK",
+    ):
+        """Splits `input` into chunks sized in characters, or in tokens when a registered
+        `tokenizer` is given. With `embedding` chunks are cut where the similarity between
+        neighbouring sentences drops below `threshold`."""
+        self.builder.add_chunk_step(
+            self.__name(name), capacity, input, output, tokenizer, embedding, threshold
+        )
         self.graph.steps.append(step_item(name=self.__name(name)))
         self.step_index += 1
         return self
@@ -848,6 +1855,164 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def annotate_rewards(
+        self,
+        output: str = "rewards",
+        rewards: Optional[Dict[str, str]] = None,
+        judge_llm: Optional[str] = None,
+        judges: Optional[Dict[str, str]] = None,
+        weights: Optional[Dict[str, float]] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.0,
+        name: str = "ANNOTATE-REWARDS",
+    ):
+        """Stores scalar rewards in the `output` object: heuristics from `rewards`
+        (`field:<path>`, `length_penalty:<field>:<max>`, `regex:<field>:<pattern>`), scores of the
+        `judges` rubric templates asked with `judge_llm` and a weighted `total` with `weights`."""
+        self.builder.add_rewards_step(
+            self.__name(name),
+            output,
+            list((rewards or {}).items()),
+            judge_llm,
+            list((judges or {}).items()),
+            list((weights or {}).items()),
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def generate_preference_pair(
+        self,
+        template: str,
+        llms: Union[str, List[str]],
+        output: str,
+        samples: int = 1,
+        judge_llm: Optional[str] = None,
+        judge_template: Optional[str] = None,
+        language: Optional[str] = None,
+        min_margin: float = 0.0,
+        system_template: Optional[str] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.8,
+        name: str = "PREFERENCE-PAIR",
+    ):
+        """Writes a `{prompt, chosen, rejected}` pair to `output`. Without `judge_llm` the answer
+        of the first of two `llms` is chosen and of the second rejected, with it `samples` answers
+        per llm are scored and the best and worst ones (at least `min_margin` apart) form the pair."""
+        self.builder.add_preference_pair_step(
+            self.__name(name),
+            template,
+            [llms] if isinstance(llms, str) else llms,
+            output,
+            samples,
+            judge_llm,
+            judge_template,
+            language,
+            min_margin,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def self_consistency(
+        self,
+        template: str,
+        llm: str,
+        output: str,
+        samples: int = 5,
+        json_path: Optional[str] = None,
+        pattern: Optional[str] = None,
+        agreement_output: Optional[str] = None,
+        system_template: Optional[str] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.8,
+        name: str = "SELF-CONSISTENCY",
+    ):
+        """Generates `samples` answers and keeps the majority one in `output`.
+        Answers are read from `json_path` of a JSON response or the first group of `pattern`."""
+        self.builder.add_self_consistency_step(
+            self.__name(name),
+            template,
+            llm,
+            output,
+            samples,
+            json_path,
+            pattern,
+            agreement_output,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def judge(
+        self,
+        template: str,
+        llm: str,
+        output: str,
+        rationale_output: Optional[str] = None,
+        threshold: Optional[float] = None,
+        system_template: Optional[str] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.0,
+        name: str = "JUDGE",
+    ):
+        """Scores the item with the `template` rubric, items scoring below `threshold` are dropped."""
+        self.builder.add_judge_step(
+            self.__name(name),
+            template,
+            llm,
+            output,
+            rationale_output,
+            threshold,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def judge_pairwise(
+        self,
+        template: str,
+        llm: str,
+        candidate_a: str,
+        candidate_b: str,
+        output: str,
+        confidence_output: Optional[str] = None,
+        swap: bool = True,
+        system_template: Optional[str] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.0,
+        name: str = "JUDGE-PAIRWISE",
+    ):
+        """Asks the judge which candidate is better, `output` is set to "A", "B" or "tie".
+        The template sees the candidates as `candidate_a` and `candidate_b`."""
+        self.builder.add_pairwise_judge_step(
+            self.__name(name),
+            template,
+            llm,
+            candidate_a,
+            candidate_b,
+            output,
+            confidence_output,
+            swap,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def validate(self, py_func, name: str = "VALIDATE"):
         self.builder.add_py_validator_step(self.__name(name), PyStepValidatorWrapper(py_func))
         self.graph.steps.append(step_item(name=self.__name(name)))
@@ -869,19 +2034,352 @@ class PipelineRunner:
         self.step_index += 1
         return self
 
+    def check_length(
+        self,
+        input: Union[str, List[str]],
+        min_chars: int = None,
+        max_chars: int = None,
+        min_words: int = None,
+        max_words: int = None,
+        min_tokens: int = None,
+        max_tokens: int = None,
+        tokenizer: str = None,
+        name: str = "CHECK-LENGTH",
+    ):
+        """Drops items whose `input` field(s) are shorter or longer than the given character,
+        word or token bounds. Token bounds need a tokenizer registered with `with_tokenizer`."""
+        inputs = [input] if isinstance(input, str) else list(input)
+        self.builder.add_check_length_step(
+            self.__name(name),
+            inputs,
+            min_chars,
+            max_chars,
+            min_words,
+            max_words,
+            min_tokens,
+            max_tokens,
+            tokenizer,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def check_grounding(
+        self,
+        input: str,
+        sources: Union[str, List[str]],
+        min_ratio: float = 1.0,
+        min_overlap: float = 0.8,
+        output: str = None,
+        name: str = "CHECK-GROUNDING",
+    ):
+        """Drops items whose `input` answer makes claims not found in the `sources` field(s).
+
+        Quoted spans must appear verbatim, other sentences need `min_overlap` of their words in
+        the sources and may only cite (`[id]`) provided chunks. The grounding ratio and the
+        ungrounded claims are written to `output`."""
+        sources = [sources] if isinstance(sources, str) else list(sources)
+        self.builder.add_check_grounding_step(
+            self.__name(name), input, sources, min_ratio, min_overlap, output
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def verify_math(
+        self,
+        input: str,
+        expected: str = None,
+        expression: str = None,
+        tolerance: float = 1e-6,
+        output: str = None,
+        name: str = "VERIFY-MATH",
+    ):
+        """Drops items whose worked solution in `input` ends with a wrong answer.
+
+        The final answer (`\\boxed{}`, `####`, "The answer is", or the last number) is compared with
+        the `expected` field (a number or a reference solution) or the value of the arithmetic
+        `expression` field, within the relative `tolerance`."""
+        self.builder.add_verify_math_step(
+            self.__name(name), input, expected, expression, tolerance, output
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_embeddings(
+        self,
+        path: str,
+        input: str,
+        vector: str,
+        format: str = "parquet",
+        name: str = "WRITE-EMBEDDINGS",
+    ):
+        """Exports (item_id, text, vector) rows at the end of the run.
+        `format` is `parquet`, `ipc` (LanceDB compatible) or `fvecs` (FAISS)."""
+        self.builder.add_write_embeddings_step(self.__name(name), path, format, input, vector)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def group_by(
+        self,
+        key: str,
+        path: str,
+        aggregations: Dict[str, str],
+        name: str = "GROUP-BY",
+    ):
+        """Groups items by `key` over the whole run and writes one JSON line per group to `path`
+        at the end. `aggregations` maps output names to `count`, `concat:<field>` or
+        `sample:<field>[:<size>]`."""
+        self.builder.add_group_by_step(self.__name(name), key, path, list(aggregations.items()))
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def write_jsonl(
         self,
         path: str,
         template: Optional[str] = None,
         value: Optional[str] = "output",
+        max_lines: Optional[int] = None,
+        max_bytes: Optional[int] = None,
+        compression: Optional[str] = None,
+        flush_interval: float = 1.0,
+        op_config: Optional[dict] = None,
+        mode: str = "append",
+        manifest: bool = False,
         name: str = "WRITE-JSONL",
     ):
-        self.builder.add_write_jsonl_step(self.__name(name), path, template, value)
+        """Appends items to a JSONL file. With `max_lines` or `max_bytes` the output rolls over to numbered shards.
+        `mode` is append, overwrite or error-if-exists, with `manifest` the output is described in `<path>.manifest.json`.
+        `compression` (gzip or zstd) defaults to the one implied by a `.gz`/`.zst` extension.
+        Lines are buffered in `<path>.partial`, flushed every `flush_interval` seconds and moved to `path` when the run finishes.
+        With `op_config` (a storage config like in `with_templates`) the output is uploaded instead, appending
+        downloads an existing object and uploads it again ahead of the new lines."""
+        op_config_str: Optional[str] = (
+            json.dumps(op_config, ensure_ascii=False) if op_config else None
+        )
+        self.builder.add_write_jsonl_step(
+            self.__name(name),
+            path,
+            template,
+            value,
+            max_lines,
+            max_bytes,
+            compression,
+            flush_interval,
+            op_config_str,
+            mode,
+            manifest,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        return self
+
+    def write_py(
+        self,
+        sink,
+        columns: Optional[List[str]] = None,
+        on_finish: Optional[Callable] = None,
+        name: str = "WRITE-PY",
+    ):
+        """Streams every item (or its `columns`) as a dict to `sink`, a function or a queue with `put`.
+        `on_finish` is called once the run is done, e.g. to log a collected table."""
+        self.builder.add_py_writer_step(
+            self.__name(name), PyWriterWrapper(sink, on_finish), columns
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def tee(self, sinks: Callable[["PipelineRunner"], Any], name: str = "TEE"):
+        """Writes every item to all sinks added by `sinks` in a single step,
+        e.g. `lambda p: p.write_jsonl("out.jsonl").write_ipc("out.arrow").print()`.
+        Only writer, `print` and `dump` steps are accepted."""
+        start = self.builder.steps_count()
+        sinks(self)
+        self.builder.add_tee_step(self.__name(name), start)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def subpipeline(
+        self,
+        child: Union["Pipeline", "PipelineRunner"],
+        outputs: List[str],
+        name: str = "SUBPIPELINE",
+    ):
+        """Runs the steps of the `child` pipeline, with its own datasets and LLMs, for each item
+        and copies its `outputs` back to the item. The child's iteration is ignored and the child
+        can't be used afterwards."""
+        self.builder.add_subpipeline_step(self.__name(name), child.builder, outputs)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_ipc(
+        self,
+        path: str,
+        columns: Optional[List[str]] = None,
+        op_config: Optional[dict] = None,
+        name: str = "WRITE-IPC",
+    ):
+        """Writes `columns` (the whole item by default) to an Arrow IPC (Feather) file at the end of the run."""
+        op_config_str: Optional[str] = (
+            json.dumps(op_config, ensure_ascii=False) if op_config else None
+        )
+        self.builder.add_write_ipc_step(self.__name(name), path, columns, op_config_str)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_sqlite(
+        self,
+        path: str,
+        table: str,
+        columns: Union[List[str], Dict[str, str]],
+        name: str = "WRITE-SQLITE",
+    ):
+        """Inserts a row per item into `table` of the SQLite database at `path`.
+        `columns` maps table columns to item keys, a list uses the same names for both."""
+        if not isinstance(columns, dict):
+            columns = {column: column for column in columns}
+        self.builder.add_write_sqlite_step(self.__name(name), path, table, list(columns.items()))
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_postgres(
+        self,
+        conn_str: str,
+        table: str,
+        columns: Union[List[str], Dict[str, str]],
+        batch_size: int = 100,
+        conflict_columns: Optional[List[str]] = None,
+        conflict_action: str = "update",
+        name: str = "WRITE-POSTGRES",
+    ):
+        """Inserts items into an existing PostgreSQL `table` in batches of `batch_size`.
+        `columns` maps table columns to item keys, a list uses the same names for both.
+        With `conflict_columns` conflicting rows are updated (`conflict_action="update"`)
+        or skipped (`"nothing"`)."""
+        if not isinstance(columns, dict):
+            columns = {column: column for column in columns}
+        self.builder.add_write_postgres_step(
+            self.__name(name),
+            conn_str,
+            table,
+            list(columns.items()),
+            batch_size,
+            conflict_columns,
+            conflict_action,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_http(
+        self,
+        url: str,
+        template: Optional[str] = None,
+        columns: Optional[List[str]] = None,
+        headers: Optional[Dict[str, str]] = None,
+        batch_size: int = 1,
+        max_attempts: int = 3,
+        backoff_ms: int = 500,
+        timeout: int = 30,
+        name: str = "WRITE-HTTP",
+    ):
+        """POSTs every item as JSON to `url`: the rendered `template`, the item `columns` or the whole item.
+        With `batch_size` above 1 the items are sent as JSON arrays. `headers` are added to every
+        request, e.g. `{"Authorization": "Bearer ..."}`. Connection errors, 429 and 5xx responses
+        are retried up to `max_attempts` times with exponential backoff."""
+        self.builder.add_write_http_step(
+            self.__name(name),
+            url,
+            template,
+            columns,
+            list((headers or {}).items()),
+            batch_size,
+            max_attempts,
+            backoff_ms,
+            timeout,
+        )
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
+    def write_kafka(
+        self,
+        brokers: Union[str, List[str]],
+        topic: str,
+        template: Optional[str] = None,
+        columns: Optional[List[str]] = None,
+        key: Optional[str] = None,
+        batch_size: int = 10000,
+        linger_ms: int = 5,
+        config: Optional[Dict[str, str]] = None,
+        name: str = "WRITE-KAFKA",
+    ):
+        """Publishes every item as a JSON message to the Kafka `topic`: the rendered `template`, the item `columns` or the whole item.
+        `key` is an inline template of the message key, e.g. `"{{item_id}}"`. Messages are batched up to
+        `batch_size` waiting at most `linger_ms`. `config` adds librdkafka settings, e.g. `security.protocol`.
+        Needs tweaktune built with the `kafka` feature, otherwise a `ValueError` is raised."""
+        if not isinstance(brokers, str):
+            brokers = ",".join(brokers)
+        self.builder.add_write_kafka_step(
+            self.__name(name),
+            brokers,
+            topic,
+            template,
+            columns,
+            key,
+            batch_size,
+            linger_ms,
+            list((config or {}).items()),
+        )
         self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
         return self
 
-    def write_csv(self, path: str, columns: List[str], delimeter: str, name: str = "WRITE-JSONL"):
-        self.builder.add_write_csv_step(self.__name(name), path, columns, delimeter)
+    def write_csv(
+        self,
+        path: str,
+        columns: List[str],
+        delimeter: str = ",",
+        compression: Optional[str] = None,
+        flush_interval: float = 1.0,
+        op_config: Optional[dict] = None,
+        header: bool = False,
+        quoting: str = "necessary",
+        quote_char: str = '"',
+        escape_char: Optional[str] = None,
+        mode: str = "append",
+        templates: Optional[Dict[str, str]] = None,
+        name: str = "WRITE-CSV",
+    ):
+        """Writes `columns` of every item as a CSV line, a column listed in `templates` is rendered with its template.
+        `quoting` is necessary, always, non_numeric or never; quotes are doubled unless `escape_char` is set.
+        The `header` is written when the output starts empty, `mode` is append or overwrite. With `op_config`
+        appending downloads an existing object and uploads it again ahead of the new lines."""
+        op_config_str: Optional[str] = (
+            json.dumps(op_config, ensure_ascii=False) if op_config else None
+        )
+        self.builder.add_write_csv_step(
+            self.__name(name),
+            path,
+            columns,
+            delimiter=delimeter,
+            header=header,
+            quoting=quoting,
+            quote=quote_char,
+            escape=escape_char,
+            mode=mode,
+            templates=templates or {},
+            compression=compression,
+            flush_interval=flush_interval,
+            op_config=op_config_str,
+        )
         self.graph.steps.append(step_item(name=self.__name(name)))
         return self
 
@@ -896,6 +2394,19 @@ class PipelineRunner:
         self.graph.steps.append(step_item(name=self.__name(name)))
         return self
 
+    def dump(
+        self,
+        dir: str,
+        condition: Optional[str] = None,
+        failed: bool = False,
+        name: str = "DUMP",
+    ):
+        """Writes items matching the `condition` template (and failed items with `failed`) as JSON files to `dir`."""
+        self.builder.add_dump_step(self.__name(name), dir, condition, failed)
+        self.graph.steps.append(step_item(name=self.__name(name)))
+        self.step_index += 1
+        return self
+
     def debug(self, target: str = None):
         self.log(LogLevel.DEBUG.value, target)
         return self
@@ -906,13 +2417,104 @@ class PipelineRunner:
         self.logger = True
         return self
 
-    def run(self):
+    def run(self, bus=None, profile: bool = False):
+        """Runs the pipeline and returns a `RunResult` with `status` ("completed" or "interrupted"),
+        `processed` and `failed` counts. Ctrl-C stops new items and flushes the outputs,
+        a second Ctrl-C exits immediately. `bus`, a queue or a callback, receives the
+        `PipelineEvent`s of the run. `profile` prints the time and allocations of each step
+        at the end."""
+        if not self.logger:
+            self.log(LogLevel.ERROR.value, None)
+            self.logger = True
+
+        self.builder.compile()
+        return self.builder.run(bus, profile)
+
+    def run_until(
+        self, n: int, max_iterations: Optional[int] = None, bus=None, profile: bool = False
+    ):
+        """Runs an `iter_range` pipeline until `n` items come out valid (no step failed or filtered
+        them), drawing as many iterations as needed, at most `max_iterations`, instead of a fixed
+        range. No more than `n` valid items are written. Returns a `RunResult`, its report has the
+        `valid` count and the `iterations` drawn."""
+        if not self.logger:
+            self.log(LogLevel.ERROR.value, None)
+            self.logger = True
+
+        self.builder.compile()
+        return self.builder.run_until(n, max_iterations, bus, profile)
+
+    def sweep(self, grid: Dict[str, List[Any]], report: Optional[str] = None):
+        """Runs the pipeline once for every combination of the `grid` values, available to templates
+        as `params`; `temperature` and `model` also set those of the API LLMs. File writers, nested
+        and sub-pipeline ones included, and the quarantine add the combination to their file names
+        (`qa_temperature-0.2.jsonl`). Returns the `RunResult`s and
+        writes a comparison of the runs to `report`, by default under `sweeps/` of the metadata
+        directory."""
+        if not self.logger:
+            self.log(LogLevel.ERROR.value, None)
+            self.logger = True
+
+        self.builder.compile()
+        return self.builder.sweep(
+            [(key, json.dumps(list(values), ensure_ascii=False)) for key, values in grid.items()],
+            report,
+        )
+
+    def pause(self):
+        """Starts no new items until `resume`, the running ones finish. Useful from a bus
+        callback to let a struggling endpoint recover."""
+        self.builder.pause()
+        return self
+
+    def resume(self):
+        self.builder.resume()
+        return self
+
+    def run_async(self, bus=None, profile: bool = False):
+        """Starts the pipeline on a background thread and returns a `RunHandle` right away:
+        `status()`, `progress()` (processed, failed and elapsed seconds), `wait(timeout=None)`
+        returning the `RunResult`, `cancel()` and `done()`. `bus` and `profile` as for `run`."""
         if not self.logger:
             self.log(LogLevel.ERROR.value, None)
             self.logger = True
 
         self.builder.compile()
-        return self.builder.run()
+        return self.builder.spawn_run(bus, profile)
+
+    def cluster_embeddings(
+        self,
+        key: str,
+        k: int,
+        max_iter: Optional[int] = None,
+        seed: Optional[int] = None,
+        output_path: Optional[str] = None,
+        output_field: str = "cluster",
+    ) -> int:
+        """Clusters the embeddings stored for `key` after the run (requires metadata).
+        When `output_path` points to a JSONL output, rows get the cluster id in `output_field`."""
+        return self.builder.cluster_embeddings(
+            key, k, max_iter, seed, output_path, output_field
+        )
+
+    def compile(self):
+        """Compiles the templates and checks the LLMs, datasets, embeddings, tokenizers and
+        templates the steps name and that the outputs are writable, raising a `ValueError`
+        with all the problems found. `run` does it before starting."""
+        self.builder.compile()
+        return self
+
+    def to_config(self, path: Optional[str] = None, format: Optional[str] = None) -> str:
+        """Serializes the configured pipeline with its steps and settings to YAML or JSON, secrets
+        are redacted. The format follows the extension of `path`, written when given."""
+        if format is None:
+            format = "json" if path is not None and path.endswith(".json") else "yaml"
+        graph = json.dumps(self.graph.model_dump(), default=config_value)
+        config = self.builder.to_config(graph, format)
+        if path is not None:
+            with open(path, "w") as f:
+                f.write(config)
+        return config
 
     def ui(self, host: str = "0.0.0.0", port: int = 8080):
         self.builder.compile()
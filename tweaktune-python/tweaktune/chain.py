@@ -5,7 +5,7 @@ from pydantic import BaseModel
 
 from tweaktune.common import StepStatus
 from tweaktune.tweaktune import StepsChain
-from tweaktune.wrappers import PyStepWrapper
+from tweaktune.wrappers import PyStepValidatorWrapper, PyStepWrapper
 
 
 class Chain:
@@ -80,6 +80,32 @@ class Chain:
         self.step_index += 1
         return self
 
+    def judge(
+        self,
+        template: str,
+        llm: str,
+        output: str,
+        rationale_output: Optional[str] = None,
+        threshold: Optional[float] = None,
+        system_template: Optional[str] = None,
+        max_tokens: int = 1024,
+        temperature: float = 0.0,
+        name: str = "JUDGE",
+    ):
+        self.steps_chain.add_judge_step(
+            self.__name(name),
+            template,
+            llm,
+            output,
+            rationale_output,
+            threshold,
+            system_template,
+            max_tokens,
+            temperature,
+        )
+        self.step_index += 1
+        return self
+
     def print(self, *args, **kwargs):
         template = kwargs.get("template", None)
         columns = kwargs.get("columns", None)
@@ -105,6 +131,11 @@ class Chain:
         self.step_index += 1
         return self
 
+    def validate(self, py_func, name: str = "VALIDATE"):
+        self.steps_chain.add_py_validator_step(self.__name(name), PyStepValidatorWrapper(py_func))
+        self.step_index += 1
+        return self
+
     def add_column(self, output: str, func: Union[Callable, str], name: str = "PY-ADD-COLUMN"):
         if callable(func):
 
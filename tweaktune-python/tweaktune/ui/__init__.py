@@ -5,7 +5,7 @@ import threading
 
 from nicegui import ui
 
-from tweaktune import Graph
+from tweaktune import Graph, PipelineEvent
 
 
 def renger_graph_section(graph, section: str) -> str:
@@ -123,6 +123,7 @@ def run_ui(builder, graph, host: str = "0.0.0.0", port: int = 8080):
     )
 
     def run_builder_thread(bus):
+        builder.load_datasets(bus)
         builder.run(bus)
 
     def run_builder():
@@ -266,19 +267,21 @@ def run_ui(builder, graph, host: str = "0.0.0.0", port: int = 8080):
 
     def check_bus():
         while not bus.empty():
-            message = bus.get()
-            if message is None:
+            event = bus.get()
+            if event is None:
                 break
-            m = json.loads(message)
-            if m["event_type"] == "log":
-                log.push(m["data"])
-            elif m["event_type"] == "progress":
-                data = m["data"]
-                progress.value = data["index"] + 1
-                progress.props(f"max={data['total']}")
-            elif m["event_type"] == "finished":
-                ui.notify(m["data"]["message"])
-                data = m["data"]
+            if isinstance(event, PipelineEvent.Log):
+                log.push(event.message)
+            elif isinstance(event, PipelineEvent.DatasetLoaded):
+                log.push(f"Loaded dataset {event.name} ({event.rows} rows)")
+            elif isinstance(event, PipelineEvent.RunStarted):
+                progress.value = 0
+                if event.total is not None:
+                    progress.props(f"max={event.total}")
+            elif isinstance(event, (PipelineEvent.ItemCompleted, PipelineEvent.ItemFailed)):
+                progress.value += 1
+            elif isinstance(event, PipelineEvent.RunFinished):
+                ui.notify(event.status.capitalize())
                 progress.props("color=blue")
                 progress.props(":thickness=1")
 
@@ -132,6 +132,24 @@ class MistralrsWrapper(LLMWrapper):
         return res.choices[0].message.content
 
 
+class PyWriterWrapper:
+    def __init__(self, sink, on_finish=None):
+        self.sink = sink
+        self.on_finish = on_finish
+
+    def process(self, record):
+        record = json.loads(record)
+        # queues get the record, anything else is called with it
+        if hasattr(self.sink, "put"):
+            self.sink.put(record)
+        else:
+            self.sink(record)
+
+    def finish(self):
+        if self.on_finish is not None:
+            self.on_finish()
+
+
 class PyStepValidatorWrapper:
     def __init__(self, func):
         self.func = func